@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fs::OpenOptions as FileOpenOptions,
+    hash::Hasher,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use fnv::FnvHasher;
+
+use crate::{
+    db::{meta_page_buf, OpenOptions, DB},
+    errors::{Error, Result},
+    page::PageID,
+};
+
+const MAGIC: &[u8; 8] = b"jammincr";
+
+// The magic, then the transaction the backup starts after, the transaction it ends at,
+// the pagesize, the number of pages in the database and the number of pages in the backup
+const HEADER_SIZE: usize = 48;
+
+// The pages written by each recent commit, so a backup can copy only the pages that changed.
+#[derive(Default)]
+pub(crate) struct PageHistory {
+    // Every commit after this transaction is in commits
+    start: u64,
+    commits: VecDeque<(u64, Vec<PageID>)>,
+}
+
+impl PageHistory {
+    // Forgets every commit, for when the file is changed by something other than a commit.
+    pub(crate) fn reset(&mut self, tx_id: u64) {
+        self.start = tx_id;
+        self.commits.clear();
+    }
+
+    // Adds a commit, only keeping the most recent `limit` of them.
+    pub(crate) fn record(&mut self, tx_id: u64, pages: Vec<PageID>, limit: u64) {
+        self.commits.push_back((tx_id, pages));
+        while self.commits.len() as u64 > limit {
+            if let Some((tx_id, _)) = self.commits.pop_front() {
+                self.start = tx_id;
+            }
+        }
+    }
+
+    // Every page written after since_tx_id up to and including tx_id, or None if some of those commits weren't kept.
+    fn changed(&self, since_tx_id: u64, tx_id: u64) -> Option<BTreeSet<PageID>> {
+        if since_tx_id < self.start || since_tx_id > tx_id {
+            return None;
+        }
+        Some(
+            self.commits
+                .iter()
+                .filter(|(id, _)| *id > since_tx_id && *id <= tx_id)
+                .flat_map(|(_, pages)| pages.iter().copied())
+                .collect(),
+        )
+    }
+}
+
+// Keeps a running hash of everything that goes through the stream,
+// so a backup that was cut short or damaged is caught before it is applied.
+struct Hashed<S> {
+    stream: S,
+    hasher: FnvHasher,
+}
+
+impl<S> Hashed<S> {
+    fn new(stream: S) -> Hashed<S> {
+        Hashed {
+            stream,
+            hasher: FnvHasher::default(),
+        }
+    }
+}
+
+impl<W: Write> Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.write(buf);
+        self.stream.write_all(buf)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.stream.read_exact(buf)?;
+        self.hasher.write(buf);
+        Ok(())
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.read(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+// Writes every page that changed after since_tx_id, or every page if it is 0, returning the transaction it ends at.
+pub(crate) fn write_incremental<W: Write>(db: &DB, since_tx_id: u64, w: &mut W) -> Result<u64> {
+    let tx = db.tx(false)?;
+    let tx = tx.inner.borrow();
+    let (meta, pages) = (&tx.meta, &tx.pages);
+    let page_ids: BTreeSet<PageID> = if since_tx_id == 0 {
+        (0..meta.num_pages).collect()
+    } else {
+        let mut changed = db
+            .inner
+            .page_history
+            .lock()?
+            .changed(since_tx_id, meta.tx_id)
+            .ok_or(Error::HistoryUnavailable)?;
+        changed.insert(meta.meta_page as PageID);
+        changed
+    };
+
+    let pagesize = meta.pagesize;
+    let mut w = Hashed::new(w);
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    for n in [
+        since_tx_id,
+        meta.tx_id,
+        pagesize,
+        meta.num_pages,
+        page_ids.len() as u64,
+    ] {
+        header.extend_from_slice(&n.to_be_bytes());
+    }
+    w.write(&header)?;
+    for page_id in page_ids {
+        w.write(&page_id.to_be_bytes())?;
+        if page_id < 2 {
+            // A commit since this transaction started can have written a newer meta page to the file,
+            // so the meta pages always come from this transaction's own meta
+            w.write(&meta_page_buf(meta, page_id))?;
+        } else {
+            let start = (page_id * pagesize) as usize;
+            w.write(&pages.data[start..start + pagesize as usize])?;
+        }
+    }
+    let hash = w.hasher.finish();
+    w.stream.write_all(&hash.to_be_bytes())?;
+    Ok(meta.tx_id)
+}
+
+// Writes the pages from a backup into the file at path, returning the transaction the file is now at.
+pub(crate) fn apply_incremental<R: Read>(path: &Path, r: &mut R) -> Result<u64> {
+    let mut r = Hashed::new(r);
+    let mut magic = [0; 8];
+    r.read(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::InvalidDB(String::from(
+            "Stream is not an incremental backup",
+        )));
+    }
+    let since_tx_id = r.read_u64()?;
+    let tx_id = r.read_u64()?;
+    let pagesize = r.read_u64()?;
+    let num_pages = r.read_u64()?;
+    let count = r.read_u64()?;
+    if since_tx_id != 0 {
+        let base = OpenOptions::new()
+            .pagesize(pagesize)
+            .read_only(true)
+            .open(path)?;
+        let base_tx_id = base.inner.meta()?.tx_id;
+        if base_tx_id != since_tx_id {
+            return Err(Error::InvalidDB(format!(
+                "Backup starts after transaction {}, but the database is at transaction {}",
+                since_tx_id, base_tx_id
+            )));
+        }
+    }
+
+    let mut file = FileOpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(since_tx_id == 0)
+        .open(path)?;
+    if file.metadata()?.len() < num_pages * pagesize {
+        file.set_len(num_pages * pagesize)?;
+    }
+    // The meta pages are written last, so the file doesn't point at any of the new pages
+    // until all of them are in place and the whole backup has checked out.
+    let mut meta_pages = Vec::new();
+    let mut buf = vec![0; pagesize as usize];
+    for _ in 0..count {
+        let page_id = r.read_u64()?;
+        r.read(&mut buf)?;
+        if page_id >= num_pages {
+            return Err(Error::InvalidDB(format!(
+                "Backup page {} is out of bounds",
+                page_id
+            )));
+        }
+        if page_id < 2 {
+            meta_pages.push((page_id, buf.clone()));
+            continue;
+        }
+        file.seek(SeekFrom::Start(page_id * pagesize))?;
+        file.write_all(&buf)?;
+    }
+    let mut hash = [0; 8];
+    r.stream.read_exact(&mut hash)?;
+    if u64::from_be_bytes(hash) != r.hasher.finish() {
+        return Err(Error::InvalidDB(String::from("Backup is corrupt")));
+    }
+    file.sync_all()?;
+    for (page_id, buf) in meta_pages {
+        file.seek(SeekFrom::Start(page_id * pagesize))?;
+        file.write_all(&buf)?;
+    }
+    file.sync_all()?;
+    Ok(tx_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::RandomFile;
+
+    #[test]
+    fn test_backup_incremental() -> Result<()> {
+        let random_file = RandomFile::new();
+        let restored = RandomFile::new();
+        let db = OpenOptions::new().keep_freed_for(5).open(&random_file)?;
+        let commit = |range: std::ops::Range<u64>| -> Result<()> {
+            let tx = db.tx(true)?;
+            let b = tx.get_or_create_bucket("abc")?;
+            for i in range {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()
+        };
+        commit(0..5000)?;
+
+        let mut base = Vec::new();
+        let tx_id = db.backup_incremental(0, &mut base)?;
+        commit(5000..5010)?;
+        commit(0..10)?;
+        let mut delta = Vec::new();
+        let new_tx_id = db.backup_incremental(tx_id, &mut delta)?;
+        assert_eq!(new_tx_id, tx_id + 2);
+        // only the pages that changed are in it
+        assert!(delta.len() < base.len() / 2);
+
+        // a delta has to go on top of the backup it was taken since
+        assert!(matches!(
+            DB::restore_incremental(&restored, &mut delta.as_slice()),
+            Err(Error::Io(_))
+        ));
+        assert_eq!(
+            DB::restore_incremental(&restored, &mut base.as_slice())?,
+            tx_id
+        );
+        let mut corrupt = delta.clone();
+        corrupt[100] ^= 1;
+        assert_eq!(
+            DB::restore_incremental(&restored, &mut corrupt.as_slice()),
+            Err(Error::InvalidDB(String::from("Backup is corrupt")))
+        );
+        assert_eq!(
+            DB::restore_incremental(&restored, &mut delta.as_slice())?,
+            new_tx_id
+        );
+        {
+            let restored = DB::open(&restored)?;
+            restored.check()?;
+            assert!(restored.logically_eq(&db)?);
+        }
+        assert_eq!(
+            DB::restore_incremental(&restored, &mut delta.as_slice()),
+            Err(Error::InvalidDB(format!(
+                "Backup starts after transaction {}, but the database is at transaction {}",
+                tx_id, new_tx_id
+            )))
+        );
+
+        // nothing changed, so only the meta page is in it
+        assert_eq!(
+            db.backup_incremental(new_tx_id, &mut Vec::new())?,
+            new_tx_id
+        );
+        // only the last five commits are kept
+        for i in 0..5 {
+            commit(i..i + 1)?;
+        }
+        assert_eq!(
+            db.backup_incremental(new_tx_id, &mut Vec::new())?,
+            new_tx_id + 5
+        );
+        commit(0..1)?;
+        assert_eq!(
+            db.backup_incremental(new_tx_id, &mut Vec::new()),
+            Err(Error::HistoryUnavailable)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_incremental_without_history() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        db.set_user_version(1)?;
+        let tx_id = db.backup_incremental(0, &mut Vec::new())?;
+        db.set_user_version(2)?;
+        assert_eq!(
+            db.backup_incremental(tx_id, &mut Vec::new()),
+            Err(Error::HistoryUnavailable)
+        );
+        assert_eq!(
+            db.backup_incremental(tx_id + 1, &mut Vec::new())?,
+            tx_id + 1
+        );
+        Ok(())
+    }
+
+    // Commits while the backup is being written, once the header has gone through
+    struct CommitDuring<'a> {
+        db: &'a DB,
+        buf: Vec<u8>,
+    }
+
+    impl Write for CommitDuring<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.buf.is_empty() {
+                // two commits, so both meta pages in the file are newer than the backup
+                for i in 0..2_u64 {
+                    let tx = self.db.tx(true).unwrap();
+                    tx.get_bucket("abc")
+                        .unwrap()
+                        .put(i.to_be_bytes(), "new")
+                        .unwrap();
+                    tx.commit().unwrap();
+                }
+            }
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backup_incremental_commit_during() -> Result<()> {
+        let random_file = RandomFile::new();
+        let restored = RandomFile::new();
+        // big enough that the commits during the backup don't need to grow the file
+        let db = OpenOptions::new().num_pages(100).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), "old")?;
+            }
+            tx.commit()?;
+        }
+        let mut w = CommitDuring {
+            db: &db,
+            buf: Vec::new(),
+        };
+        let tx_id = db.backup_incremental(0, &mut w)?;
+        assert_eq!(db.tx(false)?.inner.borrow().meta.tx_id, tx_id + 2);
+        assert_eq!(
+            DB::restore_incremental(&restored, &mut w.buf.as_slice())?,
+            tx_id
+        );
+        let restored = DB::open(&restored)?;
+        restored.check()?;
+        let tx = restored.tx(false)?;
+        assert_eq!(tx.inner.borrow().meta.tx_id, tx_id);
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.kv_pairs().count(), 100);
+        assert!(b.kv_pairs().all(|kv| kv.value() == b"old"));
+        Ok(())
+    }
+}
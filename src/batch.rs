@@ -0,0 +1,451 @@
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex, PoisonError,
+    },
+    thread,
+};
+
+use crate::{
+    db::DB,
+    errors::{Error, Result},
+    tx::Tx,
+};
+
+struct BatchOp {
+    path: Vec<Vec<u8>>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    done: Sender<Result<()>>,
+}
+
+/// A handle for combining writes from many callers into shared transactions.
+///
+/// Created with [`DB::batch_writer`]. Each call to [`put`](#method.put) is sent to a background thread,
+/// which applies every write that is waiting in a single transaction and commits it once.
+/// While one batch is being committed, new writes queue up for the next one,
+/// so the cost of syncing the file is spread across all of them.
+///
+/// The handle can be cloned and sent to other threads. The background thread stops once every handle has been dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// use jammdb::{DB};
+/// # use jammdb::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let db = DB::open("my.db")?;
+/// let writer = db.batch_writer();
+///
+/// let handles: Vec<_> = (0..10_u64)
+///     .map(|i| {
+///         let writer = writer.clone();
+///         std::thread::spawn(move || writer.put(&["users"], i.to_be_bytes(), "data"))
+///     })
+///     .collect();
+/// for h in handles {
+///     h.join().unwrap()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct BatchWriter {
+    sender: Sender<BatchOp>,
+}
+
+impl BatchWriter {
+    pub(crate) fn new(db: DB) -> BatchWriter {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(db, receiver));
+        BatchWriter { sender }
+    }
+
+    /// Puts a key / value pair into the bucket at the given path, creating the buckets if needed.
+    ///
+    /// Blocks until the batch containing this write has been committed.
+    ///
+    /// # Errors
+    ///
+    /// Will return any error from [`Tx::get_or_create_bucket_path`](struct.Tx.html#method.get_or_create_bucket_path)
+    /// or [`Bucket::put`](struct.Bucket.html#method.put) for this write, or the error from committing the batch.
+    /// A write that fails leaves nothing behind, not even the buckets it created,
+    /// and does not stop the rest of the batch from being committed.
+    /// If the background thread has stopped, an [`Io`](enum.Error.html#variant.Io) error
+    /// with the kind [`BrokenPipe`](std::io::ErrorKind::BrokenPipe) is returned.
+    pub fn put<P: AsRef<[u8]>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        path: &[P],
+        key: K,
+        value: V,
+    ) -> Result<()> {
+        let (done, result) = channel();
+        let op = BatchOp {
+            path: path.iter().map(|p| p.as_ref().to_vec()).collect(),
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+            done,
+        };
+        self.sender.send(op).map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+}
+
+// The background thread only goes away if it panicked, since every handle keeps it running
+fn stopped() -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "batch writer stopped",
+    ))
+}
+
+pub(crate) type BatchFn = Box<dyn Fn(&Tx) -> Result<()> + Send>;
+
+struct BatchCall {
+    f: BatchFn,
+    done: Sender<Result<()>>,
+}
+
+// The closures waiting to be run by DB::batch. Whichever caller takes the leader lock runs everything
+// that's queued in one transaction, while new calls queue up behind it for the next leader.
+#[derive(Default)]
+pub(crate) struct BatchQueue {
+    calls: Mutex<Vec<BatchCall>>,
+    leader: Mutex<()>,
+}
+
+pub(crate) fn batch(db: &DB, f: BatchFn) -> Result<()> {
+    let (done, result) = channel();
+    db.inner.batch.calls.lock()?.push(BatchCall { f, done });
+    {
+        // Nothing is guarded by the lock itself, so a leader that panicked didn't leave anything half done
+        let _leader = db
+            .inner
+            .batch
+            .leader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // The last leader may have run our call already. If not, it's still in the queue,
+        // since a leader always finishes every call it takes before letting go of the lock.
+        if let Ok(result) = result.try_recv() {
+            return result;
+        }
+        let calls = mem::take(&mut *db.inner.batch.calls.lock()?);
+        run_calls(db, calls);
+    }
+    result
+        .recv()
+        .map_err(|_| Error::Sync("batch leader panicked"))?
+}
+
+// Runs every call in one transaction. If one fails, the transaction is thrown away,
+// that call is run again in a transaction of its own, and the rest are tried again without it.
+fn run_calls(db: &DB, mut calls: Vec<BatchCall>) {
+    while !calls.is_empty() {
+        let tx = match db.tx(true) {
+            Ok(tx) => tx,
+            Err(e) => {
+                for call in calls {
+                    let _ = call.done.send(Err(e.duplicate()));
+                }
+                return;
+            }
+        };
+        // Also whether the call that failed panicked
+        let failed = calls.iter().enumerate().find_map(|(index, call)| {
+            match panic::catch_unwind(AssertUnwindSafe(|| (call.f)(&tx))) {
+                Ok(Ok(())) => None,
+                Ok(Err(_)) => Some((index, false)),
+                Err(_) => Some((index, true)),
+            }
+        });
+        match failed {
+            None => {
+                let result = tx.commit();
+                for call in calls {
+                    let _ = call
+                        .done
+                        .send(result.as_ref().map_err(Error::duplicate).copied());
+                }
+                return;
+            }
+            Some((index, panicked)) => {
+                drop(tx);
+                let call = calls.remove(index);
+                // A closure that panicked would most likely panic again, so don't run it on its own
+                let result = if panicked {
+                    Err(Error::Sync(PANICKED))
+                } else {
+                    db.tx(true).and_then(|tx| {
+                        run_call(&call, &tx)?;
+                        tx.commit()
+                    })
+                };
+                let _ = call.done.send(result);
+            }
+        }
+    }
+}
+
+const PANICKED: &str = "batch closure panicked";
+
+// Runs a caller's closure, turning a panic into an error for that caller,
+// so it doesn't take down the leader that is running everyone else's calls too.
+fn run_call(call: &BatchCall, tx: &Tx) -> Result<()> {
+    match panic::catch_unwind(AssertUnwindSafe(|| (call.f)(tx))) {
+        Ok(result) => result,
+        Err(_) => Err(Error::Sync(PANICKED)),
+    }
+}
+
+fn run(db: DB, receiver: Receiver<BatchOp>) {
+    // Wait for at least one write, then grab everything else that's already waiting
+    while let Ok(op) = receiver.recv() {
+        let mut ops = vec![op];
+        ops.extend(receiver.try_iter());
+        run_ops(&db, ops);
+    }
+}
+
+// Applies every write in one transaction. A write that fails may have already created some of the buckets
+// in its path, so the transaction is thrown away and the rest are tried again without it.
+fn run_ops(db: &DB, mut ops: Vec<BatchOp>) {
+    while !ops.is_empty() {
+        let tx = match db.tx(true) {
+            Ok(tx) => tx,
+            Err(e) => {
+                for op in ops {
+                    let _ = op.done.send(Err(e.duplicate()));
+                }
+                return;
+            }
+        };
+        let results: Vec<Result<()>> = ops
+            .iter()
+            .map(|op| {
+                tx.get_or_create_bucket_path(&op.path)
+                    .and_then(|b| b.put(op.key.as_slice(), op.value.as_slice()).map(|_| ()))
+            })
+            .collect();
+        if results.iter().all(Result::is_ok) {
+            let result = tx.commit();
+            for op in ops {
+                let _ = op
+                    .done
+                    .send(result.as_ref().map_err(Error::duplicate).copied());
+            }
+            return;
+        }
+        drop(tx);
+        let mut remaining = Vec::with_capacity(ops.len());
+        for (op, result) in ops.into_iter().zip(results) {
+            match result {
+                Ok(()) => remaining.push(op),
+                Err(e) => {
+                    let _ = op.done.send(Err(e));
+                }
+            }
+        }
+        ops = remaining;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::RandomFile;
+
+    #[test]
+    fn test_batch_writer() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let writer = db.batch_writer();
+        let handles: Vec<_> = (0..20_u64)
+            .map(|i| {
+                let writer = writer.clone();
+                thread::spawn(move || writer.put(&["a", "b"], i.to_be_bytes(), i.to_be_bytes()))
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("a")?.put("kv", "value")?;
+            tx.commit()?;
+        }
+        // a bad path only fails that write
+        assert_eq!(
+            writer.put(&["a", "kv"], "key", "value"),
+            Err(Error::IncompatibleValue)
+        );
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket_path(&["a", "b"])?;
+        assert_eq!(b.kv_pairs().count(), 20);
+        for i in 0..20_u64 {
+            assert_eq!(b.get_kv(i.to_be_bytes()).unwrap().value(), i.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_writer_failed_put() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = crate::OpenOptions::new()
+            .utf8_keys(true)
+            .open(&random_file)?;
+        // the path is created before the key is rejected, but the bucket isn't committed with the rest of the batch
+        let (done, failed) = channel();
+        let (other_done, other) = channel();
+        let ops = vec![
+            BatchOp {
+                path: vec![b"new".to_vec()],
+                key: vec![0xC3, 0x28],
+                value: b"value".to_vec(),
+                done,
+            },
+            BatchOp {
+                path: vec![b"other".to_vec()],
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+                done: other_done,
+            },
+        ];
+        run_ops(&db, ops);
+        assert_eq!(failed.recv().unwrap(), Err(Error::NonUtf8Key));
+        assert_eq!(other.recv().unwrap(), Ok(()));
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("new").err(), Some(Error::BucketMissing));
+        assert_eq!(
+            tx.get_bucket("other")?.get_kv("key").unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_writer_stopped() {
+        let (sender, receiver) = channel();
+        drop(receiver);
+        let writer = BatchWriter { sender };
+        match writer.put(&["abc"], "key", "value") {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            result => panic!("Expected a BrokenPipe error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_batch() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let commits = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        {
+            let commits = commits.clone();
+            db.on_commit(std::sync::Arc::new(move |_| {
+                commits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }))?;
+        }
+        // start every call at once, so they have to queue up behind each other
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(50));
+        let handles: Vec<_> = (0..50_u64)
+            .map(|i| {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    db.batch(move |tx| {
+                        // give the others time to queue up behind this batch
+                        thread::sleep(std::time::Duration::from_millis(1));
+                        let b = tx.get_or_create_bucket("abc")?;
+                        // a failing call doesn't keep the rest from being committed
+                        if i % 10 == 0 {
+                            return Err(Error::KeyValueMissing);
+                        }
+                        b.put(i.to_be_bytes(), i.to_be_bytes())?;
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        for (i, h) in handles.into_iter().enumerate() {
+            let result = h.join().unwrap();
+            match i % 10 {
+                0 => assert_eq!(result, Err(Error::KeyValueMissing)),
+                _ => assert_eq!(result, Ok(())),
+            }
+        }
+        // the 45 calls that succeeded shared commits
+        let commits = commits.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(commits < 45, "{} commits", commits);
+
+        // a call that only fails alongside another one succeeds when it's run on its own
+        let (first, first_result) = channel();
+        let (second, second_result) = channel();
+        let calls = vec![
+            BatchCall {
+                f: Box::new(|tx| {
+                    tx.get_bucket("abc")?.put("other", "value")?;
+                    Ok(())
+                }),
+                done: first,
+            },
+            BatchCall {
+                f: Box::new(|tx| {
+                    let b = tx.get_bucket("abc")?;
+                    if b.get_kv("other").is_some() {
+                        return Err(Error::KeyValueMissing);
+                    }
+                    b.put("mine", "value")?;
+                    Ok(())
+                }),
+                done: second,
+            },
+        ];
+        run_calls(&db, calls);
+        assert_eq!(first_result.recv().unwrap(), Ok(()));
+        assert_eq!(second_result.recv().unwrap(), Ok(()));
+
+        // a panic is only that caller's error, and isn't run again
+        let (first, first_result) = channel();
+        let (second, second_result) = channel();
+        let calls = vec![
+            BatchCall {
+                f: Box::new(|_| panic!("oops")),
+                done: first,
+            },
+            BatchCall {
+                f: Box::new(|tx| {
+                    tx.get_bucket("abc")?.put("after-panic", "value")?;
+                    Ok(())
+                }),
+                done: second,
+            },
+        ];
+        run_calls(&db, calls);
+        assert_eq!(
+            first_result.recv().unwrap(),
+            Err(Error::Sync("batch closure panicked"))
+        );
+        assert_eq!(second_result.recv().unwrap(), Ok(()));
+        // and doesn't stop later batches
+        assert_eq!(
+            db.batch(|_| panic!("oops")),
+            Err(Error::Sync("batch closure panicked"))
+        );
+        db.batch(|tx| {
+            tx.get_bucket("abc")?.put("later", "value")?;
+            Ok(())
+        })?;
+
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.kv_pairs().count(), 49);
+        for i in (0..50_u64).filter(|i| i % 10 != 0) {
+            assert_eq!(b.get_kv(i.to_be_bytes()).unwrap().value(), i.to_be_bytes());
+        }
+        Ok(())
+    }
+}
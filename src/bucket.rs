@@ -1,20 +1,23 @@
 use std::{
     cell::{RefCell, RefMut},
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
     marker::PhantomData,
     mem::{align_of, size_of},
-    ops::RangeBounds,
+    ops::{Bound, ControlFlow, RangeBounds},
     rc::Rc,
 };
 
 use crate::{
     bytes::{Bytes, ToBytes},
     cursor::{search, Cursor, Range, ToBuckets, ToKVPairs},
-    data::{Data, KVPair},
+    data::{ChangeKind, Data, KVPair, OwnedKVPair},
+    db::VERSION,
     errors::{Error, Result},
     freelist::TxFreelist,
-    node::{Leaf, Node, NodeData, NodeID},
-    page::{Page, PageID, Pages},
+    node::{Leaf, Node, NodeData, NodeID, LEAF_SIZE},
+    page::{LeafElement, Page, PageID, Pages, CHECKSUM_SIZE},
     page_node::{PageNode, PageNodeID},
     BucketName,
 };
@@ -82,6 +85,9 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     /// Adds to or replaces key / value data in the bucket.
     /// Returns an error if the key currently exists but is a bucket instead of a key / value pair.
     ///
+    /// Values may be empty, which is useful for using a bucket as a set.
+    /// An empty value is still stored, so [`get`](#method.get) will return it instead of `None`.
+    ///
     /// # Examples
     ///
     /// ```no_run
@@ -101,6 +107,10 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     /// // update data
     /// bucket.put("123", "789")?;
     ///
+    /// // store a key with an empty value
+    /// bucket.put("present", [])?;
+    /// assert_eq!(bucket.get_kv("present").unwrap().value(), b"");
+    ///
     /// bucket.create_bucket("nested-bucket")?;
     ///
     /// assert!(bucket.put("nested-bucket", "data").is_err());
@@ -123,26 +133,15 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
         Ok(b.put(key, value)?.map(|v| v.into()))
     }
 
-    pub fn get<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<Data<'b, 'tx>> {
-        let mut b = self.inner.borrow_mut();
-        if b.deleted {
-            panic!("Cannot get data from a deleted bucket.");
-        }
-        b.get(key).map(|data| data.into())
-    }
-
-    pub fn get_kv<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<KVPair<'b, 'tx>> {
-        let mut b = self.inner.borrow_mut();
-        if b.deleted {
-            panic!("Cannot get data from a deleted bucket.");
-        }
-        match b.get(key) {
-            Some(data) => data.into(),
-            None => None,
-        }
-    }
-
-    /// Deletes a key / value pair from the bucket
+    /// Adds key / value data to the bucket, replacing a nested bucket with the same name if there is one.
+    ///
+    /// Unlike [`put`](#method.put), which returns an error if the key belongs to a nested bucket,
+    /// this **deletes** that bucket and everything in it, then stores the key / value pair in its place.
+    /// This is meant for migrations that deliberately change the type of a key.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction.
     ///
     /// # Examples
     ///
@@ -152,77 +151,90 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     ///
     /// # fn main() -> Result<(), Error> {
     /// let db = DB::open("my.db")?;
-    /// let mut tx = db.tx(false)?;
+    /// let mut tx = db.tx(true)?;
     ///
-    /// let bucket = tx.get_bucket("my-bucket")?;
-    /// // check if data is there
-    /// assert!(bucket.get_kv("some-key").is_some());
-    /// // delete the key / value pair
-    /// bucket.delete("some-key")?;
-    /// // data should no longer exist
-    /// assert!(bucket.get_kv("some-key").is_none());
+    /// let bucket = tx.create_bucket("my-bucket")?;
+    /// bucket.create_bucket("settings")?;
+    /// // settings are now stored in a single value
+    /// bucket.put_force("settings", "{}")?;
+    /// assert!(bucket.get_kv("settings").is_some());
     ///
     /// # Ok(())
     /// # }
     /// ```
-    pub fn delete<T: AsRef<[u8]>>(&self, key: T) -> Result<KVPair> {
+    pub fn put_force<T: ToBytes<'tx>, S: ToBytes<'tx>>(&self, key: T, value: S) -> Result<()> {
         if !self.writable {
             return Err(Error::ReadOnlyTx);
         }
+        let key = key.to_bytes();
+        let mut freelist = self.freelist.borrow_mut();
         let mut b = self.inner.borrow_mut();
         if b.deleted {
-            panic!("Cannot delete data from a deleted bucket.");
+            panic!("Cannot put data into a deleted bucket.");
         }
-        Ok(b.delete(key)?.into())
+        b.check_key(key.as_ref())?;
+        match b.delete_bucket(&key, &mut freelist) {
+            // the key was a bucket, or is not a bucket, either way it's ready for the new value
+            Ok(()) | Err(Error::BucketMissing) | Err(Error::IncompatibleValue) => (),
+            Err(e) => return Err(e),
+        }
+        b.put(key, value)?;
+        Ok(())
     }
 
-    /// Gets an already created bucket.
+    /// Adds to or replaces key / value data in the bucket, reading the value from `reader`.
     ///
-    /// Returns an error if
-    /// 1. the given key does not exist
-    /// 2. the key is for key / value data, not a bucket
+    /// The reader is read until it runs out, and everything it returned becomes the value.
+    /// Returns the number of bytes in the value. This is meant for storing large values
+    /// from files or sockets without building them yourself first, but the whole value is still
+    /// buffered in memory until the transaction is committed.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction,
+    /// an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the key is a nested bucket,
+    /// or an [`Io`](enum.Error.html#variant.Io) error if the reader fails, in which case nothing is stored.
     ///
     /// # Examples
     ///
     /// ```no_run
+    /// use std::fs::File;
     /// use jammdb::{DB};
     /// # use jammdb::Error;
     ///
     /// # fn main() -> Result<(), Error> {
     /// let db = DB::open("my.db")?;
-    /// let mut tx = db.tx(false)?;
-    ///
-    /// // get a root-level bucket
-    /// let bucket = tx.get_bucket("my-bucket")?;
-    ///
-    /// // get nested bucket
-    /// let mut sub_bucket = bucket.get_bucket("nested-bucket")?;
+    /// let mut tx = db.tx(true)?;
     ///
-    /// // get nested bucket
-    /// let sub_sub_bucket = sub_bucket.get_bucket("double-nested-bucket")?;
+    /// let bucket = tx.create_bucket("files")?;
+    /// let size = bucket.put_reader("photo.jpg", File::open("photo.jpg")?)?;
+    /// assert_eq!(bucket.get_kv("photo.jpg").unwrap().value().len(), size);
     ///
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
-        let mut b = self.inner.borrow_mut();
-        if b.deleted {
-            panic!("Cannot get bucket from a deleted bucket.");
+    pub fn put_reader<T: ToBytes<'tx>, R: Read>(&self, key: T, mut reader: R) -> Result<usize> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
         }
-        let inner = b.get_bucket(name)?;
-        Ok(Bucket {
-            inner,
-            freelist: self.freelist.clone(),
-            writable: self.writable,
-            _phantom: PhantomData,
-        })
+        if self.inner.borrow().deleted {
+            panic!("Cannot put data into a deleted bucket.");
+        }
+        let mut value = Vec::new();
+        let size = reader.read_to_end(&mut value)?;
+        self.put(key, value)?;
+        Ok(size)
     }
 
-    /// Creates a new bucket.
+    /// Adds key / value data to the bucket only if the key does not already exist.
     ///
-    /// Returns an error if
-    /// 1. the given key already exists
-    /// 2. It is in a read-only transaction
+    /// The value is produced by calling `f`, which is only called if the key is missing,
+    /// so you don't pay to build a value that would be thrown away.
+    /// Returns whether the value was inserted. If the key already exists, as either key / value data
+    /// or a nested bucket, nothing is changed and `false` is returned.
+    /// Any error returned by `f` is passed along and nothing is inserted.
+    ///
+    /// `f` is called while the bucket is being modified, so it must not use this bucket.
     ///
     /// # Examples
     ///
@@ -234,39 +246,44 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     /// let db = DB::open("my.db")?;
     /// let mut tx = db.tx(true)?;
     ///
-    /// // create a root-level bucket
     /// let bucket = tx.create_bucket("my-bucket")?;
     ///
-    /// // create nested bucket
-    /// let mut sub_bucket = bucket.create_bucket("nested-bucket")?;
-    ///
-    /// // create nested bucket
-    /// let mut sub_sub_bucket = sub_bucket.create_bucket("double-nested-bucket")?;
+    /// // the value is only built the first time
+    /// assert!(bucket.put_if_absent_with("key", || Ok("expensive value".to_string()))?);
+    /// assert!(!bucket.put_if_absent_with("key", || Ok("expensive value".to_string()))?);
     ///
     /// # Ok(())
     /// # }
     /// ```
-    pub fn create_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
+    pub fn put_if_absent_with<T, F, S>(&self, key: T, f: F) -> Result<bool>
+    where
+        T: ToBytes<'tx>,
+        F: FnOnce() -> Result<S>,
+        S: ToBytes<'tx>,
+    {
         if !self.writable {
             return Err(Error::ReadOnlyTx);
         }
         let mut b = self.inner.borrow_mut();
         if b.deleted {
-            panic!("Cannot create bucket in a deleted bucket.");
+            panic!("Cannot put data into a deleted bucket.");
         }
-        let inner = b.create_bucket(name)?;
-        Ok(Bucket {
-            inner,
-            freelist: self.freelist.clone(),
-            writable: self.writable,
-            _phantom: PhantomData,
-        })
+        b.put_if_absent_with(key, f)
     }
 
-    /// Creates a new bucket if it doesn't exist
+    /// Adds `delta` to the counter stored at `key`, and returns its new value.
     ///
-    /// Returns an error if
-    /// 1. It is in a read-only transaction
+    /// The counter is stored as a big-endian `u64`, the same as `value.to_be_bytes()`,
+    /// and a key that doesn't exist yet is treated as a counter at zero.
+    /// Reading the old value and writing the new one happen with a single lookup,
+    /// and since only one writable transaction can be open at a time, no other increment can sneak in between.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction,
+    /// an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the key is a nested bucket
+    /// or its value isn't exactly eight bytes long, or a [`CounterOverflow`](enum.Error.html#variant.CounterOverflow) error
+    /// if the new value would be negative or larger than `u64::MAX`. The stored value is left alone on an error.
     ///
     /// # Examples
     ///
@@ -276,48 +293,35 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     ///
     /// # fn main() -> Result<(), Error> {
     /// let db = DB::open("my.db")?;
-    /// {
-    ///     let mut tx = db.tx(true)?;
-    ///     // create a root-level bucket
-    ///     let bucket = tx.get_or_create_bucket("my-bucket")?;
-    ///     tx.commit()?;
-    /// }
-    /// {
-    ///     let mut tx = db.tx(true)?;
-    ///     // get the existing a root-level bucket
-    ///     let bucket = tx.get_or_create_bucket("my-bucket")?;
-    /// }
+    /// let mut tx = db.tx(true)?;
     ///
+    /// let bucket = tx.get_or_create_bucket("stats")?;
+    /// assert_eq!(bucket.increment("page-views", 1)?, 1);
+    /// assert_eq!(bucket.increment("page-views", 10)?, 11);
+    /// assert_eq!(bucket.increment("page-views", -1)?, 10);
+    /// assert_eq!(bucket.get_kv("page-views").unwrap().as_u64(), Some(10));
     /// # Ok(())
     /// # }
-    /// ```    
-    pub fn get_or_create_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
+    /// ```
+    pub fn increment<T: AsRef<[u8]>>(&self, key: T, delta: i64) -> Result<u64> {
         if !self.writable {
             return Err(Error::ReadOnlyTx);
         }
         let mut b = self.inner.borrow_mut();
         if b.deleted {
-            panic!("Cannot get or create bucket from a deleted bucket.");
+            panic!("Cannot put data into a deleted bucket.");
         }
-        let inner = b.get_or_create_bucket(name)?;
-        Ok(Bucket {
-            inner,
-            freelist: self.freelist.clone(),
-            writable: self.writable,
-            _phantom: PhantomData,
-        })
+        b.increment(key, delta)
     }
 
-    /// Deletes an bucket.
+    /// Adds every key / value pair from an iterator to the bucket, as if calling [`put`](#method.put) for each one.
     ///
-    /// Returns an error if
-    /// 1. the given key does not exist
-    /// 2. the key is for key / value data, not a bucket
-    /// 3. It is in a read-only transaction
+    /// Stops at the first error and returns it. Any pairs added before the error stay in the bucket.
     ///
     /// # Examples
     ///
     /// ```no_run
+    /// use std::collections::BTreeMap;
     /// use jammdb::{DB};
     /// # use jammdb::Error;
     ///
@@ -325,35 +329,121 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     /// let db = DB::open("my.db")?;
     /// let mut tx = db.tx(true)?;
     ///
-    /// // get a root-level bucket
-    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// let mut map = BTreeMap::new();
+    /// map.insert("a", "1");
+    /// map.insert("b", "2");
     ///
-    /// // delete nested bucket
-    /// bucket.delete_bucket("nested-bucket")?;
+    /// let bucket = tx.create_bucket("my-bucket")?;
+    /// bucket.try_extend(map)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_extend<I, K, V>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToBytes<'tx>,
+        V: ToBytes<'tx>,
+    {
+        for (k, v) in iter {
+            self.put(k, v)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the data at the given key, or `None` if the key does not exist.
+    ///
+    /// The pair isn't checked against the checksum stored with it
+    /// (see [`OpenOptions::value_checksums`](struct.OpenOptions.html#method.value_checksums)),
+    /// use [`try_get`](#method.try_get) for that.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this bucket has been deleted, or if a branch on the way to the key is corrupt.
+    /// Use [`try_get`](#method.try_get) to get those as errors instead.
+    pub fn get<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<Data<'b, 'tx>> {
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot get data from a deleted bucket.");
+        }
+        match b.get(key, false) {
+            Ok(data) => data.map(|data| data.into()),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Gets the data at the given key, without panicking if this bucket has been deleted.
+    ///
+    /// This is the same as [`get`](#method.get), returning `Ok(None)` if the key doesn't exist,
+    /// but for code that might be holding on to a bucket after it was deleted, or reading a file that may be corrupt.
+    /// Unlike `get`, a key / value pair is checked against the checksum stored with it, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketDeleted`](enum.Error.html#variant.BucketDeleted) error if this bucket has been deleted,
+    /// a [`ValueCorrupt`](enum.Error.html#variant.ValueCorrupt) error if the value doesn't match its checksum,
+    /// or an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if a branch on the way to the key is corrupt.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, Error};
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
     ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// tx.delete_bucket("my-bucket")?;
+    /// assert_eq!(bucket.try_get("key").err(), Some(Error::BucketDeleted));
     /// # Ok(())
     /// # }
     /// ```
-    pub fn delete_bucket<T: ToBytes<'tx>>(&self, key: T) -> Result<()> {
-        if !self.writable {
-            return Err(Error::ReadOnlyTx);
+    pub fn try_get<T: AsRef<[u8]>>(&self, key: T) -> Result<Option<Data<'b, 'tx>>> {
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            return Err(Error::BucketDeleted);
         }
+        Ok(b.get(key, true)?.map(|data| data.into()))
+    }
 
-        let mut freelist = self.freelist.borrow_mut();
+    /// Gets the key / value pair at the given key, or `None` if the key does not exist or is a nested bucket.
+    ///
+    /// Like [`get`](#method.get), the pair isn't checked against its checksum.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`get`](#method.get).
+    /// Use [`try_get`](#method.try_get) to get those as errors instead.
+    pub fn get_kv<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<KVPair<'b, 'tx>> {
         let mut b = self.inner.borrow_mut();
         if b.deleted {
-            panic!("Cannot delete bucket from a deleted bucket.");
+            panic!("Cannot get data from a deleted bucket.");
+        }
+        match b.get(key, false) {
+            Ok(Some(data)) => data.into(),
+            Ok(None) => None,
+            Err(e) => panic!("{}", e),
         }
-        b.delete_bucket(key, &mut freelist)
     }
 
-    /// Get a cursor to iterate over the bucket.
+    /// Gets a key / value pair along with the id of the leaf page it was found on.
+    ///
+    /// This is meant for building caches tied to the physical layout of the database,
+    /// where an entry can be dropped once its page has been freed.
+    /// Page ids are an implementation detail, so don't rely on them for anything else.
     ///
+    /// In a writable transaction, a modified key / value pair lives in memory until the transaction is committed.
+    /// In that case the page it was originally loaded from is returned (which will be freed on commit),
+    /// or `0` if it is in a part of the bucket that has never been written to a page.
+    ///
+    /// Returns `None` if the key does not exist or is a nested bucket.
+    /// Like [`get`](#method.get), the pair isn't checked against its checksum, so use [`try_get`](#method.try_get)
+    /// first if the file may be corrupt.
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// use jammdb::{DB, Data};
+    /// use jammdb::{DB};
     /// # use jammdb::Error;
     ///
     /// # fn main() -> Result<(), Error> {
@@ -361,31 +451,31 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     /// let mut tx = db.tx(false)?;
     ///
     /// let bucket = tx.get_bucket("my-bucket")?;
-    ///
-    /// for data in bucket.cursor() {
-    ///     match data {
-    ///         Data::Bucket(b) => println!("found a bucket with the name {:?}", b.name()),
-    ///         Data::KeyValue(kv) => println!("found a kv pair {:?} {:?}", kv.key(), kv.value()),
-    ///     }
+    /// if let Some((kv, page_id)) = bucket.get_located("my-key") {
+    ///     println!("{:?} is on page {}", kv.value(), page_id);
     /// }
-    ///
     /// # Ok(())
     /// # }
     /// ```
-    pub fn cursor<'a>(&'a self) -> Cursor<'b, 'tx> {
-        {
-            let b = self.inner.borrow();
-            if b.deleted {
-                panic!("Cannot create cursor from a deleted bucket.");
-            }
+    pub fn get_located<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<(KVPair<'b, 'tx>, u64)> {
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot get data from a deleted bucket.");
         }
-        Cursor::new(self)
+        let (leaf, page_id) = match b.get_located(key) {
+            Ok(located) => located?,
+            Err(e) => panic!("{}", e),
+        };
+        let kv: Option<KVPair> = leaf.into();
+        kv.map(|kv| (kv, page_id))
     }
 
-    /// Returns the next integer for the bucket.
-    /// The integer is automatically incremented each time a new key is added to the bucket.
-    /// You can it as a unique key for the bucket, since it will increment each time you add something new.
-    /// It will not increment if you [`put`](#method.put) a key that already exists
+    /// Returns the length of the value stored at `key`, without reading the value itself.
+    ///
+    /// Only the page headers are read to find the pair, so this is a cheap way to decide
+    /// whether a value is small enough to read all at once, even if its data has never been paged in.
+    /// That also means the pair isn't checked against its checksum, since that would mean reading the whole value.
+    /// Returns `None` if the key does not exist or is a nested bucket.
     ///
     /// # Examples
     ///
@@ -395,769 +485,4040 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     ///
     /// # fn main() -> Result<(), Error> {
     /// let db = DB::open("my.db")?;
-    /// let mut tx = db.tx(true)?;
+    /// let mut tx = db.tx(false)?;
     ///
-    /// // create a root-level bucket
-    /// let bucket = tx.create_bucket("my-bucket")?;
-    /// // starts at 0
-    /// assert_eq!(bucket.next_int(), 0);
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// if bucket.value_len("video").map_or(false, |len| len > 1024 * 1024) {
+    ///     println!("that's a big one");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
     ///
-    /// let next_int = bucket.next_int();
-    /// bucket.put(next_int.to_be_bytes(), [0]);
-    /// // auto-incremented after inserting a key / value pair
-    /// assert_eq!(bucket.next_int(), 1);
+    /// # Panics
     ///
-    /// bucket.put(0_u64.to_be_bytes(), [0, 0]);
-    /// // not incremented after updating a key / value pair
-    /// assert_eq!(bucket.next_int(), 1);
+    /// Panics if the bucket has been deleted, or if a branch on the way to the key is corrupt.
+    pub fn value_len<T: AsRef<[u8]>>(&self, key: T) -> Option<u64> {
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot get data from a deleted bucket.");
+        }
+        match b.value_len(key) {
+            Ok(len) => len,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Copies the value stored at `key` into the given buffer.
     ///
-    /// bucket.create_bucket("nested-bucket")?;
-    /// // auto-incremented after creating a nested bucket
-    /// assert_eq!(bucket.next_int(), 2);
+    /// The buffer is always cleared first, and then the value's bytes are copied into it.
+    /// Returns the length of the value, or `None` if the key does not exist.
+    /// This lets you reuse a single allocation across many reads,
+    /// and the copied data is not tied to the life of the transaction.
+    ///
+    /// Returns an error if the key is for a nested bucket, not key / value data,
+    /// or if the value doesn't match its checksum, like [`try_get`](#method.try_get).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// let mut buf = Vec::new();
+    /// for key in ["a", "b", "c"] {
+    ///     if let Some(len) = bucket.read_value_into(key, &mut buf)? {
+    ///         println!("{} has a {} byte value {:?}", key, len, buf);
+    ///     }
+    /// }
     ///
     /// # Ok(())
     /// # }
     /// ```
-    pub fn next_int(&self) -> u64 {
-        let b = self.inner.borrow();
-        if b.deleted {
-            panic!("Cannot get next int from a deleted bucket.");
+    pub fn read_value_into<T: AsRef<[u8]>>(
+        &self,
+        key: T,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<usize>> {
+        buf.clear();
+        match self.try_get(key)? {
+            Some(Data::KeyValue(kv)) => {
+                buf.extend_from_slice(kv.value());
+                Ok(Some(buf.len()))
+            }
+            Some(Data::Bucket(_)) => Err(Error::IncompatibleValue),
+            None => Ok(None),
         }
-        b.meta.next_int
     }
 
-    /// Iterator over the sub-buckets in this bucket.
-    pub fn buckets<'a>(&'a self) -> impl Iterator<Item = (BucketName<'b, 'tx>, Bucket<'b, 'tx>)> {
-        self.cursor().to_buckets()
-    }
-
-    /// Iterator over the key / value pairs in this bucket.
-    pub fn kv_pairs<'a>(&'a self) -> impl Iterator<Item = KVPair<'b, 'tx>> {
-        self.cursor().to_kv_pairs()
-    }
-
-    pub fn range<'a, R>(&'a self, r: R) -> Range<'a, 'b, 'tx, R>
+    /// Checks that the value stored at `key` hashes back to the key itself.
+    ///
+    /// This is meant for content-addressed buckets, where each key is a hash of its value.
+    /// The `hasher` is given the value straight from the database, so nothing is copied
+    /// unless the hasher does so itself. Returns whether the hash matched the key.
+    ///
+    /// Returns an error if
+    /// 1. the given key does not exist
+    /// 2. the key is for a nested bucket, not key / value data
+    /// 3. the value doesn't match its checksum, like [`try_get`](#method.try_get)
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("content")?;
+    /// // reverse the bytes as a (very) poor hash function
+    /// let valid = bucket.verify_value("cba", |value| value.iter().rev().cloned().collect())?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verify_value<T, H>(&self, key: T, hasher: H) -> Result<bool>
     where
-        R: RangeBounds<&'a [u8]>,
+        T: AsRef<[u8]>,
+        H: FnOnce(&[u8]) -> Vec<u8>,
     {
-        Range {
-            c: self.cursor(),
-            bounds: r,
-            _phantom: PhantomData,
+        let key = key.as_ref();
+        match self.try_get(key)? {
+            Some(Data::KeyValue(kv)) => Ok(hasher(kv.value()) == key),
+            Some(Data::Bucket(_)) => Err(Error::IncompatibleValue),
+            None => Err(Error::KeyValueMissing),
         }
     }
-}
-
-// and we'll implement IntoIterator
-impl<'b, 'tx> IntoIterator for Bucket<'b, 'tx> {
-    type Item = Data<'b, 'tx>;
-    type IntoIter = Cursor<'b, 'tx>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.cursor()
-    }
-}
-
-pub(crate) struct InnerBucket<'b> {
-    pub(crate) meta: BucketMeta,
-    root: PageNodeID,
-    pub(crate) deleted: bool,
-    dirty: bool,
-    buckets: HashMap<Bytes<'b>, Rc<RefCell<InnerBucket<'b>>>>,
-    pub(crate) nodes: Vec<Rc<RefCell<Node<'b>>>>,
-    // Maps a PageID to it's NodeID, so we don't create multiple nodes for a single page
-    page_node_ids: HashMap<PageID, NodeID>,
-    // Maps PageIDs to their parent's PageID
-    page_parents: HashMap<PageID, PageID>,
-    pages: Pages,
-}
 
-impl<'b> InnerBucket<'b> {
-    pub(crate) fn from_meta(meta: BucketMeta, pages: Pages) -> InnerBucket<'b> {
-        debug_assert!(
-            meta.root_page > 1,
-            "bucket cannot have root page {}, reserved for meta",
-            meta.root_page
-        );
-        InnerBucket {
-            meta,
-            root: PageNodeID::Page(meta.root_page),
-            deleted: false,
-            dirty: false,
-            buckets: HashMap::new(),
-            nodes: Vec::new(),
-            page_node_ids: HashMap::new(),
-            page_parents: HashMap::new(),
-            pages,
+    /// Deletes a key / value pair from the bucket
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// // check if data is there
+    /// assert!(bucket.get_kv("some-key").is_some());
+    /// // delete the key / value pair
+    /// bucket.delete("some-key")?;
+    /// // data should no longer exist
+    /// assert!(bucket.get_kv("some-key").is_none());
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn delete<T: AsRef<[u8]>>(&self, key: T) -> Result<KVPair<'_, '_>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
         }
-    }
-
-    fn new_child<'a>(&'a mut self, name: Bytes<'b>) -> RefMut<InnerBucket<'b>> {
-        self.dirty = true;
-        let n = Node::new(0, Page::TYPE_LEAF, self.pages.pagesize);
-        let mut page_node_ids = HashMap::new();
-        page_node_ids.insert(0, 0);
-        let b = InnerBucket {
-            meta: BucketMeta::default(),
-            root: PageNodeID::Node(0),
-            deleted: false,
-            dirty: true,
-            buckets: HashMap::new(),
-            nodes: vec![Rc::new(RefCell::new(n))],
-            page_node_ids,
-            page_parents: HashMap::new(),
-            pages: self.pages.clone(),
-        };
-        self.buckets.insert(name.clone(), Rc::new(RefCell::new(b)));
-        let b = self.buckets.get_mut(&name).unwrap();
-        b.borrow_mut()
-    }
-
-    pub(crate) fn add_page_parent(&mut self, page: PageID, parent: PageID) {
-        debug_assert!(
-            self.meta.root_page == parent || self.page_parents.contains_key(&parent),
-            "cannot find reference to parent page ID \"{}\"",
-            parent
-        );
-        self.page_parents.insert(page, parent);
-    }
-
-    pub(crate) fn page_node<'a>(&'a self, id: PageNodeID) -> PageNode<'b> {
-        match id {
-            PageNodeID::Page(page) => {
-                if let Some(node_id) = self.page_node_ids.get(&page) {
-                    PageNode::Node(self.nodes[*node_id as usize].clone())
-                } else {
-                    PageNode::Page(self.pages.page(page))
-                }
-            }
-            PageNodeID::Node(node) => PageNode::Node(self.nodes[node as usize].clone()),
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot delete data from a deleted bucket.");
         }
+        Ok(b.delete(key)?.into())
     }
 
-    pub fn get<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Option<Leaf<'b>> {
-        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self);
-        let last = stack.last().unwrap();
-        if exists {
-            let page_node = self.page_node(last.id);
-            page_node.val(last.index)
-        } else {
-            None
+    /// Deletes every key / value pair that `f` returns false for, like [`Vec::retain`].
+    ///
+    /// `f` is called with the key and value of each pair in order, and nested buckets are always kept.
+    /// Returns the number of pairs that were deleted.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// let bucket = tx.get_bucket("sessions")?;
+    /// // drop every session that has been logged out
+    /// let removed = bucket.retain(|_, value| value != b"logged-out")?;
+    /// println!("removed {} sessions", removed);
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn retain<F>(&self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
         }
-    }
-
-    pub fn put<'a, T: ToBytes<'b>, S: ToBytes<'b>>(
-        &'a mut self,
-        key: T,
-        value: S,
-    ) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
-        let k = key.to_bytes();
-        let v = value.to_bytes();
-
-        match self.put_leaf(Leaf::Kv(k, v))? {
-            Some(data) => match data {
-                Leaf::Kv(k, v) => Ok(Some((k, v))),
-                _ => panic!("Unexpected data"),
-            },
-            None => Ok(None),
+        // find everything to delete first, since the tree can't change under the cursor
+        let keys: Vec<Vec<u8>> = self
+            .kv_pairs()
+            .filter(|kv| !f(kv.key(), kv.value()))
+            .map(|kv| kv.key().to_vec())
+            .collect();
+        let mut b = self.inner.borrow_mut();
+        for key in keys.iter() {
+            b.delete(key)?;
         }
+        Ok(keys.len())
     }
 
-    fn delete<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Result<(Bytes<'b>, Bytes<'b>)> {
-        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self);
-        let last = stack.last().unwrap();
-        if exists {
-            let page_node = self.page_node(last.id);
-            let data = page_node.val(last.index).unwrap();
-            if data.is_kv() {
-                let current_id = last.id;
-                let index = last.index;
-                self.dirty = true;
-                let node = self.node(current_id, None);
-                let mut node = node.borrow_mut();
-                match node.delete(index) {
-                    Leaf::Kv(k, v) => Ok((k, v)),
-                    _ => panic!("Unexpected data"),
-                }
-            } else {
-                Err(Error::IncompatibleValue)
-            }
-        } else {
-            Err(Error::KeyValueMissing)
+    /// Replaces the key of every key / value pair in this bucket with the key returned by `f`.
+    ///
+    /// This is for fixing the encoding of keys that were written the wrong way, like integers stored
+    /// in little-endian that don't sort correctly. Every pair is read before anything is changed, and the new keys
+    /// are all checked first, so if this returns an error the bucket is left as it was. Nested buckets keep their names.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction,
+    /// a [`KeyCollision`](enum.Error.html#variant.KeyCollision) error if `f` returns the same key for two pairs,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if it returns the name of a nested bucket.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// // switch u64 keys from little-endian to big-endian so they sort numerically
+    /// let bucket = tx.get_bucket("numbers")?;
+    /// bucket.rekey(|key| {
+    ///     let n = u64::from_le_bytes(key.try_into().unwrap());
+    ///     n.to_be_bytes().to_vec()
+    /// })?;
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rekey<F: Fn(&[u8]) -> Vec<u8>>(&self, f: F) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
         }
-    }
-
-    fn put_leaf<'a>(&'a mut self, leaf: Leaf<'b>) -> Result<Option<Leaf<'b>>> {
-        let (exists, stack) = search(leaf.key(), self.meta.root_page, self);
-        let last = stack.last().unwrap();
-        let current_data = if exists {
-            let page_node = self.page_node(last.id);
-            let current = page_node.val(last.index).unwrap();
-            if current.is_kv() != leaf.is_kv() {
+        // read everything first, since the tree can't change under the cursor
+        let pairs: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = self
+            .kv_pairs()
+            .map(|kv| (kv.key().to_vec(), f(kv.key()), kv.value().to_vec()))
+            .collect();
+        let bucket_names: HashSet<Vec<u8>> = self
+            .buckets()
+            .map(|(name, _)| name.name().to_vec())
+            .collect();
+        let mut b = self.inner.borrow_mut();
+        let mut new_keys = HashSet::with_capacity(pairs.len());
+        for (_, new_key, _) in pairs.iter() {
+            b.check_key(new_key)?;
+            if bucket_names.contains(new_key) {
                 return Err(Error::IncompatibleValue);
             }
-            Some(current)
-        } else {
-            self.meta.next_int += 1;
-            None
-        };
-        let node = self.node(last.id, None);
-        let mut node = node.borrow_mut();
-        node.insert_data(leaf);
-        self.dirty = true;
-
-        Ok(current_data)
+            if !new_keys.insert(new_key.as_slice()) {
+                return Err(Error::KeyCollision {
+                    key: new_key.clone(),
+                });
+            }
+        }
+        for (old_key, _, _) in pairs.iter() {
+            b.delete(old_key)?;
+        }
+        for (_, new_key, value) in pairs {
+            b.put(new_key, value)?;
+        }
+        Ok(())
     }
 
-    pub(crate) fn create_bucket<T: ToBytes<'b>>(&mut self, name: T) -> Result<Rc<RefCell<Self>>> {
-        self.bucket_getter(name.to_bytes(), true, true)
+    /// Gets an already created bucket.
+    ///
+    /// Returns an error if
+    /// 1. the given key does not exist
+    /// 2. the key is for key / value data, not a bucket
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// // get a root-level bucket
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    ///
+    /// // get nested bucket
+    /// let mut sub_bucket = bucket.get_bucket("nested-bucket")?;
+    ///
+    /// // get nested bucket
+    /// let sub_sub_bucket = sub_bucket.get_bucket("double-nested-bucket")?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot get bucket from a deleted bucket.");
+        }
+        let inner = b.get_bucket(name)?;
+        Ok(Bucket {
+            inner,
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        })
     }
 
-    pub(crate) fn get_bucket<'a, T: ToBytes<'b>>(
-        &'a mut self,
-        name: T,
-    ) -> Result<Rc<RefCell<Self>>> {
-        self.bucket_getter(name.to_bytes(), false, false)
+    /// Creates a new bucket.
+    ///
+    /// Returns an error if
+    /// 1. the given key already exists
+    /// 2. It is in a read-only transaction
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    ///
+    /// // create a root-level bucket
+    /// let bucket = tx.create_bucket("my-bucket")?;
+    ///
+    /// // create nested bucket
+    /// let mut sub_bucket = bucket.create_bucket("nested-bucket")?;
+    ///
+    /// // create nested bucket
+    /// let mut sub_sub_bucket = sub_bucket.create_bucket("double-nested-bucket")?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn create_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot create bucket in a deleted bucket.");
+        }
+        let inner = b.create_bucket(name)?;
+        Ok(Bucket {
+            inner,
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        })
     }
 
-    pub(crate) fn get_or_create_bucket<T: ToBytes<'b>>(
-        &mut self,
-        name: T,
-    ) -> Result<Rc<RefCell<Self>>> {
-        self.bucket_getter(name.to_bytes(), true, false)
+    /// Creates a new bucket if it doesn't exist
+    ///
+    /// Returns an error if
+    /// 1. It is in a read-only transaction
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// {
+    ///     let mut tx = db.tx(true)?;
+    ///     // create a root-level bucket
+    ///     let bucket = tx.get_or_create_bucket("my-bucket")?;
+    ///     tx.commit()?;
+    /// }
+    /// {
+    ///     let mut tx = db.tx(true)?;
+    ///     // get the existing a root-level bucket
+    ///     let bucket = tx.get_or_create_bucket("my-bucket")?;
+    /// }
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```    
+    pub fn get_or_create_bucket<'a, T: ToBytes<'tx>>(&'a self, name: T) -> Result<Bucket<'b, 'tx>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot get or create bucket from a deleted bucket.");
+        }
+        let inner = b.get_or_create_bucket(name)?;
+        Ok(Bucket {
+            inner,
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        })
     }
 
-    fn bucket_getter<'a>(
-        &'a mut self,
-        name: Bytes<'b>,
-        should_create: bool,
-        must_create: bool,
-    ) -> Result<Rc<RefCell<InnerBucket<'b>>>> {
-        if !self.buckets.contains_key(&name) {
-            let (exists, stack) = search(name.as_ref(), self.meta.root_page, self);
-            let last = stack.last().unwrap();
-            if !exists {
-                if should_create {
-                    self.meta.next_int += 1;
-                    let leaf = {
-                        let b = self.new_child(name.clone());
-                        let meta = b.meta;
-                        Leaf::Bucket(name.clone(), meta)
-                    };
-                    let node = self.node(last.id, None);
-                    let mut node = node.borrow_mut();
-                    node.insert_data(leaf);
-                } else {
-                    return Err(Error::BucketMissing);
-                }
-            } else {
-                let page_node = self.page_node(last.id);
-                match page_node.val(last.index) {
-                    Some(leaf) => match leaf {
-                        Leaf::Bucket(name, meta) => {
-                            if must_create {
-                                return Err(Error::BucketExists);
-                            }
-                            let b = Self::from_meta(meta, self.pages.clone());
-                            self.buckets.insert(name.clone(), Rc::new(RefCell::new(b)));
-                        }
-                        _ => return Err(Error::IncompatibleValue),
-                    },
-                    None => return Err(Error::BucketMissing),
-                }
+    /// Gets a nested bucket, or creates it and fills it in with `init` if it doesn't exist.
+    ///
+    /// `init` is only called when the bucket is created, so it runs once no matter how many times this is called
+    /// for the same name. If `init` returns an error, the new bucket is deleted again and the error is returned.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the name is used by a key / value pair.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// let users = tx.get_bucket("users")?;
+    /// let settings = users.get_or_create_bucket_with("settings", |settings| {
+    ///     settings.put("theme", "dark")?;
+    ///     settings.put("language", "en")?;
+    ///     Ok(())
+    /// })?;
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_or_create_bucket_with<T, F>(&self, name: T, init: F) -> Result<Bucket<'b, 'tx>>
+    where
+        T: ToBytes<'tx>,
+        F: FnOnce(&Bucket<'b, 'tx>) -> Result<()>,
+    {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let name = name.to_bytes();
+        let (inner, created) = {
+            let mut b = self.inner.borrow_mut();
+            if b.deleted {
+                panic!("Cannot get or create bucket from a deleted bucket.");
+            }
+            b.bucket_getter(name.clone(), true, false)?
+        };
+        let bucket = Bucket {
+            inner,
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        };
+        if created {
+            if let Err(e) = init(&bucket) {
+                self.delete_bucket(name)?;
+                return Err(e);
             }
-        } else if must_create {
-            return Err(Error::BucketExists);
         }
-        Ok(self.buckets.get(&name).unwrap().clone())
+        Ok(bucket)
     }
 
-    pub(crate) fn delete_bucket<T: ToBytes<'b>>(
-        &mut self,
-        name: T,
-        freelist: &mut TxFreelist,
-    ) -> Result<()> {
-        let name = name.to_bytes();
-        // make sure the bucket is in our map
-        self.get_bucket(&name)?;
-
-        // remove the bucket from the map so we won't have a reference to it anymore
-        let bucket = self.buckets.remove(&name).unwrap();
-        let mut b = bucket.borrow_mut();
-        // Mark it as deleted in case there is still a Bucket or cursor with a reference to this bucket.
-        b.deleted = true;
-        // check that the bucket wasn't just created and never comitted
-        let mut remaining_pages = Vec::new();
-        if b.meta.root_page != 0 {
-            // create a stack of pages to free and keep going until
-            // we've freed every reachable page starting from this bucket's root page
-            remaining_pages.push(b.meta.root_page);
-            while let Some(page_id) = remaining_pages.pop() {
-                let page = self.pages.page(page_id);
-                let num_pages = page.overflow + 1;
-                match page.page_type {
-                    // every branch element's page much be freed
-                    Page::TYPE_BRANCH => {
-                        page.branch_elements()
-                            .iter()
-                            .for_each(|b| remaining_pages.push(b.page));
-                    }
-                    Page::TYPE_LEAF => {
-                        // every nested bucket's pages must be freed
-                        page.leaf_elements().iter().for_each(|leaf| {
-                            if leaf.node_type == Node::TYPE_BUCKET {
-                                let meta: BucketMeta = leaf.value().into();
-                                remaining_pages.push(meta.root_page);
-                            }
-                        });
-                    }
-                    _ => (),
-                }
-                freelist.free(page_id, num_pages);
-            }
+    /// Gets a nested bucket by following a path of bucket names.
+    ///
+    /// Each name in the path is looked up in the bucket found by the previous one,
+    /// so `bucket.get_bucket_path(&["a", "b"])` is the same as `bucket.get_bucket("a")?.get_bucket("b")`.
+    /// An empty path returns this bucket.
+    ///
+    /// Returns an error if
+    /// 1. any bucket along the path does not exist
+    /// 2. any name along the path is for key / value data, not a bucket
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// let sub_sub_bucket = bucket.get_bucket_path(&["nested-bucket", "double-nested-bucket"])?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_bucket_path<'a, T: AsRef<[u8]>>(&'a self, path: &[T]) -> Result<Bucket<'b, 'tx>> {
+        let mut bucket = Bucket {
+            inner: self.inner.clone(),
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        };
+        for name in path {
+            bucket = bucket.get_bucket(name.as_ref().to_vec())?;
         }
-        // delete the element from this bucket
-        let (exists, stack) = search(name.as_ref(), self.meta.root_page, self);
-        let last = stack.last().unwrap();
-        if exists {
-            let page_node = self.page_node(last.id);
-            let data = page_node.val(last.index).unwrap();
+        Ok(bucket)
+    }
 
-            if !data.is_kv() {
-                self.dirty = true;
-                let current_id = last.id;
-                let index = last.index;
+    /// Gets a nested bucket by following a path of bucket names,
+    /// creating any buckets along the path that do not exist yet.
+    ///
+    /// An empty path returns this bucket.
+    ///
+    /// Returns an error if
+    /// 1. any name along the path is for key / value data, not a bucket
+    /// 2. It is in a read-only transaction
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    ///
+    /// let bucket = tx.get_or_create_bucket("my-bucket")?;
+    /// let sub_sub_bucket = bucket.get_or_create_bucket_path(&["nested-bucket", "double-nested-bucket"])?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_or_create_bucket_path<'a, T: AsRef<[u8]>>(
+        &'a self,
+        path: &[T],
+    ) -> Result<Bucket<'b, 'tx>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut bucket = Bucket {
+            inner: self.inner.clone(),
+            freelist: self.freelist.clone(),
+            writable: self.writable,
+            _phantom: PhantomData,
+        };
+        for name in path {
+            bucket = bucket.get_or_create_bucket(name.as_ref().to_vec())?;
+        }
+        Ok(bucket)
+    }
+
+    /// Makes sure every bucket along a path of bucket names exists, returning the last one.
+    ///
+    /// This is [`get_or_create_bucket_path`](#method.get_or_create_bucket_path) under a name meant for setting up
+    /// a layout of nested buckets when a database is opened, in a single call that does nothing when the buckets are already there.
+    /// Only a name that already exists as key / value data can fail, and every bucket before it in the path
+    /// must already exist too, so a failed call never leaves new buckets behind. An empty path returns this bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if any name along the path
+    /// is for key / value data, or a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// let app = tx.get_or_create_bucket("app")?;
+    /// app.ensure_path(&["users", "by-email"])?;
+    /// app.ensure_path(&["users", "by-name"])?;
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn ensure_path<'a, T: AsRef<[u8]>>(&'a self, path: &[T]) -> Result<Bucket<'b, 'tx>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        if self.inner.borrow().deleted {
+            panic!("Cannot get or create bucket from a deleted bucket.");
+        }
+        self.get_or_create_bucket_path(path)
+    }
+
+    /// Deletes an bucket.
+    ///
+    /// Returns an error if
+    /// 1. the given key does not exist
+    /// 2. the key is for key / value data, not a bucket
+    /// 3. It is in a read-only transaction
+    /// 4. the bucket's pages are corrupt and refer back to each other
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    ///
+    /// // get a root-level bucket
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    ///
+    /// // delete nested bucket
+    /// bucket.delete_bucket("nested-bucket")?;
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn delete_bucket<T: ToBytes<'tx>>(&self, key: T) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+
+        let mut freelist = self.freelist.borrow_mut();
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot delete bucket from a deleted bucket.");
+        }
+        b.delete_bucket(key, &mut freelist)
+    }
+
+    /// Rebuilds this bucket's B+ tree from scratch so its pages are densely packed.
+    ///
+    /// Every key / value pair and nested bucket entry is read in order, the bucket's old pages are
+    /// given back to the freelist, and the data is written out to fresh pages when the transaction is committed.
+    /// This is useful to reclaim space in a single bucket that has seen a lot of deletes,
+    /// without compacting the whole database.
+    /// Nested buckets are not rebuilt, they keep pointing at their existing pages.
+    ///
+    /// Any cursors or iterators over this bucket that were created before calling `compact`
+    /// should not be used afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is in a read-only transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// bucket.compact()?;
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact(&self) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut freelist = self.freelist.borrow_mut();
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot compact a deleted bucket.");
+        }
+        b.compact(&mut freelist)
+    }
+
+    /// Get a cursor to iterate over the bucket.
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, Data};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    ///
+    /// for data in bucket.cursor() {
+    ///     match data {
+    ///         Data::Bucket(b) => println!("found a bucket with the name {:?}", b.name()),
+    ///         Data::KeyValue(kv) => println!("found a kv pair {:?} {:?}", kv.key(), kv.value()),
+    ///     }
+    /// }
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cursor<'a>(&'a self) -> Cursor<'b, 'tx> {
+        {
+            let b = self.inner.borrow();
+            if b.deleted {
+                panic!("Cannot create cursor from a deleted bucket.");
+            }
+        }
+        Cursor::new(self)
+    }
+
+    /// Iterator over every entry in this bucket, with each entry's key alongside its [`Data`].
+    ///
+    /// This is the same as iterating over a [`cursor`](#method.cursor), but the key is pulled out of the `Data`
+    /// so nested buckets and key / value pairs can be handled the same way without matching on them first.
+    /// The key is copied, since it can't borrow from the `Data` it is returned with.
+    /// If you don't need an owned key, [`Data::key`](enum.Data.html#method.key) gives you the same thing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    ///
+    /// for (key, data) in bucket.iter() {
+    ///     println!("{:?} is a bucket: {}", key, !data.is_kv());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Vec<u8>, Data<'b, 'tx>)> {
+        self.cursor().map(|data| (data.key().to_vec(), data))
+    }
+
+    /// Calls `f` on every entry in this bucket in order, until it returns [`ControlFlow::Break`].
+    ///
+    /// Returns `ControlFlow::Break(())` if `f` stopped the walk early,
+    /// or `ControlFlow::Continue(())` if it saw every entry.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::ops::ControlFlow;
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    ///
+    /// // print everything up to the first nested bucket
+    /// bucket.for_each_until(|data| {
+    ///     if !data.is_kv() {
+    ///         return ControlFlow::Break(());
+    ///     }
+    ///     println!("{:?}", data.key());
+    ///     ControlFlow::Continue(())
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn for_each_until<F>(&self, mut f: F) -> ControlFlow<()>
+    where
+        F: FnMut(Data<'b, 'tx>) -> ControlFlow<()>,
+    {
+        for data in self.cursor() {
+            f(data)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns the next integer for the bucket.
+    /// The integer is automatically incremented each time a new key is added to the bucket.
+    /// You can it as a unique key for the bucket, since it will increment each time you add something new.
+    /// It will not increment if you [`put`](#method.put) a key that already exists
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    ///
+    /// // create a root-level bucket
+    /// let bucket = tx.create_bucket("my-bucket")?;
+    /// // starts at 0
+    /// assert_eq!(bucket.next_int(), 0);
+    ///
+    /// let next_int = bucket.next_int();
+    /// bucket.put(next_int.to_be_bytes(), [0]);
+    /// // auto-incremented after inserting a key / value pair
+    /// assert_eq!(bucket.next_int(), 1);
+    ///
+    /// bucket.put(0_u64.to_be_bytes(), [0, 0]);
+    /// // not incremented after updating a key / value pair
+    /// assert_eq!(bucket.next_int(), 1);
+    ///
+    /// bucket.create_bucket("nested-bucket")?;
+    /// // auto-incremented after creating a nested bucket
+    /// assert_eq!(bucket.next_int(), 2);
+    ///
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn next_int(&self) -> u64 {
+        let b = self.inner.borrow();
+        if b.deleted {
+            panic!("Cannot get next int from a deleted bucket.");
+        }
+        b.meta.next_int
+    }
+
+    /// Returns the bucket's user counter.
+    ///
+    /// This is a number stored alongside the bucket that jammdb never changes on its own,
+    /// unlike [`next_int`](#method.next_int). It is meant for caching something like an approximate
+    /// number of entries, which is much cheaper to read than counting them, but it is up to you to keep it up to date.
+    /// Buckets start with a counter of zero.
+    ///
+    /// Using the counter makes the bucket unreadable by releases from before the counter was added.
+    /// A counter of zero isn't stored, but once it is set to anything else the bucket is written in a longer format,
+    /// and the database file moves to a newer format version so those releases refuse to open it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// let bucket = tx.get_or_create_bucket("users")?;
+    /// if bucket.get("alice").is_none() {
+    ///     bucket.put("alice", "data")?;
+    ///     bucket.set_counter(bucket.counter() + 1)?;
+    /// }
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn counter(&self) -> u64 {
+        let b = self.inner.borrow();
+        if b.deleted {
+            panic!("Cannot get counter from a deleted bucket.");
+        }
+        b.meta.counter
+    }
+
+    /// Sets the bucket's user counter.
+    ///
+    /// The new value is saved when the transaction is committed. See [`counter`](#method.counter) for more details.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is a read-only transaction.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    pub fn set_counter(&self, counter: u64) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut b = self.inner.borrow_mut();
+        if b.deleted {
+            panic!("Cannot set counter on a deleted bucket.");
+        }
+        b.meta.counter = counter;
+        b.dirty = true;
+        if counter != 0 {
+            // Buckets with a counter are stored in a format older releases can't read
+            let mut freelist = self.freelist.borrow_mut();
+            freelist.meta.version = freelist.meta.version.max(VERSION);
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the page this bucket's B+ tree is rooted at.
+    ///
+    /// This is advanced, unstable information meant for inspection and debugging tools.
+    /// The root page changes every time a transaction that modifies the bucket is committed,
+    /// and for a bucket modified in the current transaction it reflects the last committed state.
+    pub fn root_page(&self) -> u64 {
+        let b = self.inner.borrow();
+        if b.deleted {
+            panic!("Cannot get root page from a deleted bucket.");
+        }
+        b.meta.root_page
+    }
+
+    /// Returns the number of bytes a key / value pair takes up in a leaf page.
+    ///
+    /// This is the size of the element header plus the key and value, which is what
+    /// is used to decide how many pairs fit in a page. When the database was opened with
+    /// [`OpenOptions::value_checksums`](struct.OpenOptions.html#method.value_checksums),
+    /// it also includes the 4 byte checksum written after each pair.
+    /// It does not include the page header, or any space lost at the end of a page,
+    /// so use it for budgeting rather than exact file sizes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(true)?;
+    ///
+    /// let bucket = tx.get_or_create_bucket("users")?;
+    /// let size = bucket.entry_size(b"key", b"value");
+    /// assert!(size > 8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn entry_size(&self, key: &[u8], value: &[u8]) -> usize {
+        let checksum = match self.inner.borrow().pages.value_checksums {
+            true => CHECKSUM_SIZE as usize,
+            false => 0,
+        };
+        LEAF_SIZE as usize + key.len() + value.len() + checksum
+    }
+
+    /// Checks that this bucket's B+ tree is well formed.
+    ///
+    /// Walks every page of the bucket in order, making sure the keys are strictly increasing
+    /// within and across leaf pages, and that each key in a branch page is the first key of the page it points to.
+    /// This is the same ordering that [`DB::check`](struct.DB.html#method.check) enforces, but only for this bucket,
+    /// so it is much cheaper when you only need to verify one bucket.
+    ///
+    /// Nested buckets are not validated, and for a bucket modified in the current transaction
+    /// this validates the last committed state.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error describing the first problem found.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// tx.get_bucket("my-bucket")?.validate()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        let b = self.inner.borrow();
+        if b.deleted {
+            panic!("Cannot validate a deleted bucket.");
+        }
+        b.validate()
+    }
+
+    /// Iterator over the sub-buckets in this bucket.
+    pub fn buckets<'a>(&'a self) -> impl Iterator<Item = (BucketName<'b, 'tx>, Bucket<'b, 'tx>)> {
+        self.cursor().to_buckets()
+    }
+
+    /// Collects the names of and handles to every sub-bucket in this bucket.
+    ///
+    /// Unlike [`buckets`](#method.buckets), nothing is borrowed from this bucket once this returns,
+    /// which makes it easy to walk nested buckets recursively.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{Bucket, DB};
+    /// # use jammdb::Error;
+    ///
+    /// fn count_pairs(bucket: Bucket) -> usize {
+    ///     let mut count = bucket.kv_pairs().count();
+    ///     for (_name, child) in bucket.child_buckets_owned() {
+    ///         count += count_pairs(child);
+    ///     }
+    ///     count
+    /// }
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// println!("{}", count_pairs(tx.get_bucket("my-bucket")?));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn child_buckets_owned(&self) -> Vec<(Vec<u8>, Bucket<'b, 'tx>)> {
+        self.buckets()
+            .map(|(name, bucket)| (name.name().to_vec(), bucket))
+            .collect()
+    }
+
+    /// Iterator over the key / value pairs in this bucket.
+    pub fn kv_pairs<'a>(&'a self) -> impl Iterator<Item = KVPair<'b, 'tx>> {
+        self.cursor().to_kv_pairs()
+    }
+
+    /// Iterator over the key / value pairs in this bucket, optimized for reading the whole bucket.
+    ///
+    /// This yields the same pairs as [`kv_pairs`](#method.kv_pairs), but instead of keeping track of its position
+    /// in the tree for every element like a [`Cursor`] does, it goes through each leaf page in order
+    /// and yields everything on it before moving on to the next one.
+    /// If any part of the bucket has been modified in this transaction, it falls back to using a cursor.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the bucket's pages
+    /// contain a cycle or point past the end of the file. Every page is checked before the first pair is returned.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// let total: usize = bucket.fast_scan()?.map(|kv| kv.value().len()).sum();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fast_scan<'a>(&'a self) -> Result<impl Iterator<Item = KVPair<'b, 'tx>>> {
+        if self.inner.borrow().deleted {
+            panic!("Cannot scan a deleted bucket.");
+        }
+        let leaves = self.leaf_elements()?;
+        let cursor = leaves.is_none().then(|| self.kv_pairs());
+        let pages_iter = leaves
+            .into_iter()
+            .flatten()
+            .filter(|leaf| leaf.node_type() == Node::TYPE_DATA)
+            .map(|leaf| KVPair::new(Bytes::Slice(leaf.key()), Bytes::Slice(leaf.value())));
+        Ok(cursor.into_iter().flatten().chain(pages_iter))
+    }
+
+    /// Calls `f` with every key in this bucket in order, including the names of nested buckets.
+    ///
+    /// This is the cheapest way to go through a bucket's keys. Like [`fast_scan`](#method.fast_scan),
+    /// it reads each leaf page in turn, and every key is passed straight from the memory mapped file
+    /// without being copied or wrapped in anything. It's meant for feeding keys into something
+    /// like a bloom filter, where you look at each key once and don't need to keep it.
+    /// If any part of the bucket has been modified in this transaction, it falls back to using a cursor.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the bucket's pages
+    /// contain a cycle or point past the end of the file, before `f` is called for any key.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::{Hash, Hasher};
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    ///
+    /// // a tiny one-hash bloom filter
+    /// let mut bits = vec![false; 1 << 16];
+    /// tx.get_bucket("my-bucket")?.stream_keys(|key| {
+    ///     let mut hasher = DefaultHasher::new();
+    ///     key.hash(&mut hasher);
+    ///     let bit = hasher.finish() as usize % bits.len();
+    ///     bits[bit] = true;
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_keys<F: FnMut(&[u8])>(&self, mut f: F) -> Result<()> {
+        if self.inner.borrow().deleted {
+            panic!("Cannot scan a deleted bucket.");
+        }
+        match self.leaf_elements()? {
+            Some(leaves) => leaves.for_each(|leaf| f(leaf.key())),
+            None => self.cursor().for_each(|data| f(data.key())),
+        }
+        Ok(())
+    }
+
+    // Walks every element on this bucket's leaf pages in order, going straight through the pages.
+    // Returns None if part of the bucket has been modified, since then the pages are out of date.
+    fn leaf_elements(&self) -> Result<Option<impl Iterator<Item = &'tx LeafElement>>> {
+        let b = self.inner.borrow();
+        // Nodes only exist for the parts of the tree that have been (or are about to be) modified,
+        // and those need the cursor to see past the pages.
+        if b.meta.root_page == 0 || !b.nodes.is_empty() {
+            return Ok(None);
+        }
+        // Find every leaf page before reading any of them, since the page ids come straight from the file
+        // and one that's out of bounds has to be reported before the caller has seen part of the bucket.
+        let mut leaf_pages: Vec<&'tx Page> = Vec::new();
+        let mut visited = HashSet::new();
+        let mut page_stack = vec![b.meta.root_page];
+        while let Some(page_id) = page_stack.pop() {
+            if !visited.insert(page_id) {
+                return Err(Error::InvalidDB(format!(
+                    "cycle detected at page {}",
+                    page_id
+                )));
+            }
+            if page_id <= 1 || page_id >= b.pages.num_pages {
+                return Err(Error::InvalidDB(format!(
+                    "Page {} is out of bounds",
+                    page_id
+                )));
+            }
+            let page: &'tx Page = b.pages.page(page_id);
+            if page_id + page.overflow >= b.pages.num_pages {
+                return Err(Error::InvalidDB(format!(
+                    "Page {} overflows out of bounds",
+                    page_id
+                )));
+            }
+            match page.page_type {
+                Page::TYPE_BRANCH => {
+                    page_stack.extend(page.branch_elements().iter().rev().map(|b| b.page))
+                }
+                _ => leaf_pages.push(page),
+            }
+        }
+        Ok(Some(
+            leaf_pages
+                .into_iter()
+                .flat_map(|page| page.leaf_elements().iter()),
+        ))
+    }
+
+    /// Returns the size of the largest key and the largest value in this bucket, in bytes.
+    ///
+    /// Only key / value pairs directly in this bucket are looked at, not nested buckets.
+    /// The sizes come straight from the leaf pages like [`fast_scan`](#method.fast_scan), so no data is copied.
+    /// This is useful for finding out whether a few oversized entries are responsible for a bucket's pages being bloated.
+    /// An empty bucket returns `(0, 0)`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the bucket's pages
+    /// contain a cycle or point past the end of the file.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this bucket has been deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    ///
+    /// let (key_size, value_size) = tx.get_bucket("my-bucket")?.size_extremes()?;
+    /// println!("largest key: {} bytes, largest value: {} bytes", key_size, value_size);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn size_extremes(&self) -> Result<(usize, usize)> {
+        Ok(self
+            .fast_scan()?
+            .fold((0, 0), |(key_size, value_size), kv| {
+                (
+                    key_size.max(kv.key().len()),
+                    value_size.max(kv.value().len()),
+                )
+            }))
+    }
+
+    // Walks both buckets side by side, returning false as soon as they differ.
+    pub(crate) fn logically_eq(&self, other: &Bucket) -> Result<bool> {
+        let (mut mine, mut theirs) = (self.cursor(), other.cursor());
+        loop {
+            match (mine.next(), theirs.next()) {
+                (None, None) => return Ok(true),
+                (Some(Data::KeyValue(a)), Some(Data::KeyValue(b))) => {
+                    if a.kv() != b.kv() {
+                        return Ok(false);
+                    }
+                }
+                (Some(Data::Bucket(a)), Some(Data::Bucket(b))) => {
+                    if a.name() != b.name() {
+                        return Ok(false);
+                    }
+                    let (a, b) = (self.get_bucket(&a)?, other.get_bucket(&b)?);
+                    if a.next_int() != b.next_int()
+                        || a.counter() != b.counter()
+                        || !a.logically_eq(&b)?
+                    {
+                        return Ok(false);
+                    }
+                }
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    // Copies every key / value pair and nested bucket into dest, keeping each nested bucket's next_int and counter.
+    pub(crate) fn copy_into(&self, dest: &Bucket) -> Result<()> {
+        for data in self.cursor() {
+            match data {
+                Data::KeyValue(kv) => {
+                    dest.put(kv.key().to_vec(), kv.value().to_vec())?;
+                }
+                Data::Bucket(b) => {
+                    let from = self.get_bucket(&b)?;
+                    let to = dest.create_bucket(b.name().to_vec())?;
+                    from.copy_into(&to)?;
+                    // copying the data bumps next_int, so set it afterwards
+                    to.inner.borrow_mut().meta.next_int = from.next_int();
+                    to.set_counter(from.counter())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Merge-joins both buckets by key, calling f with every difference and the path of buckets it is in.
+    // If neither bucket is writable, a nested bucket still rooted at the same page hasn't changed,
+    // since committed pages are never modified in place, so there's no need to look inside it.
+    pub(crate) fn diff<F>(&self, newer: &Bucket, path: &mut Vec<Vec<u8>>, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&[Vec<u8>], Difference) -> Result<bool>,
+    {
+        let (mut old, mut new) = (self.cursor().peekable(), newer.cursor().peekable());
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (None, None) => return Ok(()),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(a), Some(b)) => a.key().cmp(b.key()),
+            };
+            match order {
+                Ordering::Less => {
+                    let data = old.next().unwrap();
+                    self.diff_all(data, ChangeKind::Removed, path, f)?;
+                }
+                Ordering::Greater => {
+                    let data = new.next().unwrap();
+                    newer.diff_all(data, ChangeKind::Added, path, f)?;
+                }
+                Ordering::Equal => match (old.next().unwrap(), new.next().unwrap()) {
+                    (Data::KeyValue(a), Data::KeyValue(b)) => {
+                        if a.value() != b.value() {
+                            f(
+                                path,
+                                Difference::Pair(b.key(), b.value(), ChangeKind::Changed),
+                            )?;
+                        }
+                    }
+                    (Data::Bucket(name), Data::Bucket(_)) => {
+                        let name = name.name().to_vec();
+                        let (a, b) = (
+                            self.get_bucket(name.clone())?,
+                            newer.get_bucket(name.clone())?,
+                        );
+                        let committed = !self.writable && !newer.writable;
+                        if committed && a.root_page() == b.root_page() {
+                            continue;
+                        }
+                        path.push(name);
+                        a.diff(&b, path, f)?;
+                        path.pop();
+                    }
+                    // The key changed type, so everything under the old one is gone
+                    (a, b) => {
+                        self.diff_all(a, ChangeKind::Removed, path, f)?;
+                        newer.diff_all(b, ChangeKind::Added, path, f)?;
+                    }
+                },
+            }
+        }
+    }
+
+    // Reports a key / value pair or nested bucket that is only in this bucket as added or removed,
+    // followed by everything in the nested bucket if f asks for it.
+    fn diff_all<F>(
+        &self,
+        data: Data,
+        kind: ChangeKind,
+        path: &mut Vec<Vec<u8>>,
+        f: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&[Vec<u8>], Difference) -> Result<bool>,
+    {
+        match data {
+            Data::KeyValue(kv) => {
+                f(path, Difference::Pair(kv.key(), kv.value(), kind))?;
+            }
+            Data::Bucket(name) => {
+                if f(path, Difference::Bucket(name.name(), kind))? {
+                    let name = name.name().to_vec();
+                    let b = self.get_bucket(name.clone())?;
+                    path.push(name);
+                    for data in b.cursor() {
+                        b.diff_all(data, kind, path, f)?;
+                    }
+                    path.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies every key / value pair in this bucket into a [`BTreeMap`], skipping nested buckets.
+    ///
+    /// All of the data is copied into memory, so this is meant for small buckets like configuration,
+    /// where having an owned map that outlives the transaction is more convenient than iterating.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let config = {
+    ///     let tx = db.tx(false)?;
+    ///     let bucket = tx.get_bucket("config")?;
+    ///     bucket.to_btreemap()
+    /// };
+    /// println!("{:?}", config.get(b"name".as_slice()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_btreemap(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        self.kv_pairs()
+            .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+            .collect()
+    }
+
+    /// Copies every key / value pair in this bucket into a [`HashMap`], skipping nested buckets.
+    ///
+    /// Like [`to_btreemap`](#method.to_btreemap), this copies all of the data into memory and is meant for small buckets.
+    pub fn to_hashmap(&self) -> HashMap<Vec<u8>, Vec<u8>> {
+        self.kv_pairs()
+            .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+            .collect()
+    }
+
+    /// Iterator over the key / value pairs in this bucket, along with the result of calling `f` on each value.
+    ///
+    /// This is handy for parsing values as you go. The pairs are not copied,
+    /// so the key is still available through [`KVPair::key`](struct.KVPair.html#method.key).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("counters")?;
+    /// for (kv, count) in bucket.map_values(|v| u64::from_be_bytes(v.try_into().unwrap())) {
+    ///     println!("{:?} = {}", kv.key(), count);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_values<'a, T, F>(&'a self, f: F) -> impl Iterator<Item = (KVPair<'b, 'tx>, T)> + 'a
+    where
+        F: Fn(&[u8]) -> T + 'a,
+    {
+        self.kv_pairs().map(move |kv| {
+            let t = f(kv.value());
+            (kv, t)
+        })
+    }
+
+    /// Iterator over the key / value pairs whose key comes after `seq`, for buckets keyed by big-endian `u64`s.
+    ///
+    /// Keys written with [`next_int`](#method.next_int) as `next_int.to_be_bytes()` sort in the order they were inserted,
+    /// so a bucket used as an append-only log can be read as a change feed by remembering the last sequence number seen.
+    /// Keys written as little-endian or native-endian bytes will not sort by their value, so this will skip or repeat entries.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("events")?;
+    /// let last_seen = 41_u64;
+    /// for kv in bucket.since(last_seen) {
+    ///     let seq = u64::from_be_bytes(kv.key().try_into().unwrap());
+    ///     println!("event {} = {:?}", seq, kv.value());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn since(&self, seq: u64) -> impl Iterator<Item = KVPair<'b, 'tx>> {
+        let key = seq.to_be_bytes();
+        let mut cursor = self.cursor();
+        cursor.seek(key);
+        // the cursor lands on the last entry if seq is past the end
+        cursor
+            .to_kv_pairs()
+            .skip_while(move |kv| kv.key() <= &key[..])
+    }
+
+    /// Iterator over the key / value pairs in this bucket whose value matches `pred`.
+    ///
+    /// This is a full scan of the bucket, so it is O(n) in the number of entries.
+    /// It is meant for reverse lookups in small buckets, not as a replacement for an index.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("users-by-id")?;
+    /// for kv in bucket.find_by_value(|v| v == b"bob") {
+    ///     println!("bob's id is {:?}", kv.key());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_by_value<'a, F>(&'a self, pred: F) -> impl Iterator<Item = KVPair<'b, 'tx>> + 'a
+    where
+        F: Fn(&[u8]) -> bool + 'a,
+    {
+        self.kv_pairs().filter(move |kv| pred(kv.value()))
+    }
+
+    /// Estimates how many key / value pairs and nested buckets fall within a range, without scanning it.
+    ///
+    /// Only the paths from the root of the bucket's B+ tree to the start and end of the range are visited,
+    /// and the number of elements in between is extrapolated from the size of the nodes along those paths.
+    /// The estimate is exact for small buckets that fit in a single page, but can be far off for
+    /// larger buckets with unevenly filled pages. Use [`range`](#method.range) if you need an exact count.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("my-bucket")?;
+    /// let start: &[u8] = b"a";
+    /// let end: &[u8] = b"m";
+    /// if bucket.estimate_range_count(start..end) > 1000 {
+    ///     println!("that's a big range");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn estimate_range_count<'a, R>(&self, r: R) -> u64
+    where
+        R: RangeBounds<&'a [u8]>,
+    {
+        let b = self.inner.borrow();
+        if b.deleted {
+            panic!("Cannot estimate range of a deleted bucket.");
+        }
+        let estimate = |bound, end| match b.estimate_position(bound, end) {
+            Ok(estimate) => estimate,
+            Err(e) => panic!("{}", e),
+        };
+        let (start, start_total) = estimate(r.start_bound(), false);
+        let (end, end_total) = estimate(r.end_bound(), true);
+        if end <= start {
+            return 0;
+        }
+        // The right edge of a tree is usually less full than the rest, so only use it if we have to
+        let total = match (r.start_bound(), r.end_bound()) {
+            (_, Bound::Unbounded) => start_total,
+            (Bound::Unbounded, _) => end_total,
+            _ => (start_total + end_total) / 2.0,
+        };
+        ((end - start) * total).round() as u64
+    }
+
+    pub fn range<'a, R>(&'a self, r: R) -> Range<'a, 'b, 'tx, R>
+    where
+        R: RangeBounds<&'a [u8]>,
+    {
+        Range {
+            c: self.cursor(),
+            bounds: r,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Copies up to `limit` key / value pairs from the given range,
+    /// skipping over any nested buckets.
+    ///
+    /// This is the same as iterating over [`range`](#method.range) yourself,
+    /// but the results don't borrow from the transaction, so they're easy to return from a function.
+    /// Every key and value is copied into its own allocation,
+    /// so keep `limit` small enough that all of them fit comfortably in memory.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, OwnedKVPair};
+    /// # use jammdb::Error;
+    ///
+    /// fn first_page(db: &DB) -> Result<Vec<OwnedKVPair>, Error> {
+    ///     let tx = db.tx(false)?;
+    ///     let bucket = tx.get_bucket("my-bucket")?;
+    ///     let start: &[u8] = b"a";
+    ///     let end: &[u8] = b"m";
+    ///     Ok(bucket.range_collect(start..end, 20))
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bucket has been deleted.
+    pub fn range_collect<'a, R>(&'a self, r: R, limit: usize) -> Vec<OwnedKVPair>
+    where
+        R: RangeBounds<&'a [u8]>,
+    {
+        self.range(r)
+            .to_kv_pairs()
+            .take(limit)
+            .map(OwnedKVPair::from)
+            .collect()
+    }
+
+    /// Iterator over the data in this bucket whose key starts with `prefix`, in order.
+    ///
+    /// This seeks straight to the prefix and stops at the first key that doesn't start with it,
+    /// so it only reads the matching part of the bucket. Unlike a [`range`](#method.range),
+    /// there's no end key to work out, which is easy to get wrong for prefixes ending in `0xFF`.
+    /// An empty prefix matches everything.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// let bucket = tx.get_bucket("users")?;
+    /// for data in bucket.prefix(b"user:42:") {
+    ///     println!("{:?}", data.key());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bucket has been deleted.
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = Data<'b, 'tx>> + 'a {
+        let mut cursor = self.cursor();
+        cursor.seek(prefix);
+        // the cursor lands on the entry before the prefix if nothing comes after it
+        cursor
+            .skip_while(move |data| data.key() < prefix)
+            .take_while(move |data| data.key().starts_with(prefix))
+    }
+}
+
+// and we'll implement IntoIterator
+impl<'b, 'tx> IntoIterator for Bucket<'b, 'tx> {
+    type Item = Data<'b, 'tx>;
+    type IntoIter = Cursor<'b, 'tx>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.cursor()
+    }
+}
+
+pub(crate) struct InnerBucket<'b> {
+    pub(crate) meta: BucketMeta,
+    root: PageNodeID,
+    pub(crate) deleted: bool,
+    dirty: bool,
+    buckets: HashMap<Bytes<'b>, Rc<RefCell<InnerBucket<'b>>>>,
+    pub(crate) nodes: Vec<Rc<RefCell<Node<'b>>>>,
+    // Maps a PageID to it's NodeID, so we don't create multiple nodes for a single page
+    page_node_ids: HashMap<PageID, NodeID>,
+    // Maps PageIDs to their parent's PageID
+    page_parents: HashMap<PageID, PageID>,
+    pages: Pages,
+    // reject new keys that aren't valid UTF-8
+    pub(crate) utf8_keys: bool,
+}
+
+impl<'b> InnerBucket<'b> {
+    pub(crate) fn from_meta(meta: BucketMeta, pages: Pages) -> InnerBucket<'b> {
+        debug_assert!(
+            meta.root_page > 1,
+            "bucket cannot have root page {}, reserved for meta",
+            meta.root_page
+        );
+        InnerBucket {
+            meta,
+            root: PageNodeID::Page(meta.root_page),
+            deleted: false,
+            dirty: false,
+            buckets: HashMap::new(),
+            nodes: Vec::new(),
+            page_node_ids: HashMap::new(),
+            page_parents: HashMap::new(),
+            pages,
+            utf8_keys: false,
+        }
+    }
+
+    fn new_child<'a>(&'a mut self, name: Bytes<'b>) -> RefMut<'a, InnerBucket<'b>> {
+        self.dirty = true;
+        let n = Node::new(
+            0,
+            Page::TYPE_LEAF,
+            self.pages.pagesize,
+            self.pages.value_checksums,
+        );
+        let mut page_node_ids = HashMap::new();
+        page_node_ids.insert(0, 0);
+        let b = InnerBucket {
+            meta: BucketMeta::default(),
+            root: PageNodeID::Node(0),
+            deleted: false,
+            dirty: true,
+            buckets: HashMap::new(),
+            nodes: vec![Rc::new(RefCell::new(n))],
+            page_node_ids,
+            page_parents: HashMap::new(),
+            pages: self.pages.clone(),
+            utf8_keys: self.utf8_keys,
+        };
+        self.buckets.insert(name.clone(), Rc::new(RefCell::new(b)));
+        let b = self.buckets.get_mut(&name).unwrap();
+        b.borrow_mut()
+    }
+
+    pub(crate) fn add_page_parent(&mut self, page: PageID, parent: PageID) {
+        debug_assert!(
+            self.meta.root_page == parent || self.page_parents.contains_key(&parent),
+            "cannot find reference to parent page ID \"{}\"",
+            parent
+        );
+        self.page_parents.insert(page, parent);
+    }
+
+    pub(crate) fn page_node<'a>(&'a self, id: PageNodeID) -> PageNode<'b> {
+        match id {
+            PageNodeID::Page(page) => {
+                if let Some(node_id) = self.page_node_ids.get(&page) {
+                    PageNode::Node(self.nodes[*node_id as usize].clone())
+                } else {
+                    PageNode::Page(self.pages.page(page))
+                }
+            }
+            PageNodeID::Node(node) => PageNode::Node(self.nodes[node as usize].clone()),
+        }
+    }
+
+    // The child at index of a branch, as long as it's really part of the database.
+    pub(crate) fn index_page(&self, page_node: &PageNode, index: usize) -> Result<PageID> {
+        page_node.index_page(index, self.pages.num_pages)
+    }
+
+    // With verify, also makes sure the value wasn't corrupted on disk.
+    pub fn get<T: AsRef<[u8]>>(&mut self, key: T, verify: bool) -> Result<Option<Leaf<'b>>> {
+        let key = key.as_ref();
+        let (exists, stack) = search(key, self.meta.root_page, self)?;
+        let last = stack.last().unwrap();
+        if !exists {
+            return Ok(None);
+        }
+        let page_node = self.page_node(last.id);
+        if verify && !page_node.checksum_ok(last.index) {
+            return Err(Error::ValueCorrupt { key: key.to_vec() });
+        }
+        Ok(page_node.val(last.index))
+    }
+
+    fn get_located<T: AsRef<[u8]>>(&mut self, key: T) -> Result<Option<(Leaf<'b>, PageID)>> {
+        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self)?;
+        if !exists {
+            return Ok(None);
+        }
+        let last = stack.last().unwrap();
+        let page_node = self.page_node(last.id);
+        let page_id = match &page_node {
+            PageNode::Page(p) => p.id,
+            PageNode::Node(n) => n.borrow().page_id,
+        };
+        Ok(page_node.val(last.index).map(|leaf| (leaf, page_id)))
+    }
+
+    fn value_len<T: AsRef<[u8]>>(&mut self, key: T) -> Result<Option<u64>> {
+        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self)?;
+        if !exists {
+            return Ok(None);
+        }
+        let last = stack.last().unwrap();
+        Ok(self.page_node(last.id).value_len(last.index))
+    }
+
+    pub fn put<'a, T: ToBytes<'b>, S: ToBytes<'b>>(
+        &'a mut self,
+        key: T,
+        value: S,
+    ) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        let k = key.to_bytes();
+        self.check_key(k.as_ref())?;
+        let v = value.to_bytes();
+
+        match self.put_leaf(Leaf::Kv(k, v))? {
+            Some(data) => match data {
+                Leaf::Kv(k, v) => Ok(Some((k, v))),
+                _ => panic!("Unexpected data"),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_if_absent_with<T, F, S>(&mut self, key: T, f: F) -> Result<bool>
+    where
+        T: ToBytes<'b>,
+        F: FnOnce() -> Result<S>,
+        S: ToBytes<'b>,
+    {
+        let k = key.to_bytes();
+        self.check_key(k.as_ref())?;
+        let (exists, stack) = search(k.as_ref(), self.meta.root_page, self)?;
+        if exists {
+            return Ok(false);
+        }
+        let v = f()?.to_bytes();
+        let last = stack.last().unwrap();
+        self.meta.next_int += 1;
+        let node = self.node(last.id, None);
+        let mut node = node.borrow_mut();
+        node.insert_data(Leaf::Kv(k, v));
+        self.dirty = true;
+        Ok(true)
+    }
+
+    fn increment<T: AsRef<[u8]>>(&mut self, key: T, delta: i64) -> Result<u64> {
+        let k = key.as_ref();
+        self.check_key(k)?;
+        let (exists, stack) = search(k, self.meta.root_page, self)?;
+        let last = stack.last().unwrap();
+        let current = if exists {
+            let page_node = self.page_node(last.id);
+            if !page_node.checksum_ok(last.index) {
+                return Err(Error::ValueCorrupt { key: k.to_vec() });
+            }
+            match page_node.val(last.index) {
+                Some(Leaf::Kv(_, v)) => match v.as_ref().try_into() {
+                    Ok(bytes) => u64::from_be_bytes(bytes),
+                    Err(_) => return Err(Error::IncompatibleValue),
+                },
+                _ => return Err(Error::IncompatibleValue),
+            }
+        } else {
+            0
+        };
+        let value = if delta >= 0 {
+            current.checked_add(delta as u64)
+        } else {
+            current.checked_sub(delta.unsigned_abs())
+        }
+        .ok_or(Error::CounterOverflow)?;
+        if !exists {
+            self.meta.next_int += 1;
+        }
+        let node = self.node(last.id, None);
+        let mut node = node.borrow_mut();
+        node.insert_data(Leaf::Kv(
+            k.to_vec().to_bytes(),
+            value.to_be_bytes().to_vec().to_bytes(),
+        ));
+        self.dirty = true;
+        Ok(value)
+    }
+
+    fn delete<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Result<(Bytes<'b>, Bytes<'b>)> {
+        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self)?;
+        let last = stack.last().unwrap();
+        if exists {
+            let page_node = self.page_node(last.id);
+            let data = page_node.val(last.index).unwrap();
+            if data.is_kv() {
+                let current_id = last.id;
+                let index = last.index;
+                self.dirty = true;
+                let node = self.node(current_id, None);
+                let mut node = node.borrow_mut();
+                match node.delete(index) {
+                    Leaf::Kv(k, v) => Ok((k, v)),
+                    _ => panic!("Unexpected data"),
+                }
+            } else {
+                Err(Error::IncompatibleValue)
+            }
+        } else {
+            Err(Error::KeyValueMissing)
+        }
+    }
+
+    pub(crate) fn check_key(&self, key: &[u8]) -> Result<()> {
+        if self.utf8_keys && std::str::from_utf8(key).is_err() {
+            return Err(Error::NonUtf8Key);
+        }
+        Ok(())
+    }
+
+    fn put_leaf<'a>(&'a mut self, leaf: Leaf<'b>) -> Result<Option<Leaf<'b>>> {
+        let (exists, stack) = search(leaf.key(), self.meta.root_page, self)?;
+        let last = stack.last().unwrap();
+        let current_data = if exists {
+            let page_node = self.page_node(last.id);
+            let current = page_node.val(last.index).unwrap();
+            if current.is_kv() != leaf.is_kv() {
+                return Err(Error::IncompatibleValue);
+            }
+            Some(current)
+        } else {
+            self.meta.next_int += 1;
+            None
+        };
+        let node = self.node(last.id, None);
+        let mut node = node.borrow_mut();
+        node.insert_data(leaf);
+        self.dirty = true;
+
+        Ok(current_data)
+    }
+
+    pub(crate) fn create_bucket<T: ToBytes<'b>>(&mut self, name: T) -> Result<Rc<RefCell<Self>>> {
+        self.bucket_getter(name.to_bytes(), true, true)
+            .map(|(b, _)| b)
+    }
+
+    pub(crate) fn get_bucket<'a, T: ToBytes<'b>>(
+        &'a mut self,
+        name: T,
+    ) -> Result<Rc<RefCell<Self>>> {
+        self.bucket_getter(name.to_bytes(), false, false)
+            .map(|(b, _)| b)
+    }
+
+    pub(crate) fn get_or_create_bucket<T: ToBytes<'b>>(
+        &mut self,
+        name: T,
+    ) -> Result<Rc<RefCell<Self>>> {
+        self.bucket_getter(name.to_bytes(), true, false)
+            .map(|(b, _)| b)
+    }
+
+    // Also returns whether the bucket was just created.
+    fn bucket_getter<'a>(
+        &'a mut self,
+        name: Bytes<'b>,
+        should_create: bool,
+        must_create: bool,
+    ) -> Result<(Rc<RefCell<InnerBucket<'b>>>, bool)> {
+        let mut created = false;
+        if !self.buckets.contains_key(&name) {
+            let (exists, stack) = search(name.as_ref(), self.meta.root_page, self)?;
+            let last = stack.last().unwrap();
+            if !exists {
+                if should_create {
+                    self.check_key(name.as_ref())?;
+                    self.meta.next_int += 1;
+                    let leaf = {
+                        let b = self.new_child(name.clone());
+                        let meta = b.meta;
+                        Leaf::Bucket(name.clone(), meta)
+                    };
+                    let node = self.node(last.id, None);
+                    let mut node = node.borrow_mut();
+                    node.insert_data(leaf);
+                    created = true;
+                } else {
+                    return Err(Error::BucketMissing);
+                }
+            } else {
+                let page_node = self.page_node(last.id);
+                match page_node.val(last.index) {
+                    Some(leaf) => match leaf {
+                        Leaf::Bucket(name, meta) => {
+                            if must_create {
+                                return Err(Error::BucketExists);
+                            }
+                            let mut b = Self::from_meta(meta, self.pages.clone());
+                            b.utf8_keys = self.utf8_keys;
+                            self.buckets.insert(name.clone(), Rc::new(RefCell::new(b)));
+                        }
+                        _ => return Err(Error::IncompatibleValue),
+                    },
+                    None => return Err(Error::BucketMissing),
+                }
+            }
+        } else if must_create {
+            return Err(Error::BucketExists);
+        }
+        Ok((self.buckets.get(&name).unwrap().clone(), created))
+    }
+
+    pub(crate) fn delete_bucket<T: ToBytes<'b>>(
+        &mut self,
+        name: T,
+        freelist: &mut TxFreelist,
+    ) -> Result<()> {
+        let name = name.to_bytes();
+        // make sure the bucket is in our map
+        let root_page = self.get_bucket(&name)?.borrow().meta.root_page;
+        // check that the bucket wasn't just created and never comitted,
+        // and find every page to free before changing anything in case the tree is corrupt
+        let pages = if root_page != 0 {
+            self.tree_pages(root_page, true)?
+        } else {
+            Vec::new()
+        };
+
+        // remove the bucket from the map so we won't have a reference to it anymore
+        let bucket = self.buckets.remove(&name).unwrap();
+        let mut b = bucket.borrow_mut();
+        // Mark it as deleted in case there is still a Bucket or cursor with a reference to this bucket.
+        b.deleted = true;
+        for (page_id, num_pages) in pages {
+            freelist.free(page_id, num_pages);
+        }
+        // delete the element from this bucket
+        let (exists, stack) = search(name.as_ref(), self.meta.root_page, self)?;
+        let last = stack.last().unwrap();
+        if exists {
+            let page_node = self.page_node(last.id);
+            let data = page_node.val(last.index).unwrap();
+
+            if !data.is_kv() {
+                self.dirty = true;
+                let current_id = last.id;
+                let index = last.index;
                 let node = self.node(current_id, None);
                 let mut node = node.borrow_mut();
                 node.delete(index);
                 Ok(())
-            } else {
-                Err(Error::IncompatibleValue)
+            } else {
+                Err(Error::IncompatibleValue)
+            }
+        } else {
+            panic!("Did not find data for bucket we already deleted")
+        }
+    }
+
+    // Replace this bucket's tree with a single leaf node holding all of its data,
+    // which will be split into packed nodes when the bucket is spilled.
+    pub(crate) fn compact(&mut self, freelist: &mut TxFreelist) -> Result<()> {
+        // the bucket has never been written, so it's already a single node
+        if self.meta.root_page == 0 {
+            return Ok(());
+        }
+        // the tree we're replacing, but not the nested buckets' pages
+        let pages = self.tree_pages(self.meta.root_page, false)?;
+        let mut leaves = Vec::new();
+        self.collect_leaves(PageNodeID::Page(self.meta.root_page), &mut leaves)?;
+        for (page_id, num_pages) in pages {
+            freelist.free(page_id, num_pages);
+        }
+        // the old nodes' pages were just freed, so make sure they never get written
+        for node in self.nodes.iter() {
+            let mut node = node.borrow_mut();
+            node.deleted = true;
+            node.page_id = 0;
+        }
+
+        let node_id = self.nodes.len() as u64;
+        let mut n = Node::new(
+            node_id,
+            Page::TYPE_LEAF,
+            self.pages.pagesize,
+            self.pages.value_checksums,
+        );
+        n.data = NodeData::Leaves(leaves);
+        self.nodes.push(Rc::new(RefCell::new(n)));
+        self.page_node_ids.clear();
+        self.page_node_ids.insert(0, node_id);
+        self.page_parents.clear();
+        self.meta.root_page = 0;
+        self.root = PageNodeID::Node(node_id);
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Finds every page in the committed tree starting at root_page, along with how many pages each one spans.
+    // If `nested` is set, the trees of nested buckets are included too.
+    // A corrupt file could have a page that points back up the tree, so make sure we never visit a page twice.
+    fn tree_pages(&self, root_page: PageID, nested: bool) -> Result<Vec<(PageID, u64)>> {
+        let mut visited = HashSet::new();
+        let mut pages = Vec::new();
+        let mut remaining_pages = vec![root_page];
+        while let Some(page_id) = remaining_pages.pop() {
+            if !visited.insert(page_id) {
+                return Err(Error::InvalidDB(format!(
+                    "cycle detected at page {}",
+                    page_id
+                )));
+            }
+            let page = self.pages.page(page_id);
+            match page.page_type {
+                Page::TYPE_BRANCH => page
+                    .branch_elements()
+                    .iter()
+                    .for_each(|b| remaining_pages.push(b.page)),
+                Page::TYPE_LEAF if nested => page.leaf_elements().iter().for_each(|leaf| {
+                    if leaf.node_type() == Node::TYPE_BUCKET {
+                        let meta: BucketMeta = leaf.value().into();
+                        remaining_pages.push(meta.root_page);
+                    }
+                }),
+                _ => (),
+            }
+            pages.push((page_id, page.overflow + 1));
+        }
+        Ok(pages)
+    }
+
+    // Walks the committed tree in key order, checking that keys only ever increase
+    // and that branch keys match the pages they point to.
+    fn validate(&self) -> Result<()> {
+        if self.meta.root_page == 0 {
+            return Ok(());
+        }
+        let mut visited = HashSet::new();
+        let mut last = None;
+        self.validate_page(self.meta.root_page, &mut visited, &mut last)?;
+        Ok(())
+    }
+
+    // Validates one page and everything below it, returning the page's first key.
+    // `last` is the last leaf key seen so far, across every page.
+    fn validate_page<'a>(
+        &self,
+        page_id: PageID,
+        visited: &mut HashSet<PageID>,
+        last: &mut Option<&'a [u8]>,
+    ) -> Result<Option<&'a [u8]>> {
+        if page_id <= 1 || (page_id + 1) * self.pages.pagesize > self.pages.data.len() as u64 {
+            return Err(Error::InvalidDB(format!(
+                "Page {} is out of bounds",
+                page_id
+            )));
+        }
+        if !visited.insert(page_id) {
+            return Err(Error::InvalidDB(format!(
+                "cycle detected at page {}",
+                page_id
+            )));
+        }
+        let page: &'a Page = self.pages.page(page_id);
+        match page.page_type {
+            Page::TYPE_BRANCH => {
+                let mut first = None;
+                for b in page.branch_elements().iter() {
+                    let child_first = self.validate_page(b.page, visited, last)?;
+                    if child_first != Some(b.key()) {
+                        return Err(Error::InvalidDB(format!(
+                            "Branch page {} has key {:?} for page {}, which starts with {:?}",
+                            page_id,
+                            String::from_utf8_lossy(b.key()),
+                            b.page,
+                            child_first.map(String::from_utf8_lossy),
+                        )));
+                    }
+                    first = first.or(child_first);
+                }
+                if first.is_none() {
+                    return Err(Error::InvalidDB(format!(
+                        "Branch page {} is empty",
+                        page_id
+                    )));
+                }
+                Ok(first)
+            }
+            Page::TYPE_LEAF => {
+                for leaf in page.leaf_elements().iter() {
+                    let key = leaf.key();
+                    if let Some(prev) = *last {
+                        if prev >= key {
+                            return Err(Error::InvalidDB(format!(
+                                "Leaf page {} has key {:?} after {:?}",
+                                page_id,
+                                String::from_utf8_lossy(key),
+                                String::from_utf8_lossy(prev),
+                            )));
+                        }
+                    }
+                    *last = Some(key);
+                }
+                Ok(page.leaf_elements().first().map(|leaf| leaf.key()))
+            }
+            t => Err(Error::InvalidDB(format!(
+                "Page {} has type {} in a bucket",
+                page_id, t
+            ))),
+        }
+    }
+
+    // Walks down to the leaf where a range bound belongs, returning how far through the bucket the bound is (from 0 to 1),
+    // and an estimate of the bucket's total number of elements, assuming every node is as full as the ones we visit.
+    fn estimate_position(&self, bound: Bound<&&[u8]>, end: bool) -> Result<(f64, f64)> {
+        let mut position = 0.0;
+        let mut scale = 1.0;
+        let mut total = 1.0;
+        let mut page_node = self.page_node(PageNodeID::Page(self.meta.root_page));
+        while !page_node.leaf() {
+            let len = page_node.len();
+            let index = match bound {
+                Bound::Included(key) | Bound::Excluded(key) => page_node.index(key).0,
+                Bound::Unbounded if end => len - 1,
+                Bound::Unbounded => 0,
+            };
+            position += scale * index as f64 / len as f64;
+            scale /= len as f64;
+            total *= len as f64;
+            page_node = self.page_node(PageNodeID::Page(self.index_page(&page_node, index)?));
+        }
+        let len = page_node.len();
+        if len > 0 {
+            let index = match bound {
+                // an included end or excluded start bound is just after the key
+                Bound::Included(key) | Bound::Excluded(key) => match page_node.insertion_index(key)
+                {
+                    (i, true) if end == matches!(bound, Bound::Included(_)) => i + 1,
+                    (i, _) => i,
+                },
+                Bound::Unbounded if end => len,
+                Bound::Unbounded => 0,
+            };
+            position += scale * index as f64 / len as f64;
+        } else if end {
+            position = 1.0;
+        }
+        Ok((position, total * len as f64))
+    }
+
+    fn collect_leaves(&self, id: PageNodeID, leaves: &mut Vec<Leaf<'b>>) -> Result<()> {
+        let page_node = self.page_node(id);
+        if page_node.leaf() {
+            for i in 0..page_node.len() {
+                leaves.push(page_node.val(i).unwrap());
+            }
+        } else {
+            for i in 0..page_node.len() {
+                self.collect_leaves(PageNodeID::Page(self.index_page(&page_node, i)?), leaves)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn node<'a>(
+        &'a mut self,
+        id: PageNodeID,
+        parent: Option<&mut Node>,
+    ) -> Rc<RefCell<Node<'b>>> {
+        let id: NodeID = match id {
+            PageNodeID::Page(page_id) => {
+                if let Some(node_id) = self.page_node_ids.get(&page_id) {
+                    return self.nodes[*node_id as usize].clone();
+                }
+                debug_assert!(
+                    self.meta.root_page == page_id || self.page_parents.contains_key(&page_id),
+                    "cannot find reference to page ID \"{}\"",
+                    page_id,
+                );
+                let node_id = self.nodes.len() as u64;
+                self.page_node_ids.insert(page_id, node_id);
+                let n: Node = Node::from_page(
+                    node_id,
+                    self.pages.page(page_id),
+                    self.pages.pagesize,
+                    self.pages.value_checksums,
+                );
+                self.nodes.push(Rc::new(RefCell::new(n)));
+                // If this node is not for the root page, then recursively create nodes for the parent pages
+                if self.meta.root_page != page_id {
+                    let n = self.nodes[node_id as usize].clone();
+                    let mut n = n.borrow_mut();
+                    let node_key = n.data.first_key();
+                    if let Some(parent) = parent {
+                        parent.insert_child(node_id, node_key);
+                        n.parent = Some(parent.id);
+                    } else {
+                        let parent = self.node(PageNodeID::Page(self.page_parents[&page_id]), None);
+                        let mut parent = parent.borrow_mut();
+                        parent.insert_child(node_id, node_key);
+                        n.parent = Some(parent.id);
+                    }
+                }
+                node_id
+            }
+            PageNodeID::Node(id) => id,
+        };
+        self.nodes.get_mut(id as usize).unwrap().clone()
+    }
+
+    pub(crate) fn new_node<'a>(&'a mut self, data: NodeData<'b>) -> Rc<RefCell<Node<'b>>> {
+        debug_assert!(data.len() >= 2);
+        let node_id = self.nodes.len() as u64;
+        let n = Node::with_data(
+            node_id,
+            data,
+            self.pages.pagesize,
+            self.pages.value_checksums,
+        );
+        self.nodes.push(Rc::new(RefCell::new(n)));
+        self.nodes[node_id as usize].clone()
+    }
+
+    fn is_dirty(&mut self) -> bool {
+        // If it isn't marked as dirty, make sure by checking
+        // the sub-buckets to see if they're dirty.
+        if !self.dirty {
+            for (_key, b) in self.buckets.iter() {
+                let mut b = b.borrow_mut();
+                if b.is_dirty() {
+                    self.dirty = true;
+                    break;
+                }
+            }
+        }
+        self.dirty
+    }
+
+    // Make sure none of the nodes are too empty, merging nodes smaller than merge_threshold of a page.
+    // With no threshold, only merge the nodes that are too small to be valid.
+    pub(crate) fn rebalance(
+        &mut self,
+        tx_freelist: &mut TxFreelist,
+        merge_threshold: Option<f32>,
+    ) -> Result<()> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        for b in self.buckets.values() {
+            let mut b = b.borrow_mut();
+            b.rebalance(tx_freelist, merge_threshold)?;
+        }
+
+        // merge emptyish nodes with siblings
+        self.merge_nodes(tx_freelist, merge_threshold);
+
+        Ok(())
+    }
+
+    fn merge_nodes(&mut self, tx_freelist: &mut TxFreelist, merge_threshold: Option<f32>) {
+        // If we haven't initialized any nodes yet, make sure we have the root node.
+        // If there is even one node, we are guarunteed to hage loaded the root node too.
+        if self.page_node_ids.is_empty() {
+            self.node(PageNodeID::Page(self.meta.root_page), None);
+        }
+        let mut stack: Vec<(bool, u64)> = vec![(false, self.page_node_ids[&self.meta.root_page])];
+
+        while let Some((visited, node_id)) = stack.pop() {
+            let node = self.nodes[node_id as usize].clone();
+            let mut node = node.borrow_mut();
+            // If this is a leaf node or our second time visiting a branch node, try to merge it
+            if visited || node.leaf() {
+                // Do nothing if this node needs no merging
+                let needs_merging = match merge_threshold {
+                    Some(threshold) => node.needs_merging(threshold),
+                    None => node.too_few_keys(),
+                };
+                if !needs_merging {
+                    continue;
+                }
+                // Handle root node speially
+                if node.page_id == self.meta.root_page {
+                    // If the root node has only one branch, promote that page to the root page
+                    if !node.leaf() && node.data.len() == 1 {
+                        // delete the root node
+                        node.free_page(tx_freelist);
+                        node.deleted = true;
+                        let page_id = if let NodeData::Branches(branches) = &node.data {
+                            branches[0].page
+                        } else {
+                            // We already know it was a branch node, so we can't get here.
+                            unreachable!()
+                        };
+                        // Just double check that the child page wasn't accidentally pointing at a meta page
+                        debug_assert!(
+                            page_id > 1,
+                            "cannot have page <= 1, those are reserved for metadata"
+                        );
+                        // Make that child page the bucket's root page.
+                        self.meta.root_page = page_id;
+                        self.root = PageNodeID::Page(page_id);
+                    }
+                } else {
+                    // else find a sibling and merge this node with that one
+                    let parent_id = node.parent.expect("non root node must have parent");
+                    let parent_ref = self.nodes[parent_id as usize].clone();
+
+                    // borrow the parent in a separate scope so we can drop it before we initialize the sibling node
+                    let mut parent = parent_ref.borrow_mut();
+                    if let NodeData::Branches(branches) = &mut parent.data {
+                        // If there is only one branch in the parent, then we cannot delete this node
+                        // since there are no siblings to move the data to.
+                        // When we handle the parent, it will get merged with it's siblings or promoted
+                        // to root.
+                        if branches.len() == 1 {
+                            continue;
+                        }
+                        // check if there is any data left to copy
+                        // find the child's branch element in the parent node's data
+                        let index = match branches.binary_search_by_key(
+                            &node.original_key.clone().unwrap().as_ref(),
+                            |b| b.key(),
+                        ) {
+                            Ok(i) => i,
+                            _ => panic!("child branch not found"),
+                        };
+                        if node.data.len() > 0 && branches.len() > 1 {
+                            // add that child's data to a sibling node
+                            let sibling_page = if index == 0 {
+                                // right sibling
+                                branches[index + 1].page
+                            } else {
+                                // left sibling
+                                branches[index - 1].page
+                            };
+
+                            self.page_parents.insert(sibling_page, parent.page_id);
+                            let sibling =
+                                self.node(PageNodeID::Page(sibling_page), Some(&mut parent));
+
+                            let mut sibling = sibling.borrow_mut();
+                            // Copy this node's data over to it's sibling
+                            sibling.data.merge(&mut node.data);
+                            if !node.children.is_empty() {
+                                // Move all children nodes over to that sibling too
+                                for child in node.children.iter() {
+                                    let c = &mut self.nodes[*child as usize];
+                                    let mut c = c.borrow_mut();
+                                    c.parent = Some(sibling.id);
+                                }
+                                sibling.children.append(&mut node.children);
+                            }
+                        }
+                        // free the child's page and mark it as deleted
+                        node.free_page(tx_freelist);
+                        node.deleted = true;
+                        if let NodeData::Branches(branches) = &mut parent.data {
+                            // remove the child from this node
+                            branches.remove(index);
+                        }
+                        if let Some(i) = parent.children.iter().position(|x| *x == node.id) {
+                            parent.children.remove(i);
+                        };
+                    }
+                }
+            } else {
+                // Add self back to stack to be processed after children
+                stack.push((true, node_id));
+                // Add all children to the stack, in reverse order so we pop them off
+                // the stack from left to right
+                for id in node.children.iter().rev() {
+                    stack.push((false, *id));
+                }
+            }
+        }
+    }
+
+    // Make sure none of the nodes are too full, creating other nodes as needed.
+    // Then, write all of those nodes to dirty pages.
+    // Drops every node in this bucket and its sub-buckets so they are read from their pages again.
+    // This must only be called right after spilling, once all of the nodes have been written to pages.
+    pub(crate) fn reload(&mut self, pages: &Pages) {
+        for b in self.buckets.values() {
+            b.borrow_mut().reload(pages);
+        }
+        self.root = PageNodeID::Page(self.meta.root_page);
+        self.dirty = false;
+        self.nodes.clear();
+        self.page_node_ids.clear();
+        self.page_parents.clear();
+        self.pages = pages.clone();
+    }
+
+    pub(crate) fn spill(&mut self, tx_freelist: &mut TxFreelist) -> Result<BucketMeta> {
+        if !self.is_dirty() {
+            return Ok(self.meta);
+        }
+
+        #[allow(clippy::mutable_key_type)]
+        let mut bucket_metas: HashMap<Bytes, BucketMeta> = HashMap::new();
+        for (key, b) in self.buckets.iter() {
+            let mut b = b.borrow_mut();
+            let old_meta = b.meta;
+            let bucket_meta = b.spill(tx_freelist)?;
+            // Buckets that were only read keep their meta, so leave their leaf untouched
+            if bucket_meta == old_meta {
+                continue;
+            }
+            // Store updated bucket metadata in a map since self is borrowed
+            bucket_metas.insert(key.clone(), bucket_meta);
+        }
+        // Update our pointers to the sub-buckets' new pages
+        for (name, meta) in bucket_metas {
+            self.put_leaf(Leaf::Bucket(name, meta))?;
+        }
+
+        let root = self.nodes[self.page_node_ids[&self.meta.root_page] as usize].clone();
+        let mut root = root.borrow_mut();
+        let page_id = root
+            .spill(self, tx_freelist, None)?
+            .expect("root node did not return a new page_id");
+        self.meta.root_page = page_id;
+
+        Ok(self.meta)
+    }
+}
+
+// Something that differs between two buckets, found by Bucket::diff.
+pub(crate) enum Difference<'a> {
+    // A key / value pair, with its value in the newer bucket, or in the older one if it was removed
+    Pair(&'a [u8], &'a [u8], ChangeKind),
+    // A nested bucket that is only in one of them, whose contents are reported after it if the callback returns true
+    Bucket(&'a [u8], ChangeKind),
+}
+
+pub const META_SIZE: usize = std::mem::size_of::<BucketMeta>();
+// Buckets written before the counter was added only have the root page and next int
+const OLD_META_SIZE: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BucketMeta {
+    pub(crate) root_page: PageID,
+    pub(crate) next_int: u64,
+    pub(crate) counter: u64,
+}
+
+impl AsRef<[u8]> for BucketMeta {
+    // A counter of zero is left off, so those buckets keep the old 16 byte format.
+    // Any other counter makes it 24 bytes, which releases from before the counter reject.
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        let ptr = self as *const BucketMeta as *const u8;
+        let size = match self.counter {
+            0 => OLD_META_SIZE,
+            _ => META_SIZE,
+        };
+        unsafe { std::slice::from_raw_parts(ptr, size) }
+    }
+}
+
+impl From<&[u8]> for BucketMeta {
+    // Because we need the pointer to match BucketMeta's alignment,
+    // we allocate a buffer on the stack that will definitely have
+    // space for the BucketMeta. Then we choose a point in that buffer
+    // that is aligned property, copy the data from value over,
+    // and cast our BucketMeta from there.
+    // Older, shorter values leave the rest of the buffer zeroed.
+    fn from(value: &[u8]) -> Self {
+        const SIZE: usize = size_of::<BucketMeta>();
+        const ALIGN: usize = align_of::<BucketMeta>();
+        debug_assert!(value.len() == SIZE || value.len() == OLD_META_SIZE);
+        let mut buf = [0_u8; SIZE + ALIGN];
+        let ptr = buf.as_mut_ptr();
+        unsafe {
+            let ptr = ptr.add(ptr.align_offset(ALIGN));
+            std::ptr::copy(value.as_ptr(), ptr, value.len().min(SIZE));
+            *(ptr as *const BucketMeta)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{testutil::RandomFile, OpenOptions, DB};
+
+    #[test]
+    fn bytes() {
+        let meta = BucketMeta {
+            root_page: 3,
+            next_int: 1,
+            counter: 2,
+        };
+        let bytes = meta.as_ref();
+        assert_eq!(
+            bytes,
+            &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(BucketMeta::from(bytes), meta);
+        // buckets written before the counter existed start it at zero
+        let old = BucketMeta::from(&bytes[..OLD_META_SIZE]);
+        assert_eq!(old.root_page, 3);
+        assert_eq!(old.next_int, 1);
+        assert_eq!(old.counter, 0);
+        // and buckets without one are still written that way
+        assert_eq!(old.as_ref(), &bytes[..OLD_META_SIZE]);
+    }
+
+    macro_rules! deleted_bucket_test {
+    	($($name:ident: ($expected_err:expr, $value:expr))*) => {
+    	$(
+    		#[test]
+            #[should_panic(expected = $expected_err)]
+    		fn $name() {
+                let random_file = RandomFile::new();
+                let db = DB::open(&random_file).unwrap();
+                let tx = db.tx(true).unwrap();
+                let b = tx.create_bucket("abc").unwrap();
+                tx.delete_bucket("abc").unwrap();
+                #[allow(clippy::redundant_closure_call)]
+                $value(&b);
+    		}
+    	)*
+    	}
+    }
+
+    deleted_bucket_test! {
+        deleted_bucket_put: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
+            let _ = b.put("a", "b");
+        })
+        deleted_bucket_put_if_absent_with: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
+            let _ = b.put_if_absent_with("a", || Ok("b"));
+        })
+        deleted_bucket_put_reader: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
+            let _ = b.put_reader("a", &b"b"[..]);
+        })
+        deleted_bucket_put_force: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
+            let _ = b.put_force("a", "b");
+        })
+        deleted_bucket_get: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
+            b.get("a");
+        })
+        deleted_bucket_delete: ("Cannot delete data from a deleted bucket.", |b: &Bucket| {
+            let _ = b.delete("a");
+        })
+        deleted_bucket_get_kv: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
+            b.get_kv("a");
+        })
+        deleted_bucket_iter: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.iter();
+        })
+        deleted_bucket_get_located: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
+            b.get_located("a");
+        })
+        deleted_bucket_counter: ("Cannot get counter from a deleted bucket.", |b: &Bucket| {
+            b.counter();
+        })
+        deleted_bucket_set_counter: ("Cannot set counter on a deleted bucket.", |b: &Bucket| {
+            let _ = b.set_counter(1);
+        })
+        deleted_bucket_fast_scan: ("Cannot scan a deleted bucket.", |b: &Bucket| {
+            let _ = b.fast_scan();
+        })
+        deleted_bucket_stream_keys: ("Cannot scan a deleted bucket.", |b: &Bucket| {
+            let _ = b.stream_keys(|_| ());
+        })
+        deleted_bucket_validate: ("Cannot validate a deleted bucket.", |b: &Bucket| {
+            let _ = b.validate();
+        })
+        deleted_bucket_get_bucket: ("Cannot get bucket from a deleted bucket.", |b: &Bucket| {
+            let _ = b.get_bucket("a");
+        })
+        deleted_bucket_create_bucket: ("Cannot create bucket in a deleted bucket.", |b: &Bucket| {
+            let _ = b.create_bucket("a");
+        })
+        deleted_bucket_get_or_create_bucket: ("Cannot get or create bucket from a deleted bucket.", |b: &Bucket| {
+            let _ = b.get_or_create_bucket("a");
+        })
+        deleted_bucket_ensure_path: ("Cannot get or create bucket from a deleted bucket.", |b: &Bucket| {
+            let _ = b.ensure_path(&["a"]);
+        })
+        deleted_bucket_delete_bucket: ("Cannot delete bucket from a deleted bucket.", |b: &Bucket| {
+            let _ = b.delete_bucket("a");
+        })
+        deleted_bucket_next_int: ("Cannot get next int from a deleted bucket.", |b: &Bucket| {
+            b.next_int();
+        })
+        deleted_bucket_root_page: ("Cannot get root page from a deleted bucket.", |b: &Bucket| {
+            b.root_page();
+        })
+        deleted_bucket_compact: ("Cannot compact a deleted bucket.", |b: &Bucket| {
+            let _ = b.compact();
+        })
+        deleted_bucket_estimate_range_count: ("Cannot estimate range of a deleted bucket.", |b: &Bucket| {
+            b.estimate_range_count::<std::ops::RangeFull>(..);
+        })
+        deleted_bucket_cursor: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            b.cursor();
+        })
+        deleted_bucket_buckets: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.buckets();
+        })
+        deleted_bucket_child_buckets_owned: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.child_buckets_owned();
+        })
+        deleted_bucket_increment: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
+            let _ = b.increment("a", 1);
+        })
+        deleted_bucket_value_len: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
+            let _ = b.value_len("a");
+        })
+        deleted_bucket_range_collect: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.range_collect::<std::ops::RangeFull>(.., 10);
+        })
+        deleted_bucket_prefix: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.prefix(b"a");
+        })
+        deleted_bucket_kv_pairs: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.kv_pairs();
+        })
+        deleted_bucket_retain: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.retain(|_, _| true);
+        })
+        deleted_bucket_get_or_create_bucket_with: ("Cannot get or create bucket from a deleted bucket.", |b: &Bucket| {
+            let _ = b.get_or_create_bucket_with("abc", |_| Ok(()));
+        })
+        deleted_bucket_rekey: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.rekey(|k| k.to_vec());
+        })
+        deleted_bucket_for_each_until: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
+            let _ = b.for_each_until(|_| ControlFlow::Continue(()));
+        })
+    }
+
+    macro_rules! bucket_errors {
+    	($($name:ident: ($rw: expr, $value:expr))*) => {
+    	$(
+    		#[test]
+    		fn $name() -> Result<()> {
+                let random_file = RandomFile::new();
+                let db = DB::open(&random_file)?;
+                {
+
+                    let tx = db.tx(true)?;
+                    tx.create_bucket("abc")?;
+                    tx.commit()?;
+                }
+                let tx = db.tx($rw)?;
+                let b = tx.get_bucket("abc")?;
+                #[allow(clippy::redundant_closure_call)]
+                $value(&b);
+                Ok(())
+    		}
+    	)*
+    	}
+    }
+
+    bucket_errors! {
+        ro_tx_put_data: (false, |b: &Bucket| {
+            assert_eq!(b.put("abc", "def").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_put_if_absent_with: (false, |b: &Bucket| {
+            assert_eq!(b.put_if_absent_with("abc", || Ok("def")).expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_increment: (false, |b: &Bucket| {
+            assert_eq!(b.increment("abc", 1).expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_compact: (false, |b: &Bucket| {
+            assert_eq!(b.compact().expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_put_force: (false, |b: &Bucket| {
+            assert_eq!(b.put_force("abc", "def").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_delete_data: (false, |b: &Bucket| {
+            assert_eq!(b.delete("abc").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_delete_bucket: (false, |b: &Bucket| {
+            assert_eq!(b.delete_bucket("abc").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
+        })
+        ro_tx_get_or_create_bucket: (false, |b: &Bucket| {
+            match b.get_or_create_bucket("abc")  {
+                Ok(_) => panic!("Expected a ReadOnlyTx error"),
+                Err(e) => assert!(e == Error::ReadOnlyTx)
+            }
+        })
+        ro_tx_create_bucket: (false, |b: &Bucket| {
+            match b.create_bucket("abc")  {
+                Ok(_) => panic!("Expected a ReadOnlyTx error"),
+                Err(e) => assert!(e == Error::ReadOnlyTx)
+            }
+        })
+        double_create_bucket: (true, |b: &Bucket| {
+            b.create_bucket("abc").unwrap();
+            match  b.create_bucket("abc") {
+                Ok(_) => panic!("Expected a BucketExists error"),
+                Err(e) => assert!(e == Error::BucketExists)
+            }
+        })
+        kv_bucket_mismatch: (true, |b: &Bucket| {
+            b.put("abc", "def").unwrap();
+            match  b.get_bucket("abc") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+            match  b.create_bucket("abc") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+            match  b.get_or_create_bucket("abc") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+            match  b.delete_bucket("abc") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+        })
+        bucket_kv_mismatch: (true, |b: &Bucket| {
+            b.create_bucket("abc").unwrap();
+            match b.put("abc", "def") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+            match b.delete("abc") {
+                Ok(_) => panic!("Expected a IncompatibleValue error"),
+                Err(e) => assert!(e == Error::IncompatibleValue)
+            }
+            assert!(b.get_kv("abc").is_none())
+        })
+    }
+
+    #[test]
+    fn test_verify_value() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let reverse = |value: &[u8]| value.iter().rev().cloned().collect::<Vec<u8>>();
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("cba", "abc")?;
+            b.put("xyz", "abc")?;
+            b.create_bucket("nested")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert!(b.verify_value("cba", reverse)?);
+        assert!(!b.verify_value("xyz", reverse)?);
+        assert_eq!(
+            b.verify_value("nested", reverse),
+            Err(Error::IncompatibleValue)
+        );
+        assert_eq!(
+            b.verify_value("missing", reverse),
+            Err(Error::KeyValueMissing)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_force() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .strict_mode(true)
+            .open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            let nested = b.create_bucket("nested")?;
+            for i in 0..100_u64 {
+                nested.put(i.to_be_bytes(), [0; 16])?;
             }
-        } else {
-            panic!("Did not find data for bucket we already deleted")
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.put("nested", "value"), Err(Error::IncompatibleValue));
+            b.put_force("nested", "value")?;
+            // plain key / value pairs are just overwritten
+            b.put_force("kv", "1")?;
+            b.put_force("kv", "2")?;
+            tx.commit()?;
         }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.get_kv("nested").unwrap().value(), b"value");
+        assert_eq!(b.get_kv("kv").unwrap().value(), b"2");
+        assert_eq!(b.buckets().count(), 0);
+        // the nested bucket's pages went back to the freelist
+        db.check()?;
+        Ok(())
     }
 
-    pub(crate) fn node<'a>(
-        &'a mut self,
-        id: PageNodeID,
-        parent: Option<&mut Node>,
-    ) -> Rc<RefCell<Node<'b>>> {
-        let id: NodeID = match id {
-            PageNodeID::Page(page_id) => {
-                if let Some(node_id) = self.page_node_ids.get(&page_id) {
-                    return self.nodes[*node_id as usize].clone();
-                }
-                debug_assert!(
-                    self.meta.root_page == page_id || self.page_parents.contains_key(&page_id),
-                    "cannot find reference to page ID \"{}\"",
-                    page_id,
-                );
-                let node_id = self.nodes.len() as u64;
-                self.page_node_ids.insert(page_id, node_id);
-                let n: Node =
-                    Node::from_page(node_id, self.pages.page(page_id), self.pages.pagesize);
-                self.nodes.push(Rc::new(RefCell::new(n)));
-                // If this node is not for the root page, then recursively create nodes for the parent pages
-                if self.meta.root_page != page_id {
-                    let n = self.nodes[node_id as usize].clone();
-                    let mut n = n.borrow_mut();
-                    let node_key = n.data.first_key();
-                    if let Some(parent) = parent {
-                        parent.insert_child(node_id, node_key);
-                        n.parent = Some(parent.id);
-                    } else {
-                        let parent = self.node(PageNodeID::Page(self.page_parents[&page_id]), None);
-                        let mut parent = parent.borrow_mut();
-                        parent.insert_child(node_id, node_key);
-                        n.parent = Some(parent.id);
-                    }
-                }
-                node_id
-            }
-            PageNodeID::Node(id) => id,
-        };
-        self.nodes.get_mut(id as usize).unwrap().clone()
+    #[test]
+    fn test_put_if_absent_with() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("a", "1")?;
+            b.create_bucket("c")?;
+            let mut calls = 0;
+            assert!(!b.put_if_absent_with("a", || {
+                calls += 1;
+                Ok("2")
+            })?);
+            assert!(!b.put_if_absent_with("c", || {
+                calls += 1;
+                Ok("2")
+            })?);
+            assert_eq!(calls, 0);
+            assert!(b.put_if_absent_with("b", || Ok("2"))?);
+            assert_eq!(
+                b.put_if_absent_with("d", || Err::<&str, _>(Error::KeyValueMissing)),
+                Err(Error::KeyValueMissing)
+            );
+            assert_eq!(b.next_int(), 3);
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.get_kv("a").unwrap().value(), b"1");
+        assert_eq!(b.get_kv("b").unwrap().value(), b"2");
+        assert!(b.get("d").is_none());
+        Ok(())
     }
 
-    pub(crate) fn new_node<'a>(&'a mut self, data: NodeData<'b>) -> Rc<RefCell<Node<'b>>> {
-        debug_assert!(data.len() >= 2);
-        let node_id = self.nodes.len() as u64;
-        let n = Node::with_data(node_id, data, self.pages.pagesize);
-        self.nodes.push(Rc::new(RefCell::new(n)));
-        self.nodes[node_id as usize].clone()
+    #[test]
+    fn test_read_value_into() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("a", "12345")?;
+        b.put("b", "67")?;
+        b.create_bucket("c")?;
+
+        let mut buf = Vec::new();
+        assert_eq!(b.read_value_into("a", &mut buf)?, Some(5));
+        assert_eq!(buf, b"12345");
+        assert_eq!(b.read_value_into("b", &mut buf)?, Some(2));
+        assert_eq!(buf, b"67");
+        assert_eq!(b.read_value_into("z", &mut buf)?, None);
+        assert!(buf.is_empty());
+        assert_eq!(
+            b.read_value_into("c", &mut buf),
+            Err(Error::IncompatibleValue)
+        );
+        Ok(())
     }
 
-    fn is_dirty(&mut self) -> bool {
-        // If it isn't marked as dirty, make sure by checking
-        // the sub-buckets to see if they're dirty.
-        if !self.dirty {
-            for (_key, b) in self.buckets.iter() {
-                let mut b = b.borrow_mut();
-                if b.is_dirty() {
-                    self.dirty = true;
-                    break;
-                }
+    fn count_pages(pages: &Pages, page_id: PageID) -> usize {
+        let page = pages.page(page_id);
+        match page.page_type {
+            Page::TYPE_BRANCH => {
+                1 + page
+                    .branch_elements()
+                    .iter()
+                    .map(|b| count_pages(pages, b.page))
+                    .sum::<usize>()
             }
+            _ => 1,
         }
-        self.dirty
     }
 
-    // Make sure none of the nodes are too empty
-    pub(crate) fn rebalance(&mut self, tx_freelist: &mut TxFreelist) -> Result<()> {
-        if !self.is_dirty() {
-            return Ok(());
-        }
-        for b in self.buckets.values() {
-            let mut b = b.borrow_mut();
-            b.rebalance(tx_freelist)?;
+    #[test]
+    fn test_estimate_range_count() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            // a single leaf is exact
+            b.put("a", "1")?;
+            b.put("b", "2")?;
+            b.put("c", "3")?;
+            let (a, b_, c, z): (&[u8], &[u8], &[u8], &[u8]) = (b"a", b"b", b"c", b"z");
+            assert_eq!(b.estimate_range_count(..), 3);
+            assert_eq!(b.estimate_range_count(a..c), 2);
+            assert_eq!(b.estimate_range_count(a..=c), 3);
+            assert_eq!(b.estimate_range_count(b_..), 2);
+            assert_eq!(
+                b.estimate_range_count((Bound::Excluded(a), Bound::Unbounded)),
+                2
+            );
+            assert_eq!(b.estimate_range_count(..b_), 1);
+            assert_eq!(b.estimate_range_count(c..a), 0);
+            assert_eq!(b.estimate_range_count(z..), 0);
+
+            let empty = tx.create_bucket("empty")?;
+            assert_eq!(empty.estimate_range_count(..), 0);
+
+            let b = tx.create_bucket("big")?;
+            for i in 0..10_000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
         }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("big")?;
+        let within = |estimate: u64, actual: u64| {
+            assert!(
+                estimate >= actual / 2 && estimate <= actual * 2,
+                "{} is not close to {}",
+                estimate,
+                actual
+            );
+        };
+        within(b.estimate_range_count(..), 10_000);
+        let start = 2_000_u64.to_be_bytes();
+        let end = 7_000_u64.to_be_bytes();
+        within(b.estimate_range_count(&start[..]..&end[..]), 5_000);
+        within(b.estimate_range_count(&end[..]..), 3_000);
+        Ok(())
+    }
 
-        // merge emptyish nodes with siblings
-        self.merge_nodes(tx_freelist);
+    #[test]
+    fn test_try_extend() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        let map: HashMap<String, String> = (0..10)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        b.try_extend(map)?;
+        assert_eq!(b.kv_pairs().count(), 10);
+        assert_eq!(b.get_kv("key3").unwrap().value(), b"value3");
 
+        b.create_bucket("c")?;
+        assert_eq!(
+            b.try_extend(vec![("a", "1"), ("c", "2"), ("d", "3")]),
+            Err(Error::IncompatibleValue)
+        );
+        assert!(b.get("a").is_some());
+        assert!(b.get("d").is_none());
         Ok(())
     }
 
-    fn merge_nodes(&mut self, tx_freelist: &mut TxFreelist) {
-        // If we haven't initialized any nodes yet, make sure we have the root node.
-        // If there is even one node, we are guarunteed to hage loaded the root node too.
-        if self.page_node_ids.is_empty() {
-            self.node(PageNodeID::Page(self.meta.root_page), None);
+    #[test]
+    fn test_map_values() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for i in 0..5_u64 {
+            b.put(i.to_be_bytes(), (i * 10).to_be_bytes())?;
         }
-        let mut stack: Vec<(bool, u64)> = vec![(false, self.page_node_ids[&self.meta.root_page])];
+        b.create_bucket("nested")?;
+        let values: Vec<(Vec<u8>, u64)> = b
+            .map_values(|v| u64::from_be_bytes(v.try_into().unwrap()))
+            .map(|(kv, v)| (kv.key().to_vec(), v))
+            .collect();
+        assert_eq!(
+            values,
+            (0..5_u64)
+                .map(|i| (i.to_be_bytes().to_vec(), i * 10))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
 
-        while let Some((visited, node_id)) = stack.pop() {
-            let node = self.nodes[node_id as usize].clone();
-            let mut node = node.borrow_mut();
-            // If this is a leaf node or our second time visiting a branch node, try to merge it
-            if visited || node.leaf() {
-                // Do nothing if this node needs no merging
-                if !node.needs_merging() {
-                    continue;
-                }
-                // Handle root node speially
-                if node.page_id == self.meta.root_page {
-                    // If the root node has only one branch, promote that page to the root page
-                    if !node.leaf() && node.data.len() == 1 {
-                        // delete the root node
-                        node.free_page(tx_freelist);
-                        node.deleted = true;
-                        let page_id = if let NodeData::Branches(branches) = &node.data {
-                            branches[0].page
-                        } else {
-                            // We already know it was a branch node, so we can't get here.
-                            unreachable!()
-                        };
-                        // Just double check that the child page wasn't accidentally pointing at a meta page
-                        debug_assert!(
-                            page_id > 1,
-                            "cannot have page <= 1, those are reserved for metadata"
-                        );
-                        // Make that child page the bucket's root page.
-                        self.meta.root_page = page_id;
-                        self.root = PageNodeID::Page(page_id);
-                    }
-                } else {
-                    // else find a sibling and merge this node with that one
-                    let parent_id = node.parent.expect("non root node must have parent");
-                    let parent_ref = self.nodes[parent_id as usize].clone();
+    #[test]
+    fn test_find_by_value() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("a", "x")?;
+        b.put("b", "y")?;
+        b.put("c", "x")?;
+        b.create_bucket("x")?;
+        let keys: Vec<Vec<u8>> = b
+            .find_by_value(|v| v == b"x")
+            .map(|kv| kv.key().to_vec())
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(b.find_by_value(|v| v.is_empty()).count(), 0);
+        Ok(())
+    }
 
-                    // borrow the parent in a separate scope so we can drop it before we initialize the sibling node
-                    let mut parent = parent_ref.borrow_mut();
-                    if let NodeData::Branches(branches) = &mut parent.data {
-                        // If there is only one branch in the parent, then we cannot delete this node
-                        // since there are no siblings to move the data to.
-                        // When we handle the parent, it will get merged with it's siblings or promoted
-                        // to root.
-                        if branches.len() == 1 {
-                            continue;
-                        }
-                        // check if there is any data left to copy
-                        // find the child's branch element in the parent node's data
-                        let index = match branches.binary_search_by_key(
-                            &node.original_key.clone().unwrap().as_ref(),
-                            |b| b.key(),
-                        ) {
-                            Ok(i) => i,
-                            _ => panic!("child branch not found"),
-                        };
-                        if node.data.len() > 0 && branches.len() > 1 {
-                            // add that child's data to a sibling node
-                            let sibling_page = if index == 0 {
-                                // right sibling
-                                branches[index + 1].page
-                            } else {
-                                // left sibling
-                                branches[index - 1].page
-                            };
+    #[test]
+    fn test_to_maps() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("b", "2")?;
+        b.put("a", "1")?;
+        b.create_bucket("nested")?.put("c", "3")?;
+        let expected = [
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+        ];
+        assert_eq!(b.to_btreemap(), BTreeMap::from(expected.clone()));
+        assert_eq!(b.to_hashmap(), HashMap::from(expected));
+        assert!(tx.create_bucket("empty")?.to_hashmap().is_empty());
+        Ok(())
+    }
 
-                            self.page_parents.insert(sibling_page, parent.page_id);
-                            let sibling =
-                                self.node(PageNodeID::Page(sibling_page), Some(&mut parent));
+    #[test]
+    fn test_retain() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), (i % 3).to_be_bytes())?;
+            }
+            b.create_bucket("nested")?;
+            let removed = b.retain(|_, v| v != 0_u64.to_be_bytes())?;
+            assert_eq!(removed, 34);
+            assert_eq!(b.retain(|_, _| true)?, 0);
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.kv_pairs().count(), 66);
+            assert!(b.kv_pairs().all(|kv| kv.value() != 0_u64.to_be_bytes()));
+            assert!(b.get_bucket("nested").is_ok());
+            assert_eq!(b.retain(|_, _| false), Err(Error::ReadOnlyTx));
+        }
+        let tx = db.tx(true)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.retain(|_, _| false)?, 66);
+        assert_eq!(b.kv_pairs().count(), 0);
+        assert_eq!(b.buckets().count(), 1);
+        Ok(())
+    }
 
-                            let mut sibling = sibling.borrow_mut();
-                            // Copy this node's data over to it's sibling
-                            sibling.data.merge(&mut node.data);
-                            if !node.children.is_empty() {
-                                // Move all children nodes over to that sibling too
-                                for child in node.children.iter() {
-                                    let c = &mut self.nodes[*child as usize];
-                                    let mut c = c.borrow_mut();
-                                    c.parent = Some(sibling.id);
-                                }
-                                sibling.children.append(&mut node.children);
-                            }
-                        }
-                        // free the child's page and mark it as deleted
-                        node.free_page(tx_freelist);
-                        node.deleted = true;
-                        if let NodeData::Branches(branches) = &mut parent.data {
-                            // remove the child from this node
-                            branches.remove(index);
-                        }
-                        if let Some(i) = parent.children.iter().position(|x| *x == node.id) {
-                            parent.children.remove(i);
-                        };
-                    }
-                }
-            } else {
-                // Add self back to stack to be processed after children
-                stack.push((true, node_id));
-                // Add all children to the stack, in reverse order so we pop them off
-                // the stack from left to right
-                for id in node.children.iter().rev() {
-                    stack.push((false, *id));
-                }
+    #[test]
+    fn test_child_buckets_owned() -> Result<()> {
+        fn walk(bucket: Bucket, path: String, paths: &mut Vec<String>) {
+            for (name, child) in bucket.child_buckets_owned() {
+                let path = format!("{}/{}", path, String::from_utf8(name).unwrap());
+                paths.push(path.clone());
+                walk(child, path, paths);
+            }
+        }
+
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let root = tx.create_bucket("root")?;
+            root.put("key", "value")?;
+            let a = root.create_bucket("a")?;
+            a.create_bucket("x")?.create_bucket("y")?;
+            root.create_bucket("b")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let mut paths = Vec::new();
+        walk(tx.get_bucket("root")?, String::new(), &mut paths);
+        assert_eq!(paths, vec!["/a", "/a/x", "/a/x/y", "/b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_get() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("key", "value")?;
+        b.create_bucket("nested")?;
+        assert_eq!(b.try_get("missing")?, None);
+        assert_eq!(b.try_get("key")?.unwrap().kv().value(), b"value");
+        assert!(matches!(b.try_get("nested")?, Some(Data::Bucket(_))));
+        tx.delete_bucket("abc")?;
+        assert_eq!(b.try_get("key").err(), Some(Error::BucketDeleted));
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_checksums() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .value_checksums(true)
+                .open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..500_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            b.create_bucket("nested")?.put("key", "value")?;
+            b.put("corrupt-me", "precious value")?;
+            tx.commit()?;
+            db.check()?;
+            // older releases can't read the checksums
+            assert_eq!(db.inner.meta()?.version, VERSION);
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            for i in 0..500_u64 {
+                let kv = b.get_kv(i.to_be_bytes()).unwrap();
+                assert_eq!(kv.value(), i.to_string().as_bytes());
             }
         }
+        // flip a bit in the value, right in the file
+        let mut data = std::fs::read(&random_file.path)?;
+        let pos = data
+            .windows(14)
+            .position(|w| w == b"precious value")
+            .unwrap();
+        data[pos] ^= 1;
+        std::fs::write(&random_file.path, data)?;
+
+        // the checksums are still checked with the option turned off
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(
+            b.try_get("corrupt-me").err(),
+            Some(Error::ValueCorrupt {
+                key: b"corrupt-me".to_vec()
+            })
+        );
+        assert_eq!(b.try_get(7_u64.to_be_bytes())?.unwrap().kv().value(), b"7");
+        assert_eq!(
+            db.check(),
+            Err(Error::ValueCorrupt {
+                key: b"corrupt-me".to_vec()
+            })
+        );
+        Ok(())
     }
 
-    // Make sure none of the nodes are too full, creating other nodes as needed.
-    // Then, write all of those nodes to dirty pages.
-    pub(crate) fn spill(&mut self, tx_freelist: &mut TxFreelist) -> Result<BucketMeta> {
-        if !self.is_dirty() {
-            return Ok(self.meta);
+    #[test]
+    fn test_value_checksums_after_flush() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .value_checksums(true)
+                .open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            // enough to grow the file, which maps it again
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), vec![0; 100_000])?;
+            }
+            tx.flush_dirty()?;
+            b.put("corrupt-me", "written after the flush")?;
+            tx.commit()?;
         }
+        let mut data = std::fs::read(&random_file.path)?;
+        let pos = data
+            .windows(23)
+            .position(|w| w == b"written after the flush")
+            .unwrap();
+        data[pos] ^= 1;
+        std::fs::write(&random_file.path, data)?;
 
-        #[allow(clippy::mutable_key_type)]
-        let mut bucket_metas: HashMap<Bytes, BucketMeta> = HashMap::new();
-        for (key, b) in self.buckets.iter() {
-            let mut b = b.borrow_mut();
-            let bucket_meta = b.spill(tx_freelist)?;
-            // Store updated bucket metadata in a map since self is borrowed
-            bucket_metas.insert(key.clone(), bucket_meta);
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let tx = db.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.try_get("corrupt-me").err(),
+            Some(Error::ValueCorrupt {
+                key: b"corrupt-me".to_vec()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_checksums_get_unchecked() {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .value_checksums(true)
+                .open(&random_file)
+                .unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket("abc")
+                .unwrap()
+                .put("key", "value")
+                .unwrap();
+            tx.commit().unwrap();
         }
-        // Update our pointers to the sub-buckets' new pages
-        for (name, meta) in bucket_metas {
-            self.put_leaf(Leaf::Bucket(name, meta))?;
+        let mut data = std::fs::read(&random_file.path).unwrap();
+        let pos = data.windows(5).position(|w| w == b"value").unwrap();
+        data[pos] ^= 1;
+        std::fs::write(&random_file.path, data).unwrap();
+
+        let db = DB::open(&random_file).unwrap();
+        let tx = db.tx(false).unwrap();
+        let b = tx.get_bucket("abc").unwrap();
+        // only try_get and the methods that return a Result check the checksum
+        assert_eq!(b.get_kv("key").unwrap().value(), b"walue");
+        assert_eq!(b.value_len("key"), Some(5));
+        assert!(b.get_located("key").is_some());
+        let corrupt = Some(Error::ValueCorrupt {
+            key: b"key".to_vec(),
+        });
+        assert_eq!(b.try_get("key").err(), corrupt);
+        assert_eq!(b.read_value_into("key", &mut Vec::new()).err(), corrupt);
+    }
+
+    #[test]
+    fn test_get_or_create_bucket_with() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let mut calls = 0;
+        {
+            let tx = db.tx(true)?;
+            let root = tx.create_bucket("root")?;
+            let seeded = root.get_or_create_bucket_with("seeded", |b| {
+                calls += 1;
+                b.put("default", "value")?;
+                Ok(())
+            })?;
+            assert_eq!(seeded.get_kv("default").unwrap().value(), b"value");
+            seeded.put("default", "changed")?;
+            let again = root.get_or_create_bucket_with("seeded", |_| {
+                calls += 1;
+                Ok(())
+            })?;
+            assert_eq!(again.get_kv("default").unwrap().value(), b"changed");
+            tx.commit()?;
         }
+        {
+            let tx = db.tx(true)?;
+            let root = tx.get_bucket("root")?;
+            let seeded = root.get_or_create_bucket_with("seeded", |_| {
+                calls += 1;
+                Ok(())
+            })?;
+            assert_eq!(seeded.get_kv("default").unwrap().value(), b"changed");
+            assert_eq!(calls, 1);
 
-        let root = self.nodes[self.page_node_ids[&self.meta.root_page] as usize].clone();
-        let mut root = root.borrow_mut();
-        let page_id = root
-            .spill(self, tx_freelist, None)?
-            .expect("root node did not return a new page_id");
-        self.meta.root_page = page_id;
+            let failed = root.get_or_create_bucket_with("failed", |b| {
+                b.put("key", "value")?;
+                Err(Error::Conflict)
+            });
+            assert_eq!(failed.err(), Some(Error::Conflict));
+            assert_eq!(root.get_bucket("failed").err(), Some(Error::BucketMissing));
 
-        Ok(self.meta)
+            root.put("kv", "value")?;
+            assert_eq!(
+                root.get_or_create_bucket_with("kv", |_| Ok(())).err(),
+                Some(Error::IncompatibleValue)
+            );
+        }
+        let tx = db.tx(false)?;
+        let root = tx.get_bucket("root")?;
+        assert_eq!(
+            root.get_or_create_bucket_with("seeded", |_| Ok(())).err(),
+            Some(Error::ReadOnlyTx)
+        );
+        Ok(())
     }
-}
 
-pub const META_SIZE: usize = std::mem::size_of::<BucketMeta>();
+    #[test]
+    fn test_rekey() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..300_u64 {
+                b.put(i.to_le_bytes(), i.to_string())?;
+            }
+            b.create_bucket("nested")?.put("key", "value")?;
+            let to_be = |key: &[u8]| {
+                let n = u64::from_le_bytes(key.try_into().unwrap());
+                n.to_be_bytes().to_vec()
+            };
+            b.rekey(to_be)?;
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            let values: Vec<String> = b
+                .kv_pairs()
+                .map(|kv| String::from_utf8(kv.value().to_vec()).unwrap())
+                .collect();
+            let expected: Vec<String> = (0..300_u64).map(|i| i.to_string()).collect();
+            assert_eq!(values, expected);
+            assert!(b.get_bucket("nested")?.get_kv("key").is_some());
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
-pub(crate) struct BucketMeta {
-    pub(crate) root_page: PageID,
-    pub(crate) next_int: u64,
-}
+            assert_eq!(
+                b.rekey(|_| b"same".to_vec()),
+                Err(Error::KeyCollision {
+                    key: b"same".to_vec()
+                })
+            );
+            assert_eq!(
+                b.rekey(|k| if k == 5_u64.to_be_bytes() {
+                    b"nested".to_vec()
+                } else {
+                    k.to_vec()
+                }),
+                Err(Error::IncompatibleValue)
+            );
+            // nothing changed
+            assert_eq!(b.kv_pairs().count(), 300);
+            assert_eq!(b.get_kv(5_u64.to_be_bytes()).unwrap().value(), b"5");
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.rekey(|k| k.to_vec()), Err(Error::ReadOnlyTx));
+        Ok(())
+    }
 
-impl AsRef<[u8]> for BucketMeta {
-    #[inline]
-    fn as_ref(&self) -> &[u8] {
-        let ptr = self as *const BucketMeta as *const u8;
-        unsafe { std::slice::from_raw_parts(ptr, META_SIZE) }
+    #[test]
+    fn test_utf8_keys() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().utf8_keys(true).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            assert_eq!(tx.create_bucket([0xFF]).err(), Some(Error::NonUtf8Key));
+            let b = tx.create_bucket("abc")?;
+            b.put("key", [0xFF])?;
+            assert_eq!(b.put([0xC3, 0x28], "value"), Err(Error::NonUtf8Key));
+            assert_eq!(b.put_force([0xFF], "value"), Err(Error::NonUtf8Key));
+            let nested = b.create_bucket("nested")?;
+            assert_eq!(nested.put([0xFF], "value"), Err(Error::NonUtf8Key));
+            assert_eq!(b.kv_pairs().count(), 1);
+            tx.commit()?;
+        }
+        let tx = db.tx(true)?;
+        let nested = tx.get_bucket_path(&["abc", "nested"])?;
+        assert_eq!(nested.put([0xFF], "value"), Err(Error::NonUtf8Key));
+        assert_eq!(
+            nested.put_if_absent_with([0xFF], || Ok("value")),
+            Err(Error::NonUtf8Key)
+        );
+        nested.put("ключ", "value")?;
+        Ok(())
     }
-}
 
-impl From<&[u8]> for BucketMeta {
-    // Because we need the pointer to match BucketMeta's alignment,
-    // we allocate a buffer on the stack that will definitely have
-    // space for the BucketMeta. Then we choose a point in that buffer
-    // that is aligned property, copy the data from value over,
-    // and cast our BucketMeta from there.
-    fn from(value: &[u8]) -> Self {
-        const SIZE: usize = size_of::<BucketMeta>();
-        const ALIGN: usize = align_of::<BucketMeta>();
-        debug_assert_eq!(SIZE, value.len());
-        let mut buf = [0_u8; SIZE + ALIGN];
-        let ptr = buf.as_mut_ptr();
-        unsafe {
-            let ptr = ptr.add(ptr.align_offset(ALIGN));
-            std::ptr::copy(value.as_ptr(), ptr, SIZE);
-            *(ptr as *const BucketMeta)
+    #[test]
+    fn test_for_each_until() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for i in 0..10_u64 {
+            b.put(i.to_be_bytes(), i.to_string())?;
+        }
+        let mut seen = Vec::new();
+        let result = b.for_each_until(|data| {
+            let i = u64::from_be_bytes(data.key().try_into().unwrap());
+            if i == 5 {
+                return ControlFlow::Break(());
+            }
+            seen.push(i);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(result, ControlFlow::Break(()));
+        assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+
+        let mut count = 0;
+        let result = b.for_each_until(|_| {
+            count += 1;
+            ControlFlow::Continue(())
+        });
+        assert_eq!(result, ControlFlow::Continue(()));
+        assert_eq!(count, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_scan() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let keys = |iter: &mut dyn Iterator<Item = KVPair>| -> Vec<Vec<u8>> {
+            iter.map(|kv| kv.key().to_vec()).collect()
+        };
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+                if i % 100 == 0 {
+                    b.create_bucket(format!("bucket-{}", i))?;
+                }
+            }
+            // nothing has been written to pages yet
+            assert_eq!(keys(&mut b.fast_scan()?), keys(&mut b.kv_pairs()));
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let scanned = keys(&mut b.fast_scan()?);
+            assert_eq!(scanned.len(), 2000);
+            assert_eq!(scanned, keys(&mut b.kv_pairs()));
+            for kv in b.fast_scan()? {
+                assert_eq!(b.get_kv(kv.key()).unwrap().value(), kv.value());
+            }
         }
+        let tx = db.tx(true)?;
+        let b = tx.get_bucket("abc")?;
+        // an untouched bucket in a writable transaction reads the pages too
+        assert_eq!(b.fast_scan()?.count(), 2000);
+        b.delete(5_u64.to_be_bytes())?;
+        b.put("new", "value")?;
+        assert_eq!(keys(&mut b.fast_scan()?), keys(&mut b.kv_pairs()));
+        assert_eq!(b.fast_scan()?.count(), 2000);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn test_stream_keys() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let streamed = |b: &Bucket| {
+            let mut keys = Vec::new();
+            b.stream_keys(|key| keys.push(key.to_vec()))?;
+            Ok::<_, Error>(keys)
+        };
+        let expected = |b: &Bucket| -> Vec<Vec<u8>> { b.iter().map(|(key, _)| key).collect() };
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+                if i % 100 == 0 {
+                    b.create_bucket(format!("bucket-{}", i))?;
+                }
+            }
+            assert_eq!(streamed(&b)?, expected(&b));
+            tx.commit()?;
+        }
+        let tx = db.tx(true)?;
+        let b = tx.get_bucket("abc")?;
+        let keys = streamed(&b)?;
+        assert_eq!(keys.len(), 2020);
+        assert_eq!(keys, expected(&b));
+        b.delete(5_u64.to_be_bytes())?;
+        assert_eq!(streamed(&b)?, expected(&b));
+        Ok(())
+    }
 
-    use super::*;
-    use crate::{testutil::RandomFile, DB};
+    #[test]
+    fn test_size_extremes() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            assert_eq!(b.size_extremes()?, (0, 0));
+            b.put("a", vec![0; 10_000])?;
+            b.put("a-longer-key", "small")?;
+            b.create_bucket("a-bucket-with-the-longest-name")?;
+            assert_eq!(b.size_extremes()?, (12, 10_000));
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc")?.size_extremes()?, (12, 10_000));
+        Ok(())
+    }
 
     #[test]
-    fn bytes() {
-        let meta = BucketMeta {
-            root_page: 3,
-            next_int: 1,
+    fn test_fast_scan_page_bounds() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.get_bucket("abc")?.root_page();
+            root_page
         };
-        let bytes = meta.as_ref();
-        assert_eq!(bytes, &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        // Point one of the branches far past the end of the file
+        let mut buf = std::fs::read(&random_file)?;
+        let offset = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            &page.branch_elements()[1].page as *const PageID as usize - buf.as_ptr() as usize
+        };
+        let bad_page: PageID = 1 << 40;
+        buf[offset..offset + 8].copy_from_slice(&bad_page.to_ne_bytes());
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        let out_of_bounds = Error::InvalidDB(format!("Page {} is out of bounds", bad_page));
+        assert_eq!(b.fast_scan().err(), Some(out_of_bounds.duplicate()));
+        let mut streamed = 0;
+        assert_eq!(
+            b.stream_keys(|_| streamed += 1),
+            Err(out_of_bounds.duplicate())
+        );
+        // nothing is passed on before the bad page is found
+        assert_eq!(streamed, 0);
+        assert_eq!(b.size_extremes(), Err(out_of_bounds));
+        Ok(())
     }
 
-    macro_rules! deleted_bucket_test {
-    	($($name:ident: ($expected_err:expr, $value:expr))*) => {
-    	$(
-    		#[test]
-            #[should_panic(expected = $expected_err)]
-    		fn $name() {
-                let random_file = RandomFile::new();
-                let db = DB::open(&random_file).unwrap();
-                let tx = db.tx(true).unwrap();
-                let b = tx.create_bucket("abc").unwrap();
-                tx.delete_bucket("abc").unwrap();
-                #[allow(clippy::redundant_closure_call)]
-                $value(&b);
-    		}
-    	)*
-    	}
+    #[test]
+    fn test_ensure_path() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.ensure_path(&["a", "b", "c"])?.put("key", "value")?;
+            // already there, so nothing changes
+            let c = b.ensure_path(&["a", "b", "c"])?;
+            assert_eq!(c.get_kv("key").unwrap().value(), b"value");
+            b.ensure_path(&["a", "d"])?;
+            assert_eq!(b.get_bucket("a")?.buckets().count(), 2);
+
+            // nothing is created when part of the path is key / value data
+            b.get_bucket_path(&["a", "b"])?.put("kv", "value")?;
+            assert_eq!(
+                b.ensure_path(&["a", "b", "kv", "z"]).err(),
+                Some(Error::IncompatibleValue)
+            );
+            assert_eq!(b.get_bucket_path(&["a", "b"])?.buckets().count(), 1);
+            assert!(b.ensure_path::<&str>(&[])?.get_bucket("a").is_ok());
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert!(b.get_bucket_path(&["a", "b", "c"]).is_ok());
+        assert_eq!(b.ensure_path(&["a"]).err(), Some(Error::ReadOnlyTx));
+        Ok(())
     }
 
-    deleted_bucket_test! {
-        deleted_bucket_put: ("Cannot put data into a deleted bucket.", |b: &Bucket| {
-            let _ = b.put("a", "b");
-        })
-        deleted_bucket_get: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
-            b.get("a");
-        })
-        deleted_bucket_delete: ("Cannot delete data from a deleted bucket.", |b: &Bucket| {
-            let _ = b.delete("a");
-        })
-        deleted_bucket_get_kv: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
-            b.get_kv("a");
-        })
-        deleted_bucket_get_bucket: ("Cannot get bucket from a deleted bucket.", |b: &Bucket| {
-            let _ = b.get_bucket("a");
-        })
-        deleted_bucket_create_bucket: ("Cannot create bucket in a deleted bucket.", |b: &Bucket| {
-            let _ = b.create_bucket("a");
-        })
-        deleted_bucket_get_or_create_bucket: ("Cannot get or create bucket from a deleted bucket.", |b: &Bucket| {
-            let _ = b.get_or_create_bucket("a");
-        })
-        deleted_bucket_delete_bucket: ("Cannot delete bucket from a deleted bucket.", |b: &Bucket| {
-            let _ = b.delete_bucket("a");
-        })
-        deleted_bucket_next_int: ("Cannot get next int from a deleted bucket.", |b: &Bucket| {
-            b.next_int();
-        })
-        deleted_bucket_cursor: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
-            b.cursor();
-        })
-        deleted_bucket_buckets: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
-            let _ = b.buckets();
-        })
-        deleted_bucket_kv_pairs: ("Cannot create cursor from a deleted bucket.", |b: &Bucket| {
-            let _ = b.kv_pairs();
-        })
+    #[test]
+    fn test_put_reader() -> Result<()> {
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let value: Vec<u8> = (0..50_000_u32).map(|i| i as u8).collect();
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            assert_eq!(b.put_reader("big", &value[..])?, value.len());
+            assert_eq!(b.put_reader("empty", std::io::empty())?, 0);
+            assert!(matches!(
+                b.put_reader("broken", FailingReader),
+                Err(Error::Io(_))
+            ));
+            assert!(b.get("broken").is_none());
+            b.create_bucket("nested")?;
+            assert_eq!(
+                b.put_reader("nested", &value[..]),
+                Err(Error::IncompatibleValue)
+            );
+            tx.commit()?;
+        }
+        db.check()?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.get_kv("big").unwrap().value(), &value[..]);
+        assert_eq!(b.get_kv("empty").unwrap().value(), b"");
+        assert_eq!(b.put_reader("big", &value[..]), Err(Error::ReadOnlyTx));
+        Ok(())
     }
 
-    macro_rules! bucket_errors {
-    	($($name:ident: ($rw: expr, $value:expr))*) => {
-    	$(
-    		#[test]
-    		fn $name() -> Result<()> {
-                let random_file = RandomFile::new();
-                let db = DB::open(&random_file)?;
-                {
+    #[test]
+    fn test_counter() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = DB::open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            assert_eq!(b.counter(), 0);
+            b.put("a", "1")?;
+            // not maintained by the bucket
+            assert_eq!(b.counter(), 0);
+            b.set_counter(0)?;
+            tx.commit()?;
+            // the file keeps its old format until a counter is used
+            assert_eq!(db.inner.meta()?.version, 1);
 
-                    let tx = db.tx(true)?;
-                    tx.create_bucket("abc")?;
-                    tx.commit()?;
-                }
-                let tx = db.tx($rw)?;
-                let b = tx.get_bucket("abc")?;
-                #[allow(clippy::redundant_closure_call)]
-                $value(&b);
-                Ok(())
-    		}
-    	)*
-    	}
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            b.set_counter(1)?;
+            b.create_bucket("nested")?.set_counter(u64::MAX)?;
+            tx.commit()?;
+            // changing only the counter is still saved
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.set_counter(42)?;
+            tx.commit()?;
+        }
+        let db = DB::open(&random_file)?;
+        db.check()?;
+        assert_eq!(db.inner.meta()?.version, VERSION);
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.counter(), 42);
+        assert_eq!(b.next_int(), 2);
+        assert_eq!(b.get_bucket("nested")?.counter(), u64::MAX);
+        assert_eq!(b.set_counter(0), Err(Error::ReadOnlyTx));
+        Ok(())
     }
 
-    bucket_errors! {
-        ro_tx_put_data: (false, |b: &Bucket| {
-            assert_eq!(b.put("abc", "def").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
-        })
-        ro_tx_delete_data: (false, |b: &Bucket| {
-            assert_eq!(b.delete("abc").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
-        })
-        ro_tx_delete_bucket: (false, |b: &Bucket| {
-            assert_eq!(b.delete_bucket("abc").expect_err("Expected a ReadOnlyTx error"), Error::ReadOnlyTx);
-        })
-        ro_tx_get_or_create_bucket: (false, |b: &Bucket| {
-            match b.get_or_create_bucket("abc")  {
-                Ok(_) => panic!("Expected a ReadOnlyTx error"),
-                Err(e) => assert!(e == Error::ReadOnlyTx)
+    #[test]
+    fn test_validate() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            // not committed yet, so there's nothing to check
+            b.validate()?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
             }
-        })
-        ro_tx_create_bucket: (false, |b: &Bucket| {
-            match b.create_bucket("abc")  {
-                Ok(_) => panic!("Expected a ReadOnlyTx error"),
-                Err(e) => assert!(e == Error::ReadOnlyTx)
+            tx.create_bucket("def")?;
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            b.validate()?;
+            tx.get_bucket("def")?.validate()?;
+            b.root_page()
+        };
+        // Swap two of the root's children, so the branch keys no longer match
+        let mut buf = std::fs::read(&random_file)?;
+        let (first, second) = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            let elements = page.branch_elements();
+            let offset =
+                |i: usize| &elements[i].page as *const PageID as usize - buf.as_ptr() as usize;
+            (offset(1), offset(2))
+        };
+        let first_page = buf[first..first + 8].to_vec();
+        buf.copy_within(second..second + 8, first);
+        buf[second..second + 8].copy_from_slice(&first_page);
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        let tx = db.tx(false)?;
+        match tx.get_bucket("abc")?.validate() {
+            Err(Error::InvalidDB(msg)) => assert!(
+                msg.starts_with(&format!("Branch page {} has key", root_page)),
+                "{}",
+                msg
+            ),
+            r => panic!("expected InvalidDB, got {:?}", r),
+        }
+        tx.get_bucket("def")?.validate()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("a", "1")?;
+        b.create_bucket("b")?;
+        b.put("c", "3")?;
+        let entries: Vec<(Vec<u8>, bool)> = b.iter().map(|(k, data)| (k, data.is_kv())).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), true),
+                (b"b".to_vec(), false),
+                (b"c".to_vec(), true)
+            ]
+        );
+        let (key, data) = b.iter().last().unwrap();
+        assert_eq!(key, data.key());
+        assert_eq!(data.kv().value(), b"3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_size() -> Result<()> {
+        for value_checksums in [false, true] {
+            let random_file = RandomFile::new();
+            let db = OpenOptions::new()
+                .value_checksums(value_checksums)
+                .open(&random_file)?;
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..20_u8)
+                .map(|i| (vec![i; i as usize + 1], vec![i; i as usize * 3]))
+                .collect();
+            {
+                let tx = db.tx(true)?;
+                let b = tx.create_bucket("abc")?;
+                for (k, v) in pairs.iter() {
+                    b.put(k.as_slice(), v.as_slice())?;
+                }
+                tx.commit()?;
             }
-        })
-        double_create_bucket: (true, |b: &Bucket| {
-            b.create_bucket("abc").unwrap();
-            match  b.create_bucket("abc") {
-                Ok(_) => panic!("Expected a BucketExists error"),
-                Err(e) => assert!(e == Error::BucketExists)
+            // everything fits in one leaf, where the entries should end right after the last value
+            // (and its checksum, if there is one)
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let expected: usize = pairs.iter().map(|(k, v)| b.entry_size(k, v)).sum();
+            let b = b.inner.borrow();
+            let page = b.pages.page(b.meta.root_page);
+            assert_eq!(page.page_type, Page::TYPE_LEAF);
+            let elements = page.leaf_elements();
+            let last = elements.last().unwrap().value();
+            let mut used = last.as_ptr() as usize + last.len() - elements.as_ptr() as usize;
+            if value_checksums {
+                used += CHECKSUM_SIZE as usize;
             }
-        })
-        kv_bucket_mismatch: (true, |b: &Bucket| {
-            b.put("abc", "def").unwrap();
-            match  b.get_bucket("abc") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+            assert_eq!(used, expected);
+            let sizes: usize = elements.iter().map(|e| e.size() as usize).sum();
+            assert_eq!(sizes, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_length_values() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("empty", [])?;
+            b.put("empty-slice", &b""[..])?;
+            b.put("emptied", "value")?;
+            // enough empty values to fill a few pages
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), [])?;
             }
-            match  b.create_bucket("abc") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+            assert_eq!(b.get_kv("empty").unwrap().value(), b"");
+            assert!(b.get("missing").is_none());
+            tx.commit()?;
+        }
+        {
+            let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            let old = b.put("emptied", [])?;
+            assert_eq!(old.unwrap().value(), b"value");
+            // replacing an empty value works too
+            let old = b.put("empty-slice", "now full")?;
+            assert_eq!(old.unwrap().value(), b"");
+            tx.commit()?;
+        }
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        db.check()?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        for key in ["empty", "emptied"] {
+            assert_eq!(b.get_kv(key).unwrap().value(), b"");
+        }
+        assert_eq!(b.get_kv("empty-slice").unwrap().value(), b"now full");
+        for i in 0..1000_u64 {
+            assert_eq!(b.get_kv(i.to_be_bytes()).unwrap().value(), b"");
+        }
+        assert_eq!(b.kv_pairs().count(), 1003);
+        assert!(b.get("missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_located() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("a", "1")?;
+            b.create_bucket("nested")?;
+            // not written to a page yet
+            assert_eq!(b.get_located("a").unwrap().1, 0);
+            for i in 0..500_u64 {
+                b.put(i.to_be_bytes(), [0; 8])?;
             }
-            match  b.get_or_create_bucket("abc") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        let (first, first_page) = b.get_located(0_u64.to_be_bytes()).unwrap();
+        assert_eq!(first.value(), [0; 8]);
+        let (_, last_page) = b.get_located(499_u64.to_be_bytes()).unwrap();
+        assert_ne!(first_page, last_page);
+        {
+            let inner = b.inner.borrow();
+            for page_id in [first_page, last_page] {
+                assert_eq!(inner.pages.page(page_id).page_type, Page::TYPE_LEAF);
             }
-            match  b.delete_bucket("abc") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+        }
+        assert!(b.get_located("nested").is_none());
+        assert!(b.get_located("missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_since() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for i in 0..1000_u64 {
+            // 256 would sort before 255 if it were little-endian
+            b.put(b.next_int().to_be_bytes(), i.to_le_bytes())?;
+        }
+        let seqs = |seq| -> Vec<u64> {
+            b.since(seq)
+                .map(|kv| u64::from_be_bytes(kv.key().try_into().unwrap()))
+                .collect()
+        };
+        assert_eq!(seqs(0), (1..1000).collect::<Vec<_>>());
+        assert_eq!(seqs(255), (256..1000).collect::<Vec<_>>());
+        assert_eq!(seqs(998), vec![999]);
+        assert!(seqs(999).is_empty());
+        assert!(seqs(5000).is_empty());
+        b.delete(500_u64.to_be_bytes())?;
+        assert_eq!(seqs(499), (501..1000).collect::<Vec<_>>());
+        assert_eq!(seqs(500), (501..1000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_skips_unchanged_buckets() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..300 {
+                b.create_bucket(format!("{:04}", i))?.put("key", "value")?;
             }
-        })
-        bucket_kv_mismatch: (true, |b: &Bucket| {
-            b.create_bucket("abc").unwrap();
-            match b.put("abc", "def") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+            tx.commit()?;
+        }
+        let first_leaf = |tx: &crate::Tx| -> Result<PageID> {
+            let b = tx.get_bucket("abc")?;
+            let b = b.inner.borrow();
+            let page = b.pages.page(b.meta.root_page);
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            Ok(page.branch_elements()[0].page)
+        };
+        let before = first_leaf(&db.tx(false)?)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            // only read from the first bucket, which lives on the first leaf
+            assert!(b.get_bucket("0000")?.get("key").is_some());
+            b.get_bucket("0299")?.put("key", "new value")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        assert_eq!(first_leaf(&tx)?, before);
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(
+            b.get_bucket("0000")?.get_kv("key").unwrap().value(),
+            b"value"
+        );
+        assert_eq!(
+            b.get_bucket("0299")?.get_kv("key").unwrap().value(),
+            b"new value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), [0; 16])?;
             }
-            match b.delete("abc") {
-                Ok(_) => panic!("Expected a IncompatibleValue error"),
-                Err(e) => assert!(e == Error::IncompatibleValue)
+            let nested = b.create_bucket("nested")?;
+            nested.put("a", "b")?;
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for i in (0..2000_u64).filter(|i| i % 5 < 2) {
+                b.delete(i.to_be_bytes())?;
             }
-            assert!(b.get_kv("abc").is_none())
-        })
+            tx.commit()?;
+        }
+        let before = {
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let b = b.inner.borrow();
+            count_pages(&b.pages, b.meta.root_page)
+        };
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            b.compact()?;
+            // the data is still readable before committing
+            assert_eq!(b.get_kv(2_u64.to_be_bytes()).unwrap().value(), [0; 16]);
+            assert!(b.get(1_u64.to_be_bytes()).is_none());
+            // compacting again is a no-op
+            b.compact()?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        let after = {
+            let b = b.inner.borrow();
+            count_pages(&b.pages, b.meta.root_page)
+        };
+        assert!(after < before, "{} should be less than {}", after, before);
+        let keys: Vec<u64> = b
+            .kv_pairs()
+            .map(|kv| u64::from_be_bytes(kv.key().try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            keys,
+            (0..2000_u64).filter(|i| i % 5 >= 2).collect::<Vec<u64>>()
+        );
+        assert_eq!(b.get_bucket("nested")?.get_kv("a").unwrap().value(), b"b");
+        db.check()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_page() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let root_page = {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("a", "1")?;
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.get_bucket("abc")?.root_page();
+            assert!(root_page > 1);
+            root_page
+        };
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            b.put("b", "2")?;
+            // still points at the committed tree until we commit
+            assert_eq!(b.root_page(), root_page);
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        assert_ne!(tx.get_bucket("abc")?.root_page(), root_page);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_path() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_or_create_bucket_path(&["a", "b", "c"])?;
+            b.put("key", "value")?;
+            let b = tx.get_bucket("a")?;
+            b.put("kv", "value")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket_path(&["a", "b", "c"])?;
+        assert_eq!(b.get_kv("key").unwrap().value(), b"value");
+        let a = tx.get_bucket("a")?;
+        let c = a.get_bucket_path(&[b"b", b"c"])?;
+        assert!(c.get_kv("key").is_some());
+        assert!(tx.get_bucket_path::<&str>(&[]).is_err());
+        assert!(tx.get_bucket_path(&["a", "missing", "c"]).err() == Some(Error::BucketMissing));
+        assert!(tx.get_bucket_path(&["a", "kv"]).err() == Some(Error::IncompatibleValue));
+        assert!(a.get_or_create_bucket_path(&["b"]).err() == Some(Error::ReadOnlyTx));
+        Ok(())
     }
 
     #[test]
@@ -1210,4 +4571,130 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_range_collect() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in (0..100_u64).filter(|i| *i != 11) {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            b.create_bucket(11_u64.to_be_bytes())?;
+            tx.commit()?;
+        }
+        let pairs = {
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let (start, end) = (10_u64.to_be_bytes(), 50_u64.to_be_bytes());
+            b.range_collect(&start[..]..&end[..], 5)
+        };
+        let keys: Vec<u64> = pairs
+            .iter()
+            .map(|kv| u64::from_be_bytes(kv.key().try_into().unwrap()))
+            .collect();
+        assert_eq!(keys, vec![10, 12, 13, 14, 15]);
+        assert_eq!(pairs[1].value(), b"12");
+        let (k, v) = pairs[0].clone().into_parts();
+        assert_eq!((k, v), (10_u64.to_be_bytes().to_vec(), b"10".to_vec()));
+
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.range_collect::<std::ops::RangeFull>(.., 1000).len(), 99);
+        assert!(b.range_collect::<std::ops::RangeFull>(.., 0).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_len() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("small", "abc")?;
+            b.put("big", vec![1_u8; 5000])?;
+            b.put("empty", "")?;
+            b.create_bucket("nested")?;
+            // in memory before the first commit
+            assert_eq!(b.value_len("small"), Some(3));
+            assert_eq!(b.value_len("nested"), None);
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.value_len("small"), Some(3));
+        assert_eq!(b.value_len("big"), Some(5000));
+        assert_eq!(b.value_len("empty"), Some(0));
+        assert_eq!(b.value_len("nested"), None);
+        assert_eq!(b.value_len("missing"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_increment() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            assert_eq!(b.increment("count", 5)?, 5);
+            assert_eq!(b.increment("count", -2)?, 3);
+            assert_eq!(b.next_int(), 1);
+            b.put("short", "abc")?;
+            b.put("max", u64::MAX.to_be_bytes().to_vec())?;
+            b.create_bucket("nested")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(true)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.increment("count", 1)?, 4);
+        assert_eq!(b.get_kv("count").unwrap().as_u64(), Some(4));
+        assert_eq!(b.increment("count", -5), Err(Error::CounterOverflow));
+        assert_eq!(b.increment("max", 1), Err(Error::CounterOverflow));
+        assert_eq!(b.increment("max", -1)?, u64::MAX - 1);
+        assert_eq!(b.increment("max", i64::MIN)?, u64::MAX - 1 - (1 << 63));
+        assert_eq!(b.increment("max", i64::MIN), Err(Error::CounterOverflow));
+        assert_eq!(b.increment("short", 1), Err(Error::IncompatibleValue));
+        assert_eq!(b.increment("nested", 1), Err(Error::IncompatibleValue));
+        assert_eq!(b.get_kv("short").unwrap().value(), b"abc");
+        assert_eq!(b.get_kv("count").unwrap().as_u64(), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for i in 0..=255_u8 {
+            for j in 0..10_u8 {
+                b.put([i, j], "value")?;
+            }
+        }
+        b.put([0xff_u8], "short")?;
+        b.create_bucket([7_u8, 0xff])?;
+        let keys = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            b.prefix(prefix).map(|data| data.key().to_vec()).collect()
+        };
+        let expected = |i: u8| -> Vec<Vec<u8>> { (0..10).map(|j| vec![i, j]).collect() };
+        assert_eq!(keys(&[0]), expected(0));
+        // nested buckets are included
+        let mut sevens = expected(7);
+        sevens.push(vec![7, 0xff]);
+        assert_eq!(keys(&[7]), sevens);
+        // no end bound to get wrong at the end of the key space
+        let mut last = vec![vec![0xff]];
+        last.extend(expected(0xff));
+        assert_eq!(keys(&[0xff]), last);
+        assert_eq!(keys(&[0xff, 9]), vec![vec![0xff, 9]]);
+        assert!(keys(&[0xff, 10]).is_empty());
+        assert!(keys(&[0xff, 9, 0]).is_empty());
+        assert!(keys(&[3, 10]).is_empty());
+        assert_eq!(keys(&[]).len(), 256 * 10 + 2);
+        Ok(())
+    }
 }
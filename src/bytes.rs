@@ -73,6 +73,166 @@ impl<'a> ToBytes<'a> for &Bytes<'a> {
     }
 }
 
+impl<'a> ToBytes<'a> for CompositeKey {
+    fn to_bytes(self) -> Bytes<'a> {
+        Bytes::Vec(Rc::new(self.build()))
+    }
+}
+
+/// Builds keys made of several values that sort in the same order as the tuple of those values.
+///
+/// Just sticking the bytes of each value together doesn't sort correctly, since
+/// a variable length value like `"ab"` followed by `"c"` would end up next to `"a"` followed by `"bc"`.
+/// Integers are written big-endian (with the sign bit flipped for signed ints), and byte strings
+/// have every `0x00` byte escaped as `0x00 0xFF` and end with `0x00 0x00`,
+/// so keys compare segment by segment. Use [`CompositeKey::decode`] to read the values back out.
+///
+/// # Examples
+///
+/// ```
+/// use jammdb::CompositeKey;
+///
+/// let a = CompositeKey::new().push_u64(1).push_str("ab").push_u32(7).build();
+/// let b = CompositeKey::new().push_u64(1).push_str("abc").push_u32(0).build();
+/// assert!(a < b);
+///
+/// let mut decoder = CompositeKey::decode(&a);
+/// assert_eq!(decoder.u64(), Some(1));
+/// assert_eq!(decoder.str(), Some(String::from("ab")));
+/// assert_eq!(decoder.u32(), Some(7));
+/// assert!(decoder.is_empty());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompositeKey {
+    buf: Vec<u8>,
+}
+
+impl CompositeKey {
+    /// Creates an empty key.
+    pub fn new() -> CompositeKey {
+        CompositeKey::default()
+    }
+
+    /// Appends a `u32` segment.
+    pub fn push_u32(mut self, i: u32) -> Self {
+        self.buf.extend_from_slice(&i.to_be_bytes());
+        self
+    }
+
+    /// Appends a `u64` segment.
+    pub fn push_u64(mut self, i: u64) -> Self {
+        self.buf.extend_from_slice(&i.to_be_bytes());
+        self
+    }
+
+    /// Appends an `i64` segment, with negative numbers sorting before positive ones.
+    pub fn push_i64(mut self, i: i64) -> Self {
+        self.buf
+            .extend_from_slice(&((i as u64) ^ (1 << 63)).to_be_bytes());
+        self
+    }
+
+    /// Appends a variable length segment.
+    pub fn push_bytes<T: AsRef<[u8]>>(mut self, bytes: T) -> Self {
+        for b in bytes.as_ref() {
+            self.buf.push(*b);
+            if *b == 0 {
+                self.buf.push(0xFF);
+            }
+        }
+        self.buf.extend_from_slice(&[0, 0]);
+        self
+    }
+
+    /// Appends a string segment.
+    pub fn push_str<T: AsRef<str>>(self, s: T) -> Self {
+        self.push_bytes(s.as_ref().as_bytes())
+    }
+
+    /// Returns the encoded key.
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Reads the segments of a key made with a [`CompositeKey`].
+    ///
+    /// The segments have to be read in the same order and with the same types they were pushed in,
+    /// since the encoding doesn't record the type of each segment.
+    pub fn decode(key: &[u8]) -> CompositeKeyDecoder<'_> {
+        CompositeKeyDecoder { key }
+    }
+}
+
+/// Reads the segments of a [`CompositeKey`] back out, in the order they were pushed.
+///
+/// Each method returns `None` if the rest of the key can't be read as that type.
+#[derive(Debug, Clone)]
+pub struct CompositeKeyDecoder<'a> {
+    key: &'a [u8],
+}
+
+impl<'a> CompositeKeyDecoder<'a> {
+    fn fixed<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.key.len() < N {
+            return None;
+        }
+        let (head, rest) = self.key.split_at(N);
+        self.key = rest;
+        head.try_into().ok()
+    }
+
+    /// Reads a `u32` segment.
+    pub fn u32(&mut self) -> Option<u32> {
+        self.fixed().map(u32::from_be_bytes)
+    }
+
+    /// Reads a `u64` segment.
+    pub fn u64(&mut self) -> Option<u64> {
+        self.fixed().map(u64::from_be_bytes)
+    }
+
+    /// Reads an `i64` segment.
+    pub fn i64(&mut self) -> Option<i64> {
+        self.fixed()
+            .map(|b| (u64::from_be_bytes(b) ^ (1 << 63)) as i64)
+    }
+
+    /// Reads a variable length segment.
+    pub fn bytes(&mut self) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut i = 0;
+        loop {
+            match (self.key.get(i)?, self.key.get(i + 1)) {
+                (0, Some(0)) => break,
+                (0, Some(0xFF)) => {
+                    bytes.push(0);
+                    i += 2;
+                }
+                (0, _) => return None,
+                (b, _) => {
+                    bytes.push(*b);
+                    i += 1;
+                }
+            }
+        }
+        self.key = &self.key[i + 2..];
+        Some(bytes)
+    }
+
+    /// Reads a string segment.
+    pub fn str(&mut self) -> Option<String> {
+        let mut copy = self.clone();
+        let s = String::from_utf8(copy.bytes()?).ok()?;
+        *self = copy;
+        Some(s)
+    }
+
+    /// Returns true once every segment has been read.
+    pub fn is_empty(&self) -> bool {
+        self.key.is_empty()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Bytes<'a> {
     Slice(&'a [u8]),
@@ -147,6 +307,43 @@ mod tests {
         assert!(ptr == ptr2);
     }
 
+    #[test]
+    fn composite_key() {
+        let keys = [
+            CompositeKey::new().push_i64(-5).push_bytes(b"a"),
+            CompositeKey::new().push_i64(-5).push_bytes(b"a\0"),
+            CompositeKey::new().push_i64(-5).push_bytes(b"a\0\0"),
+            CompositeKey::new().push_i64(-5).push_bytes(b"ab"),
+            CompositeKey::new().push_i64(0).push_bytes(b""),
+            CompositeKey::new().push_i64(3).push_bytes(b"").push_u32(1),
+            CompositeKey::new().push_i64(3).push_bytes(b"\0"),
+            CompositeKey::new().push_i64(i64::MAX).push_bytes(b"\xFF"),
+        ];
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|k| k.clone().build()).collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+
+        let key = CompositeKey::new()
+            .push_u64(42)
+            .push_bytes(b"x\0\xFFy")
+            .push_str("name")
+            .push_i64(-1)
+            .push_u32(9)
+            .build();
+        let mut decoder = CompositeKey::decode(&key);
+        assert_eq!(decoder.u64(), Some(42));
+        assert_eq!(decoder.bytes(), Some(b"x\0\xFFy".to_vec()));
+        assert_eq!(decoder.str(), Some(String::from("name")));
+        assert_eq!(decoder.i64(), Some(-1));
+        assert!(!decoder.is_empty());
+        assert_eq!(decoder.u32(), Some(9));
+        assert!(decoder.is_empty());
+        assert_eq!(decoder.u32(), None);
+        assert_eq!(CompositeKey::decode(b"ab").bytes(), None);
+        assert_eq!(CompositeKey::decode(b"a\0b").bytes(), None);
+    }
+
     #[test]
     fn from_str() {
         let s = "abc";
@@ -8,6 +8,7 @@ use std::{
 use crate::{
     bucket::{Bucket, InnerBucket},
     data::Data,
+    errors::Result,
     freelist::TxFreelist,
     page::PageID,
     page_node::PageNodeID,
@@ -87,11 +88,86 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
         if b.deleted {
             panic!("Cannot seek cursor on a deleted bucket.");
         }
-        let (exists, stack) = search(key.as_ref(), b.meta.root_page, &mut b);
+        let (exists, stack) = match search(key.as_ref(), b.meta.root_page, &mut b) {
+            Ok(found) => found,
+            Err(e) => panic!("{}", e),
+        };
         self.stack = stack;
         exists
     }
 
+    /// Moves the cursor to the given key, and returns the data it landed on.
+    ///
+    /// This is the same as calling [`seek`](#method.seek) followed by [`current`](#method.current).
+    /// Returns whether or not the key exists in the bucket, along with the data at the cursor's new position.
+    pub fn seek_get<T: AsRef<[u8]>>(&mut self, key: T) -> (bool, Option<Data<'b, 'tx>>) {
+        let exists = self.seek(key);
+        (exists, self.current())
+    }
+
+    /// Moves the cursor back to the previous element and returns it.
+    ///
+    /// If the cursor hasn't been moved yet, it starts from the last element in the bucket.
+    /// Returns `None` once it is already at the first element.
+    pub fn prev(&mut self) -> Option<Data<'b, 'tx>> {
+        if self.bucket.borrow().deleted {
+            panic!("Cannot get data from a deleted bucket.");
+        }
+        if self.stack.is_empty() {
+            self.seek_last();
+        } else if !self.step_back() {
+            return None;
+        }
+        self.next_called = true;
+        self.current()
+    }
+
+    /// Moves the cursor to the given key, then iterates backwards from there.
+    ///
+    /// The first element returned is the key itself if it exists, otherwise the last element before where it would be.
+    /// If the key is past the end of the bucket, this starts from the last element.
+    /// This is handy for paging through a bucket in descending order, like getting the ten entries at or before a key.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// let bucket = tx.get_bucket("events")?;
+    ///
+    /// let mut cursor = bucket.cursor();
+    /// for data in cursor.seek_and_rev(1_700_000_000_u64.to_be_bytes()).take(10) {
+    ///     println!("{:?}", data.key());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn seek_and_rev<'c, T: AsRef<[u8]>>(
+        &'c mut self,
+        key: T,
+    ) -> impl Iterator<Item = Data<'b, 'tx>> + 'c {
+        self.seek(key.as_ref());
+        self.next_called = true;
+        // The seek lands on the first element if the key is before all of them
+        let mut first = self.current().filter(|data| data.key() <= key.as_ref());
+        let mut done = first.is_none();
+        std::iter::from_fn(move || {
+            if let Some(data) = first.take() {
+                return Some(data);
+            }
+            if done {
+                return None;
+            }
+            let data = self.prev();
+            done = data.is_none();
+            data
+        })
+    }
+
     /// Returns the data at the cursor's current position.
     /// You can use this to get data after doing a [`seek`](#method.seek).
     pub fn current<'a>(&'a self) -> Option<Data<'b, 'tx>> {
@@ -108,6 +184,37 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
         }
     }
 
+    /// Turns the cursor into an iterator over its key / value pairs that skips any pair
+    /// with the same value as the pair just before it, so only the first pair of each run is returned.
+    ///
+    /// Buckets are always sorted by key, so this only finds every distinct value when equal values are stored next to each other,
+    /// like in an index bucket whose keys start with the value. Nested buckets are skipped, and don't break up a run.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    /// // keys are `color || user id`, and values are the color
+    /// let bucket = tx.get_bucket("users-by-color")?;
+    ///
+    /// for kv in bucket.cursor().dedup_by_value() {
+    ///     println!("someone likes {:?}", kv.value());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dedup_by_value(self) -> DedupByValue<'b, 'tx, Self> {
+        DedupByValue {
+            i: self,
+            last: None,
+        }
+    }
+
     fn seek_first(&mut self) {
         let b = self.bucket.borrow();
         if self.stack.is_empty() {
@@ -125,7 +232,10 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
             if page_node.len() == 0 {
                 break;
             }
-            let page_id = page_node.index_page(elem.index);
+            let page_id = match b.index_page(&page_node, elem.index) {
+                Ok(page_id) => page_id,
+                Err(e) => panic!("{}", e),
+            };
 
             self.stack.push(SearchPath {
                 index: 0,
@@ -133,6 +243,51 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
             });
         }
     }
+
+    // Moves down to the last element under the cursor's current position.
+    fn seek_last(&mut self) {
+        let b = self.bucket.borrow();
+        if self.stack.is_empty() {
+            let root = PageNodeID::Page(b.meta.root_page);
+            self.stack.push(SearchPath {
+                index: b.page_node(root).len().saturating_sub(1),
+                id: root,
+            });
+        }
+        loop {
+            let elem = self.stack.last().unwrap();
+            let page_node = b.page_node(elem.id);
+            if page_node.leaf() || page_node.len() == 0 {
+                break;
+            }
+            let id = match b.index_page(&page_node, elem.index) {
+                Ok(page_id) => PageNodeID::Page(page_id),
+                Err(e) => panic!("{}", e),
+            };
+            self.stack.push(SearchPath {
+                index: b.page_node(id).len().saturating_sub(1),
+                id,
+            });
+        }
+    }
+
+    // Moves to the element before the current one, returning false if there isn't one.
+    fn step_back(&mut self) -> bool {
+        // Leave the cursor where it is if it's already at the first element
+        if self.stack.iter().all(|elem| elem.index == 0) {
+            return false;
+        }
+        loop {
+            let elem = self.stack.last_mut().unwrap();
+            if elem.index > 0 {
+                elem.index -= 1;
+                break;
+            }
+            self.stack.pop();
+        }
+        self.seek_last();
+        true
+    }
 }
 
 // function that searches the bucket for a given key
@@ -140,7 +295,7 @@ pub(crate) fn search(
     key: &[u8],
     mut page_id: PageID,
     b: &mut InnerBucket,
-) -> (bool, Vec<SearchPath>) {
+) -> Result<(bool, Vec<SearchPath>)> {
     let mut stack = Vec::new();
     loop {
         let page_node = b.page_node(PageNodeID::Page(page_id));
@@ -149,11 +304,11 @@ pub(crate) fn search(
         let leaf = page_node.leaf();
         stack.push(SearchPath { index, id });
         if leaf {
-            return (exact, stack);
+            return Ok((exact, stack));
         }
-        let next_page_id = page_node.index_page(index);
+        let next_page_id = b.index_page(&page_node, index)?;
         if next_page_id == 0 {
-            return (false, stack);
+            return Ok((false, stack));
         }
         b.add_page_parent(next_page_id, page_id);
         page_id = next_page_id;
@@ -351,6 +506,37 @@ where
     }
 }
 
+/// An iterator over a bucket's key / value pairs that skips runs of equal values,
+/// created with [`Cursor::dedup_by_value`].
+pub struct DedupByValue<'b, 'tx, I> {
+    i: I,
+    last: Option<KVPair<'b, 'tx>>,
+}
+
+impl<'b, 'tx, I> Iterator for DedupByValue<'b, 'tx, I>
+where
+    I: Iterator<Item = Data<'b, 'tx>>,
+{
+    type Item = KVPair<'b, 'tx>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for data in self.i.by_ref() {
+            if let Data::KeyValue(kv) = data {
+                if self
+                    .last
+                    .as_ref()
+                    .map_or(false, |last| last.value() == kv.value())
+                {
+                    continue;
+                }
+                self.last = Some(kv.clone());
+                return Some(kv);
+            }
+        }
+        None
+    }
+}
+
 pub trait ToKVPairs<'b, 'tx>: Iterator<Item = Data<'b, 'tx>> + Sized {
     fn to_kv_pairs(self) -> KVPairs<Self>;
 }
@@ -372,7 +558,88 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::{db::DB, errors::Result, testutil::RandomFile};
+    use crate::{
+        bucket::Bucket,
+        db::{OpenOptions, DB},
+        errors::Result,
+        testutil::RandomFile,
+    };
+
+    #[test]
+    fn test_seek_get() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        b.put("a", "1")?;
+        b.put("c", "3")?;
+        let mut cursor = b.cursor();
+
+        let (exists, data) = cursor.seek_get("c");
+        assert!(exists);
+        assert_eq!(data.unwrap().kv().value(), b"3");
+
+        // lands just before where the key would be
+        let (exists, data) = cursor.seek_get("b");
+        assert!(!exists);
+        assert_eq!(data.unwrap().key(), b"a");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_and_rev() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let check = |b: &Bucket| {
+            let keys = |iter: &mut dyn Iterator<Item = crate::Data>| -> Vec<u64> {
+                iter.map(|data| u64::from_be_bytes(data.key().try_into().unwrap()))
+                    .collect()
+            };
+            let mut cursor = b.cursor();
+            assert_eq!(
+                keys(&mut cursor.seek_and_rev(500_u64.to_be_bytes()).take(3)),
+                vec![500, 498, 496]
+            );
+            assert_eq!(
+                keys(&mut cursor.seek_and_rev(501_u64.to_be_bytes()).take(2)),
+                vec![500, 498]
+            );
+            assert_eq!(
+                keys(&mut cursor.seek_and_rev(u64::MAX.to_be_bytes()).take(2)),
+                vec![1998, 1996]
+            );
+            assert_eq!(keys(&mut cursor.seek_and_rev(0_u64.to_be_bytes())), vec![0]);
+            assert_eq!(keys(&mut cursor.seek_and_rev([])).len(), 0);
+            let all = keys(&mut cursor.seek_and_rev(1999_u64.to_be_bytes()));
+            assert_eq!(all, (0..1000).rev().map(|i| i * 2).collect::<Vec<u64>>());
+
+            // prev on a new cursor starts from the end
+            let mut cursor = b.cursor();
+            let mut reversed = Vec::new();
+            while let Some(data) = cursor.prev() {
+                reversed.push(data.key().to_vec());
+            }
+            let mut forward: Vec<Vec<u8>> = b.cursor().map(|data| data.key().to_vec()).collect();
+            forward.reverse();
+            assert_eq!(reversed, forward);
+            // and going forward again picks up after the first element
+            assert_eq!(cursor.next().unwrap().key(), 2_u64.to_be_bytes());
+        };
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            assert_eq!(b.cursor().seek_and_rev("a").count(), 0);
+            assert!(b.cursor().prev().is_none());
+            for i in 0..1000_u64 {
+                b.put((i * 2).to_be_bytes(), i.to_string())?;
+            }
+            check(&b);
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        check(&tx.get_bucket("abc")?);
+        Ok(())
+    }
 
     #[test]
     fn test_iters() -> Result<()> {
@@ -457,4 +724,41 @@ mod tests {
         tx.delete_bucket("abc").unwrap();
         c.next();
     }
+
+    #[test]
+    fn test_dedup_by_value() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for (k, v) in [
+            ("a", "red"),
+            ("b", "red"),
+            ("c", "blue"),
+            ("e", "blue"),
+            ("f", "red"),
+        ] {
+            b.put(k, v)?;
+        }
+        // a bucket in the middle of a run doesn't split it
+        b.create_bucket("d")?;
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = b
+            .cursor()
+            .dedup_by_value()
+            .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"a".to_vec(), b"red".to_vec()),
+                (b"c".to_vec(), b"blue".to_vec()),
+                (b"f".to_vec(), b"red".to_vec()),
+            ]
+        );
+        assert_eq!(
+            tx.create_bucket("empty")?.cursor().dedup_by_value().count(),
+            0
+        );
+        Ok(())
+    }
 }
@@ -44,13 +44,45 @@ impl<'b, 'tx> Data<'b, 'tx> {
     /// Asserts that the `Data` is a `KVPair` and returns the inner data
     ///
     /// Panics if the data is a Bucket.
-    pub fn kv(&self) -> &KVPair {
+    pub fn kv(&self) -> &KVPair<'_, '_> {
         if let Self::KeyValue(kv) = self {
             return kv;
         }
         panic!("Cannot get KVPair from BucketData");
     }
 
+    /// Returns a reference to the `KVPair` if the `Data` is a key / value pair.
+    pub fn as_kv(&self) -> Option<&KVPair<'b, 'tx>> {
+        match self {
+            Self::KeyValue(kv) => Some(kv),
+            Self::Bucket(_) => None,
+        }
+    }
+
+    /// Returns a reference to the `BucketName` if the `Data` is a nested bucket.
+    pub fn as_bucket(&self) -> Option<&BucketName<'b, 'tx>> {
+        match self {
+            Self::Bucket(b) => Some(b),
+            Self::KeyValue(_) => None,
+        }
+    }
+
+    /// Converts the `Data` into a `KVPair`, or `None` if it is a nested bucket.
+    pub fn into_kv(self) -> Option<KVPair<'b, 'tx>> {
+        match self {
+            Self::KeyValue(kv) => Some(kv),
+            Self::Bucket(_) => None,
+        }
+    }
+
+    /// Converts the `Data` into a `BucketName`, or `None` if it is a key / value pair.
+    pub fn into_bucket(self) -> Option<BucketName<'b, 'tx>> {
+        match self {
+            Self::Bucket(b) => Some(b),
+            Self::KeyValue(_) => None,
+        }
+    }
+
     pub fn key(&self) -> &[u8] {
         match self {
             Self::Bucket(b) => b.name(),
@@ -59,6 +91,17 @@ impl<'b, 'tx> Data<'b, 'tx> {
     }
 }
 
+/// How a key / value pair differs between two transactions, as reported by [`DB::diff_snapshots`](struct.DB.html#method.diff_snapshots).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The pair is only in the newer transaction
+    Added,
+    /// The pair is only in the older transaction
+    Removed,
+    /// The key is in both transactions, with different values
+    Changed,
+}
+
 impl<'b, 'tx> From<Leaf<'tx>> for Data<'b, 'tx> {
     fn from(val: Leaf<'tx>) -> Self {
         match val {
@@ -166,6 +209,17 @@ pub struct KVPair<'b, 'tx> {
     _phantom: PhantomData<&'b ()>,
 }
 
+macro_rules! int_values {
+    ($($name:ident: $int:ty),*) => (
+    $(
+        #[doc = concat!("Returns the value as a big-endian `", stringify!($int), "`, or `None` if it isn't the right length.")]
+        pub fn $name(&self) -> Option<$int> {
+            self.value().try_into().ok().map(<$int>::from_be_bytes)
+        }
+    )*
+)
+}
+
 impl<'b, 'tx> KVPair<'b, 'tx> {
     pub(crate) fn new(key: Bytes<'tx>, value: Bytes<'tx>) -> Self {
         KVPair {
@@ -189,6 +243,18 @@ impl<'b, 'tx> KVPair<'b, 'tx> {
     pub fn kv(&self) -> (&[u8], &[u8]) {
         (self.key(), self.value())
     }
+
+    // Values written with to_be_bytes() can be read back without checking the length by hand
+    int_values!(
+        as_u16: u16,
+        as_u32: u32,
+        as_u64: u64,
+        as_u128: u128,
+        as_i16: i16,
+        as_i32: i32,
+        as_i64: i64,
+        as_i128: i128
+    );
 }
 
 impl<'b, 'tx> From<(Bytes<'tx>, Bytes<'tx>)> for KVPair<'b, 'tx> {
@@ -206,6 +272,42 @@ impl<'b, 'tx> From<Leaf<'tx>> for Option<KVPair<'b, 'tx>> {
     }
 }
 
+/// Key / Value Pair copied out of the database
+///
+/// Unlike a [`KVPair`], this owns its key and value,
+/// so it can be kept around after the transaction is closed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OwnedKVPair {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl OwnedKVPair {
+    /// Returns the key of the key / value pair as a byte slice.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value of the key / value pair as a byte slice.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Consumes the pair, returning the key and value.
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>) {
+        (self.key, self.value)
+    }
+}
+
+impl<'b, 'tx> From<KVPair<'b, 'tx>> for OwnedKVPair {
+    fn from(kv: KVPair<'b, 'tx>) -> Self {
+        OwnedKVPair {
+            key: kv.key().to_vec(),
+            value: kv.value().to_vec(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,6 +326,43 @@ mod tests {
         assert_eq!(kv.value(), &v[..]);
     }
 
+    #[test]
+    fn test_kv_pair_ints() {
+        let (k, v) = (b"k".to_vec(), 258_u64.to_be_bytes());
+        let kv = KVPair::new(Bytes::Slice(&k), Bytes::Slice(&v));
+        assert_eq!(kv.as_u64(), Some(258));
+        assert_eq!(kv.as_i64(), Some(258));
+        assert_eq!(kv.as_u32(), None);
+        assert_eq!(kv.as_u128(), None);
+
+        let v = (-2_i32).to_be_bytes();
+        let kv = KVPair::new(Bytes::Slice(&k), Bytes::Slice(&v));
+        assert_eq!(kv.as_i32(), Some(-2));
+        assert_eq!(kv.as_u32(), Some(u32::MAX - 1));
+        assert_eq!(kv.as_u16(), None);
+
+        let kv = KVPair::new(Bytes::Slice(&k), Bytes::Slice(&[1, 2]));
+        assert_eq!(kv.as_u16(), Some(258));
+        assert_eq!(kv.as_i16(), Some(258));
+        assert_eq!(kv.as_i128(), None);
+    }
+
+    #[test]
+    fn test_data_conversions() {
+        let kv: Data = Data::KeyValue(KVPair::new(Bytes::Slice(b"k"), Bytes::Slice(b"v")));
+        let b: Data = Data::Bucket(BucketName::new(Bytes::Slice(b"b")));
+
+        assert_eq!(kv.as_kv().unwrap().value(), b"v");
+        assert!(kv.as_bucket().is_none());
+        assert_eq!(b.as_bucket().unwrap().name(), b"b");
+        assert!(b.as_kv().is_none());
+
+        assert!(kv.clone().into_bucket().is_none());
+        assert_eq!(kv.into_kv().unwrap().key(), b"k");
+        assert!(b.clone().into_kv().is_none());
+        assert_eq!(b.into_bucket().unwrap().name(), b"b");
+    }
+
     // #[test]
     // fn test_bucket_data() {
     //     let name = b"Hello Bucket!";
@@ -1,10 +1,17 @@
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
 use std::{
-    fs::{File, OpenOptions as FileOpenOptions},
-    io::Write,
-    path::Path,
-    sync::{Arc, Mutex, RwLock},
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions as FileOpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use fs4::FileExt;
@@ -12,11 +19,31 @@ use memmap2::Mmap;
 use page_size::get as get_page_size;
 
 use crate::{
-    bucket::BucketMeta, errors::Result, freelist::Freelist, meta::Meta, page::Page, tx::Tx,
+    backup::{self, PageHistory},
+    batch::{self, BatchQueue, BatchWriter},
+    data::ChangeKind,
+    errors::{Error, Result},
+    freelist::{FirstFit, Freelist, PageAllocator},
+    meta::{Meta, RootMeta, COMPARATOR_BYTEWISE},
+    namespace::Namespace,
+    page::{Page, Pages},
+    tx::{check_meta, Tx},
+    wal::{self, Wal},
 };
 
 const MAGIC_VALUE: u32 = 0x00AB_CDEF;
-const VERSION: u32 = 1;
+// New files use the oldest format that every release can read
+const BASE_VERSION: u32 = 1;
+// Files move to this version once they use something older releases can't read, like a bucket counter
+pub(crate) const VERSION: u32 = 2;
+
+// Every database file open in this process, so the same file isn't opened twice by accident.
+// A Vec since it can be built in a static, and there are rarely more than a handful.
+static OPEN_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// How many average sized key / value pairs a suggested pagesize should hold, and the largest one to suggest
+const SUGGESTED_ENTRIES_PER_PAGE: u64 = 32;
+const MAX_SUGGESTED_PAGESIZE: u64 = 64 * 1024;
 
 // Minimum number of bytes to allocate when growing the databse
 pub(crate) const MIN_ALLOC_SIZE: u64 = 8 * 1024 * 1024;
@@ -45,9 +72,13 @@ const DEFAULT_NUM_PAGES: usize = 32;
 /// # Ok(())
 /// # }
 /// ```
+#[derive(Clone)]
 pub struct OpenOptions {
     pagesize: u64,
     num_pages: usize,
+    enforce_num_pages: bool,
+    allow_multiple_opens: bool,
+    wal: Option<PathBuf>,
     flags: DBFlags,
 }
 
@@ -61,7 +92,7 @@ impl OpenOptions {
     ///
     /// By default, your OS's pagesize is used as the database's pagesize, but if the file is
     /// moved across systems with different page sizes, it is necessary to set the correct value.
-    /// Trying to open an existing database with the incorrect page size will result in a panic.
+    /// Trying to open an existing database with the incorrect page size will result in an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error.
     ///
     /// # Panics
     /// Will panic if you try to set the pagesize < 1024 bytes.
@@ -88,6 +119,19 @@ impl OpenOptions {
         self
     }
 
+    /// Requires an existing database file to have at least [`num_pages`](#method.num_pages) pages.
+    ///
+    /// A new file starts out with `num_pages` pages and only grows as data is added, so an existing file
+    /// with fewer pages than that was created with different options.
+    /// The default is `false`, where the number of pages is ignored when opening an existing file.
+    /// When enabled, opening an existing database that has fewer pages than `num_pages` returns an
+    /// [`InvalidDB`](enum.Error.html#variant.InvalidDB) error, so you can catch a misconfiguration
+    /// instead of silently using the file's value.
+    pub fn enforce_num_pages(mut self, enforce_num_pages: bool) -> Self {
+        self.enforce_num_pages = enforce_num_pages;
+        self
+    }
+
     /// Enables or disables "Strict Mode", where each transaction will check the database for errors before finalizing a write.
     ///
     /// The default is `false`, but you may enable this if you want an extra degree of safety for your data at the cost of
@@ -120,24 +164,317 @@ impl OpenOptions {
         self
     }
 
+    /// Enables or disables syncing only the parts of the file a transaction wrote when it is committed.
+    ///
+    /// Normally committing a transaction syncs the entire file, which can be slow for a large database
+    /// even when only a few pages changed. With this enabled, Linux's `sync_file_range` is used on just the pages
+    /// that were written. Commits that grow the file still sync the entire file, so its new size is saved.
+    ///
+    /// The default is `false`. Be aware that `sync_file_range` does not flush the drive's own write cache,
+    /// so a commit may not survive a power failure on drives with a volatile cache.
+    /// It is not recommended to enable this unless you know what you are doing.
+    ///
+    /// This setting only works on Linux, and is a no-op on other platforms.
+    pub fn sync_file_range(mut self, sync_file_range: bool) -> Self {
+        self.flags.sync_file_range = sync_file_range;
+        self
+    }
+
+    /// Sets how often committed transactions are synced to disk.
+    ///
+    /// The default is [`SyncMode::Always`], which syncs the file before a commit returns, so a committed transaction
+    /// survives a crash or power failure. Syncing is usually the slowest part of a commit, so a bulk import
+    /// that can simply be started over if it fails can go much faster by syncing less often.
+    /// Commits that aren't synced are still written to the file, so they are seen by every transaction
+    /// and by other processes, but they can be lost if the machine goes down before the OS writes them out,
+    /// and since pages can reach the disk in any order, the file may be left corrupt.
+    /// Call [`DB::sync`](struct.DB.html#method.sync) once you're done to make everything durable.
+    pub fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.flags.sync_mode = sync_mode;
+        self
+    }
+
+    /// Enables or disables asking Linux to back the memory map with [transparent huge pages](https://www.kernel.org/doc/html/latest/admin-guide/mm/transhuge.html).
+    ///
+    /// The default is `false`, but you may enable this for very large, read-heavy databases
+    /// to reduce TLB misses on random reads.
+    /// This is done with `madvise(MADV_HUGEPAGE)` rather than `MAP_HUGETLB`, which only works for files on a `hugetlbfs` mount.
+    /// Huge pages are 2MB aligned, so a database smaller than that will not benefit,
+    /// and file-backed mappings only use them if the kernel was built with `CONFIG_READ_ONLY_THP_FOR_FS`.
+    /// If the kernel does not support transparent huge pages the hint is ignored.
+    ///
+    /// This setting only works on Linux, and is a no-op on other platforms.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.flags.huge_pages = huge_pages;
+        self
+    }
+
+    /// Enables or disables rebalancing the B+ trees of modified buckets when a transaction is committed.
+    ///
+    /// The default is `true`. Rebalancing merges nodes that have become mostly empty, which only happens after deletes.
+    /// You may disable this to skip that work for insert-only workloads, but deleting data with rebalancing
+    /// disabled can leave the database with lots of underfull pages.
+    /// Nodes left with fewer than two elements are still merged, since they cannot be written out on their own.
+    pub fn auto_rebalance(mut self, auto_rebalance: bool) -> Self {
+        self.flags.auto_rebalance = auto_rebalance;
+        self
+    }
+
+    /// Sets how empty a node has to get, as a fraction of the pagesize, before rebalancing merges it with a sibling.
+    ///
+    /// The default is `0.25`. Nodes are split into pieces about half a page in size when they outgrow a page,
+    /// so the gap between that and this threshold is what keeps a node from bouncing between being merged and split.
+    /// If a workload deletes and re-inserts a lot, lowering this widens the gap and cuts down on that churn,
+    /// at the cost of leaving more pages partly empty. Setting it to `0.0` only merges nodes with fewer than two elements,
+    /// like disabling [`auto_rebalance`](#method.auto_rebalance).
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `merge_threshold` is not at least `0.0` and less than `0.5`,
+    /// since nodes that were just split could then be merged right away.
+    pub fn merge_threshold(mut self, merge_threshold: f32) -> Self {
+        if !(0.0..0.5).contains(&merge_threshold) {
+            panic!("Merge threshold must be at least 0.0 and less than 0.5");
+        }
+        self.flags.merge_threshold = merge_threshold;
+        self
+    }
+
+    /// Sets the maximum number of bytes a writable transaction may buffer in memory for dirty pages.
+    ///
+    /// Every page modified in a transaction is kept in memory until the transaction is committed,
+    /// so very large transactions can use a lot of memory.
+    /// When this limit would be exceeded, the transaction returns a
+    /// [`TransactionTooLarge`](enum.Error.html#variant.TransactionTooLarge) error instead of allocating more.
+    ///
+    /// By default there is no limit.
+    pub fn max_tx_memory(mut self, max_tx_memory: usize) -> Self {
+        self.flags.max_tx_memory = Some(max_tx_memory);
+        self
+    }
+
+    /// Sets the maximum size in bytes the database file is allowed to grow to.
+    ///
+    /// By default there is no limit. When committing a transaction would need a bigger file,
+    /// the commit returns a [`DatabaseFull`](enum.Error.html#variant.DatabaseFull) error without writing anything,
+    /// so the database is left exactly as it was. Deleting data frees up pages that later transactions can reuse.
+    /// The file normally grows in large chunks, but it is never grown past this size.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.flags.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// Enables or disables keeping track of which pages each transaction reads.
+    ///
+    /// When enabled, [`Tx::pages_accessed`](struct.Tx.html#method.pages_accessed) returns how many distinct pages
+    /// a transaction has read, which can help tell whether queries would benefit from
+    /// [`mmap_populate`](#method.mmap_populate). The default is `false`, since every page read
+    /// has to be recorded in a set, which slows down reads.
+    pub fn track_page_access(mut self, track_page_access: bool) -> Self {
+        self.flags.track_page_access = track_page_access;
+        self
+    }
+
+    /// Enables or disables zeroing the unused part of every page that is written.
+    ///
+    /// Pages are reused once the data on them has been freed, and normally only the bytes the new data needs are written,
+    /// so the rest of the page keeps whatever was there before. That can leave old values sitting in the file long after
+    /// they were deleted. With this enabled, every page is written in full, with the space after the new data zeroed.
+    ///
+    /// The default is `false`. Enabling it means writing up to a page more for every node,
+    /// and buffering whole pages in memory until the transaction is committed.
+    /// Pages that are freed but not reused still hold their old contents until they are.
+    pub fn zero_on_free(mut self, zero_on_free: bool) -> Self {
+        self.flags.zero_on_free = zero_on_free;
+        self
+    }
+
+    /// Enables or disables rejecting keys that are not valid UTF-8.
+    ///
+    /// Keys are arbitrary bytes, but if you only ever mean to store strings, this catches binary keys
+    /// written by mistake. When enabled, [`Bucket::put`](struct.Bucket.html#method.put) and the methods that create buckets
+    /// return a [`NonUtf8Key`](enum.Error.html#variant.NonUtf8Key) error instead of storing the key.
+    /// Keys that are already in the database are not checked.
+    ///
+    /// The default is `false`.
+    pub fn utf8_keys(mut self, utf8_keys: bool) -> Self {
+        self.flags.utf8_keys = utf8_keys;
+        self
+    }
+
+    /// Enables or disables storing a checksum with every key / value pair.
+    ///
+    /// The checksum is written after the value in its leaf page, and checked whenever the pair is read with
+    /// [`Bucket::get`](struct.Bucket.html#method.get), [`Bucket::get_kv`](struct.Bucket.html#method.get_kv)
+    /// or [`Bucket::try_get`](struct.Bucket.html#method.try_get), and when the whole database is checked for consistency.
+    /// This catches corruption of the values themselves, even when the page around them looks fine.
+    /// A pair that doesn't match its checksum is reported as a [`ValueCorrupt`](enum.Error.html#variant.ValueCorrupt) error,
+    /// or a panic from the methods that don't return a `Result`.
+    /// Pairs read any other way, like with a [`Cursor`](struct.Cursor.html), [`Bucket::kv_pairs`](struct.Bucket.html#method.kv_pairs)
+    /// or [`Bucket::fast_scan`](struct.Bucket.html#method.fast_scan), aren't checked, so run [`DB::check`](struct.DB.html#method.check)
+    /// when scans need to be trusted too.
+    ///
+    /// Committing with this enabled moves the file to a newer format, since older releases can't read the checksums.
+    ///
+    /// It costs four bytes per pair and hashing every pair that gets written.
+    /// Pairs are only given a checksum when their page is written, so pages that haven't changed since
+    /// this was enabled aren't checked, and pages written after it is disabled lose their checksums.
+    ///
+    /// The default is `false`.
+    pub fn value_checksums(mut self, value_checksums: bool) -> Self {
+        self.flags.value_checksums = value_checksums;
+        self
+    }
+
+    /// Opens the database without ever writing to the file, so it only hands out read-only transactions.
+    ///
+    /// The file is opened without write permission, so it can be on a read-only filesystem,
+    /// like a database baked into a container image. Only a shared lock is taken on it,
+    /// so any number of processes can open the same file read-only at once, but a process that writes to it can't.
+    /// The freelist isn't loaded either, so [`DB::free_pages`](struct.DB.html#method.free_pages) is always empty.
+    ///
+    /// Anything that would write to the file, like a writable transaction, [`DB::rollback_to_previous`](struct.DB.html#method.rollback_to_previous),
+    /// or [`DB::compact_in_place`](struct.DB.html#method.compact_in_place), returns a [`ReadOnlyDB`](enum.Error.html#variant.ReadOnlyDB) error.
+    /// Opening a file that doesn't exist fails instead of creating it, and so does setting a [`wal`](#method.wal),
+    /// since opening the log can trim it.
+    ///
+    /// The default is `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{OpenOptions};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = OpenOptions::new().read_only(true).open("/data/baked.db")?;
+    /// let tx = db.tx(false)?;
+    /// let count = tx.get_bucket("users")?.kv_pairs().count();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.flags.read_only = read_only;
+        self
+    }
+
+    /// Opens the database only for inspecting it, without changing anything about the file or anyone else using it.
+    ///
+    /// This is meant for diagnostics on a file that may be corrupt, like running [`DB::check`](struct.DB.html#method.check)
+    /// or reading it with read-only transactions, where even a small repair would destroy evidence.
+    /// It implies [`read_only`](#method.read_only), so nothing is ever written to the file and it is never grown or remapped,
+    /// and on top of that:
+    ///
+    /// * No lock is taken on the file, so it can be inspected while another process has it open for writing,
+    ///   without blocking that process. Reads can then see a commit that is still being written.
+    /// * The file isn't registered as open in this process, so it can be opened normally at the same time.
+    /// * A damaged meta page is left as it is, and the other one is read instead.
+    /// * Setting a [`wal`](#method.wal) fails, so no log file is created or trimmed.
+    ///
+    /// The default is `false`.
+    pub fn verify_only(mut self, verify_only: bool) -> Self {
+        self.flags.verify_only = verify_only;
+        if verify_only {
+            self.flags.read_only = true;
+        }
+        self
+    }
+
+    /// Sets a file to log the changes made by every committed transaction to, for replicating the database.
+    ///
+    /// Each commit appends a record of every key / value pair that was put or deleted, and every bucket
+    /// that was created or deleted, and the log is synced along with the database file.
+    /// Ship the log somewhere else and use [`DB::replay_wal_into`](struct.DB.html#method.replay_wal_into)
+    /// to apply the same changes to another database.
+    /// The log is only ever appended to, so it is up to you to rotate it once it has been replayed.
+    ///
+    /// Only changes to data are logged, bucket counters and the [`user_version`](struct.DB.html#method.user_version) are not.
+    /// [`DB::rollback_to_previous`](struct.DB.html#method.rollback_to_previous) and [`DB::compact_in_place`](struct.DB.html#method.compact_in_place)
+    /// change the file without committing a transaction, so they return an error while the log is set.
+    /// If the process crashes after a transaction was logged but before it was committed,
+    /// that record is removed the next time the database is opened.
+    ///
+    /// The default is `None`.
+    pub fn wal(mut self, wal: Option<PathBuf>) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Sets the [`PageAllocator`](trait.PageAllocator.html) that picks which free pages are reused.
+    ///
+    /// The allocator should start out without any free pages, since it is given the database's free pages when it is opened.
+    /// The default reuses the first run of free pages that is long enough.
+    pub fn allocator(mut self, allocator: Box<dyn PageAllocator>) -> Self {
+        self.flags.allocator = allocator;
+        self
+    }
+
+    /// Allows opening a database file that is already open somewhere else in this process.
+    ///
+    /// By default, [`open`](#method.open) returns an [`AlreadyOpen`](enum.Error.html#variant.AlreadyOpen) error
+    /// if the file is already open in this process, since the second open would wait forever for the file lock
+    /// held by the first one, or on platforms where the lock is only advisory, let both write to the file at the same time.
+    /// Only enable this if you handle that yourself, like opening the file again once another thread closes it.
+    /// To share a database between threads, clone the [`DB`](struct.DB.html) instead.
+    pub fn allow_multiple_opens(mut self, allow_multiple_opens: bool) -> Self {
+        self.allow_multiple_opens = allow_multiple_opens;
+        self
+    }
+
+    /// Sets how many transactions freed pages are kept for before they can be reused.
+    ///
+    /// By default (`0`), pages freed by a commit can be reused by the next writable transaction
+    /// once no read-only transaction can still see them. With a value of `n`, they are also held back
+    /// until `n` more transactions have been started, so the data from recent commits stays intact in the file
+    /// for debugging or recovery tools to read. This trades a bigger file for a longer history.
+    /// [`DB::rollback_to_previous`](struct.DB.html#method.rollback_to_previous) needs at least `1`.
+    ///
+    /// The history is only kept while the database is open. Pages that are being held back are written to
+    /// the file's freelist like any other free page, so they can be reused right away after the database is reopened.
+    pub fn keep_freed_for(mut self, transactions: u64) -> Self {
+        self.flags.keep_freed_for = transactions;
+        self
+    }
+
+    /// Sets how long [`open`](#method.open) will wait for the exclusive file lock.
+    ///
+    /// By default (or with `None`), opening the database blocks until the lock is released, however long that takes.
+    /// With a timeout, the lock is retried with a growing delay between attempts, and if it still can't be taken
+    /// when the timeout runs out, an [`Io`](enum.Error.html#variant.Io) error with the kind
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock) is returned instead.
+    /// This keeps a process from hanging forever because another process is still holding on to the file.
+    pub fn lock_timeout(mut self, lock_timeout: Option<Duration>) -> Self {
+        self.flags.lock_timeout = lock_timeout;
+        self
+    }
+
     /// Opens the database with the current options.
     ///
     /// If the file does not exist, it will initialize an empty database with a size of (`num_pages * pagesize`) bytes.
     /// If it does exist, the file is opened with both read and write permissions, and we attempt to create an
     /// [exclusive lock](https://en.wikipedia.org/wiki/File_locking) on the file. Getting the file lock will block until the lock
-    /// is released to prevent you from having two processes modifying the file at the same time. This lock is not foolproof though,
+    /// is released (or the [`lock_timeout`](#method.lock_timeout) runs out) to prevent you from having two processes modifying the file at the same time. This lock is not foolproof though,
     /// so it is up to the user to make sure only one process has access to the database at a time (unless it is read-only).
     ///
     /// # Errors
     ///
     /// Will return an error if there are issues creating a new file, opening an existing file, obtaining the file lock, or creating the memory map.
     ///
-    /// # Panics
-    ///
-    /// Will panic if the pagesize the database is opened with is not the same as the pagesize it was created with.
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the file was not created by jammdb,
+    /// was written with a newer file format than this version supports,
+    /// or if the pagesize the database is opened with is not the same as the pagesize it was created with.
     pub fn open<P: AsRef<Path>>(self, path: P) -> Result<DB> {
         let path: &Path = path.as_ref();
-        let file = if !path.exists() {
+        let exists = path.exists();
+        let mut open_path = None;
+        // Without a file lock, a verifier can't get in the way of another open
+        let register = !self.allow_multiple_opens && !self.flags.verify_only;
+        if exists && register {
+            open_path = Some(OpenPath::register(path)?);
+        }
+        let file = if self.flags.read_only {
+            File::open(path)?
+        } else if !exists {
             init_file(
                 path,
                 self.pagesize,
@@ -147,12 +484,109 @@ impl OpenOptions {
         } else {
             open_file(path, false, self.flags.direct_writes)?
         };
+        // We can't find the real path of a new file until it has been created
+        if !exists && register {
+            open_path = Some(OpenPath::register(path)?);
+        }
+        let path = path.canonicalize()?;
+        let mut db = self.open_existing(file, exists)?;
+        let inner = Arc::get_mut(&mut db.inner).expect("database was just opened");
+        inner.open_path = open_path;
+        inner.path = Some(path);
+        Ok(db)
+    }
+
+    /// Opens the database in a file that has already been opened by the caller.
+    ///
+    /// This is the same as [`open`](#method.open), but works with files that don't have a path you can use,
+    /// like one passed in from another process or an anonymous temporary file.
+    /// Since there is no path, it can't be checked against the other databases open in this process.
+    /// The file must be opened with both read and write permissions.
+    /// If the file is empty, a new database is initialized in it, otherwise it is opened as an existing database.
+    /// The [`direct_writes`](#method.direct_writes) option is ignored, since that is set when the file is opened.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`open`](#method.open).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::OpenOptions as FileOptions;
+    /// use jammdb::{OpenOptions};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let file = FileOptions::new().read(true).write(true).create(true).open("my.db")?;
+    /// let db = OpenOptions::new().open_file(file)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_file(self, mut file: File) -> Result<DB> {
+        let exists = file.metadata()?.len() > 0;
+        if !exists {
+            if self.flags.read_only {
+                return Err(Error::ReadOnlyDB);
+            }
+            init_db(&mut file, self.pagesize, self.num_pages)?;
+        }
+        self.open_existing(file, exists)
+    }
 
-        let db = DBInner::open(file, self.pagesize, self.flags)?;
+    fn open_existing(self, file: File, exists: bool) -> Result<DB> {
+        if self.flags.read_only && self.wal.is_some() {
+            return Err(Error::ReadOnlyDB);
+        }
+        let file_len = file.metadata()?.len();
+        let mut db = DBInner::open(file, self.pagesize, self.flags)?;
+        if exists && self.enforce_num_pages {
+            let file_pages = file_len / self.pagesize;
+            if file_pages < self.num_pages as u64 {
+                return Err(Error::InvalidDB(format!(
+                    "Database has {} pages, expected at least {}",
+                    file_pages, self.num_pages
+                )));
+            }
+        }
+        if let Some(path) = self.wal {
+            db.wal = Some(Mutex::new(Wal::open(&path, db.meta()?.tx_id)?));
+        }
         Ok(DB {
             inner: Arc::new(db),
         })
     }
+
+    /// Opens every database file with a `.db` extension in the given directory with the current options.
+    ///
+    /// The files are opened one at a time, sorted by file name, so that every process using this method
+    /// acquires the file locks in the same order. That way two processes opening the same set of files
+    /// can't deadlock waiting on each other's locks. The returned map is keyed by the file name
+    /// without its `.db` extension.
+    ///
+    /// Each database is completely independent, this is just a convenience for opening many of them.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the directory cannot be read, or if any of the files cannot be opened.
+    /// See [`open`](#method.open) for details.
+    pub fn open_all<P: AsRef<Path>>(self, dir: P) -> Result<BTreeMap<String, DB>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map_or(false, |ext| ext == "db") {
+                paths.push(path);
+            }
+        }
+        paths.sort_unstable();
+
+        let mut dbs = BTreeMap::new();
+        for path in paths {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let db = self.clone().open(&path)?;
+            dbs.insert(name, db);
+        }
+        Ok(dbs)
+    }
 }
 
 impl Default for OpenOptions {
@@ -164,19 +598,62 @@ impl Default for OpenOptions {
         OpenOptions {
             pagesize,
             num_pages: DEFAULT_NUM_PAGES,
+            enforce_num_pages: false,
+            allow_multiple_opens: false,
+            wal: None,
             flags: DBFlags {
                 strict_mode: false,
                 mmap_populate: false,
                 direct_writes: false,
+                huge_pages: false,
+                max_tx_memory: None,
+                lock_timeout: None,
+                max_file_size: None,
+                keep_freed_for: 0,
+                zero_on_free: false,
+                utf8_keys: false,
+                value_checksums: false,
+                track_page_access: false,
+                sync_file_range: false,
+                sync_mode: SyncMode::Always,
+                auto_rebalance: true,
+                merge_threshold: 0.25,
+                read_only: false,
+                verify_only: false,
+                allocator: Box::new(FirstFit::default()),
             },
         }
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct DBFlags {
     pub(crate) strict_mode: bool,
     pub(crate) mmap_populate: bool,
     pub(crate) direct_writes: bool,
+    pub(crate) huge_pages: bool,
+    pub(crate) max_tx_memory: Option<usize>,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) keep_freed_for: u64,
+    pub(crate) zero_on_free: bool,
+    pub(crate) utf8_keys: bool,
+    pub(crate) value_checksums: bool,
+    pub(crate) track_page_access: bool,
+    pub(crate) sync_file_range: bool,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) auto_rebalance: bool,
+    pub(crate) merge_threshold: f32,
+    pub(crate) read_only: bool,
+    pub(crate) verify_only: bool,
+    pub(crate) allocator: Box<dyn PageAllocator>,
+}
+
+impl DBFlags {
+    // How small a node can get before it's merged when committing, if nodes are being rebalanced at all.
+    pub(crate) fn merge_threshold(&self) -> Option<f32> {
+        self.auto_rebalance.then_some(self.merge_threshold)
+    }
 }
 
 /// A database
@@ -186,11 +663,31 @@ pub(crate) struct DBFlags {
 /// If you want to use the database across threads, so you can `clone` the database
 /// to have concurrent transactions (you're really just cloning an [`Arc`] so it's pretty cheap).
 /// **Do not** try to open multiple transactions in the same thread, you're pretty likely to cause a deadlock.
+///
+/// Read-only transactions on different threads don't wait for each other or for the writable transaction.
+/// Opening one only takes a shared lock on the memory map and briefly records its ID, it never touches the lock
+/// held by the writable transaction. The one exception is a commit that needs to grow the file, which has to remap it:
+/// it waits for the read-only transactions that are already open to be dropped, and new ones wait for it to finish.
 #[derive(Clone)]
 pub struct DB {
     pub(crate) inner: Arc<DBInner>,
 }
 
+/// A reference to a [`DB`] that doesn't keep it open, made with [`DB::downgrade`](struct.DB.html#method.downgrade).
+///
+/// Once every [`DB`] clone has been dropped, the file is closed and [`upgrade`](#method.upgrade) returns `None`.
+#[derive(Clone)]
+pub struct WeakDB {
+    inner: Weak<DBInner>,
+}
+
+impl WeakDB {
+    /// Returns the database, if it is still open.
+    pub fn upgrade(&self) -> Option<DB> {
+        self.inner.upgrade().map(|inner| DB { inner })
+    }
+}
+
 impl DB {
     /// Opens a database using the default [`OpenOptions`].
     ///
@@ -214,211 +711,2352 @@ impl DB {
         OpenOptions::new().open(path)
     }
 
+    /// Opens every database file in a directory using the default [`OpenOptions`].
+    ///
+    /// Same as calling `OpenOptions::new().open_all(dir)`.
+    /// Please read the documentation for [`OpenOptions::open_all`](struct.OpenOptions.html#method.open_all) for details.
+    pub fn open_all<P: AsRef<Path>>(dir: P) -> Result<BTreeMap<String, DB>> {
+        OpenOptions::new().open_all(dir)
+    }
+
+    /// Opens a database using the pagesize it was created with, so you don't need to know it ahead of time.
+    ///
+    /// The pagesize is read from the file's first meta page, which is at the start of the file no matter what the pagesize is.
+    /// If that page is damaged, the second meta page is used instead.
+    /// This is useful for files created on a system with a different pagesize, which [`open`](#method.open)
+    /// would otherwise refuse to read. If the file doesn't exist yet, it is created with the default pagesize,
+    /// and otherwise this is the same as calling `OpenOptions::new().pagesize(pagesize).open(path)`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if neither meta page is valid,
+    /// and otherwise the same as [`open`](#method.open).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open_auto("copied-from-another-machine.db")?;
+    /// println!("opened with a pagesize of {}", db.pagesize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_auto<P: AsRef<Path>>(path: P) -> Result<DB> {
+        let path = path.as_ref();
+        let mut options = OpenOptions::new();
+        if path.exists() {
+            if let Some(pagesize) = stored_pagesize(path)? {
+                options = options.pagesize(pagesize);
+            }
+        }
+        options.open(path)
+    }
+
     /// Creates a [`Tx`].
     /// This transaction is either read-only or writable depending on the `writable` parameter.
     /// Please read the docs on a [`Tx`] for more details.
-    pub fn tx(&self, writable: bool) -> Result<Tx> {
+    pub fn tx(&self, writable: bool) -> Result<Tx<'_>> {
         Tx::new(self, writable)
     }
 
-    /// Returns the database's pagesize.
-    pub fn pagesize(&self) -> u64 {
-        self.inner.pagesize
+    /// Returns a [`BatchWriter`] that combines writes from many callers into shared transactions.
+    ///
+    /// Please read the docs on a [`BatchWriter`] for more details.
+    pub fn batch_writer(&self) -> BatchWriter {
+        BatchWriter::new(self.clone())
     }
 
-    #[doc(hidden)]
-    pub fn check(&self) -> Result<()> {
-        self.tx(false)?.check()
+    /// Runs `f` in a writable transaction shared with any other calls to `batch` made around the same time,
+    /// and returns once that transaction has been committed.
+    ///
+    /// Calls from many threads are queued up, and whichever thread gets to them first runs all of them
+    /// in a single transaction, so the cost of syncing the file is paid once for the whole batch.
+    /// While a batch is being committed, new calls wait and become the next batch.
+    /// If `f` returns an error, the shared transaction is thrown away and `f` is run again on its own,
+    /// in a transaction of its own, so its error (if it still fails) only comes back to its caller.
+    /// The rest of the batch is then run again without it.
+    /// If `f` panics, it isn't run again, and the panic comes back to its caller as an error instead.
+    ///
+    /// Because of that, `f` may be run more than once, and must do the same thing every time.
+    /// It can also be run on another caller's thread, which is why it has to be `Send` and `'static`.
+    /// Like [`tx`](#method.tx), don't call this while holding a writable transaction on the same thread.
+    /// For simple writes, a [`BatchWriter`] does the same without the closures.
+    ///
+    /// # Errors
+    ///
+    /// Will return the error from `f` when it is run on its own, a [`Sync`](enum.Error.html#variant.Sync) error if `f` panicked,
+    /// or any error from starting or committing the transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    ///
+    /// let handles: Vec<_> = (0..10_u64)
+    ///     .map(|i| {
+    ///         let db = db.clone();
+    ///         std::thread::spawn(move || {
+    ///             db.batch(move |tx| {
+    ///                 tx.get_or_create_bucket("users")?.put(i.to_be_bytes(), "data")?;
+    ///                 Ok(())
+    ///             })
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for h in handles {
+    ///     h.join().unwrap()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn batch<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&Tx) -> Result<()> + Send + 'static,
+    {
+        batch::batch(self, Box::new(f))
     }
-}
-pub(crate) struct DBInner {
-    pub(crate) data: Mutex<Arc<Mmap>>,
-    pub(crate) mmap_lock: RwLock<()>,
-    pub(crate) freelist: Mutex<Freelist>,
-    pub(crate) file: Mutex<File>,
-    pub(crate) open_ro_txs: Mutex<Vec<u64>>,
-    pub(crate) flags: DBFlags,
-
-    pub(crate) pagesize: u64,
-}
-
-impl DBInner {
-    pub(crate) fn open(file: File, pagesize: u64, flags: DBFlags) -> Result<DBInner> {
-        file.lock_exclusive()?;
-        let mmap = mmap(&file, flags.mmap_populate)?;
-        let mmap = Mutex::new(Arc::new(mmap));
-        let db = DBInner {
-            data: mmap,
-            mmap_lock: RwLock::new(()),
-            freelist: Mutex::new(Freelist::new()),
-
-            file: Mutex::new(file),
-            open_ro_txs: Mutex::new(Vec::new()),
-
-            pagesize,
-            flags,
-        };
-
-        {
-            let meta = db.meta()?;
-            let data = db.data.lock()?;
-            let free_pages = Page::from_buf(&data, meta.freelist_page, pagesize).freelist();
 
-            if !free_pages.is_empty() {
-                db.freelist.lock()?.init(free_pages);
-            }
-        }
+    /// Returns a [`Namespace`] for working inside the root level bucket with the given name.
+    ///
+    /// The bucket isn't created until something is written to the namespace.
+    /// Please read the docs on a [`Namespace`] for more details.
+    pub fn namespace(&self, name: &[u8]) -> Namespace {
+        Namespace::new(self.clone(), name.to_vec())
+    }
 
-        Ok(db)
+    /// Returns the names of every namespace, sorted.
+    ///
+    /// Namespaces are just root level buckets, so this is the name of every root level bucket,
+    /// whether or not it was created through a [`Namespace`].
+    pub fn namespaces(&self) -> Result<Vec<Vec<u8>>> {
+        let tx = self.tx(false)?;
+        let names = tx.buckets().map(|(name, _)| name.name().to_vec()).collect();
+        Ok(names)
     }
 
-    pub(crate) fn resize(&self, file: &File, new_size: u64) -> Result<Arc<Mmap>> {
-        file.allocate(new_size)?;
-        let _lock = self.mmap_lock.write()?;
-        let mut data = self.data.lock()?;
-        let mmap = mmap(file, self.flags.mmap_populate)?;
-        *data = Arc::new(mmap);
-        Ok(data.clone())
+    /// Returns the database's pagesize.
+    pub fn pagesize(&self) -> u64 {
+        self.inner.pagesize
     }
 
-    pub(crate) fn meta(&self) -> Result<Meta> {
-        let data = self.data.lock()?;
+    /// Syncs every commit that hasn't been synced to disk yet.
+    ///
+    /// This is only needed when the database was opened with a [`SyncMode`] other than
+    /// [`Always`](enum.SyncMode.html#variant.Always), like at the end of a bulk import.
+    /// The [write-ahead log](struct.OpenOptions.html#method.wal) is synced too, if there is one.
+    /// This does nothing for a database opened with [`read_only`](struct.OpenOptions.html#method.read_only).
+    /// It waits for any open writable transaction, so don't call it while holding one on the same thread.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{OpenOptions, SyncMode};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = OpenOptions::new().sync_mode(SyncMode::Never).open("import.db")?;
+    /// for i in 0..1000_u64 {
+    ///     let tx = db.tx(true)?;
+    ///     tx.get_or_create_bucket("rows")?.put(i.to_be_bytes(), "data")?;
+    ///     tx.commit()?;
+    /// }
+    /// db.sync()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sync(&self) -> Result<()> {
+        // A read-only handle never writes, so there's nothing of its own to sync
+        if self.inner.flags.read_only {
+            return Ok(());
+        }
+        let file = self.inner.lock_writer()?;
+        // The log goes to disk before the commits it describes, just like when a commit is synced
+        if let Some(wal) = &self.inner.wal {
+            wal.lock()?.sync()?;
+        }
+        file.sync_all()?;
+        self.inner.unsynced_commits.store(0, Ordering::Release);
+        Ok(())
+    }
 
-        macro_rules! check_meta {
-            ($func:ident) => {{
-                let meta1 = Page::from_buf(&data, 0, self.pagesize).$func();
-                // Double check that we have the right pagesize before we read the second page.
-                if meta1.valid() && meta1.pagesize != self.pagesize {
-                    assert_eq!(
-                        meta1.pagesize, self.pagesize,
-                        "Invalid pagesize from meta1 {}. Expected {}.",
-                        meta1.pagesize, self.pagesize
-                    );
-                }
-                let meta2 = Page::from_buf(&data, 1, self.pagesize).$func();
-                match (meta1.valid(), meta2.valid()) {
-                    (true, true) => {
-                        assert_eq!(
-                            meta1.pagesize, self.pagesize,
-                            "Invalid pagesize from meta1 {}. Expected {}.",
-                            meta1.pagesize, self.pagesize
-                        );
-                        assert_eq!(
-                            meta2.pagesize, self.pagesize,
-                            "Invalid pagesize from meta2 {}. Expected {}.",
-                            meta2.pagesize, self.pagesize
-                        );
-                        if meta1.tx_id > meta2.tx_id {
-                            Some(meta1)
-                        } else {
-                            Some(meta2)
-                        }
-                    }
-                    (true, false) => {
-                        assert_eq!(
-                            meta1.pagesize, self.pagesize,
-                            "Invalid pagesize from meta1 {}. Expected {}.",
-                            meta1.pagesize, self.pagesize
-                        );
-                        Some(meta1)
-                    }
-                    (false, true) => {
-                        assert_eq!(
-                            meta2.pagesize, self.pagesize,
-                            "Invalid pagesize from meta2 {}. Expected {}.",
-                            meta2.pagesize, self.pagesize
-                        );
-                        Some(meta2)
-                    }
-                    (false, false) => None,
+    /// Registers a function to be called after every successful commit.
+    ///
+    /// The hook is called once the data has been written (and synced, unless the [`SyncMode`] skips it)
+    /// and the transaction's lock has been released,
+    /// so it is safe to start a new transaction from inside it.
+    /// Hooks are called in the order they were registered, and kept until the database is closed.
+    ///
+    /// A hook that holds on to a clone of the [`DB`] keeps the database from ever being closed,
+    /// along with its file lock, so capture a [`WeakDB`] from [`downgrade`](#method.downgrade) instead.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let weak = db.downgrade();
+    /// db.on_commit(Arc::new(move |event| {
+    ///     println!("tx {} wrote {} bytes", event.tx_id, event.bytes_written);
+    ///     if let Some(db) = weak.upgrade() {
+    ///         let tx = db.tx(false).unwrap();
+    ///         // ...
+    ///     }
+    /// }))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_commit(&self, hook: Arc<dyn Fn(CommitEvent) + Send + Sync>) -> Result<()> {
+        self.inner.commit_hooks.lock()?.push(hook);
+        Ok(())
+    }
+
+    /// Returns a [`WeakDB`] that can get this database back without keeping it open.
+    pub fn downgrade(&self) -> WeakDB {
+        WeakDB {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Returns the total number of key / value pairs in every bucket in the database.
+    ///
+    /// This reads the pages directly instead of iterating over each bucket,
+    /// so it is much cheaper than counting with [`Bucket::kv_pairs`](struct.Bucket.html#method.kv_pairs).
+    /// Nested buckets are not counted.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the pages contain a cycle or point past the end of the file.
+    pub fn count_all_kv(&self) -> Result<u64> {
+        self.tx(false)?.count_all_kv()
+    }
+
+    /// Suggests a pagesize that fits the data already in the database.
+    ///
+    /// This reads the size of every key / value pair from the page headers, like [`count_all_kv`](#method.count_all_kv),
+    /// and returns the smallest multiple of the OS's pagesize that holds about 32 pairs of the average size,
+    /// up to 64KB. An empty database gets the OS's pagesize. This is only advice, meant for picking the
+    /// [`OpenOptions::pagesize`](struct.OpenOptions.html#method.pagesize) of a new file to copy the data into.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the pages contain a cycle or point past the end of the file.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, OpenOptions};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let pagesize = db.suggest_pagesize()?;
+    /// let new_db = OpenOptions::new().pagesize(pagesize).open("my-new.db")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn suggest_pagesize(&self) -> Result<u64> {
+        let (mut count, mut total_size) = (0_u64, 0_u64);
+        self.tx(false)?.for_each_committed_kv(|leaf| {
+            count += 1;
+            total_size += leaf.size();
+        })?;
+        let os_pagesize = get_page_size() as u64;
+        if count == 0 {
+            return Ok(os_pagesize);
+        }
+        let wanted = size_of::<Page>() as u64 + (total_size / count) * SUGGESTED_ENTRIES_PER_PAGE;
+        let pagesize = (wanted + os_pagesize - 1) / os_pagesize * os_pagesize;
+        Ok(pagesize.min(MAX_SUGGESTED_PAGESIZE.max(os_pagesize)))
+    }
+
+    /// Checks whether two databases hold exactly the same data.
+    ///
+    /// Every bucket, key / value pair and bucket's [`next_int`](struct.Bucket.html#method.next_int) is compared,
+    /// stopping at the first difference. How the data is laid out in pages doesn't matter,
+    /// so this is handy for making sure a backup or migration preserved everything.
+    /// Both databases are read in their own read-only transactions.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let backup = DB::open("my-backup.db")?;
+    /// assert!(db.logically_eq(&backup)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn logically_eq(&self, other: &DB) -> Result<bool> {
+        self.tx(false)?.logically_eq(&other.tx(false)?)
+    }
+
+    /// Finds every key / value pair that differs between two snapshots of the database.
+    ///
+    /// A snapshot is a read-only transaction, which keeps seeing the database as it was when it was started,
+    /// even while newer transactions are committed. Each item is the path to a changed pair, made of the names of
+    /// the buckets it is nested in followed by its key, along with how it changed between `old` and `new`.
+    /// Items are in the order they are found in the buckets, so sorted by path.
+    ///
+    /// Nested buckets that weren't modified between the snapshots still share the same pages,
+    /// so they are skipped without reading them, which makes this much faster than comparing every pair.
+    /// Buckets don't have changes of their own, so an empty bucket that was added or removed doesn't show up.
+    ///
+    /// Committing a transaction that needs to grow the file waits for every read-only transaction to finish,
+    /// so be careful holding on to an old snapshot on the same thread as a writable transaction.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if either snapshot is from a different database,
+    /// since pages are only shared between snapshots of the same one.
+    /// Will also return an error if walking either snapshot fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{ChangeKind, DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let old = db.tx(false)?;
+    /// {
+    ///     let tx = db.tx(true)?;
+    ///     tx.get_or_create_bucket("users")?.put("alice", "data")?;
+    ///     tx.commit()?;
+    /// }
+    /// let new = db.tx(false)?;
+    /// for (path, kind) in db.diff_snapshots(&old, &new)? {
+    ///     if kind == ChangeKind::Added {
+    ///         println!("added {:?}", path);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff_snapshots(
+        &self,
+        old: &Tx,
+        new: &Tx,
+    ) -> Result<impl Iterator<Item = (Vec<Vec<u8>>, ChangeKind)>> {
+        let ours = |tx: &Tx| Arc::ptr_eq(&tx.inner.borrow().db.inner, &self.inner);
+        if !ours(old) || !ours(new) {
+            return Err(Error::InvalidDB(String::from(
+                "Snapshot is from a different database",
+            )));
+        }
+        Ok(old.diff(new)?.into_iter())
+    }
+
+    /// Returns the application's schema version stored in the database.
+    ///
+    /// This is a number for your own use, separate from the version of jammdb's file format,
+    /// meant for keeping track of which migrations have been applied. It starts at zero.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// if db.user_version()? < 2 {
+    ///     let tx = db.tx(true)?;
+    ///     tx.get_or_create_bucket("users")?.ensure_path(&["by-email"])?;
+    ///     tx.commit()?;
+    ///     db.set_user_version(2)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn user_version(&self) -> Result<u32> {
+        Ok(self.inner.meta()?.user_version)
+    }
+
+    /// Sets the application's schema version stored in the database.
+    ///
+    /// This commits a transaction that only changes the version, so it waits for any open writable transaction.
+    /// Setting a version other than zero moves the file to a newer format, which releases from before
+    /// the user version existed refuse to open.
+    /// See [`user_version`](#method.user_version) for more details.
+    pub fn set_user_version(&self, user_version: u32) -> Result<()> {
+        let tx = self.tx(true)?;
+        tx.inner.borrow_mut().meta.user_version = user_version;
+        tx.commit()
+    }
+
+    /// Returns the metadata stored with [`set_metadata`](#method.set_metadata), or an empty `Vec` if there isn't any.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// if db.metadata()? != b"my-app v1" {
+    ///     println!("this file wasn't made by my-app");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn metadata(&self) -> Result<Vec<u8>> {
+        Ok(self.tx(false)?.metadata())
+    }
+
+    /// Stores a small blob of bytes for the whole file, like a format identifier or the name of the app that made it.
+    ///
+    /// The blob is kept on its own page that the meta page points to, not in any bucket,
+    /// so tools can read it without knowing anything about how the data is laid out.
+    /// It can be at most a page, minus the page header, and setting it to an empty slice removes it.
+    /// This commits a transaction that only changes the metadata, so it waits for any open writable transaction.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`MetadataTooLarge`](enum.Error.html#variant.MetadataTooLarge) error if `bytes` doesn't fit in a page.
+    pub fn set_metadata(&self, bytes: &[u8]) -> Result<()> {
+        let tx = self.tx(true)?;
+        tx.set_metadata(bytes)?;
+        tx.commit()
+    }
+
+    /// Returns the transaction IDs of every open read-only transaction, oldest first.
+    ///
+    /// Pages freed after the oldest of these can't be reused until it is dropped,
+    /// so a read-only transaction that is never dropped will keep the file growing.
+    /// Use this to find one, and [`force_release_read_tx`](#method.force_release_read_tx) if you know it is gone for good.
+    pub fn open_read_tx_ids(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .inner
+            .open_ro_txs
+            .lock()?
+            .iter()
+            .map(|(tx_id, _)| *tx_id)
+            .collect())
+    }
+
+    /// Forgets one read-only transaction with the given ID, so the pages it was keeping can be reused.
+    ///
+    /// This is only for recovering from a read-only transaction that was leaked without being dropped,
+    /// like with [`std::mem::forget`]. Returns `false` if there was no open transaction with that ID.
+    /// If several are open with the same ID, the one that was started first is forgotten,
+    /// and dropping it later does nothing, so the others stay open.
+    /// A leaked transaction also keeps a read lock on the memory map, so the file still can't grow
+    /// while it is around, and this does nothing about that.
+    ///
+    /// # Safety
+    ///
+    /// Every transaction with this ID must really be gone. If one of them is still being used,
+    /// the pages it is reading can be overwritten by later commits while it reads them.
+    pub unsafe fn force_release_read_tx(&self, tx_id: u64) -> Result<bool> {
+        let mut open_ro_txs = self.inner.open_ro_txs.lock()?;
+        // The transaction that was released does nothing when it is dropped
+        match open_ro_txs.iter().position(|(id, _)| *id == tx_id) {
+            Some(index) => {
+                open_ro_txs.remove(index);
+                if open_ro_txs.is_empty() {
+                    self.inner.readers_drained.notify_all();
                 }
-            }};
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Blocks until there are no open read-only transactions, or until the timeout elapses.
+    ///
+    /// Returns `true` once every read-only transaction has been dropped, or `false` if some were
+    /// still open after `timeout`. Use this before maintenance that wants no readers holding on to old pages.
+    /// New read-only transactions can still be started at any time, including right after this returns,
+    /// so stop handing out new ones first if you need them to stay drained.
+    /// Calling this while the same thread holds a read-only transaction will always time out.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use jammdb::{DB, Error};
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// if db.wait_for_readers(Duration::from_secs(5))? {
+    ///     // no readers are open
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_for_readers(&self, timeout: Duration) -> Result<bool> {
+        let open_ro_txs = self.inner.open_ro_txs.lock()?;
+        let (open_ro_txs, _) =
+            self.inner
+                .readers_drained
+                .wait_timeout_while(open_ro_txs, timeout, |txs| !txs.is_empty())?;
+        Ok(open_ro_txs.is_empty())
+    }
+
+    /// Applies every transaction recorded in a log written with [`OpenOptions::wal`](struct.OpenOptions.html#method.wal) to `dest`.
+    ///
+    /// Each logged transaction is applied in its own transaction, in the order they were committed,
+    /// and the number applied is returned. The last record is skipped if it was only partly written,
+    /// so it is safe to replay a log that is still being appended to.
+    /// Replaying a log more than once applies the same changes again,
+    /// so keep track of how far you've gotten if you ship the log in pieces.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if a record in the log is corrupt,
+    /// or any error from committing the changes to `dest`. Every transaction before the one that failed has already been committed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let standby = DB::open("standby.db")?;
+    /// let applied = DB::replay_wal_into("shipped.wal", &standby)?;
+    /// println!("applied {} transactions", applied);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replay_wal_into<P: AsRef<Path>>(wal: P, dest: &DB) -> Result<usize> {
+        wal::replay(wal.as_ref(), dest)
+    }
+
+    /// Writes a backup of every page that changed after the transaction `since_tx_id` to `w`,
+    /// returning the ID of the last transaction it includes.
+    ///
+    /// Pass `0` to back up every page, which makes a base backup. After that, pass the ID returned
+    /// by the previous backup to get only what changed since, and apply each one in order with
+    /// [`restore_incremental`](#method.restore_incremental). Each backup holds a read-only transaction
+    /// only while it is being written, so frequent small backups never pin pages for long.
+    ///
+    /// Only the pages written by the last [`keep_freed_for`](struct.OpenOptions.html#method.keep_freed_for)
+    /// commits are remembered, and only since the database was opened, so back up at least that often.
+    /// Rolling back or compacting the database also forgets them, so take a new base backup afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`HistoryUnavailable`](enum.Error.html#variant.HistoryUnavailable) error if the changes made
+    /// since `since_tx_id` aren't all remembered, or an [`Io`](enum.Error.html#variant.Io) error if writing fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, OpenOptions};
+    /// # use jammdb::Error;
+    /// use std::fs::File;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = OpenOptions::new().keep_freed_for(100).open("my.db")?;
+    /// let mut tx_id = db.backup_incremental(0, &mut File::create("base.backup")?)?;
+    /// // ... a few commits later
+    /// tx_id = db.backup_incremental(tx_id, &mut File::create("delta-1.backup")?)?;
+    ///
+    /// // somewhere else
+    /// DB::restore_incremental("restored.db", &mut File::open("base.backup")?)?;
+    /// DB::restore_incremental("restored.db", &mut File::open("delta-1.backup")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backup_incremental<W: Write>(&self, since_tx_id: u64, w: &mut W) -> Result<u64> {
+        backup::write_incremental(self, since_tx_id, w)
+    }
+
+    /// Applies a backup written by [`backup_incremental`](#method.backup_incremental) to the database file at `path`,
+    /// returning the ID of the transaction the file is now at.
+    ///
+    /// A base backup replaces whatever is in the file, and each later one must be applied to a file
+    /// that is at the transaction it was taken since. The database must not be open while this runs.
+    /// The new meta page is only written once the whole backup has been read and checked,
+    /// but other pages are written as they are read, so if this fails partway the file should be restored again
+    /// from the base backup.
+    /// Nothing restored is written to a [write-ahead log](struct.OpenOptions.html#method.wal), so start a new log
+    /// for the restored file instead of appending to one a replica has already replayed.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the backup is corrupt
+    /// or doesn't follow on from the file's current transaction.
+    pub fn restore_incremental<P: AsRef<Path>, R: Read>(path: P, r: &mut R) -> Result<u64> {
+        backup::apply_incremental(path.as_ref(), r)
+    }
+
+    /// Locks the memory mapped file into RAM, so reading from it never has to wait on the disk.
+    ///
+    /// This uses `mlock` to keep the whole file resident once it has been read in,
+    /// and the lock is applied again whenever the file grows and is remapped, until [`unlock_memory`](#method.unlock_memory) is called.
+    /// Unlike [`OpenOptions::mmap_populate`](struct.OpenOptions.html#method.mmap_populate), which only reads the file in when it is mapped,
+    /// this stops the OS from evicting pages later under memory pressure.
+    /// On platforms without `mlock` this does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error if the file can't be locked,
+    /// which usually means it is larger than the process is allowed to lock (see `RLIMIT_MEMLOCK`).
+    /// While locking is enabled, a commit that grows the file will fail with that error too.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// db.lock_in_memory()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn lock_in_memory(&self) -> Result<()> {
+        let data = self.inner.data.lock()?;
+        lock_mmap(&data)?;
+        self.inner.memory_locked.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops keeping the memory mapped file locked into RAM.
+    ///
+    /// See [`lock_in_memory`](#method.lock_in_memory) for more details.
+    /// Older mappings that are still being read by open transactions stay locked until those transactions finish.
+    pub fn unlock_memory(&self) -> Result<()> {
+        let data = self.inner.data.lock()?;
+        self.inner.memory_locked.store(false, Ordering::Release);
+        unlock_mmap(&data)
+    }
+
+    /// Returns the root page of the previously committed transaction, if it is still available.
+    ///
+    /// The database keeps two meta pages and alternates between them on every commit,
+    /// so the one that isn't currently in use describes the state of the database before the last commit.
+    /// Returns `None` if there is no valid older commit, like in a brand new database.
+    pub fn previous_root(&self) -> Result<Option<u64>> {
+        Ok(self.inner.previous_meta()?.map(|meta| meta.root.root_page))
+    }
+
+    /// Undoes the most recent commit by making the previous commit current again.
+    ///
+    /// This writes a new meta page pointing at the previous commit's data,
+    /// so it is itself a commit with a new transaction ID. Only one step of history is kept.
+    /// The database has to be opened with [`keep_freed_for`](struct.OpenOptions.html#method.keep_freed_for) set to at least `1`,
+    /// so the pages freed by a commit aren't reused until the next commit, and stay intact even if
+    /// a writable transaction is dropped or fails partway through its commit in the meantime.
+    /// That is only known for commits made since the database was opened, so the last commit has to have been made
+    /// with this [`DB`](struct.DB.html), and after rolling back, another commit is needed before you can roll back again.
+    /// This waits for all open transactions to finish, so do not call it while holding a transaction on the same thread.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if there is no previous commit,
+    /// if the last commit wasn't made since the database was opened with `keep_freed_for` set,
+    /// or if any of the previous commit's pages have been reused since.
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error with the kind [`Unsupported`](std::io::ErrorKind::Unsupported)
+    /// if the database has a [write-ahead log](struct.OpenOptions.html#method.wal), since undoing a commit can't be logged.
+    pub fn rollback_to_previous(&self) -> Result<()> {
+        let mut file = self.inner.lock_writer()?;
+        self.inner.check_no_wal()?;
+        let _lock = self.inner.mmap_lock.write()?;
+        let current = self.inner.meta()?;
+        let mut meta = match self.inner.previous_meta()? {
+            Some(meta) => meta,
+            None => {
+                return Err(Error::InvalidDB(String::from(
+                    "No previous commit to roll back to",
+                )))
+            }
+        };
+        // The pages the last commit freed could have been reused by a transaction that never committed,
+        // and check_meta can't tell, so only trust them if the last commit was made while we've had the file open.
+        if !self.inner.previous_intact.load(Ordering::Acquire) {
+            return Err(Error::InvalidDB(String::from(
+                "The previous commit's pages may have been reused",
+            )));
+        }
+        // Make sure every page the previous commit needs is still intact before we point at it
+        let pages = self.inner.pages()?;
+        check_meta(&meta, &pages)?;
+
+        meta.tx_id = current.tx_id + 1;
+        write_meta(&mut file, &meta, meta.meta_page as u64)?;
+        file.flush()?;
+        file.sync_all()?;
+
+        let mut freelist = Freelist::with_allocator(self.inner.flags.allocator.clone());
+        freelist.init(pages.page(meta.freelist_page).freelist());
+        *self.inner.freelist.lock()? = freelist;
+        self.inner.page_history.lock()?.reset(meta.tx_id);
+        // The commit we just undid used pages that are free again now
+        self.inner.previous_intact.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the sorted IDs of every page in the database's freelist.
+    ///
+    /// This includes pages that were freed by a recent transaction but can't be reused yet
+    /// because a read-only transaction may still be using them.
+    /// Multiply a page ID by the [`pagesize`](#method.pagesize) to get its offset in the file.
+    /// A [`read_only`](struct.OpenOptions.html#method.read_only) database reads them from the latest commit's freelist page.
+    pub fn free_pages(&self) -> Result<Vec<u64>> {
+        // Nothing is ever allocated without a writer, so a read-only handle doesn't keep a freelist of its own
+        if self.inner.flags.read_only {
+            let meta = self.inner.meta()?;
+            let pages = self.inner.pages()?;
+            let mut freelist = Freelist::with_allocator(self.inner.flags.allocator.clone());
+            freelist.init(pages.page(meta.freelist_page).freelist());
+            return Ok(freelist.pages());
+        }
+        Ok(self.inner.freelist.lock()?.pages())
+    }
+
+    /// Returns the sorted IDs of pages that are neither used by any bucket nor in the freelist.
+    ///
+    /// These pages have leaked, usually because of a bug or a crash at the wrong moment, and the space they take up
+    /// can't be used again until they are given back with [`reclaim_leaked`](#method.reclaim_leaked).
+    /// A healthy database has none.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the pages that are in use
+    /// are corrupt, like pages that are used twice or that point outside of the file.
+    pub fn find_leaked_pages(&self) -> Result<Vec<u64>> {
+        self.tx(false)?.leaked_pages()
+    }
+
+    /// Adds every page found by [`find_leaked_pages`](#method.find_leaked_pages) back to the freelist,
+    /// returning how many there were.
+    ///
+    /// This commits a transaction, so it waits for any open writable transaction.
+    /// Like any other freed pages, they can't be reused until the read-only transactions open at the time are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the pages that are in use are corrupt,
+    /// in which case nothing is reclaimed.
+    pub fn reclaim_leaked(&self) -> Result<usize> {
+        let tx = self.tx(true)?;
+        let leaked = tx.leaked_pages()?;
+        if leaked.is_empty() {
+            return Ok(0);
+        }
+        {
+            let inner = tx.inner.borrow();
+            let mut freelist = inner.freelist.borrow_mut();
+            for page_id in leaked.iter() {
+                freelist.free(*page_id, 1);
+            }
+        }
+        tx.commit()?;
+        Ok(leaked.len())
+    }
+
+    /// Returns the fraction of the database's pages that are free, from `0.0` to `1.0`.
+    ///
+    /// This is the number of pages in the freelist over the total number of pages in the database,
+    /// so a high number means a lot of the file is wasted space, and compacting the data into a new file would shrink it.
+    /// Like [`free_pages`](#method.free_pages), it counts pages that were freed recently but are still
+    /// being kept for open read-only transactions, so it can be temporarily high while long-running readers are open.
+    pub fn fragmentation(&self) -> Result<f32> {
+        let free_pages = self.free_pages()?.len();
+        let num_pages = self.inner.meta()?.num_pages;
+        Ok(free_pages as f32 / num_pages as f32)
+    }
+
+    /// Shrinks the database file by copying all of its data into a new, tightly packed file that replaces it.
+    ///
+    /// The copy is written to a temporary file next to the database, named after it with a `.compact` extension,
+    /// and synced to disk before it is renamed over the original. The rename is atomic as long as both files
+    /// are on the same filesystem, so after a crash the path holds either the old file or the complete new one.
+    /// The write lock is held the whole time, so nothing can be committed while the copy is made,
+    /// and switching to the new file waits for every read-only transaction to finish, just like growing the file does.
+    /// Don't call this while holding a transaction on the same thread, or it will deadlock.
+    ///
+    /// Every bucket keeps its [`next_int`](struct.Bucket.html#method.next_int) and [`counter`](struct.Bucket.html#method.counter),
+    /// and the database keeps its [`user_version`](#method.user_version).
+    /// Transaction IDs keep counting up from where they were, with the compaction using one of them.
+    /// Use [`fragmentation`](#method.fragmentation) to decide whether compacting is worth it.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error if the database was opened with
+    /// [`OpenOptions::open_file`](struct.OpenOptions.html#method.open_file), since it doesn't have a path to replace,
+    /// or if the new file couldn't be written or renamed. If anything fails before the rename,
+    /// the temporary file is removed and the database is left as it was.
+    /// Will also return an [`Io`](enum.Error.html#variant.Io) error if the database has a
+    /// [write-ahead log](struct.OpenOptions.html#method.wal), since replacing the file can't be logged.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// if db.fragmentation()? > 0.5 {
+    ///     db.compact_in_place()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact_in_place(&self) -> Result<()> {
+        let path = match &self.inner.path {
+            Some(path) => path,
+            None => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "database was not opened from a path",
+                )))
+            }
+        };
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".compact");
+        let tmp = path.with_file_name(tmp_name);
+
+        let mut file = self.inner.lock_writer()?;
+        self.inner.check_no_wal()?;
+        // The temporary file is only ever ours, so anything there is left over from a compaction that didn't finish
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        if let Err(e) = self.compact_into(open_file(&tmp, true, false)?) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        // Windows can't replace a file that is still open or mapped, so switch over to the new file
+        // before renaming it. Our handle follows it to its new name.
+        let _lock = self.inner.mmap_lock.write()?;
+        let switched = open_file(&tmp, false, self.inner.flags.direct_writes)
+            .and_then(|new_file| self.inner.replace_file(&mut file, new_file));
+        if let Err(e) = switched {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&tmp, path) {
+            // Go back to the original file, which still has all of the same data
+            let old_file = open_file(path, false, self.inner.flags.direct_writes)?;
+            self.inner.replace_file(&mut file, old_file)?;
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        sync_dir(path)
+    }
+
+    /// Copies all of the database's data into a new, tightly packed database file at `dest`,
+    /// returning how much smaller it is.
+    ///
+    /// Every bucket is rewritten from a single read-only transaction, so the copy is consistent
+    /// and other transactions can keep going while it is made, although nothing they commit ends up in the copy.
+    /// The new file is only as big as its data, with next to no free pages, unlike the original, which never shrinks.
+    /// Like [`compact_in_place`](#method.compact_in_place), every bucket keeps its [`next_int`](struct.Bucket.html#method.next_int)
+    /// and [`counter`](struct.Bucket.html#method.counter), and the copy keeps the [`user_version`](#method.user_version)
+    /// and [`metadata`](#method.metadata). The copy has the same pagesize, so open it with the same options.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error with the kind
+    /// [`AlreadyExists`](std::io::ErrorKind::AlreadyExists) if there is already a file at `dest`,
+    /// or any other [`Io`](enum.Error.html#variant.Io) error if the new file couldn't be written.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let stats = db.compact("my-compacted.db")?;
+    /// println!("saved {} bytes", stats.reclaimed());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact<P: AsRef<Path>>(&self, dest: P) -> Result<CompactStats> {
+        let dest = dest.as_ref();
+        // Never clobber an existing file, which could even be this database,
+        // so only remove the file again if we were the ones to create it
+        let file = open_file(dest, true, false)?;
+        let result = self.compact_into(file);
+        if result.is_err() {
+            let _ = fs::remove_file(dest);
+        }
+        result
+    }
+
+    // Copies everything into a new, empty database file, and makes its transaction ID the one after ours.
+    // compact_in_place holds the write lock around this, so the copy is still current when it replaces the file.
+    fn compact_into(&self, file: File) -> Result<CompactStats> {
+        let file_handle = file.try_clone()?;
+        let dest = OpenOptions::new()
+            .pagesize(self.inner.pagesize)
+            .value_checksums(self.inner.flags.value_checksums)
+            .open_file(file)?;
+        let (tx_id, old_size) = {
+            let tx = self.tx(false)?;
+            let dest_tx = dest.tx(true)?;
+            tx.copy_into(&dest_tx)?;
+            dest_tx.commit()?;
+            let tx = tx.inner.borrow();
+            (tx.meta.tx_id, tx.pages.data.len() as u64)
+        };
+        let new_size = {
+            let mut file = dest.inner.file.lock()?;
+            let mut meta = dest.inner.meta()?;
+            meta.tx_id = tx_id + 1;
+            write_meta(&mut file, &meta, meta.meta_page as u64)?;
+            file.flush()?;
+            file.sync_all()?;
+            meta.num_pages * meta.pagesize
+        };
+        drop(dest);
+        // The file grows in big steps, so cut off the unused space at the end once it's no longer mapped
+        file_handle.set_len(new_size)?;
+        file_handle.sync_all()?;
+        Ok(CompactStats { old_size, new_size })
+    }
+
+    /// Locks the given bucket name for this process, until the returned guard is dropped.
+    ///
+    /// This is purely advisory and has nothing to do with transactions: it doesn't stop anyone from reading
+    /// or writing the bucket, and the bucket doesn't even need to exist. It only waits for any other guard
+    /// for the same name to be dropped, so code that takes the lock around an operation on a bucket never runs
+    /// at the same time as other code that does the same, whether it reads or writes.
+    /// Only names locked on this database (or a clone of it) are shared, not other opens of the same file.
+    ///
+    /// Like a [`Mutex`], taking the lock again on the same thread while holding its guard will deadlock.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, Error};
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// {
+    ///     let _guard = db.bucket_lock(b"accounts")?;
+    ///     let tx = db.tx(false)?;
+    ///     let total: usize = tx.get_bucket("accounts")?.kv_pairs().count();
+    ///     // ... nobody else holding the lock can change "accounts" until we're done
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bucket_lock(&self, name: &[u8]) -> Result<BucketGuard<'_>> {
+        let mut locked = self.inner.bucket_locks.lock()?;
+        while locked.contains(name) {
+            locked = self.inner.bucket_unlocked.wait(locked)?;
+        }
+        locked.insert(name.to_vec());
+        Ok(BucketGuard {
+            db: self,
+            name: name.to_vec(),
+        })
+    }
+
+    #[doc(hidden)]
+    pub fn check(&self) -> Result<()> {
+        self.tx(false)?.check()
+    }
+}
+/// Information about a committed transaction, passed to hooks registered with [`DB::on_commit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitEvent {
+    /// The ID of the transaction that was committed
+    pub tx_id: u64,
+    /// The number of pages written to the file, including the meta page
+    pub pages_written: u64,
+    /// The number of bytes written to the file, including the meta page
+    pub bytes_written: u64,
+}
+
+pub(crate) type CommitHook = Arc<dyn Fn(CommitEvent) + Send + Sync>;
+
+/// How often committed transactions are synced to disk, set with [`OpenOptions::sync_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Sync every commit before it returns
+    Always,
+    /// Never sync commits, only when [`DB::sync`] is called
+    Never,
+    /// Sync every `n`th commit, along with the ones before it. `0` and `1` are the same as `Always`
+    EveryNCommits(u32),
+}
+
+/// How much space was saved by [`DB::compact`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactStats {
+    /// The size of the original database file, in bytes
+    pub old_size: u64,
+    /// The size of the compacted database file, in bytes
+    pub new_size: u64,
+}
+
+impl CompactStats {
+    /// Returns how many bytes smaller the compacted file is, or zero if it isn't any smaller.
+    pub fn reclaimed(&self) -> u64 {
+        self.old_size.saturating_sub(self.new_size)
+    }
+}
+
+/// Holds the lock on a bucket name taken with [`DB::bucket_lock`], releasing it when dropped.
+pub struct BucketGuard<'a> {
+    db: &'a DB,
+    name: Vec<u8>,
+}
+
+impl<'a> BucketGuard<'a> {
+    /// Returns the name of the locked bucket.
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+}
+
+impl<'a> Drop for BucketGuard<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut locked) = self.db.inner.bucket_locks.lock() {
+            locked.remove(&self.name);
+        }
+        self.db.inner.bucket_unlocked.notify_all();
+    }
+}
+
+pub(crate) struct DBInner {
+    pub(crate) data: Mutex<Arc<Mmap>>,
+    // bumped every time the file is remapped
+    pub(crate) generation: Arc<AtomicU64>,
+    pub(crate) mmap_lock: RwLock<()>,
+    pub(crate) freelist: Mutex<Freelist>,
+    pub(crate) file: Mutex<File>,
+    // the transaction ID of every open read-only transaction, sorted and paired with a number unique to that
+    // transaction, so a transaction released with DB::force_release_read_tx doesn't remove another one when it's dropped
+    pub(crate) open_ro_txs: Mutex<Vec<(u64, u64)>>,
+    pub(crate) next_reader: AtomicU64,
+    // notified whenever open_ro_txs becomes empty
+    pub(crate) readers_drained: Condvar,
+    pub(crate) commit_hooks: Mutex<Vec<CommitHook>>,
+    // names locked with DB::bucket_lock, and notified whenever one is unlocked
+    pub(crate) bucket_locks: Mutex<BTreeSet<Vec<u8>>>,
+    pub(crate) bucket_unlocked: Condvar,
+    pub(crate) flags: DBFlags,
+    // removes the file from the open paths once the database is closed
+    pub(crate) open_path: Option<OpenPath>,
+    // where the file is, unless it was opened with OpenOptions::open_file
+    pub(crate) path: Option<PathBuf>,
+    // keep every new mapping locked into RAM
+    pub(crate) memory_locked: AtomicBool,
+    pub(crate) wal: Option<Mutex<Wal>>,
+    // the pages written by recent commits, for incremental backups
+    pub(crate) page_history: Mutex<PageHistory>,
+    // closures waiting to be run together by DB::batch
+    pub(crate) batch: BatchQueue,
+    // commits since the file was last synced, when the sync mode lets some go unsynced
+    pub(crate) unsynced_commits: AtomicU64,
+    // set by a commit in this process when keep_freed_for holds its freed pages until the next commit,
+    // so nothing has overwritten the pages the previous meta page points at
+    pub(crate) previous_intact: AtomicBool,
+
+    pub(crate) pagesize: u64,
+}
+
+pub(crate) struct OpenPath(PathBuf);
+
+impl OpenPath {
+    fn register(path: &Path) -> Result<OpenPath> {
+        let path = path.canonicalize()?;
+        let mut open_paths = OPEN_PATHS.lock()?;
+        if open_paths.contains(&path) {
+            return Err(Error::AlreadyOpen);
+        }
+        open_paths.push(path.clone());
+        Ok(OpenPath(path))
+    }
+}
+
+impl Drop for OpenPath {
+    fn drop(&mut self) {
+        if let Ok(mut open_paths) = OPEN_PATHS.lock() {
+            open_paths.retain(|path| *path != self.0);
+        }
+    }
+}
+
+impl DBInner {
+    pub(crate) fn open(file: File, pagesize: u64, flags: DBFlags) -> Result<DBInner> {
+        if !flags.verify_only {
+            lock_file(&file, !flags.read_only, flags.lock_timeout)?;
+        }
+        let mmap = mmap(&file, &flags)?;
+        let mmap = Mutex::new(Arc::new(mmap));
+        let db = DBInner {
+            data: mmap,
+            generation: Arc::new(AtomicU64::new(0)),
+            mmap_lock: RwLock::new(()),
+            freelist: Mutex::new(Freelist::with_allocator(flags.allocator.clone())),
+
+            file: Mutex::new(file),
+            open_ro_txs: Mutex::new(Vec::new()),
+            next_reader: AtomicU64::new(0),
+            readers_drained: Condvar::new(),
+            commit_hooks: Mutex::new(Vec::new()),
+            bucket_locks: Mutex::new(BTreeSet::new()),
+            bucket_unlocked: Condvar::new(),
+            open_path: None,
+            path: None,
+            memory_locked: AtomicBool::new(false),
+            wal: None,
+            page_history: Mutex::new(PageHistory::default()),
+            batch: BatchQueue::default(),
+            unsynced_commits: AtomicU64::new(0),
+            previous_intact: AtomicBool::new(false),
+
+            pagesize,
+            flags,
+        };
+
+        {
+            let meta = db.meta()?;
+            // Make sure the file was written in a format we know how to read
+            if meta.magic != MAGIC_VALUE {
+                return Err(Error::InvalidDB(format!(
+                    "Invalid magic value {:#x}",
+                    meta.magic
+                )));
+            }
+            if meta.version > VERSION {
+                return Err(Error::InvalidDB(format!(
+                    "Unsupported database version {}, expected at most {}",
+                    meta.version, VERSION
+                )));
+            }
+            if meta.comparator != COMPARATOR_BYTEWISE {
+                return Err(Error::InvalidDB(format!(
+                    "Keys are sorted with comparator {}, but only the bytewise comparator {} is supported",
+                    meta.comparator, COMPARATOR_BYTEWISE
+                )));
+            }
+            db.page_history.lock()?.reset(meta.tx_id);
+            let data = db.data.lock()?;
+            let file_pages = data.len() as u64 / pagesize;
+            if meta.num_pages > file_pages {
+                return Err(Error::InvalidDB(format!(
+                    "Database uses {} pages, but the file only has {}",
+                    meta.num_pages, file_pages
+                )));
+            }
+            let free_pages = Page::from_buf(&data, meta.freelist_page, pagesize).freelist();
+
+            if !free_pages.is_empty() && !db.flags.read_only {
+                db.freelist.lock()?.init(free_pages);
+            }
+        }
+
+        Ok(db)
+    }
+
+    pub(crate) fn resize(&self, file: &File, new_size: u64) -> Result<Pages> {
+        if self.flags.read_only {
+            return Err(Error::ReadOnlyDB);
+        }
+        file.allocate(new_size)?;
+        let _lock = self.mmap_lock.write()?;
+        let mut data = self.data.lock()?;
+        let mmap = mmap(file, &self.flags)?;
+        *data = Arc::new(mmap);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if self.memory_locked.load(Ordering::Acquire) {
+            lock_mmap(&data)?;
+        }
+        Ok(Pages::new(
+            data.clone(),
+            self.pagesize,
+            self.generation.clone(),
+        ))
+    }
+
+    // Takes the lock that every write to the file holds, unless the database can't be written to at all.
+    pub(crate) fn lock_writer(&self) -> Result<MutexGuard<'_, File>> {
+        if self.flags.read_only {
+            return Err(Error::ReadOnlyDB);
+        }
+        Ok(self.file.lock()?)
+    }
+
+    // Operations that change the file without committing a transaction can't be logged,
+    // so they are refused while there is a log that a replica is following.
+    pub(crate) fn check_no_wal(&self) -> Result<()> {
+        if self.wal.is_some() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "database has a write-ahead log",
+            )));
+        }
+        Ok(())
+    }
+
+    // Switches over to a new file that holds the same data, like a compacted copy.
+    // The caller must hold the write lock, and no read-only transactions can be open.
+    pub(crate) fn replace_file(&self, file: &mut File, new_file: File) -> Result<()> {
+        lock_file(&new_file, true, self.flags.lock_timeout)?;
+        let mut data = self.data.lock()?;
+        let mmap = mmap(&new_file, &self.flags)?;
+        if self.memory_locked.load(Ordering::Acquire) {
+            lock_mmap(&mmap)?;
+        }
+        *file = new_file;
+        *data = Arc::new(mmap);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        drop(data);
+
+        let meta = self.meta()?;
+        let pages = self.pages()?;
+        let mut freelist = Freelist::with_allocator(self.flags.allocator.clone());
+        freelist.init(pages.page(meta.freelist_page).freelist());
+        *self.freelist.lock()? = freelist;
+        self.page_history.lock()?.reset(meta.tx_id);
+        self.previous_intact.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    // Returns the pages of the file's current mapping.
+    pub(crate) fn pages(&self) -> Result<Pages> {
+        let data = self.data.lock()?;
+        Ok(Pages::new(
+            data.clone(),
+            self.pagesize,
+            self.generation.clone(),
+        ))
+    }
+
+    // Returns the meta from the meta page that isn't current, if it's valid and older.
+    pub(crate) fn previous_meta(&self) -> Result<Option<Meta>> {
+        let current = self.meta()?;
+        let data = self.data.lock()?;
+        let page = Page::from_buf(&data, u64::from(current.meta_page == 0), self.pagesize);
+        if page.page_type != Page::TYPE_META {
+            return Ok(None);
+        }
+        let meta = page.meta();
+        if meta.valid() && meta.tx_id < current.tx_id {
+            Ok(Some(meta.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn meta(&self) -> Result<Meta> {
+        let data = self.data.lock()?;
+
+        macro_rules! check_meta {
+            ($func:ident) => {{
+                let meta1 = Page::from_buf(&data, 0, self.pagesize).$func();
+                // Double check that we have the right pagesize before we read the second page.
+                if meta1.valid() && meta1.pagesize != self.pagesize {
+                    return Err(Error::InvalidDB(format!(
+                        "Invalid pagesize from meta1 {}. Expected {}.",
+                        meta1.pagesize, self.pagesize
+                    )));
+                }
+                let meta2 = Page::from_buf(&data, 1, self.pagesize).$func();
+                if meta2.valid() && meta2.pagesize != self.pagesize {
+                    return Err(Error::InvalidDB(format!(
+                        "Invalid pagesize from meta2 {}. Expected {}.",
+                        meta2.pagesize, self.pagesize
+                    )));
+                }
+                match (meta1.valid(), meta2.valid()) {
+                    (true, true) => {
+                        if meta1.tx_id > meta2.tx_id {
+                            Some(meta1)
+                        } else {
+                            Some(meta2)
+                        }
+                    }
+                    (true, false) => Some(meta1),
+                    (false, true) => Some(meta2),
+                    (false, false) => None,
+                }
+            }};
+        }
+
+        if let Some(meta) = check_meta!(meta) {
+            Ok(meta.clone())
+        } else if let Some(old_meta) = check_meta!(old_meta) {
+            Ok(old_meta.into())
+        } else if Page::from_buf(&data, 0, self.pagesize).meta().magic == MAGIC_VALUE.swap_bytes() {
+            // The meta pages are cast straight from memory, so a file from a machine with a different
+            // byte order will never have a valid hash. Give a better error than "No valid meta pages".
+            Err(Error::InvalidDB(String::from(
+                "Database was written with a foreign endianness",
+            )))
+        } else {
+            Err(Error::InvalidDB(String::from("No valid meta pages")))
+        }
+    }
+}
+
+// Writes the meta to the given meta page, which must be 0 or 1.
+pub(crate) fn write_meta(file: &mut File, meta: &Meta, meta_page_id: u64) -> Result<()> {
+    let buf = meta_page_buf(meta, meta_page_id);
+    file.seek(SeekFrom::Start(meta.pagesize * meta_page_id))?;
+    file.write_all(buf.as_slice())?;
+    Ok(())
+}
+
+// Builds the contents of the given meta page, which must be 0 or 1, holding the meta.
+pub(crate) fn meta_page_buf(meta: &Meta, meta_page_id: u64) -> Vec<u8> {
+    let mut buf = vec![0; meta.pagesize as usize];
+
+    #[allow(clippy::cast_ptr_alignment)]
+    let page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
+    page.id = meta_page_id;
+    page.page_type = Page::TYPE_META;
+    let m = page.meta_mut();
+    m.meta_page = meta_page_id as u32;
+    m.magic = meta.magic;
+    m.version = meta.version;
+    m.user_version = meta.user_version;
+    m.pagesize = meta.pagesize;
+    m.root = meta.root;
+    m.num_pages = meta.num_pages;
+    m.freelist_page = meta.freelist_page;
+    m.tx_id = meta.tx_id;
+    m.user_meta_page = meta.user_meta_page;
+    m.comparator = meta.comparator;
+    m.hash = m.hash_self();
+    buf
+}
+
+// Reads the pagesize from the first meta page, or None if the file doesn't start with one.
+// Files from before the user version was added have the pagesize in the same place.
+fn stored_pagesize(path: &Path) -> Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    // The smallest pagesize, as u64s so the page and meta are aligned when they're read out of it
+    let mut buf = [0_u64; 128];
+    let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, 1024) };
+    // Reads the meta page at offset, returning the pagesize it claims and whether its hash checks out
+    let mut read_meta = |offset: u64| -> Result<Option<(u64, bool)>> {
+        file.seek(SeekFrom::Start(offset))?;
+        if file.read_exact(bytes).is_err() {
+            return Ok(None);
+        }
+        let page = Page::from_buf(bytes, 0, 1024);
+        if page.page_type != Page::TYPE_META {
+            return Ok(None);
+        }
+        let meta = page.meta();
+        if meta.magic != MAGIC_VALUE || meta.pagesize < 1024 {
+            return Ok(None);
+        }
+        Ok(Some((
+            meta.pagesize,
+            meta.valid() || page.old_meta().valid(),
+        )))
+    };
+    let first = read_meta(0)?;
+    if let Some((pagesize, true)) = first {
+        return Ok(Some(pagesize));
+    }
+    // The first meta page is torn or corrupt, so look for the second one, which starts one page in.
+    // Try the pagesize the first one claims, then the usual ones.
+    let claimed = first.map(|(pagesize, _)| pagesize);
+    let usual = (10..=16).map(|shift| 1_u64 << shift);
+    for pagesize in claimed.into_iter().chain(usual) {
+        if read_meta(pagesize)? == Some((pagesize, true)) {
+            return Ok(Some(pagesize));
+        }
+    }
+    match first {
+        Some(_) => Err(Error::InvalidDB(String::from(
+            "No valid meta page to read the pagesize from",
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn init_file(path: &Path, pagesize: u64, num_pages: usize, direct_write: bool) -> Result<File> {
+    let mut file = open_file(path, true, direct_write)?;
+    init_db(&mut file, pagesize, num_pages)?;
+    Ok(file)
+}
+
+// Writes the meta, freelist and root pages for an empty database to the start of the file.
+fn init_db(file: &mut File, pagesize: u64, num_pages: usize) -> Result<()> {
+    file.allocate(pagesize * (num_pages as u64))?;
+    let mut buf = vec![0; (pagesize * 4) as usize];
+    let mut get_page = |index: u64| {
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            &mut *(&mut buf[(index * pagesize) as usize] as *mut u8 as *mut Page)
+        }
+    };
+    for i in 0..2 {
+        let page = get_page(i);
+        page.id = i;
+        page.page_type = Page::TYPE_META;
+        let m = page.meta_mut();
+        m.meta_page = i as u32;
+        m.magic = MAGIC_VALUE;
+        m.version = BASE_VERSION;
+        m.pagesize = pagesize;
+        m.freelist_page = 2;
+        m.root = RootMeta {
+            root_page: 3,
+            next_int: 0,
+        };
+        m.num_pages = 4;
+        m.comparator = COMPARATOR_BYTEWISE;
+        m.hash = m.hash_self();
+    }
+
+    let p = get_page(2);
+    p.id = 2;
+    p.page_type = Page::TYPE_FREELIST;
+    p.count = 0;
+
+    let p = get_page(3);
+    p.id = 3;
+    p.page_type = Page::TYPE_LEAF;
+    p.count = 0;
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&buf[..])?;
+    file.flush()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Takes the exclusive (or shared) file lock, either waiting as long as it takes or
+// polling with an exponential backoff until the timeout runs out.
+fn lock_file(file: &File, exclusive: bool, timeout: Option<Duration>) -> Result<()> {
+    let timeout = match (timeout, exclusive) {
+        (Some(timeout), _) => timeout,
+        (None, true) => return Ok(file.lock_exclusive()?),
+        (None, false) => return Ok(FileExt::lock_shared(file)?),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut delay = Duration::from_millis(1);
+    loop {
+        let result = match exclusive {
+            true => file.try_lock_exclusive(),
+            false => FileExt::try_lock_shared(file),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if e.raw_os_error() == fs4::lock_contended_error().raw_os_error() => (),
+            Err(e) => return Err(e.into()),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "timed out waiting for the database file lock",
+            )));
+        }
+        thread::sleep(delay.min(deadline - now));
+        delay = (delay * 2).min(Duration::from_millis(100));
+    }
+}
+
+// Have different mmap functions for Unix and Windows
+#[cfg(unix)]
+fn mmap(file: &File, flags: &DBFlags) -> Result<Mmap> {
+    use memmap2::MmapOptions;
+
+    let mut options = MmapOptions::new();
+    if flags.mmap_populate {
+        options.populate();
+    }
+    let mmap = unsafe { options.map(file)? };
+    // On Unix we advice the OS that page access will be random.
+    // The advice is only an optimization, and some platforms and filesystems don't allow it,
+    // so the mapping is still used if it fails.
+    let _ = mmap.advise(memmap2::Advice::Random);
+    #[cfg(target_os = "linux")]
+    if flags.huge_pages {
+        // This fails if the kernel doesn't support transparent huge pages, which is fine
+        let _ = mmap.advise(memmap2::Advice::HugePage);
+    }
+    Ok(mmap)
+}
+
+// On Windows there is no advice to give.
+#[cfg(windows)]
+fn mmap(file: &File, _flags: &DBFlags) -> Result<Mmap> {
+    let mmap = unsafe { Mmap::map(file)? };
+    Ok(mmap)
+}
+
+#[cfg(unix)]
+fn lock_mmap(mmap: &Mmap) -> Result<()> {
+    Ok(mmap.lock()?)
+}
+
+#[cfg(unix)]
+fn unlock_mmap(mmap: &Mmap) -> Result<()> {
+    Ok(mmap.unlock()?)
+}
+
+// There's no mlock on Windows, so locking the file in memory does nothing.
+#[cfg(windows)]
+fn lock_mmap(_mmap: &Mmap) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+fn unlock_mmap(_mmap: &Mmap) -> Result<()> {
+    Ok(())
+}
+
+// Syncs just the given (offset, length) ranges of the file, waiting until they are on disk.
+#[cfg(target_os = "linux")]
+pub(crate) fn sync_ranges(file: &File, ranges: &[(u64, u64)]) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    for (offset, len) in ranges {
+        let result =
+            unsafe { libc::sync_file_range(file.as_raw_fd(), *offset as i64, *len as i64, flags) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+// Everywhere else, sync the whole file.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn sync_ranges(file: &File, _ranges: &[(u64, u64)]) -> Result<()> {
+    Ok(file.sync_all()?)
+}
+
+// Syncs the directory holding the given path, so a file renamed into it stays there after a crash.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    Ok(File::open(dir)?.sync_all()?)
+}
+
+// Windows has no way to sync a directory, renames are as durable as they'll get.
+#[cfg(windows)]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_DIRECT: i32 = libc::O_DIRECT;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_DIRECT: i32 = 0;
+
+// Have different mmap functions for Unix and Windows
+#[cfg(unix)]
+fn open_file<P: AsRef<Path>>(path: P, create: bool, direct_write: bool) -> Result<File> {
+    let mut open_options = FileOpenOptions::new();
+    open_options.write(true).read(true);
+    if create {
+        open_options.create_new(true);
+    }
+    if direct_write {
+        open_options.custom_flags(O_DIRECT);
+    }
+    Ok(open_options.open(path)?)
+}
+
+#[cfg(windows)]
+fn open_file<P: AsRef<Path>>(path: P, create: bool, direct_write: bool) -> Result<File> {
+    let mut open_options = FileOpenOptions::new();
+    open_options.write(true).read(true);
+    if create {
+        open_options.create_new(true);
+    }
+    Ok(open_options.open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::RandomFile;
+
+    #[test]
+    fn test_open_options() {
+        assert_ne!(get_page_size(), 5000);
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(5000)
+                .num_pages(100)
+                .open(&random_file)
+                .unwrap();
+            assert_eq!(db.pagesize(), 5000);
+        }
+        {
+            let metadata = random_file.path.metadata().unwrap();
+            assert!(metadata.is_file());
+            assert_eq!(metadata.len(), 500_000);
+        }
+        {
+            let db = OpenOptions::new()
+                .pagesize(5000)
+                .num_pages(100)
+                .open(&random_file)
+                .unwrap();
+            assert_eq!(db.pagesize(), 5000);
+        }
+    }
+
+    #[test]
+    fn test_open_file() -> Result<()> {
+        let random_file = RandomFile::new();
+        let open = || -> Result<File> {
+            Ok(FileOpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&random_file)?)
+        };
+        {
+            let db = OpenOptions::new().pagesize(1024).open_file(open()?)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        assert_eq!(random_file.path.metadata()?.len(), 1024 * 32);
+        // an existing database is opened, not overwritten
+        let db = OpenOptions::new().pagesize(1024).open_file(open()?)?;
+        db.check()?;
+        let tx = db.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.get_kv("key").unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_timeout() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let start = Instant::now();
+        match OpenOptions::new()
+            .lock_timeout(Some(Duration::from_millis(50)))
+            .allow_multiple_opens(true)
+            .open(&random_file)
+        {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("took the lock while it was held"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // release the lock while the second open is waiting for it
+        let opener = thread::spawn(move || {
+            OpenOptions::new()
+                .lock_timeout(Some(Duration::from_secs(10)))
+                .allow_multiple_opens(true)
+                .open(&random_file)
+                .map(|_| ())
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(db);
+        opener.join().unwrap()
+    }
+
+    #[test]
+    fn test_already_open() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        assert_eq!(DB::open(&random_file).err(), Some(Error::AlreadyOpen));
+        // a different spelling of the same path is still caught
+        let relative = random_file
+            .path
+            .parent()
+            .unwrap()
+            .join(".")
+            .join(random_file.path.file_name().unwrap());
+        assert_eq!(DB::open(&relative).err(), Some(Error::AlreadyOpen));
+        // the lock is still held by the first open
+        assert!(OpenOptions::new()
+            .lock_timeout(Some(Duration::from_millis(10)))
+            .allow_multiple_opens(true)
+            .open(&random_file)
+            .is_err());
+        drop(db);
+        let _db = DB::open(&random_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_release_read_tx() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        assert!(db.open_read_tx_ids()?.is_empty());
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?;
+            tx.commit()?;
+        }
+        let tx1 = db.tx(false)?;
+        let tx2 = db.tx(false)?;
+        let tx_id = db.inner.meta()?.tx_id;
+        assert_eq!(db.open_read_tx_ids()?, vec![tx_id, tx_id]);
+
+        assert!(unsafe { db.force_release_read_tx(tx_id)? });
+        assert_eq!(db.open_read_tx_ids()?, vec![tx_id]);
+        assert!(!unsafe { db.force_release_read_tx(tx_id + 1)? });
+        // dropping a transaction that was already released doesn't release another one
+        drop(tx1);
+        assert_eq!(db.open_read_tx_ids()?, vec![tx_id]);
+        drop(tx2);
+        assert!(db.open_read_tx_ids()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaim_leaked() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        assert!(db.find_leaked_pages()?.is_empty());
+        assert_eq!(db.reclaim_leaked()?, 0);
+
+        // leak three pages at the end of the file by growing it without using them
+        let num_pages = {
+            let tx = db.tx(true)?;
+            let num_pages = {
+                let inner = tx.inner.borrow();
+                let mut freelist = inner.freelist.borrow_mut();
+                freelist.meta.num_pages += 3;
+                freelist.meta.num_pages
+            };
+            tx.get_bucket("abc")?.put("key", "new value")?;
+            tx.commit()?;
+            num_pages
+        };
+        let leaked: Vec<u64> = (num_pages - 3..num_pages).collect();
+        assert_eq!(db.find_leaked_pages()?, leaked);
+        assert!(db.check().is_err());
+
+        assert_eq!(db.reclaim_leaked()?, 3);
+        assert!(db.find_leaked_pages()?.is_empty());
+        db.check()?;
+        let free_pages = db.free_pages()?;
+        assert!(leaked.iter().all(|page_id| free_pages.contains(page_id)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_lock() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let guard = db.bucket_lock(b"abc")?;
+        assert_eq!(guard.name(), b"abc");
+        // other names aren't affected
+        drop(db.bucket_lock(b"def")?);
+
+        let (locked, wait_for_lock) = std::sync::mpsc::channel();
+        let other_db = db.clone();
+        let other = thread::spawn(move || {
+            let _guard = other_db.bucket_lock(b"abc").unwrap();
+            locked.send(()).unwrap();
+        });
+        assert!(wait_for_lock
+            .recv_timeout(Duration::from_millis(50))
+            .is_err());
+        drop(guard);
+        wait_for_lock.recv_timeout(Duration::from_secs(10)).unwrap();
+        other.join().unwrap();
+        assert!(db.inner.bucket_locks.lock()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_readers() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        assert!(db.wait_for_readers(Duration::from_millis(0))?);
+
+        let (opened, wait_for_open) = std::sync::mpsc::channel();
+        let reader_db = db.clone();
+        let reader = thread::spawn(move || {
+            let tx = reader_db.tx(false).unwrap();
+            opened.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            drop(tx);
+        });
+        wait_for_open.recv().unwrap();
+        assert!(!db.wait_for_readers(Duration::from_millis(0))?);
+        assert!(db.wait_for_readers(Duration::from_secs(10))?);
+        assert!(db.open_read_tx_ids()?.is_empty());
+        reader.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_in_memory() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(4)
+            .open(&random_file)?;
+        db.lock_in_memory()?;
+        assert!(db.inner.memory_locked.load(Ordering::Acquire));
+        {
+            // grow the file so it gets remapped and locked again
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), vec![0; 64])?;
+            }
+            tx.commit()?;
+        }
+        db.unlock_memory()?;
+        assert!(!db.inner.memory_locked.load(Ordering::Acquire));
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_pages() -> Result<()> {
+        let random_file = RandomFile::new();
+        // make the map big enough to cover a few huge pages
+        let db = OpenOptions::new()
+            .num_pages(4096)
+            .huge_pages(true)
+            .open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..10_000_u64 {
+                b.put(i.to_be_bytes(), i.to_le_bytes())?;
+            }
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.kv_pairs().count(), 10_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_logically_eq() -> Result<()> {
+        // Writes the same data in a different order for different pagesizes, so the pages look nothing alike
+        fn fill(file: &RandomFile, pagesize: u64, edit: &dyn Fn(&Tx) -> Result<()>) -> Result<DB> {
+            let db = OpenOptions::new().pagesize(pagesize).open(file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            let nested = b.create_bucket("nested")?;
+            let mut ids: Vec<u64> = (0..2000).collect();
+            if pagesize > 1024 {
+                ids.reverse();
+            }
+            for i in ids {
+                b.put(i.to_be_bytes(), i.to_le_bytes())?;
+                nested.put(i.to_be_bytes(), "x")?;
+            }
+            tx.create_bucket("def")?;
+            edit(&tx)?;
+            tx.commit()?;
+            Ok(db)
+        }
+        let file = RandomFile::new();
+        let db = fill(&file, 1024, &|_| Ok(()))?;
+        assert!(db.logically_eq(&db)?);
+
+        let eq = |edit: &dyn Fn(&Tx) -> Result<()>| -> Result<bool> {
+            let file = RandomFile::new();
+            let other = fill(&file, 4096, edit)?;
+            assert_eq!(db.logically_eq(&other)?, other.logically_eq(&db)?);
+            db.logically_eq(&other)
+        };
+        assert!(eq(&|_| Ok(()))?);
+        assert!(!eq(&|tx| {
+            let b = tx.get_bucket("abc")?;
+            b.put(5_u64.to_be_bytes(), "y").map(|_| ())
+        })?);
+        assert!(!eq(&|tx| tx
+            .get_bucket("abc")?
+            .put("extra", "y")
+            .map(|_| ()))?);
+        assert!(!eq(&|tx| {
+            let nested = tx.get_bucket_path(&["abc", "nested"])?;
+            nested.delete(7_u64.to_be_bytes()).map(|_| ())
+        })?);
+        // same data, but the nested bucket's next_int has moved on
+        assert!(!eq(&|tx| {
+            let nested = tx.get_bucket_path(&["abc", "nested"])?;
+            nested.put("tmp", "tmp")?;
+            nested.delete("tmp").map(|_| ())
+        })?);
+        assert!(!eq(&|tx| tx.delete_bucket("def"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_commit() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            let weak = db.downgrade();
+            db.on_commit(Arc::new(move |event| {
+                // the write lock has been released, so we can look at the committed data
+                let db = weak.upgrade().unwrap();
+                let tx = db.tx(true).unwrap();
+                assert!(tx.get_bucket("abc").is_ok());
+                events.lock().unwrap().push(event);
+            }))?;
+        }
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("a", vec![0_u8; 2100])?;
+            tx.commit()?;
+        }
+        // rolled back transactions don't call the hook
+        db.tx(true)?;
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let event = events[0];
+        assert_eq!(event.tx_id, 1);
+        // meta, freelist, root bucket, and a three page leaf for "abc"
+        assert_eq!(event.pages_written, 6);
+        assert!(event.bytes_written > 3 * 1024 && event.bytes_written < 6 * 1024);
+        // the hook doesn't keep the database open
+        let weak = db.downgrade();
+        drop(db);
+        assert!(weak.upgrade().is_none());
+        OpenOptions::new().pagesize(1024).open(&random_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_enforce_num_pages() -> Result<()> {
+        let random_file = RandomFile::new();
+        OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(10)
+            .open(&random_file)?;
+        // ignored by default
+        OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(20)
+            .open(&random_file)?;
+        OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(10)
+            .enforce_num_pages(true)
+            .open(&random_file)?;
+        match OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(20)
+            .enforce_num_pages(true)
+            .open(&random_file)
+        {
+            Err(e) => assert_eq!(
+                e,
+                Error::InvalidDB(String::from("Database has 10 pages, expected at least 20"))
+            ),
+            Ok(_) => panic!("Expected an InvalidDB error"),
+        }
+        // a database that grew past num_pages is still fine
+        let num_pages = {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .num_pages(10)
+                .enforce_num_pages(true)
+                .open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()?;
+            db.inner.meta()?.num_pages
+        };
+        assert!(num_pages > 10);
+        OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(10)
+            .enforce_num_pages(true)
+            .open(&random_file)?;
+        // a file that is too short for its own pages is always caught
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&random_file)?
+            .set_len((num_pages - 1) * 1024)?;
+        match OpenOptions::new().pagesize(1024).open(&random_file) {
+            Err(Error::InvalidDB(e)) => assert!(e.contains("but the file only has")),
+            Err(e) => panic!("Expected an InvalidDB error, got {}", e),
+            Ok(_) => panic!("Expected an InvalidDB error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_to_previous() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .strict_mode(true)
+            .keep_freed_for(1)
+            .open(&random_file)?;
+        assert_eq!(db.previous_root()?, None);
+        assert_eq!(
+            db.rollback_to_previous(),
+            Err(Error::InvalidDB(String::from(
+                "No previous commit to roll back to"
+            )))
+        );
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), "original")?;
+            }
+            tx.commit()?;
+        }
+        let root_page = db.tx(false)?.inner.borrow().meta.root.root_page;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), "changed")?;
+            }
+            tx.create_bucket("def")?;
+            tx.commit()?;
+        }
+        assert_eq!(db.previous_root()?, Some(root_page));
+        // a transaction that writes pages and is then dropped can't overwrite the previous commit
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), "dropped")?;
+            }
+            tx.flush_dirty()?;
+        }
+        db.rollback_to_previous()?;
+        db.check()?;
+        {
+            let tx = db.tx(false)?;
+            assert_eq!(tx.inner.borrow().meta.root.root_page, root_page);
+            assert_eq!(tx.inner.borrow().meta.tx_id, 3);
+            assert!(tx.get_bucket("def").is_err());
+            let b = tx.get_bucket("abc")?;
+            for kv in b.kv_pairs() {
+                assert_eq!(kv.value(), b"original");
+            }
+        }
+        // the commit that was undone is free to be reused, so we can't switch back to it
+        let reused = Err(Error::InvalidDB(String::from(
+            "The previous commit's pages may have been reused",
+        )));
+        assert_eq!(db.rollback_to_previous(), reused);
+        // we can keep writing after rolling back
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            b.put("new", "value")?;
+            tx.commit()?;
+        }
+        db.check()?;
+        // and the rollback survives reopening the file
+        drop(db);
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(false)?;
+            assert!(tx.get_bucket("def").is_err());
+            assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 101);
+        }
+        // nothing is known about the pages the last commit freed before we opened the file
+        assert_eq!(db.rollback_to_previous(), reused);
+        // and without keep_freed_for they can be reused by the next transaction
+        {
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.put("new", "other value")?;
+            tx.commit()?;
         }
+        assert_eq!(db.rollback_to_previous(), reused);
+        Ok(())
+    }
 
-        if let Some(meta) = check_meta!(meta) {
-            Ok(meta.clone())
-        } else if let Some(old_meta) = check_meta!(old_meta) {
-            Ok(old_meta.into())
-        } else {
-            panic!("NO VALID META PAGES");
+    #[test]
+    fn test_count_all_kv() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        assert_eq!(db.count_all_kv()?, 0);
+        {
+            let tx = db.tx(true)?;
+            let a = tx.create_bucket("a")?;
+            for i in 0..500_u64 {
+                a.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            let b = a.create_bucket("b")?;
+            for i in 0..50_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            b.create_bucket("c")?.put("x", "y")?;
+            tx.create_bucket("d")?;
+            tx.commit()?;
         }
+        assert_eq!(db.count_all_kv()?, 551);
+        Ok(())
     }
-}
 
-fn init_file(path: &Path, pagesize: u64, num_pages: usize, direct_write: bool) -> Result<File> {
-    let mut file = open_file(path, true, direct_write)?;
-    file.allocate(pagesize * (num_pages as u64))?;
-    let mut buf = vec![0; (pagesize * 4) as usize];
-    let mut get_page = |index: u64| {
-        #[allow(clippy::cast_ptr_alignment)]
-        unsafe {
-            &mut *(&mut buf[(index * pagesize) as usize] as *mut u8 as *mut Page)
+    #[test]
+    fn test_suggest_pagesize() -> Result<()> {
+        let os_pagesize = get_page_size() as u64;
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        assert_eq!(db.suggest_pagesize()?, os_pagesize);
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("small")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
         }
-    };
-    for i in 0..2 {
-        let page = get_page(i);
-        page.id = i;
-        page.page_type = Page::TYPE_META;
-        let m = page.meta_mut();
-        m.meta_page = i as u32;
-        m.magic = MAGIC_VALUE;
-        m.version = VERSION;
-        m.pagesize = pagesize;
-        m.freelist_page = 2;
-        m.root = BucketMeta {
-            root_page: 3,
-            next_int: 0,
-        };
-        m.num_pages = 4;
-        m.hash = m.hash_self();
+        // 48 byte entries easily fit in a single OS page
+        assert_eq!(db.suggest_pagesize()?, os_pagesize);
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("large")?.create_bucket("nested")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), vec![0; 1000])?;
+            }
+            tx.commit()?;
+        }
+        let pagesize = db.suggest_pagesize()?;
+        assert_eq!(pagesize % os_pagesize, 0);
+        assert!(pagesize >= 32 * 524);
+        assert!(pagesize <= MAX_SUGGESTED_PAGESIZE.max(os_pagesize));
+        Ok(())
     }
 
-    let p = get_page(2);
-    p.id = 2;
-    p.page_type = Page::TYPE_FREELIST;
-    p.count = 0;
+    #[test]
+    fn test_free_pages() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        assert!(db.free_pages()?.is_empty());
+        for _ in 0..2 {
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let free_pages = db.free_pages()?;
+        assert!(!free_pages.is_empty());
+        assert!(free_pages.windows(2).all(|w| w[0] < w[1]));
+        Ok(())
+    }
 
-    let p = get_page(3);
-    p.id = 3;
-    p.page_type = Page::TYPE_LEAF;
-    p.count = 0;
+    #[test]
+    fn test_fragmentation() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        assert_eq!(db.fragmentation()?, 0.0);
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), [0; 16].to_vec())?;
+            }
+            tx.commit()?;
+        }
+        let before = db.fragmentation()?;
+        {
+            let tx = db.tx(true)?;
+            tx.delete_bucket("abc")?;
+            tx.commit()?;
+        }
+        let after = db.fragmentation()?;
+        assert!(after > before);
+        assert!(after <= 1.0);
+        let num_pages = db.inner.meta()?.num_pages;
+        assert_eq!(after, db.free_pages()?.len() as f32 / num_pages as f32);
 
-    file.write_all(&buf[..])?;
-    file.flush()?;
-    file.sync_all()?;
-    Ok(file)
-}
+        // a read-only handle sees the same free pages
+        let free_pages = db.free_pages()?;
+        drop(db);
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .read_only(true)
+            .open(&random_file)?;
+        assert_eq!(db.free_pages()?, free_pages);
+        assert_eq!(db.fragmentation()?, after);
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::testutil::RandomFile;
+    #[test]
+    fn test_open_all() -> Result<()> {
+        let random_file = RandomFile::new();
+        std::fs::create_dir(&random_file)?;
+        for name in ["b.db", "a.db", "c.txt"] {
+            DB::open(random_file.path.join(name))?;
+        }
+        {
+            let dbs = DB::open_all(&random_file)?;
+            let names: Vec<&String> = dbs.keys().collect();
+            assert_eq!(names, vec!["a", "b"]);
+        }
+        std::fs::remove_dir_all(&random_file)?;
+        Ok(())
+    }
 
     #[test]
-    fn test_open_options() {
-        assert_ne!(get_page_size(), 5000);
+    fn test_user_version() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = DB::open(&random_file)?;
+            assert_eq!(db.user_version()?, 0);
+            assert_eq!(db.inner.meta()?.version, 1);
+            db.set_user_version(7)?;
+            assert_eq!(db.user_version()?, 7);
+            // both meta pages move to the new version, so older releases refuse the file
+            // instead of falling back to the commit before it
+            assert_eq!(db.inner.meta()?.version, VERSION);
+            let previous = db.inner.previous_meta()?.unwrap();
+            assert_eq!(previous.version, VERSION);
+            assert_eq!(previous.user_version, 0);
+            // regular commits keep it
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?;
+            tx.commit()?;
+            assert_eq!(db.user_version()?, 7);
+        }
+        let db = DB::open(&random_file)?;
+        db.check()?;
+        assert_eq!(db.user_version()?, 7);
+        db.set_user_version(0)?;
+        assert_eq!(db.user_version()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_version_unsynced() -> Result<()> {
         let random_file = RandomFile::new();
         {
             let db = OpenOptions::new()
-                .pagesize(5000)
-                .num_pages(100)
-                .open(&random_file)
-                .unwrap();
-            assert_eq!(db.pagesize(), 5000);
+                .sync_mode(SyncMode::Never)
+                .open(&random_file)?;
+            db.set_user_version(7)?;
+            // moving the other meta page to the new version is left unsynced along with the commit
+            assert_eq!(db.inner.previous_meta()?.unwrap().version, VERSION);
+            assert_eq!(db.inner.unsynced_commits.load(Ordering::Acquire), 1);
+            db.sync()?;
+            assert_eq!(db.inner.unsynced_commits.load(Ordering::Acquire), 0);
+        }
+        let db = DB::open(&random_file)?;
+        assert_eq!(db.user_version()?, 7);
+        assert_eq!(db.inner.previous_meta()?.unwrap().version, VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_snapshots() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let a = tx.create_bucket("a")?;
+            for k in ["k1", "k2", "k3"] {
+                a.put(k, "old")?;
+            }
+            tx.create_bucket("b")?.create_bucket("c")?.put("x", "old")?;
+            let same = tx.create_bucket("same")?;
+            for i in 0..1000_u64 {
+                same.put(i.to_be_bytes(), "value")?;
+            }
+            tx.commit()?;
         }
+        let old = db.tx(false)?;
         {
-            let metadata = random_file.path.metadata().unwrap();
-            assert!(metadata.is_file());
-            assert_eq!(metadata.len(), 500_000);
+            let tx = db.tx(true)?;
+            let a = tx.get_bucket("a")?;
+            a.put("k1", "new")?;
+            a.delete("k2")?;
+            a.put("k3", "old")?;
+            a.put("k4", "new")?;
+            let b = tx.get_bucket("b")?;
+            b.delete_bucket("c")?;
+            b.put("c", "new")?;
+            tx.create_bucket("d")?.put("y", "new")?;
+            tx.commit()?;
         }
+        let new = db.tx(false)?;
+
+        let path =
+            |p: &[&str]| -> Vec<Vec<u8>> { p.iter().map(|s| s.as_bytes().to_vec()).collect() };
+        let changes: Vec<_> = db.diff_snapshots(&old, &new)?.collect();
+        assert_eq!(
+            changes,
+            vec![
+                (path(&["a", "k1"]), ChangeKind::Changed),
+                (path(&["a", "k2"]), ChangeKind::Removed),
+                (path(&["a", "k4"]), ChangeKind::Added),
+                (path(&["b", "c", "x"]), ChangeKind::Removed),
+                (path(&["b", "c"]), ChangeKind::Added),
+                (path(&["d", "y"]), ChangeKind::Added),
+            ]
+        );
+        let reversed: Vec<_> = db.diff_snapshots(&new, &old)?.collect();
+        assert_eq!(reversed.len(), changes.len());
+        assert!(reversed.contains(&(path(&["a", "k4"]), ChangeKind::Removed)));
+        assert!(reversed.contains(&(path(&["b", "c", "x"]), ChangeKind::Added)));
+        assert_eq!(db.diff_snapshots(&new, &new)?.count(), 0);
+
+        // page ids only mean the same thing within one database
+        let other_file = RandomFile::new();
+        let other = DB::open(&other_file)?;
+        let other_tx = other.tx(false)?;
+        let different = Some(Error::InvalidDB(String::from(
+            "Snapshot is from a different database",
+        )));
+        assert_eq!(db.diff_snapshots(&old, &other_tx).err(), different);
+        assert_eq!(other.diff_snapshots(&old, &new).err(), different);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stale mmap")]
+    fn test_stale_pages() {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file).unwrap();
+        let pages = db.inner.pages().unwrap();
+        pages.page(0);
         {
-            let db = OpenOptions::new()
-                .pagesize(5000)
-                .num_pages(100)
-                .open(&random_file)
-                .unwrap();
-            assert_eq!(db.pagesize(), 5000);
+            let file = db.inner.file.lock().unwrap();
+            let size = file.metadata().unwrap().len();
+            db.inner.resize(&file, size * 2).unwrap();
         }
+        // the old mapping is still alive, but nothing should read from it after a remap
+        pages.page(0);
     }
 
     #[test]
@@ -434,7 +3072,6 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_different_pagesizes() {
         assert_ne!(get_page_size(), 5000);
         let random_file = RandomFile::new();
@@ -446,58 +3083,477 @@ mod tests {
                 .unwrap();
             assert_eq!(db.pagesize(), 5000);
         }
-        DB::open(&random_file).unwrap();
+        match DB::open(&random_file) {
+            Err(Error::InvalidDB(_)) => (),
+            _ => panic!("Expected an InvalidDB error"),
+        }
     }
-}
 
-// Have different mmap functions for Unix and Windows
-#[cfg(unix)]
-fn mmap(file: &File, populate: bool) -> Result<Mmap> {
-    use memmap2::MmapOptions;
+    #[test]
+    fn test_foreign_endianness() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        // Pretend the file was written on a machine with the opposite byte order
+        let mut buf = std::fs::read(&random_file)?;
+        for i in 0..2 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[i * pagesize as usize] as *mut u8 as *mut Page) };
+            let m = page.meta_mut();
+            m.magic = m.magic.swap_bytes();
+            m.hash = m.hash.swap_bytes();
+        }
+        std::fs::write(&random_file, buf)?;
+        match OpenOptions::new().pagesize(pagesize).open(&random_file) {
+            Err(e) => assert_eq!(
+                e,
+                Error::InvalidDB(String::from(
+                    "Database was written with a foreign endianness"
+                ))
+            ),
+            Ok(_) => panic!("Expected an InvalidDB error"),
+        }
+        Ok(())
+    }
 
-    let mut options = MmapOptions::new();
-    if populate {
-        options.populate();
+    #[test]
+    fn test_unsupported_version() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        // Pretend the file was written by a newer version of jammdb
+        let mut buf = std::fs::read(&random_file)?;
+        for i in 0..2 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[i * pagesize as usize] as *mut u8 as *mut Page) };
+            let m = page.meta_mut();
+            m.version = VERSION + 1;
+            m.hash = m.hash_self();
+        }
+        std::fs::write(&random_file, buf)?;
+        match OpenOptions::new().pagesize(pagesize).open(&random_file) {
+            Err(Error::InvalidDB(_)) => Ok(()),
+            _ => panic!("Expected an InvalidDB error"),
+        }
     }
-    let mmap = unsafe { options.map(file)? };
-    // On Unix we advice the OS that page access will be random.
-    mmap.advise(memmap2::Advice::Random)?;
-    Ok(mmap)
-}
 
-// On Windows there is no advice to give.
-#[cfg(windows)]
-fn mmap(file: &File, populate: bool) -> Result<Mmap> {
-    let mmap = unsafe { Mmap::map(file)? };
-    Ok(mmap)
-}
+    #[test]
+    fn test_unsupported_comparator() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        // Pretend the keys were sorted with some other ordering
+        let mut buf = std::fs::read(&random_file)?;
+        for i in 0..2 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[i * pagesize as usize] as *mut u8 as *mut Page) };
+            let m = page.meta_mut();
+            assert_eq!(m.comparator, COMPARATOR_BYTEWISE);
+            m.comparator = 1;
+            m.hash = m.hash_self();
+        }
+        std::fs::write(&random_file, buf)?;
+        match OpenOptions::new().pagesize(pagesize).open(&random_file) {
+            Err(Error::InvalidDB(_)) => Ok(()),
+            _ => panic!("Expected an InvalidDB error"),
+        }
+    }
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
-const O_DIRECT: libc::c_int = libc::O_DIRECT;
+    #[test]
+    fn test_compact_in_place() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..20_000_u64 {
+                b.put(i.to_be_bytes(), vec![0_u8; 256])?;
+            }
+            let nested = b.create_bucket("nested")?;
+            nested.next_int();
+            nested.put(nested.next_int().to_be_bytes(), "a")?;
+            nested.set_counter(7)?;
+            tx.commit()?;
+        }
+        db.set_user_version(3)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for i in (0..20_000_u64).filter(|i| i % 100 != 0) {
+                b.delete(i.to_be_bytes())?;
+            }
+            tx.commit()?;
+        }
+        let size = std::fs::metadata(&random_file.path)?.len();
+        let tx_id = db.inner.meta()?.tx_id;
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-const O_DIRECT: libc::c_int = 0;
+        db.compact_in_place()?;
+        assert!(std::fs::metadata(&random_file.path)?.len() < size);
+        assert_eq!(db.inner.meta()?.tx_id, tx_id + 1);
+        assert_eq!(db.user_version()?, 3);
+        assert!(db.find_leaked_pages()?.is_empty());
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.kv_pairs().count(), 200);
+            // copying the remaining data doesn't count as new inserts
+            assert_eq!(b.next_int(), 20_001);
+            let nested = b.get_bucket("nested")?;
+            assert_eq!(nested.next_int(), 1);
+            assert_eq!(nested.counter(), 7);
+            b.put("more", "data")?;
+            tx.commit()?;
+        }
+        db.check()?;
+        drop(db);
 
-// Have different mmap functions for Unix and Windows
-#[cfg(unix)]
-fn open_file<P: AsRef<Path>>(path: P, create: bool, direct_write: bool) -> Result<File> {
-    let mut open_options = FileOpenOptions::new();
-    open_options.write(true).read(true);
-    if create {
-        open_options.create_new(true);
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 201);
+        let mut tmp = random_file.path.clone().into_os_string();
+        tmp.push(".compact");
+        assert!(!Path::new(&tmp).exists());
+        Ok(())
     }
-    if direct_write {
-        open_options.custom_flags(O_DIRECT);
+
+    #[test]
+    fn test_compact_in_place_without_path() -> Result<()> {
+        let random_file = RandomFile::new();
+        let file = FileOpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&random_file)?;
+        let db = OpenOptions::new().open_file(file)?;
+        let err = db.compact_in_place().unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == std::io::ErrorKind::Unsupported));
+        Ok(())
     }
-    Ok(open_options.open(path)?)
-}
 
-#[cfg(windows)]
-fn open_file<P: AsRef<Path>>(path: P, create: bool, direct_write: bool) -> Result<File> {
-    let mut open_options = FileOpenOptions::new();
-    open_options.write(true).read(true);
-    if create {
-        open_options.create_new(true);
+    #[test]
+    fn test_metadata() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            assert!(db.metadata()?.is_empty());
+            assert_eq!(db.inner.meta()?.version, BASE_VERSION);
+            db.set_metadata(b"my-app v1")?;
+            assert_eq!(db.metadata()?, b"my-app v1");
+            // older releases don't know about the page
+            assert_eq!(db.inner.meta()?.version, VERSION);
+            db.check()?;
+            // replacing it frees the old page
+            db.set_metadata(b"my-app v2")?;
+            db.check()?;
+            assert!(db.find_leaked_pages()?.is_empty());
+
+            let max = (pagesize - size_of::<Page>() as u64) as usize;
+            assert_eq!(
+                db.set_metadata(&vec![1; max + 1]),
+                Err(Error::MetadataTooLarge)
+            );
+            assert_eq!(db.metadata()?, b"my-app v2");
+            db.set_metadata(&vec![1; max])?;
+            assert_eq!(db.metadata()?, vec![1; max]);
+            db.set_metadata(b"my-app v3")?;
+
+            // it survives compaction
+            db.compact_in_place()?;
+            assert_eq!(db.metadata()?, b"my-app v3");
+            db.check()?;
+        }
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        assert_eq!(db.metadata()?, b"my-app v3");
+        db.set_metadata(b"")?;
+        assert!(db.metadata()?.is_empty());
+        db.check()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_only() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.put("key", "new value")?;
+            tx.commit()?;
+        }
+        let verify = || {
+            OpenOptions::new()
+                .pagesize(1024)
+                .verify_only(true)
+                .open(&random_file)
+        };
+        // damage the newest meta page, which a writer would repair on its next commit
+        let mut original = std::fs::read(&random_file)?;
+        let newest = verify()?.inner.meta()?.meta_page as usize;
+        original[newest * 1024 + size_of::<Page>() + 8] ^= 0xff;
+        std::fs::write(&random_file, &original)?;
+        {
+            let db = verify()?;
+            // other verifiers can open the file at the same time, without allow_multiple_opens
+            let other = verify()?;
+            db.check()?;
+            other.check()?;
+            // the free pages come from the older commit too, the initial freelist and root it replaced
+            assert_eq!(db.free_pages()?, vec![2, 3]);
+            {
+                // the older commit is read instead
+                let tx = db.tx(false)?;
+                let b = tx.get_bucket("abc")?;
+                assert_eq!(b.get_kv("key").unwrap().value(), b"value");
+            }
+            assert_eq!(db.tx(true).err(), Some(Error::ReadOnlyDB));
+            assert_eq!(db.set_user_version(1), Err(Error::ReadOnlyDB));
+            assert_eq!(db.set_metadata(b"abc"), Err(Error::ReadOnlyDB));
+            assert_eq!(db.rollback_to_previous(), Err(Error::ReadOnlyDB));
+            assert_eq!(db.compact_in_place(), Err(Error::ReadOnlyDB));
+            assert_eq!(db.reclaim_leaked().err(), Some(Error::ReadOnlyDB));
+            // the file is never grown or remapped
+            {
+                let file = db.inner.file.lock()?;
+                assert_eq!(
+                    db.inner.resize(&file, original.len() as u64 * 2).err(),
+                    Some(Error::ReadOnlyDB)
+                );
+            }
+            assert_eq!(db.inner.generation.load(Ordering::Acquire), 0);
+        }
+        // nothing was repaired
+        assert_eq!(std::fs::read(&random_file)?, original);
+
+        // the log isn't created
+        let wal_file = RandomFile::new();
+        let err = OpenOptions::new()
+            .pagesize(1024)
+            .verify_only(true)
+            .wal(Some(wal_file.path.clone()))
+            .open(&random_file)
+            .err();
+        assert_eq!(err, Some(Error::ReadOnlyDB));
+        assert!(!wal_file.path.exists());
+
+        // a missing file isn't created
+        let missing = RandomFile::new();
+        assert!(OpenOptions::new().verify_only(true).open(&missing).is_err());
+        assert!(!missing.path.exists());
+
+        // no lock is taken, so a writer can open the file and commit while it is being verified,
+        // and a verifier can open it while a writer has it
+        {
+            let db = verify()?;
+            let writer = OpenOptions::new()
+                .pagesize(1024)
+                .lock_timeout(Some(Duration::ZERO))
+                .open(&random_file)?;
+            let tx = writer.tx(true)?;
+            tx.get_bucket("abc")?.put("key", "newer value")?;
+            tx.commit()?;
+            verify()?.check()?;
+            drop(db);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_auto() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        if get_page_size() as u64 != 1024 {
+            assert!(matches!(DB::open(&random_file), Err(Error::InvalidDB(_))));
+        }
+        {
+            let db = DB::open_auto(&random_file)?;
+            assert_eq!(db.pagesize(), 1024);
+            let tx = db.tx(false)?;
+            assert_eq!(
+                tx.get_bucket("abc")?.get_kv("key").unwrap().value(),
+                b"value"
+            );
+        }
+        // a new file gets the default pagesize
+        let new_file = RandomFile::new();
+        assert_eq!(DB::open_auto(&new_file)?.pagesize(), get_page_size() as u64);
+
+        // A torn first meta page isn't trusted, but the second one is found instead
+        let original = std::fs::read(&random_file)?;
+        let pagesize_offset = {
+            let page = Page::from_buf(&original, 0, 1024);
+            &page.meta().pagesize as *const u64 as usize - original.as_ptr() as usize
+        };
+        for bad_pagesize in [1024 * 3, 4096_u64] {
+            let mut data = original.clone();
+            data[pagesize_offset..pagesize_offset + 8].copy_from_slice(&bad_pagesize.to_ne_bytes());
+            std::fs::write(&random_file, &data)?;
+            assert_eq!(stored_pagesize(&random_file.path)?, Some(1024));
+        }
+        // and if neither is valid, the file is refused
+        let mut data = original;
+        for page in 0..2 {
+            let offset = page * 1024 + pagesize_offset;
+            data[offset..offset + 8].copy_from_slice(&4096_u64.to_ne_bytes());
+        }
+        std::fs::write(&random_file, &data)?;
+        assert_eq!(
+            DB::open_auto(&random_file).err(),
+            Some(Error::InvalidDB(String::from(
+                "No valid meta page to read the pagesize from"
+            )))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
+        let random_file = RandomFile::new();
+        let dest_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..20_000_u64 {
+                b.put(i.to_be_bytes(), vec![0_u8; 256])?;
+            }
+            b.create_bucket("nested")?.set_counter(7)?;
+            tx.commit()?;
+        }
+        db.set_metadata(b"my-app")?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for i in (0..20_000_u64).filter(|i| i % 100 != 0) {
+                b.delete(i.to_be_bytes())?;
+            }
+            tx.commit()?;
+        }
+        let size = std::fs::metadata(&random_file.path)?.len();
+
+        let stats = db.compact(&dest_file)?;
+        assert_eq!(stats.old_size, size);
+        assert_eq!(stats.new_size, std::fs::metadata(&dest_file.path)?.len());
+        assert_eq!(stats.reclaimed(), stats.old_size - stats.new_size);
+        assert!(stats.new_size < size / 10);
+        // the original is untouched, and won't be overwritten
+        assert_eq!(std::fs::metadata(&random_file.path)?.len(), size);
+        let err = db.compact(&random_file).unwrap_err();
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(std::fs::metadata(&random_file.path)?.len(), size);
+        db.check()?;
+
+        let copy = OpenOptions::new().pagesize(1024).open(&dest_file)?;
+        copy.check()?;
+        assert!(copy.free_pages()?.len() < 10);
+        assert!(copy.logically_eq(&db)?);
+        assert_eq!(copy.metadata()?, b"my-app");
+        {
+            let tx = copy.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.get_bucket("nested")?.counter(), 7);
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), vec![1_u8; 256])?;
+            }
+            tx.commit()?;
+        }
+        copy.check()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_mode() -> Result<()> {
+        let commit = |db: &DB, i: u64| -> Result<()> {
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("abc")?
+                .put(i.to_be_bytes(), "value")?;
+            tx.commit()
+        };
+        let unsynced = |db: &DB| db.inner.unsynced_commits.load(Ordering::Acquire);
+        {
+            let random_file = RandomFile::new();
+            let db = DB::open(&random_file)?;
+            commit(&db, 0)?;
+            assert_eq!(unsynced(&db), 0);
+        }
+        {
+            let random_file = RandomFile::new();
+            let db = OpenOptions::new()
+                .sync_mode(SyncMode::EveryNCommits(3))
+                .open(&random_file)?;
+            let counts = (0..7)
+                .map(|i| {
+                    commit(&db, i)?;
+                    Ok(unsynced(&db))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(counts, vec![1, 2, 0, 1, 2, 0, 1]);
+        }
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .sync_mode(SyncMode::Never)
+                .open(&random_file)?;
+            for i in 0..10 {
+                commit(&db, i)?;
+            }
+            assert_eq!(unsynced(&db), 10);
+            // unsynced commits are still visible
+            assert_eq!(db.tx(false)?.get_bucket("abc")?.kv_pairs().count(), 10);
+            db.sync()?;
+            assert_eq!(unsynced(&db), 0);
+        }
+        let db = DB::open(&random_file)?;
+        db.check()?;
+        assert_eq!(db.tx(false)?.get_bucket("abc")?.kv_pairs().count(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = DB::open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let mut permissions = std::fs::metadata(&random_file)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&random_file, permissions.clone())?;
+        {
+            let db = OpenOptions::new().read_only(true).open(&random_file)?;
+            {
+                let tx = db.tx(false)?;
+                assert_eq!(
+                    tx.get_bucket("abc")?.get_kv("key").unwrap().value(),
+                    b"value"
+                );
+            }
+            assert_eq!(db.tx(true).err(), Some(Error::ReadOnlyDB));
+            assert_eq!(db.batch(|_| Ok(())), Err(Error::ReadOnlyDB));
+            db.sync()?;
+            db.check()?;
+        }
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&random_file, permissions)?;
+        Ok(())
     }
-    Ok(open_options.open(path)?)
 }
@@ -1,6 +1,7 @@
 use std::{error::Error as StdError, fmt, sync::PoisonError};
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
+/// A `Result` with the error type fixed to jammdb's [`Error`]
+pub type Result<T> = std::result::Result<T, Error>;
 
 /// Possible database errors
 #[derive(Debug)]
@@ -23,6 +24,39 @@ pub enum Error {
     InvalidDB(String),
     /// Errors that can occur during allocation
     Alloc(std::alloc::LayoutError),
+    /// Tried to commit a transaction, but the database was changed by someone else since the transaction started
+    Conflict,
+    /// A writable transaction needed more memory than allowed by [`OpenOptions::max_tx_memory`](struct.OpenOptions.html#method.max_tx_memory)
+    TransactionTooLarge,
+    /// Tried to commit a transaction that would grow the file past [`OpenOptions::max_file_size`](struct.OpenOptions.html#method.max_file_size)
+    DatabaseFull,
+    /// Tried to open a database file that is already open in this process,
+    /// without [`OpenOptions::allow_multiple_opens`](struct.OpenOptions.html#method.allow_multiple_opens)
+    AlreadyOpen,
+    /// Tried to put a key or create a bucket whose name is not valid UTF-8,
+    /// with [`OpenOptions::utf8_keys`](struct.OpenOptions.html#method.utf8_keys) enabled
+    NonUtf8Key,
+    /// Tried to read from a bucket that was deleted earlier in the transaction
+    BucketDeleted,
+    /// A key / value pair didn't match the checksum stored with it when it was written,
+    /// see [`OpenOptions::value_checksums`](struct.OpenOptions.html#method.value_checksums)
+    ValueCorrupt {
+        /// The key of the corrupted pair
+        key: Vec<u8>,
+    },
+    /// Tried to give more than one key / value pair the same key
+    KeyCollision {
+        /// The key that was used more than once
+        key: Vec<u8>,
+    },
+    /// Tried to store more metadata than fits in a page with [`DB::set_metadata`](struct.DB.html#method.set_metadata)
+    MetadataTooLarge,
+    /// Tried to [`increment`](struct.Bucket.html#method.increment) a counter past the range of a `u64`
+    CounterOverflow,
+    /// Tried to write to a database opened with [`OpenOptions::read_only`](struct.OpenOptions.html#method.read_only)
+    ReadOnlyDB,
+    /// Tried to make an incremental backup from a transaction whose changes are no longer being kept
+    HistoryUnavailable,
 }
 
 impl StdError for Error {}
@@ -39,6 +73,59 @@ impl fmt::Display for Error {
             Error::Sync(s) => write!(f, "Sync Error: {}", s),
             Error::InvalidDB(s) => write!(f, "Invalid DB: {}", s),
             Error::Alloc(e) => write!(f, "Allocation error: {}", e),
+            Error::Conflict => write!(f, "Database changed since the transaction started"),
+            Error::TransactionTooLarge => write!(f, "Transaction exceeded its memory limit"),
+            Error::DatabaseFull => write!(f, "Database reached its maximum file size"),
+            Error::AlreadyOpen => write!(f, "Database is already open in this process"),
+            Error::NonUtf8Key => write!(f, "Key is not valid UTF-8"),
+            Error::BucketDeleted => write!(f, "Bucket has been deleted"),
+            Error::ValueCorrupt { key } => write!(
+                f,
+                "Value for key {:?} does not match its checksum",
+                String::from_utf8_lossy(key)
+            ),
+            Error::KeyCollision { key } => write!(
+                f,
+                "More than one key / value pair with key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            Error::MetadataTooLarge => write!(f, "Metadata does not fit in a page"),
+            Error::CounterOverflow => write!(f, "Counter is out of range"),
+            Error::ReadOnlyDB => {
+                write!(f, "Cannot write to a read-only database")
+            }
+            Error::HistoryUnavailable => {
+                write!(f, "Changes since that transaction are not available")
+            }
+        }
+    }
+}
+
+impl Error {
+    // Errors can't be cloned because of the io::Error, but sometimes one error needs to be reported to many callers.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self {
+            Error::BucketExists => Error::BucketExists,
+            Error::BucketMissing => Error::BucketMissing,
+            Error::KeyValueMissing => Error::KeyValueMissing,
+            Error::IncompatibleValue => Error::IncompatibleValue,
+            Error::ReadOnlyTx => Error::ReadOnlyTx,
+            Error::Io(e) => Error::Io(std::io::Error::new(e.kind(), e.to_string())),
+            Error::Sync(s) => Error::Sync(s),
+            Error::InvalidDB(s) => Error::InvalidDB(s.clone()),
+            Error::Alloc(e) => Error::Alloc(e.clone()),
+            Error::Conflict => Error::Conflict,
+            Error::TransactionTooLarge => Error::TransactionTooLarge,
+            Error::DatabaseFull => Error::DatabaseFull,
+            Error::AlreadyOpen => Error::AlreadyOpen,
+            Error::NonUtf8Key => Error::NonUtf8Key,
+            Error::BucketDeleted => Error::BucketDeleted,
+            Error::ValueCorrupt { key } => Error::ValueCorrupt { key: key.clone() },
+            Error::KeyCollision { key } => Error::KeyCollision { key: key.clone() },
+            Error::MetadataTooLarge => Error::MetadataTooLarge,
+            Error::CounterOverflow => Error::CounterOverflow,
+            Error::ReadOnlyDB => Error::ReadOnlyDB,
+            Error::HistoryUnavailable => Error::HistoryUnavailable,
         }
     }
 }
@@ -49,6 +136,22 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        use std::io::ErrorKind;
+        let kind = match err {
+            Error::Io(e) => return e,
+            Error::BucketMissing | Error::KeyValueMissing => ErrorKind::NotFound,
+            Error::BucketExists | Error::KeyCollision { .. } => ErrorKind::AlreadyExists,
+            Error::ReadOnlyTx | Error::ReadOnlyDB => ErrorKind::PermissionDenied,
+            Error::InvalidDB(_) | Error::ValueCorrupt { .. } => ErrorKind::InvalidData,
+            Error::NonUtf8Key | Error::MetadataTooLarge => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
 impl From<std::alloc::LayoutError> for Error {
     fn from(err: std::alloc::LayoutError) -> Error {
         Error::Alloc(err)
@@ -69,6 +172,18 @@ impl PartialEq for Error {
             (Error::KeyValueMissing, Error::KeyValueMissing) => true,
             (Error::IncompatibleValue, Error::IncompatibleValue) => true,
             (Error::ReadOnlyTx, Error::ReadOnlyTx) => true,
+            (Error::Conflict, Error::Conflict) => true,
+            (Error::TransactionTooLarge, Error::TransactionTooLarge) => true,
+            (Error::DatabaseFull, Error::DatabaseFull) => true,
+            (Error::AlreadyOpen, Error::AlreadyOpen) => true,
+            (Error::NonUtf8Key, Error::NonUtf8Key) => true,
+            (Error::BucketDeleted, Error::BucketDeleted) => true,
+            (Error::ValueCorrupt { key: k1 }, Error::ValueCorrupt { key: k2 }) => k1 == k2,
+            (Error::KeyCollision { key: k1 }, Error::KeyCollision { key: k2 }) => k1 == k2,
+            (Error::MetadataTooLarge, Error::MetadataTooLarge) => true,
+            (Error::CounterOverflow, Error::CounterOverflow) => true,
+            (Error::ReadOnlyDB, Error::ReadOnlyDB) => true,
+            (Error::HistoryUnavailable, Error::HistoryUnavailable) => true,
             (Error::Sync(s1), Error::Sync(s2)) => s1 == s2,
             (Error::InvalidDB(s1), Error::InvalidDB(s2)) => s1 == s2,
             _ => false,
@@ -109,5 +224,80 @@ mod tests {
             format!("{}", Error::InvalidDB(String::from("uh oh"))),
             "Invalid DB: uh oh"
         );
+        assert_eq!(
+            format!("{}", Error::Conflict),
+            "Database changed since the transaction started"
+        );
+        assert_eq!(
+            format!("{}", Error::TransactionTooLarge),
+            "Transaction exceeded its memory limit"
+        );
+        assert_eq!(
+            format!("{}", Error::DatabaseFull),
+            "Database reached its maximum file size"
+        );
+        assert_eq!(
+            format!("{}", Error::AlreadyOpen),
+            "Database is already open in this process"
+        );
+        assert_eq!(format!("{}", Error::NonUtf8Key), "Key is not valid UTF-8");
+        assert_eq!(
+            format!("{}", Error::BucketDeleted),
+            "Bucket has been deleted"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::ValueCorrupt {
+                    key: b"abc".to_vec()
+                }
+            ),
+            "Value for key \"abc\" does not match its checksum"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::KeyCollision {
+                    key: b"abc".to_vec()
+                }
+            ),
+            "More than one key / value pair with key \"abc\""
+        );
+        assert_eq!(
+            format!("{}", Error::MetadataTooLarge),
+            "Metadata does not fit in a page"
+        );
+        assert_eq!(
+            format!("{}", Error::CounterOverflow),
+            "Counter is out of range"
+        );
+        assert_eq!(
+            format!("{}", Error::ReadOnlyDB),
+            "Cannot write to a read-only database"
+        );
+        assert_eq!(
+            format!("{}", Error::HistoryUnavailable),
+            "Changes since that transaction are not available"
+        );
+    }
+    #[test]
+    fn test_into_io_error() {
+        fn open() -> std::io::Result<()> {
+            Err(Error::BucketMissing)?;
+            Ok(())
+        }
+        let err = open().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "Bucket does not exist");
+
+        let err: std::io::Error =
+            Error::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "slow")).into();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "slow");
+
+        let err: std::io::Error = Error::InvalidDB(String::from("uh oh")).into();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let inner = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert_eq!(*inner, Error::InvalidDB(String::from("uh oh")));
     }
 }
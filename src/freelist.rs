@@ -8,6 +8,7 @@ use std::{
 use bumpalo::Bump;
 
 use crate::{
+    errors::Error,
     meta::Meta,
     page::{Page, PageID},
     Result,
@@ -18,15 +19,24 @@ pub(crate) struct TxFreelist {
     pub(crate) inner: Freelist,
     pub(crate) pages: BTreeMap<u64, (NonNull<u8>, usize)>,
     pub(crate) arena: Bump,
+    max_memory: Option<usize>,
+    zero_on_free: bool,
 }
 
 impl<'a> TxFreelist {
-    pub(crate) fn new(meta: Meta, inner: Freelist) -> TxFreelist {
+    pub(crate) fn new(
+        meta: Meta,
+        inner: Freelist,
+        max_memory: Option<usize>,
+        zero_on_free: bool,
+    ) -> TxFreelist {
         TxFreelist {
             meta,
             inner,
             pages: BTreeMap::new(),
             arena: Bump::new(),
+            max_memory,
+            zero_on_free,
         }
     }
 
@@ -45,13 +55,27 @@ impl<'a> TxFreelist {
             size_of::<Page>(),
             bytes < (size_of::<Page>() as u64)
         );
-        let num_pages = if (bytes % self.meta.pagesize) == 0 {
+        let num_pages = if bytes % self.meta.pagesize == 0 {
             bytes / self.meta.pagesize
         } else {
             (bytes / self.meta.pagesize) + 1
         };
-        let page_id = match self.inner.allocate(num_pages as usize) {
-            Some(page_id) => page_id,
+        // Cover every page we're given, so nothing from a page's old contents is left behind the new data
+        let bytes = if self.zero_on_free {
+            num_pages * self.meta.pagesize
+        } else {
+            bytes
+        };
+        if let Some(max_memory) = self.max_memory {
+            if self.arena.allocated_bytes() + bytes as usize > max_memory {
+                return Err(Error::TransactionTooLarge);
+            }
+        }
+        let page_id = match self.inner.allocate(num_pages as usize)? {
+            Some(page_id) => {
+                self.check_allocated(page_id, num_pages)?;
+                page_id
+            }
             None => {
                 let page_id = self.meta.num_pages;
                 self.meta.num_pages += num_pages;
@@ -62,6 +86,9 @@ impl<'a> TxFreelist {
         let ptr = self
             .arena
             .alloc_layout(Layout::from_size_align(bytes as usize, 8)?);
+        if self.zero_on_free {
+            unsafe { std::ptr::write_bytes(ptr.as_ptr(), 0, bytes as usize) };
+        }
 
         let page = unsafe { &mut *(ptr.as_ptr() as *mut Page) };
         page.id = page_id;
@@ -70,28 +97,138 @@ impl<'a> TxFreelist {
 
         Ok(page)
     }
+
+    // The freelist makes sure the allocator only handed back pages that are really free,
+    // but those came from the file, so make sure they're inside it before anything is written over them.
+    fn check_allocated(&self, page_id: PageID, num_pages: u64) -> Result<()> {
+        let end = page_id.saturating_add(num_pages);
+        if page_id <= 1 || end > self.meta.num_pages {
+            return Err(Error::InvalidDB(format!(
+                "Allocator returned pages {}..{}, but only pages 2..{} can be used",
+                page_id, end, self.meta.num_pages
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Decides which free pages are reused when a transaction needs to write a page.
+///
+/// Once the pages freed by a transaction are no longer used by any open transaction, they are given to the allocator,
+/// and every write asks it for a run of contiguous pages. When it doesn't have one, the file is grown instead.
+/// The default allocator takes the first run of free pages that is long enough.
+/// Set your own with [`OpenOptions::allocator`](struct.OpenOptions.html#method.allocator)
+/// to try out other strategies and see how they affect the size of the file.
+///
+/// Each writable transaction works on its own copy of the allocator made with [`box_clone`](#tymethod.box_clone),
+/// which replaces the database's copy when the transaction is committed, and is thrown away if it isn't.
+pub trait PageAllocator: Send + Sync {
+    /// Takes `num_pages` contiguous free pages and returns the ID of the first one,
+    /// or `None` if there isn't a long enough run of free pages.
+    ///
+    /// Returning pages that weren't given to [`free`](#tymethod.free), or that were already handed out,
+    /// makes the write fail with [`Error::InvalidDB`](enum.Error.html#variant.InvalidDB).
+    fn allocate(&mut self, num_pages: usize) -> Option<u64>;
+
+    /// Adds `num_pages` contiguous pages starting at `page_id` to the free pages.
+    fn free(&mut self, page_id: u64, num_pages: u64);
+
+    /// Returns the IDs of every free page, in any order.
+    fn free_pages(&self) -> Vec<u64>;
+
+    /// Returns a copy of this allocator, including all of its free pages.
+    fn box_clone(&self) -> Box<dyn PageAllocator>;
+}
+
+impl Clone for Box<dyn PageAllocator> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Reuses the first run of free pages that is long enough
+#[derive(Clone, Default)]
+pub(crate) struct FirstFit {
+    free_pages: BTreeSet<PageID>,
+}
+
+impl PageAllocator for FirstFit {
+    fn allocate(&mut self, num_pages: usize) -> Option<PageID> {
+        if self.free_pages.is_empty() {
+            return None;
+        }
+        let mut start: PageID = 0;
+        let mut prev: PageID = 0;
+        let mut found: PageID = 0;
+
+        for id in self.free_pages.iter().cloned() {
+            debug_assert!(
+                id > 1,
+                "pageID {} cannot be in freelist, reserved for meta",
+                id
+            );
+
+            if prev == 0 || id - prev != 1 {
+                start = id;
+            }
+
+            let block_size = id - start + 1;
+            if block_size == (num_pages as u64) {
+                found = start;
+                break;
+            }
+
+            prev = id;
+        }
+
+        if found > 0 {
+            for id in found..found + (num_pages as u64) {
+                self.free_pages.remove(&id);
+            }
+            return Some(found);
+        }
+
+        None
+    }
+
+    fn free(&mut self, page_id: PageID, num_pages: u64) {
+        self.free_pages.extend(page_id..page_id + num_pages);
+    }
+
+    fn free_pages(&self) -> Vec<PageID> {
+        self.free_pages.iter().cloned().collect()
+    }
+
+    fn box_clone(&self) -> Box<dyn PageAllocator> {
+        Box::new(self.clone())
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct Freelist {
-    free_pages: BTreeSet<PageID>,
+    allocator: Box<dyn PageAllocator>,
     pending_pages: BTreeMap<u64, Vec<PageID>>,
+    // Every page given to the allocator that it hasn't handed back yet,
+    // so we can tell when it hands back a page that isn't free
+    released: BTreeSet<PageID>,
 }
 
 const HEADER_SIZE: u64 = size_of::<Page>() as u64;
 const PAGE_ID_SIZE: u64 = size_of::<PageID>() as u64;
 
 impl Freelist {
-    pub(crate) fn new() -> Freelist {
+    pub(crate) fn with_allocator(allocator: Box<dyn PageAllocator>) -> Freelist {
         Freelist {
-            free_pages: BTreeSet::new(),
+            allocator,
             pending_pages: BTreeMap::new(),
+            released: BTreeSet::new(),
         }
     }
 
     pub(crate) fn init(&mut self, free_pages: &[PageID]) {
         free_pages.iter().for_each(|id| {
-            self.free_pages.insert(*id);
+            self.allocator.free(*id, 1);
+            self.released.insert(*id);
         });
     }
 
@@ -102,7 +239,7 @@ impl Freelist {
             "cannot free page {}, reserved for meta",
             page_id
         );
-        let pages = self.pending_pages.entry(tx_id).or_insert_with(Vec::new);
+        let pages = self.pending_pages.entry(tx_id).or_default();
         pages.push(page_id);
     }
 
@@ -113,7 +250,8 @@ impl Freelist {
             if other_tx_id < tx_id {
                 let pages = self.pending_pages.remove(&other_tx_id).unwrap();
                 pages.into_iter().for_each(|p| {
-                    self.free_pages.insert(p);
+                    self.allocator.free(p, 1);
+                    self.released.insert(p);
                 });
             } else {
                 break;
@@ -121,46 +259,27 @@ impl Freelist {
         }
     }
 
-    pub(crate) fn allocate(&mut self, num_pages: usize) -> Option<PageID> {
-        if self.free_pages.is_empty() {
-            return None;
-        }
-        let mut start: PageID = 0;
-        let mut prev: PageID = 0;
-        let mut found: PageID = 0;
-
-        for id in self.free_pages.iter().cloned() {
-            debug_assert!(
-                id > 1,
-                "pageID {} cannot be in freelist, reserved for meta",
+    // takes a run of free pages from the allocator, making sure it only hands back pages it was given
+    pub(crate) fn allocate(&mut self, num_pages: usize) -> Result<Option<PageID>> {
+        let page_id = match self.allocator.allocate(num_pages) {
+            Some(page_id) => page_id,
+            None => return Ok(None),
+        };
+        let end = page_id.saturating_add(num_pages as u64);
+        if let Some(id) = (page_id..end).find(|id| !self.released.contains(id)) {
+            return Err(Error::InvalidDB(format!(
+                "Allocator returned page {}, which isn't free",
                 id
-            );
-
-            if prev == 0 || id - prev != 1 {
-                start = id;
-            }
-
-            let block_size = id - start + 1;
-            if block_size == (num_pages as u64) {
-                found = start;
-                break;
-            }
-
-            prev = id;
+            )));
         }
-
-        if found > 0 {
-            for id in found..found + (num_pages as u64) {
-                self.free_pages.remove(&id);
-            }
-            return Some(found);
+        for id in page_id..end {
+            self.released.remove(&id);
         }
-
-        None
+        Ok(Some(page_id))
     }
 
     pub(crate) fn pages(&self) -> Vec<PageID> {
-        let mut page_ids: Vec<PageID> = self.free_pages.iter().cloned().collect();
+        let mut page_ids: Vec<PageID> = self.allocator.free_pages();
         for (_, pages) in self.pending_pages.iter() {
             let mut pages = pages.to_vec();
             page_ids.append(&mut pages);
@@ -181,10 +300,7 @@ mod tests {
     use crate::{errors::Result, testutil::RandomFile, OpenOptions};
 
     fn freelist_from_vec(v: Vec<PageID>) -> Freelist {
-        let mut freelist = Freelist {
-            free_pages: v.iter().cloned().collect(),
-            pending_pages: BTreeMap::new(),
-        };
+        let mut freelist = Freelist::with_allocator(Box::new(FirstFit::default()));
         freelist.init(v.as_slice());
         freelist
     }
@@ -192,33 +308,21 @@ mod tests {
     #[test]
     fn test_allocate() {
         let mut freelist = freelist_from_vec(vec![2, 4, 6, 8, 9, 10]);
-        assert_eq!(freelist.allocate(4), None);
-        assert_eq!(freelist.allocate(1), Some(2));
-        assert_eq!(
-            freelist.free_pages.iter().cloned().collect::<Vec<u64>>(),
-            vec![4, 6, 8, 9, 10]
-        );
-        assert_eq!(freelist.allocate(1), Some(4));
-        assert_eq!(
-            freelist.free_pages.iter().cloned().collect::<Vec<u64>>(),
-            vec![6, 8, 9, 10]
-        );
-        assert_eq!(freelist.allocate(3), Some(8));
-        assert_eq!(
-            freelist.free_pages.iter().cloned().collect::<Vec<u64>>(),
-            vec![6]
-        );
-        assert_eq!(freelist.allocate(1), Some(6));
-        assert_eq!(
-            freelist.free_pages.iter().cloned().collect::<Vec<u64>>(),
-            vec![]
-        );
-        assert_eq!(freelist.allocate(1), None);
+        assert_eq!(freelist.allocate(4).unwrap(), None);
+        assert_eq!(freelist.allocate(1).unwrap(), Some(2));
+        assert_eq!(freelist.allocator.free_pages(), vec![4, 6, 8, 9, 10]);
+        assert_eq!(freelist.allocate(1).unwrap(), Some(4));
+        assert_eq!(freelist.allocator.free_pages(), vec![6, 8, 9, 10]);
+        assert_eq!(freelist.allocate(3).unwrap(), Some(8));
+        assert_eq!(freelist.allocator.free_pages(), vec![6]);
+        assert_eq!(freelist.allocate(1).unwrap(), Some(6));
+        assert_eq!(freelist.allocator.free_pages(), Vec::<u64>::new());
+        assert_eq!(freelist.allocate(1).unwrap(), None);
     }
 
     #[test]
     fn test_free() {
-        let mut freelist = Freelist::new();
+        let mut freelist = freelist_from_vec(vec![]);
 
         freelist.free(1, 5);
         assert_eq!(freelist.pending_pages.len(), 1);
@@ -233,7 +337,7 @@ mod tests {
         assert_eq!(freelist.pending_pages.len(), 2);
         assert_eq!(freelist.pending_pages.get(&1), Some(&vec![5, 4, 3]));
         assert_eq!(freelist.pending_pages.get(&2), Some(&vec![7]));
-        assert_eq!(freelist.free_pages, BTreeSet::new());
+        assert!(freelist.allocator.free_pages().is_empty());
     }
 
     #[test]
@@ -254,20 +358,20 @@ mod tests {
 
     #[test]
     fn test_release() {
-        let mut freelist = Freelist::new();
+        let mut freelist = freelist_from_vec(vec![]);
 
         freelist.free(1, 5);
         freelist.free(1, 10);
         freelist.free(1, 7);
 
-        assert_eq!(freelist.free_pages.len(), 0);
+        assert_eq!(freelist.allocator.free_pages().len(), 0);
         assert_eq!(freelist.pending_pages.len(), 1);
         freelist.release(1);
-        assert_eq!(freelist.free_pages.len(), 0);
+        assert_eq!(freelist.allocator.free_pages().len(), 0);
         assert_eq!(freelist.pending_pages.len(), 1);
 
         freelist.release(2);
-        assert_eq!(freelist.free_pages.len(), 3);
+        assert_eq!(freelist.allocator.free_pages().len(), 3);
         assert_eq!(freelist.pending_pages.len(), 0);
         assert_eq!(freelist.pages(), vec![5, 7, 10]);
     }
@@ -352,6 +456,168 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_allocate_max_memory() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .max_tx_memory(8192)
+            .open(&random_file)?;
+        let tx = db.tx(false)?;
+        let tx = tx.inner.borrow_mut();
+        let mut freelist = tx.freelist.borrow_mut();
+        freelist.allocate(1024)?;
+        assert_eq!(
+            freelist.allocate(10240).err(),
+            Some(crate::Error::TransactionTooLarge)
+        );
+        // the failed allocation didn't use any pages
+        let page = freelist.allocate(1024)?;
+        assert_eq!(page.id, 5);
+        Ok(())
+    }
+
+    // Never reuses anything, so every page is written to the end of the file
+    #[derive(Clone, Default)]
+    struct Bump {
+        freed: Vec<u64>,
+    }
+
+    impl PageAllocator for Bump {
+        fn allocate(&mut self, _num_pages: usize) -> Option<u64> {
+            None
+        }
+
+        fn free(&mut self, page_id: u64, num_pages: u64) {
+            self.freed.extend(page_id..page_id + num_pages);
+        }
+
+        fn free_pages(&self) -> Vec<u64> {
+            self.freed.clone()
+        }
+
+        fn box_clone(&self) -> Box<dyn PageAllocator> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_custom_allocator() -> Result<()> {
+        let num_pages = |options: OpenOptions| -> Result<u64> {
+            let random_file = RandomFile::new();
+            let db = options.pagesize(1024).open(&random_file)?;
+            for i in 0..20_u64 {
+                let tx = db.tx(true)?;
+                let b = tx.get_or_create_bucket("abc")?;
+                b.put(i.to_be_bytes(), vec![0; 100])?;
+                tx.commit()?;
+            }
+            db.check()?;
+            let tx = db.tx(false)?;
+            assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 20);
+            let num_pages = tx.inner.borrow().meta.num_pages;
+            Ok(num_pages)
+        };
+        let first_fit = num_pages(OpenOptions::new())?;
+        let bump = num_pages(OpenOptions::new().allocator(Box::new(Bump::default())))?;
+        assert!(bump > first_fit, "{} <= {}", bump, first_fit);
+        Ok(())
+    }
+
+    // Always hands out the same pages, whether they're free or not
+    #[derive(Clone)]
+    struct Fixed(u64);
+
+    impl PageAllocator for Fixed {
+        fn allocate(&mut self, _num_pages: usize) -> Option<u64> {
+            Some(self.0)
+        }
+
+        fn free(&mut self, _page_id: u64, _num_pages: u64) {}
+
+        fn free_pages(&self) -> Vec<u64> {
+            Vec::new()
+        }
+
+        fn box_clone(&self) -> Box<dyn PageAllocator> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_invalid_allocator() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        let check = |page_id: u64, setup: &dyn Fn(&mut TxFreelist)| -> Result<()> {
+            let tx = db.tx(false)?;
+            let tx = tx.inner.borrow_mut();
+            let mut freelist = tx.freelist.borrow_mut();
+            freelist.meta.num_pages = 100;
+            freelist.inner = Freelist::with_allocator(Box::new(Fixed(page_id)));
+            setup(&mut freelist);
+            match freelist.allocate(1024) {
+                Err(Error::InvalidDB(_)) => Ok(()),
+                Err(e) => panic!("unexpected error {:?}", e),
+                Ok(page) => panic!("allocated page {}", page.id),
+            }
+        };
+        // the meta pages, even if a corrupt freelist says they're free
+        check(0, &|freelist| freelist.inner.init(&[0]))?;
+        check(1, &|freelist| freelist.inner.init(&[1]))?;
+        // past the end of the file
+        check(100, &|freelist| freelist.inner.init(&[100]))?;
+        check(u64::MAX, &|_| ())?;
+        // freed by this transaction, so still in use by others
+        check(10, &|freelist| freelist.free(10, 1))?;
+        // in use by the database, and never freed
+        check(50, &|_| ())?;
+        // already allocated by this transaction
+        check(20, &|freelist| {
+            freelist.pages.clear();
+            freelist.pages.insert(19, (NonNull::dangling(), 1500));
+        })?;
+
+        let tx = db.tx(false)?;
+        let tx = tx.inner.borrow_mut();
+        let mut freelist = tx.freelist.borrow_mut();
+        freelist.meta.num_pages = 100;
+        freelist.inner = Freelist::with_allocator(Box::new(Fixed(30)));
+        freelist.inner.init(&[30]);
+        assert_eq!(freelist.allocate(1024)?.id, 30);
+        // and not again
+        assert!(matches!(freelist.allocate(1024), Err(Error::InvalidDB(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_zero_on_free() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(100)
+            .zero_on_free(true)
+            .open(&random_file)?;
+        let tx = db.tx(false)?;
+        let tx = tx.inner.borrow_mut();
+        let mut freelist = tx.freelist.borrow_mut();
+        // pretend the database already uses the pages we free
+        freelist.meta.num_pages = 100;
+        freelist.free(10, 2);
+        freelist.inner.release(1);
+
+        // the whole page is written, not just the bytes asked for
+        let page = freelist.allocate(100)?;
+        assert_eq!(page.id, 10);
+        let (ptr, size) = freelist.pages[&10];
+        assert_eq!(size, 1024);
+        let buf = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), size) };
+        assert!(buf[std::mem::size_of::<Page>()..].iter().all(|b| *b == 0));
+        let page = freelist.allocate(1500)?;
+        assert_eq!(page.overflow, 1);
+        assert_eq!(freelist.pages[&page.id].1, 2048);
+        Ok(())
+    }
+
     #[test]
     fn test_tx_free() -> Result<()> {
         let random_file = RandomFile::new();
@@ -111,6 +111,8 @@
 //! }
 //!
 
+mod backup;
+mod batch;
 #[allow(clippy::mutable_key_type)]
 mod bucket;
 mod bytes;
@@ -121,19 +123,24 @@ mod errors;
 mod freelist;
 mod lifetimes;
 mod meta;
+mod namespace;
 mod node;
 mod page;
 mod page_node;
 mod tx;
+mod wal;
 
+pub use batch::BatchWriter;
 pub use bucket::Bucket;
-pub use cursor::{Buckets, Cursor, KVPairs, ToBuckets, ToKVPairs};
+pub use cursor::{Buckets, Cursor, DedupByValue, KVPairs, ToBuckets, ToKVPairs};
 pub use data::*;
-pub use db::{OpenOptions, DB};
+pub use db::{BucketGuard, CommitEvent, CompactStats, OpenOptions, SyncMode, WeakDB, DB};
 pub use errors::*;
+pub use freelist::PageAllocator;
+pub use namespace::Namespace;
 pub use tx::Tx;
 
-pub use crate::bytes::ToBytes;
+pub use crate::bytes::{CompositeKey, CompositeKeyDecoder, ToBytes};
 
 #[cfg(test)]
 ///
@@ -2,7 +2,11 @@ use std::hash::Hasher;
 
 use fnv::FnvHasher;
 
-use crate::{bucket::BucketMeta, page::PageID};
+use crate::{bucket::BucketMeta, db::VERSION, page::PageID};
+
+// Keys are ordered by comparing their bytes, which is the only ordering this version knows how to search.
+// Another ordering would need its own ID, so a file sorted with it is refused instead of searched the wrong way.
+pub(crate) const COMPARATOR_BYTEWISE: u32 = 0;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -10,12 +14,23 @@ pub(crate) struct Meta {
     pub(crate) meta_page: u32,
     pub(crate) magic: u32,
     pub(crate) version: u32,
+    // This fills what used to be padding before pagesize, which was always zeroed,
+    // so files written before it existed read it as zero.
+    // Setting it moves the file to the newer version, since the hash only covers it from there on.
+    pub(crate) user_version: u32,
     pub(crate) pagesize: u64,
-    pub(crate) root: BucketMeta,
+    pub(crate) root: RootMeta,
     pub(crate) num_pages: PageID,
     pub(crate) freelist_page: PageID,
     pub(crate) tx_id: u64,
     pub(crate) hash: u64,
+    // The page holding the blob set with DB::set_metadata, or 0 if there isn't one.
+    // This comes after the hash so nothing before it moves, and the rest of a meta page was always zeroed.
+    // Like the user version, setting it moves the file to the newer version.
+    pub(crate) user_meta_page: PageID,
+    // The ordering the keys are sorted in, which has to match the one we search with.
+    // Every file written before it existed reads it as zero, which is the bytewise ordering.
+    pub(crate) comparator: u32,
 }
 
 impl Meta {
@@ -29,17 +44,59 @@ impl Meta {
         hasher.write(&self.meta_page.to_be_bytes());
         hasher.write(&self.magic.to_be_bytes());
         hasher.write(&self.version.to_be_bytes());
+        // Files at the base version are hashed the way every release expects. Newer versions hash more,
+        // so older releases, which don't check the version, refuse them instead of misreading them.
+        if self.version >= VERSION {
+            hasher.write(&self.user_version.to_be_bytes());
+        }
         hasher.write(&self.pagesize.to_be_bytes());
         hasher.write(&self.root.root_page.to_be_bytes());
         hasher.write(&self.root.next_int.to_be_bytes());
         hasher.write(&self.num_pages.to_be_bytes());
         hasher.write(&self.freelist_page.to_be_bytes());
         hasher.write(&self.tx_id.to_be_bytes());
+        if self.version >= VERSION {
+            hasher.write(&self.user_meta_page.to_be_bytes());
+        }
+        // Only hashed when it isn't the default, so files that sort bytewise hash the same as before it existed,
+        // while any other ordering is still covered by the hash and refused by releases that don't know about it.
+        if self.comparator != COMPARATOR_BYTEWISE {
+            hasher.write(&self.comparator.to_be_bytes());
+        }
 
         hasher.finish()
     }
 }
 
+// The root bucket's metadata, as it is stored in the meta page.
+// Nested buckets are stored with a BucketMeta, which has grown since this layout was fixed,
+// but the root bucket's user counter can't be set so there's no need to change the meta page.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RootMeta {
+    pub(crate) root_page: PageID,
+    pub(crate) next_int: u64,
+}
+
+impl From<RootMeta> for BucketMeta {
+    fn from(val: RootMeta) -> Self {
+        BucketMeta {
+            root_page: val.root_page,
+            next_int: val.next_int,
+            counter: 0,
+        }
+    }
+}
+
+impl From<BucketMeta> for RootMeta {
+    fn from(val: BucketMeta) -> Self {
+        RootMeta {
+            root_page: val.root_page,
+            next_int: val.next_int,
+        }
+    }
+}
+
 // OldMeta is the metadata format for versions <= 0.10
 // For now we check all databases for either metadata version,
 // but always write the new format.
@@ -55,7 +112,7 @@ pub(crate) struct OldMeta {
     pub(crate) magic: u32,
     pub(crate) version: u32,
     pub(crate) pagesize: u64,
-    pub(crate) root: BucketMeta,
+    pub(crate) root: RootMeta,
     pub(crate) num_pages: PageID,
     pub(crate) freelist_page: PageID,
     pub(crate) tx_id: u64,
@@ -100,12 +157,15 @@ impl From<&OldMeta> for Meta {
             meta_page: val.meta_page,
             magic: val.magic,
             version: val.version,
+            user_version: 0,
             pagesize: val.pagesize,
             root: val.root,
             num_pages: val.num_pages,
             freelist_page: val.freelist_page,
             tx_id: val.tx_id,
             hash: 0,
+            user_meta_page: 0,
+            comparator: COMPARATOR_BYTEWISE,
         };
 
         m.hash = m.hash_self();
@@ -123,8 +183,9 @@ mod tests {
             meta_page: 1,
             magic: 1_234_567_890,
             version: 987_654_321,
+            user_version: 0,
             pagesize: 4096,
-            root: BucketMeta {
+            root: RootMeta {
                 root_page: 2,
                 next_int: 2020,
             },
@@ -132,6 +193,8 @@ mod tests {
             freelist_page: 3,
             tx_id: 8,
             hash: 64,
+            user_meta_page: 0,
+            comparator: COMPARATOR_BYTEWISE,
         };
 
         assert!(!meta.valid());
@@ -143,6 +206,50 @@ mod tests {
 
         meta.hash = meta.hash_self();
         assert_eq!(meta.hash, meta.hash_self());
+
+        meta.user_version = 3;
+        assert_ne!(meta.hash, meta.hash_self());
+
+        meta.hash = meta.hash_self();
+        meta.user_meta_page = 5;
+        assert_ne!(meta.hash, meta.hash_self());
+
+        meta.hash = meta.hash_self();
+        meta.comparator = 1;
+        assert_ne!(meta.hash, meta.hash_self());
+        meta.comparator = COMPARATOR_BYTEWISE;
+
+        // the base version is hashed the same way as before the user version existed,
+        // so older releases can still read those files
+        meta.version = 1;
+        meta.user_version = 0;
+        meta.user_meta_page = 0;
+        let mut hasher = FnvHasher::default();
+        hasher.write(&meta.meta_page.to_be_bytes());
+        hasher.write(&meta.magic.to_be_bytes());
+        hasher.write(&meta.version.to_be_bytes());
+        hasher.write(&meta.pagesize.to_be_bytes());
+        hasher.write(&meta.root.root_page.to_be_bytes());
+        hasher.write(&meta.root.next_int.to_be_bytes());
+        hasher.write(&meta.num_pages.to_be_bytes());
+        hasher.write(&meta.freelist_page.to_be_bytes());
+        hasher.write(&meta.tx_id.to_be_bytes());
+        assert_eq!(meta.hash_self(), hasher.finish());
+        // while newer versions aren't, so older releases refuse them
+        meta.version = VERSION;
+        assert_ne!(meta.hash_self(), {
+            let mut hasher = FnvHasher::default();
+            hasher.write(&meta.meta_page.to_be_bytes());
+            hasher.write(&meta.magic.to_be_bytes());
+            hasher.write(&meta.version.to_be_bytes());
+            hasher.write(&meta.pagesize.to_be_bytes());
+            hasher.write(&meta.root.root_page.to_be_bytes());
+            hasher.write(&meta.root.next_int.to_be_bytes());
+            hasher.write(&meta.num_pages.to_be_bytes());
+            hasher.write(&meta.freelist_page.to_be_bytes());
+            hasher.write(&meta.tx_id.to_be_bytes());
+            hasher.finish()
+        });
     }
 
     #[test]
@@ -152,7 +259,7 @@ mod tests {
             magic: 1_234_567_890,
             version: 987_654_321,
             pagesize: 4096,
-            root: BucketMeta {
+            root: RootMeta {
                 root_page: 2,
                 next_int: 2020,
             },
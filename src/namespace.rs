@@ -0,0 +1,138 @@
+use crate::{bucket::Bucket, db::DB, errors::Result};
+
+/// A handle for working inside one root level bucket, like the data for a single tenant.
+///
+/// Created with [`DB::namespace`]. Every transaction started from a namespace opens its root bucket for you,
+/// and the closure you pass only ever sees that bucket, so code written against a namespace
+/// can't read or write another namespace's data by mistake.
+/// Namespaces are plain root level buckets, so you can still reach them through a [`Tx`](struct.Tx.html),
+/// and [`DB::namespaces`] lists them.
+///
+/// The handle can be cloned and sent to other threads.
+///
+/// # Examples
+///
+/// ```no_run
+/// use jammdb::{DB};
+/// # use jammdb::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let db = DB::open("my.db")?;
+/// let tenant = db.namespace(b"tenant-42");
+///
+/// tenant.update(|root| {
+///     root.get_or_create_bucket("users")?.put("alice", "data")?;
+///     Ok(())
+/// })?;
+/// let users = tenant.view(|root| Ok(root.get_bucket("users")?.kv_pairs().count()))?;
+/// assert_eq!(users, 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Namespace {
+    db: DB,
+    name: Vec<u8>,
+}
+
+impl Namespace {
+    pub(crate) fn new(db: DB, name: Vec<u8>) -> Namespace {
+        Namespace { db, name }
+    }
+
+    /// Returns the name of the namespace's root bucket.
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Runs `f` with the namespace's root bucket in a read-only transaction.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if nothing has been written
+    /// to the namespace yet, an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if its name
+    /// is taken by a root level key / value pair, or any error returned by `f`.
+    pub fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Bucket) -> Result<T>,
+    {
+        let tx = self.db.tx(false)?;
+        let root = tx.get_bucket(self.name.clone())?;
+        f(&root)
+    }
+
+    /// Runs `f` with the namespace's root bucket in a writable transaction, creating the bucket if it doesn't exist,
+    /// and commits the transaction if `f` succeeds.
+    ///
+    /// If `f` returns an error, the transaction is dropped without committing, so none of its changes are kept.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the namespace's name
+    /// is taken by a root level key / value pair, any error returned by `f`, or any error from committing.
+    pub fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Bucket) -> Result<T>,
+    {
+        let tx = self.db.tx(true)?;
+        let result = {
+            let root = tx.get_or_create_bucket(self.name.clone())?;
+            f(&root)?
+        };
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::Error, testutil::RandomFile};
+
+    #[test]
+    fn test_namespace() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        let (a, b) = (db.namespace(b"a"), db.namespace(b"b"));
+        assert_eq!(a.name(), b"a");
+        assert_eq!(a.view(|_| Ok(())), Err(Error::BucketMissing));
+
+        a.update(|root| {
+            root.put("key", "a's value")?;
+            Ok(())
+        })?;
+        b.update(|root| {
+            root.put("key", "b's value")?;
+            Ok(())
+        })?;
+        // a failed update isn't committed
+        let err = a.update(|root| {
+            root.put("key", "oops")?;
+            Err::<(), _>(Error::KeyValueMissing)
+        });
+        assert_eq!(err, Err(Error::KeyValueMissing));
+
+        let value =
+            |ns: &Namespace| ns.view(|root| Ok(root.get_kv("key").unwrap().value().to_vec()));
+        assert_eq!(value(&a)?, b"a's value");
+        assert_eq!(value(&b)?, b"b's value");
+        assert_eq!(db.namespaces()?, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        // any root level bucket counts as a namespace
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("c")?;
+            tx.commit()?;
+        }
+        assert_eq!(
+            db.namespace(b"c")
+                .view(|root| Ok(root.kv_pairs().count()))?,
+            0
+        );
+        assert_eq!(
+            db.namespaces()?,
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        Ok(())
+    }
+}
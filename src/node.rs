@@ -1,17 +1,17 @@
 use std::{cell::RefCell, mem::size_of, rc::Rc};
 
 use crate::{
-    bucket::{BucketMeta, InnerBucket, META_SIZE},
+    bucket::{BucketMeta, InnerBucket},
     bytes::Bytes,
     errors::Result,
     freelist::TxFreelist,
-    page::{BranchElement, LeafElement, Page, PageID, PageType},
+    page::{BranchElement, LeafElement, Page, PageID, PageType, CHECKSUM_SIZE},
 };
 
 pub(crate) type NodeID = u64;
 
 const HEADER_SIZE: u64 = size_of::<Page>() as u64;
-const LEAF_SIZE: u64 = size_of::<LeafElement>() as u64;
+pub(crate) const LEAF_SIZE: u64 = size_of::<LeafElement>() as u64;
 const BRANCH_SIZE: u64 = size_of::<BranchElement>() as u64;
 const MIN_KEYS_PER_NODE: usize = 2;
 const FILL_PERCENT: f32 = 0.5;
@@ -26,13 +26,15 @@ pub(crate) struct Node<'n> {
     pub(crate) original_key: Option<Bytes<'n>>,
     pub(crate) parent: Option<u64>,
     pagesize: u64,
+    // Whether key / value pairs are written with a trailing checksum
+    pub(crate) checksums: bool,
     spilled: bool,
 }
 
 impl<'n> Node<'n> {
     // This is only used when creating a root node for a new bucket
     // So the parent is always going to be None
-    pub(crate) fn new(id: NodeID, t: PageType, pagesize: u64) -> Node<'n> {
+    pub(crate) fn new(id: NodeID, t: PageType, pagesize: u64, checksums: bool) -> Node<'n> {
         let data: NodeData = match t {
             Page::TYPE_BRANCH => NodeData::Branches(Vec::new()),
             Page::TYPE_LEAF => NodeData::Leaves(Vec::new()),
@@ -47,6 +49,7 @@ impl<'n> Node<'n> {
             deleted: false,
             original_key: None,
             pagesize,
+            checksums,
             spilled: false,
             parent: None,
         }
@@ -54,7 +57,7 @@ impl<'n> Node<'n> {
 
     // This is used to initialize nodes for pages that are being modified.
     // The parent value needs to be set afterwards!
-    pub(crate) fn from_page(id: NodeID, p: &Page, pagesize: u64) -> Node<'n> {
+    pub(crate) fn from_page(id: NodeID, p: &Page, pagesize: u64, checksums: bool) -> Node<'n> {
         let data: NodeData = match p.page_type {
             Page::TYPE_BRANCH => {
                 let mut data = Vec::with_capacity(p.count as usize);
@@ -89,6 +92,7 @@ impl<'n> Node<'n> {
             deleted: false,
             original_key,
             pagesize,
+            checksums,
             spilled: false,
             parent: None,
         }
@@ -97,7 +101,12 @@ impl<'n> Node<'n> {
     // This is used to create new nodes created by splitting existing nodes.
     // They don't need to have their parent set since we no longer care about parent/child
     // relationships once we're splitting.
-    pub(crate) fn with_data(id: NodeID, data: NodeData<'n>, pagesize: u64) -> Node<'n> {
+    pub(crate) fn with_data(
+        id: NodeID,
+        data: NodeData<'n>,
+        pagesize: u64,
+        checksums: bool,
+    ) -> Node<'n> {
         let original_key = Some(data.first_key());
         Node {
             id,
@@ -108,6 +117,7 @@ impl<'n> Node<'n> {
             deleted: false,
             original_key,
             pagesize,
+            checksums,
             spilled: false,
             parent: None,
         }
@@ -179,11 +189,23 @@ impl<'n> Node<'n> {
     }
 
     fn size(&self) -> u64 {
-        HEADER_SIZE + self.data.size()
+        let checksums = match &self.data {
+            NodeData::Leaves(leaves) if self.checksums => {
+                leaves.iter().filter(|l| l.is_kv()).count() as u64 * CHECKSUM_SIZE
+            }
+            _ => 0,
+        };
+        HEADER_SIZE + self.data.size() + checksums
     }
 
-    pub(crate) fn needs_merging(&self) -> bool {
-        self.data.len() < MIN_KEYS_PER_NODE || self.size() < (self.pagesize / 4)
+    // Whether this node is smaller than `threshold` of a page, or has too few keys.
+    pub(crate) fn needs_merging(&self, threshold: f32) -> bool {
+        self.too_few_keys() || self.size() < ((self.pagesize as f32) * threshold) as u64
+    }
+
+    // Nodes with fewer keys than this must always be merged, or we may end up writing empty pages.
+    pub(crate) fn too_few_keys(&self) -> bool {
+        self.data.len() < MIN_KEYS_PER_NODE
     }
 
     pub(crate) fn spill<'a>(
@@ -316,7 +338,10 @@ impl<'n> Node<'n> {
                     //     break;
                     // }
                     count += 1;
-                    let size = LEAF_SIZE + (l.size() as u64);
+                    let mut size = LEAF_SIZE + (l.size() as u64);
+                    if self.checksums && l.is_kv() {
+                        size += CHECKSUM_SIZE;
+                    }
                     let new_size = current_size + size;
                     if count >= MIN_KEYS_PER_NODE && new_size > threshold {
                         split_indexes.push(i + 1);
@@ -475,7 +500,7 @@ pub(crate) enum Leaf<'a> {
 
 impl<'a> Leaf<'a> {
     pub(crate) fn from_leaf<'b>(l: &'b LeafElement) -> Leaf<'a> {
-        match l.node_type {
+        match l.node_type() {
             Node::TYPE_DATA => Leaf::Kv(Bytes::Slice(l.key()), Bytes::Slice(l.value())),
             Node::TYPE_BUCKET => Leaf::Bucket(Bytes::Slice(l.key()), l.value().into()),
             _ => panic!("INVALID NODE TYPE"),
@@ -512,7 +537,7 @@ impl<'a> Leaf<'a> {
 
     pub(crate) fn size(&self) -> usize {
         match self {
-            Self::Bucket(b, _) => b.size() + META_SIZE,
+            Self::Bucket(b, meta) => b.size() + meta.as_ref().len(),
             Self::Kv(k, v) => k.size() + v.size(),
         }
     }
@@ -1,8 +1,14 @@
 use std::{
+    cell::RefCell,
+    collections::HashSet,
     io::Write,
     mem::size_of,
+    rc::Rc,
     slice::{from_raw_parts, from_raw_parts_mut},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use memmap2::Mmap;
@@ -10,7 +16,7 @@ use memmap2::Mmap;
 use crate::{
     errors::Result,
     meta::{Meta, OldMeta},
-    node::{Node, NodeData, NodeType},
+    node::{Leaf, Node, NodeData, NodeType},
 };
 
 pub(crate) type PageID = u64;
@@ -21,15 +27,42 @@ pub(crate) type PageType = u8;
 pub(crate) struct Pages {
     pub(crate) data: Arc<Mmap>,
     pub(crate) pagesize: u64,
+    // Which mapping of the file data is, and the database's counter that is bumped every time the file is remapped.
+    // Once the file is remapped, nothing should read from an older mapping.
+    generation: u64,
+    current_generation: Arc<AtomicU64>,
+    // Every page read through this, if the transaction is keeping track
+    pub(crate) accessed: Option<Rc<RefCell<HashSet<PageID>>>>,
+    // Whether nodes written from these pages store a checksum after each key / value pair
+    pub(crate) value_checksums: bool,
+    // Branches pointing at this page or past it are corrupt
+    pub(crate) num_pages: PageID,
 }
 
 impl Pages {
-    pub fn new(data: Arc<Mmap>, pagesize: u64) -> Pages {
-        Pages { data, pagesize }
+    pub fn new(data: Arc<Mmap>, pagesize: u64, current_generation: Arc<AtomicU64>) -> Pages {
+        Pages {
+            num_pages: data.len() as u64 / pagesize,
+            data,
+            pagesize,
+            generation: current_generation.load(Ordering::Acquire),
+            current_generation,
+            accessed: None,
+            value_checksums: false,
+        }
     }
 
     #[inline]
     pub fn page<'a>(&self, id: PageID) -> &'a Page {
+        debug_assert_eq!(
+            self.generation,
+            self.current_generation.load(Ordering::Acquire),
+            "read page {} from a stale mmap",
+            id
+        );
+        if let Some(accessed) = &self.accessed {
+            accessed.borrow_mut().insert(id);
+        }
         #[allow(clippy::cast_ptr_alignment)]
         unsafe {
             &*(&self.data[(id * self.pagesize) as usize] as *const u8 as *const Page)
@@ -56,6 +89,7 @@ impl Page {
     pub(crate) const TYPE_LEAF: PageType = 0x02;
     pub(crate) const TYPE_META: PageType = 0x03;
     pub(crate) const TYPE_FREELIST: PageType = 0x04;
+    pub(crate) const TYPE_USER_META: PageType = 0x05;
 
     #[inline]
     pub(crate) fn from_buf(buf: &[u8], id: PageID, pagesize: u64) -> &Page {
@@ -121,6 +155,29 @@ impl Page {
         }
     }
 
+    pub(crate) fn user_meta(&self) -> &[u8] {
+        assert_eq!(
+            self.page_type,
+            Page::TYPE_USER_META,
+            "Did not find user meta page, found {}",
+            self.page_type
+        );
+        unsafe {
+            let start = &self.ptr as *const u64 as *const u8;
+            from_raw_parts(start, self.count as usize)
+        }
+    }
+
+    pub(crate) fn user_meta_mut(&mut self) -> &mut [u8] {
+        assert_eq!(
+            self.page_type,
+            Page::TYPE_USER_META,
+            "Did not find user meta page, found {}",
+            self.page_type
+        );
+        self.slice(self.count)
+    }
+
     pub(crate) fn leaf_elements(&self) -> &[LeafElement] {
         assert_eq!(
             self.page_type,
@@ -187,6 +244,17 @@ impl Page {
         let header_size;
         let mut data_size: u64 = 0;
         let mut data: Vec<&[u8]>;
+        // Computed up front so the data can borrow them while it's being written
+        let checksums: Vec<Option<[u8; 4]>> = match &n.data {
+            NodeData::Leaves(leaves) => leaves
+                .iter()
+                .map(|l| match l {
+                    Leaf::Kv(k, v) if n.checksums => Some(crc32(&[k, v]).to_be_bytes()),
+                    _ => None,
+                })
+                .collect(),
+            NodeData::Branches(_) => Vec::new(),
+        };
         match &n.data {
             NodeData::Branches(branches) => {
                 self.page_type = Page::TYPE_BRANCH;
@@ -208,9 +276,9 @@ impl Page {
                 self.page_type = Page::TYPE_LEAF;
                 header_size = size_of::<LeafElement>() as u64;
                 let mut header_offsets = header_size * (leaves.len() as u64);
-                data = Vec::with_capacity(self.count as usize * 2);
+                data = Vec::with_capacity(self.count as usize * 3);
                 let elems = self.leaf_elements_mut();
-                for (l, elem) in leaves.iter().zip(elems.iter_mut()) {
+                for ((l, elem), checksum) in leaves.iter().zip(elems.iter_mut()).zip(&checksums) {
                     elem.node_type = l.node_type();
 
                     let key = l.key();
@@ -224,6 +292,11 @@ impl Page {
 
                     data.push(key);
                     data.push(value);
+                    if let Some(checksum) = checksum {
+                        elem.node_type |= LeafElement::FLAG_CHECKSUM;
+                        data_size += CHECKSUM_SIZE;
+                        data.push(&checksum[..]);
+                    }
                 }
             }
         };
@@ -257,13 +330,49 @@ impl BranchElement {
 
 #[repr(C)]
 pub(crate) struct LeafElement {
-    pub(crate) node_type: NodeType,
+    // The high bit is set when a checksum follows the value
+    node_type: NodeType,
     pos: u64,
     key_size: u64,
     value_size: u64,
 }
 
+pub(crate) const CHECKSUM_SIZE: u64 = size_of::<u32>() as u64;
+
 impl LeafElement {
+    const FLAG_CHECKSUM: NodeType = 0x80;
+
+    pub(crate) fn node_type(&self) -> NodeType {
+        self.node_type & !LeafElement::FLAG_CHECKSUM
+    }
+
+    // Whether the key / value pair still matches its checksum, or true if it doesn't have one.
+    pub(crate) fn checksum_ok(&self) -> bool {
+        if self.node_type & LeafElement::FLAG_CHECKSUM == 0 {
+            return true;
+        }
+        let pos = (self.pos + self.key_size + self.value_size) as usize;
+        let stored = unsafe {
+            let start = self as *const LeafElement as *const u8;
+            let buf = std::slice::from_raw_parts(start, pos + CHECKSUM_SIZE as usize);
+            u32::from_be_bytes(buf[pos..].try_into().unwrap())
+        };
+        stored == crc32(&[self.key(), self.value()])
+    }
+
+    pub(crate) fn value_size(&self) -> u64 {
+        self.value_size
+    }
+
+    // The bytes this element takes up in its page, including the header and any checksum.
+    pub(crate) fn size(&self) -> u64 {
+        let checksum = match self.node_type & LeafElement::FLAG_CHECKSUM {
+            0 => 0,
+            _ => CHECKSUM_SIZE,
+        };
+        size_of::<LeafElement>() as u64 + self.key_size + self.value_size + checksum
+    }
+
     pub(crate) fn key<'a>(&self) -> &'a [u8] {
         let pos = self.pos as usize;
         unsafe {
@@ -281,3 +390,44 @@ impl LeafElement {
         }
     }
 }
+
+// The CRC of every byte value, so the checksum is a lookup per byte instead of a loop over its bits.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// CRC-32 (IEEE) of the parts, one after another.
+pub(crate) fn crc32<T: AsRef<[u8]>>(parts: &[T]) -> u32 {
+    let mut crc = !0_u32;
+    for part in parts {
+        for b in part.as_ref() {
+            crc = (crc >> 8) ^ CRC32_TABLE[((crc as u8) ^ *b) as usize];
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32::<&[u8]>(&[]), 0);
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF4_3926);
+        // the parts are checksummed as if they were one slice
+        assert_eq!(crc32(&["1234", "", "56789"]), 0xCBF4_3926);
+    }
+}
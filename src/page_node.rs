@@ -1,6 +1,7 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::{
+    errors::{Error, Result},
     node::{Leaf, Node, NodeData, NodeID},
     page::{Page, PageID},
 };
@@ -37,24 +38,36 @@ impl<'a> PageNode<'a> {
         }
     }
 
-    pub fn index_page(&self, index: usize) -> PageID {
+    // Pages at num_pages or past it aren't part of the database.
+    pub fn index_page(&self, index: usize, num_pages: PageID) -> Result<PageID> {
         match self {
             PageNode::Page(p) => {
                 if index >= p.count as usize {
-                    return 0;
+                    return Ok(0);
                 }
                 match p.page_type {
-                    Page::TYPE_BRANCH => p.branch_elements()[index].page,
+                    Page::TYPE_BRANCH => {
+                        let page = p.branch_elements()[index].page;
+                        // Pages 0 and 1 are the meta pages, so the branch is corrupt.
+                        // Following it would read a meta page as part of the tree, or read past the end of the file.
+                        if page <= 1 || page >= num_pages {
+                            return Err(Error::InvalidDB(format!(
+                                "Branch page {} points to out of bounds page {}",
+                                p.id, page
+                            )));
+                        }
+                        Ok(page)
+                    }
                     _ => panic!("INVALID PAGE TYPE FOR INDEX_PAGE"),
                 }
             }
             PageNode::Node(n) => {
                 let n = n.borrow();
                 if index >= n.data.len() {
-                    return 0;
+                    return Ok(0);
                 }
                 match &n.data {
-                    NodeData::Branches(b) => b[index].page,
+                    NodeData::Branches(b) => Ok(b[index].page),
                     _ => panic!("INVALID NODE TYPE FOR INDEX_PAGE"),
                 }
             }
@@ -62,6 +75,15 @@ impl<'a> PageNode<'a> {
     }
 
     pub fn index(&self, key: &[u8]) -> (usize, bool) {
+        match self.insertion_index(key) {
+            (i, true) => (i, true),
+            // we didn't find the element, so point at the element just "before" the missing element
+            (i, false) => (i.saturating_sub(1), false),
+        }
+    }
+
+    // Returns the index where the key is, or would be inserted, and whether it was found.
+    pub fn insertion_index(&self, key: &[u8]) -> (usize, bool) {
         let result = match self {
             PageNode::Page(p) => match p.page_type {
                 Page::TYPE_LEAF => p.leaf_elements().binary_search_by_key(&key, |e| e.key()),
@@ -75,11 +97,40 @@ impl<'a> PageNode<'a> {
         };
         match result {
             Ok(i) => (i, true),
-            // we didn't find the element, so point at the element just "before" the missing element
-            Err(mut i) => {
-                i = i.saturating_sub(1);
-                (i, false)
-            }
+            Err(i) => (i, false),
+        }
+    }
+
+    // Whether the element at index still matches its checksum.
+    // Nodes were built in memory or already read from their pages, so there's nothing to check.
+    pub fn checksum_ok(&self, index: usize) -> bool {
+        match self {
+            PageNode::Page(p) => match p.leaf_elements().get(index) {
+                Some(leaf) => leaf.checksum_ok(),
+                None => true,
+            },
+            PageNode::Node(_) => true,
+        }
+    }
+
+    // The length of the value at index, without making a slice of it, or None if it's a bucket.
+    pub fn value_len(&self, index: usize) -> Option<u64> {
+        match self {
+            PageNode::Page(p) => match p.page_type {
+                Page::TYPE_LEAF => p
+                    .leaf_elements()
+                    .get(index)
+                    .filter(|l| l.node_type() == Node::TYPE_DATA)
+                    .map(|l| l.value_size()),
+                _ => panic!("INVALID PAGE TYPE FOR VALUE_LEN"),
+            },
+            PageNode::Node(n) => match &n.borrow().data {
+                NodeData::Leaves(l) => match l.get(index) {
+                    Some(Leaf::Kv(_, v)) => Some(v.size() as u64),
+                    _ => None,
+                },
+                _ => panic!("INVALID NODE TYPE FOR VALUE_LEN"),
+            },
         }
     }
 
@@ -1,29 +1,33 @@
 use std::{
     cell::RefCell,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{Seek, SeekFrom, Write},
     marker::PhantomData,
+    mem::size_of,
     rc::Rc,
-    sync::{MutexGuard, RwLockReadGuard},
+    sync::{atomic::Ordering, Arc, MutexGuard, RwLockReadGuard},
 };
 
 use crate::{
-    bucket::{Bucket, BucketMeta, InnerBucket},
+    bucket::{Bucket, BucketMeta, Difference, InnerBucket},
     bytes::ToBytes,
     cursor::ToBuckets,
-    db::{DB, MIN_ALLOC_SIZE},
+    db::{
+        meta_page_buf, sync_ranges, write_meta, CommitEvent, SyncMode, DB, MIN_ALLOC_SIZE, VERSION,
+    },
     errors::{Error, Result},
-    freelist::TxFreelist,
+    freelist::{FirstFit, Freelist, TxFreelist},
     meta::Meta,
     node::Node,
-    page::{Page, PageID, Pages},
-    BucketName,
+    page::{LeafElement, Page, PageID, Pages},
+    wal, BucketName, ChangeKind, KVPair,
 };
 
 pub(crate) enum TxLock<'tx> {
     Rw(MutexGuard<'tx, File>),
-    Ro(RwLockReadGuard<'tx, ()>),
+    // The guard is only held to keep the mmap from being swapped out underneath us.
+    Ro(#[allow(dead_code)] RwLockReadGuard<'tx, ()>),
 }
 
 impl<'tx> TxLock<'tx> {
@@ -104,39 +108,69 @@ pub(crate) struct TxInner<'tx> {
     pub(crate) root: Rc<RefCell<InnerBucket<'tx>>>,
     pub(crate) meta: Meta,
     pub(crate) freelist: Rc<RefCell<TxFreelist>>,
-    pages: Pages,
+    pub(crate) pages: Pages,
+    // Identifies a read-only transaction's entry in the database's open_ro_txs
+    reader: u64,
+    // Pages written to the file so far, to sync them on commit and keep a history of them for incremental backups
+    written_pages: Vec<PageID>,
+    // Whether the file was grown while writing pages, so its new size needs to be synced too
+    resized: bool,
+    // Maps replaced by flush_dirty, kept alive since data from this transaction may still point into them
+    retired_pages: Vec<Pages>,
     num_freelist_pages: u64,
 }
 
 impl<'tx> Tx<'tx> {
     pub(crate) fn new(db: &'tx DB, writable: bool) -> Result<Tx<'tx>> {
         let lock = match writable {
-            true => TxLock::Rw(db.inner.file.lock()?),
+            true => TxLock::Rw(db.inner.lock_writer()?),
             false => TxLock::Ro(db.inner.mmap_lock.read()?),
         };
-        let mut freelist = db.inner.freelist.lock()?.clone();
-        let mut meta = db.inner.meta()?;
-        debug_assert!(meta.valid());
+        // Read-only transactions never allocate pages, so they don't need the shared freelist.
+        // Leaving it alone keeps readers from contending with a commit that's updating it.
+        let mut freelist = match writable {
+            true => db.inner.freelist.lock()?.clone(),
+            false => Freelist::with_allocator(Box::new(FirstFit::default())),
+        };
+        let mut meta;
+        let mut reader = 0;
         {
             let mut open_ro_txs = db.inner.open_ro_txs.lock().unwrap();
+            // A reader has to read the meta and register itself in one step, otherwise a writer could
+            // start in between, not see the reader, and reuse pages the reader is about to use.
+            meta = db.inner.meta()?;
+            debug_assert!(meta.valid());
             if writable {
                 meta.tx_id += 1;
-                if open_ro_txs.len() > 0 {
-                    freelist.release(open_ro_txs[0]);
-                } else {
-                    freelist.release(meta.tx_id);
-                }
+                let oldest_tx_id = match open_ro_txs.first() {
+                    Some((tx_id, _)) => *tx_id,
+                    None => meta.tx_id,
+                };
+                // hold on to recently freed pages if we've been asked to keep some history
+                let history = meta.tx_id.saturating_sub(db.inner.flags.keep_freed_for);
+                freelist.release(oldest_tx_id.min(history));
             } else {
-                open_ro_txs.push(meta.tx_id);
-                open_ro_txs.sort_unstable();
+                reader = db.inner.next_reader.fetch_add(1, Ordering::Relaxed);
+                let index = open_ro_txs.partition_point(|(tx_id, _)| *tx_id <= meta.tx_id);
+                open_ro_txs.insert(index, (meta.tx_id, reader));
             }
         }
-        let freelist = Rc::new(RefCell::new(TxFreelist::new(meta.clone(), freelist)));
+        let freelist = Rc::new(RefCell::new(TxFreelist::new(
+            meta.clone(),
+            freelist,
+            db.inner.flags.max_tx_memory,
+            db.inner.flags.zero_on_free,
+        )));
 
-        let data = db.inner.data.lock()?.clone();
-        let pages = Pages::new(data, db.inner.pagesize);
+        let mut pages = db.inner.pages()?;
+        if db.inner.flags.track_page_access {
+            pages.accessed = Some(Rc::default());
+        }
+        pages.value_checksums = db.inner.flags.value_checksums;
+        pages.num_pages = meta.num_pages;
         let num_freelist_pages = pages.page(meta.freelist_page).overflow + 1;
-        let root = InnerBucket::from_meta(meta.root, pages.clone());
+        let mut root = InnerBucket::from_meta(meta.root.into(), pages.clone());
+        root.utf8_keys = db.inner.flags.utf8_keys;
         let root = Rc::new(RefCell::new(root));
         let inner = TxInner {
             db,
@@ -146,6 +180,10 @@ impl<'tx> Tx<'tx> {
             freelist,
             num_freelist_pages,
             pages,
+            reader,
+            written_pages: Vec::new(),
+            resized: false,
+            retired_pages: Vec::new(),
         };
         Ok(Tx {
             inner: RefCell::new(inner),
@@ -220,13 +258,44 @@ impl<'tx> Tx<'tx> {
         })
     }
 
+    /// Returns a reference to a nested bucket by following a path of bucket names from the root.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if the path is empty
+    /// or any bucket along the path does not exist,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if any name along the path is not a bucket.
+    pub fn get_bucket_path<'b, T: AsRef<[u8]>>(&'b self, path: &[T]) -> Result<Bucket<'b, 'tx>> {
+        let (first, rest) = path.split_first().ok_or(Error::BucketMissing)?;
+        self.get_bucket(first.as_ref().to_vec())?
+            .get_bucket_path(rest)
+    }
+
+    /// Returns a reference to a nested bucket by following a path of bucket names from the root,
+    /// creating any buckets along the path that do not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if the path is empty,
+    /// an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if any name along the path is not a bucket,
+    /// or a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction.
+    pub fn get_or_create_bucket_path<'b, T: AsRef<[u8]>>(
+        &'b self,
+        path: &[T],
+    ) -> Result<Bucket<'b, 'tx>> {
+        let (first, rest) = path.split_first().ok_or(Error::BucketMissing)?;
+        self.get_or_create_bucket(first.as_ref().to_vec())?
+            .get_or_create_bucket_path(rest)
+    }
+
     /// Deletes an existing root-level bucket with the given name
     ///
     /// # Errors
     ///
     /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if the bucket does not exist,
     /// an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the key exists but is not a bucket,
-    /// or a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction.
+    /// a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction,
+    /// or an [`InvalidDB`](enum.Error.html#variant.InvalidDB) error if the bucket's pages contain a cycle.
     pub fn delete_bucket<T: ToBytes<'tx>>(&self, key: T) -> Result<()> {
         let tx = self.inner.borrow();
         if !tx.lock.writable() {
@@ -240,14 +309,299 @@ impl<'tx> Tx<'tx> {
 
     /// Iterator over the root level buckets
     pub fn buckets<'b>(&'b self) -> impl Iterator<Item = (BucketName<'b, 'tx>, Bucket<'b, 'tx>)> {
+        self.root_bucket().cursor().to_buckets()
+    }
+
+    /// Iterator over the key / value pairs of several root level buckets, merged together in sorted order.
+    ///
+    /// Each item includes the index in `names` of the bucket it came from.
+    /// If the same key is in more than one bucket, each of them is returned, in the order the buckets were given.
+    /// This is useful for reading data that has been partitioned across buckets as if it were a single bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if any of the buckets do not exist,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if any of the names are not buckets.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(false)?;
+    ///
+    /// for (i, kv) in tx.merge_buckets(&["logs-2023", "logs-2024"])? {
+    ///     println!("{:?} from partition {}", kv.key(), i);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn merge_buckets<'b, T: AsRef<[u8]>>(
+        &'b self,
+        names: &[T],
+    ) -> Result<impl Iterator<Item = (usize, KVPair<'b, 'tx>)>> {
+        let mut sources = Vec::with_capacity(names.len());
+        for name in names {
+            let b = self.get_bucket(name.as_ref().to_vec())?;
+            sources.push(b.kv_pairs().peekable());
+        }
+        Ok(std::iter::from_fn(move || {
+            // Find the source with the smallest next key, keeping the first one on ties
+            let mut next: Option<(usize, &KVPair)> = None;
+            for (i, source) in sources.iter_mut().enumerate() {
+                if let Some(kv) = source.peek() {
+                    if next.map_or(true, |(_, n)| kv.key() < n.key()) {
+                        next = Some((i, kv));
+                    }
+                }
+            }
+            let (i, _) = next?;
+            sources[i].next().map(|kv| (i, kv))
+        }))
+    }
+
+    fn root_bucket<'b>(&'b self) -> Bucket<'b, 'tx> {
         let tx = self.inner.borrow();
-        let bucket = Bucket {
+        Bucket {
             inner: tx.root.clone(),
             freelist: tx.freelist.clone(),
             writable: tx.lock.writable(),
             _phantom: PhantomData,
+        }
+    }
+
+    // Compares every bucket and key / value pair in both transactions.
+    pub(crate) fn logically_eq(&self, other: &Tx) -> Result<bool> {
+        self.root_bucket().logically_eq(&other.root_bucket())
+    }
+
+    // Copies every bucket into a writable transaction on another database, along with the user version and metadata.
+    pub(crate) fn copy_into(&self, dest: &Tx) -> Result<()> {
+        self.root_bucket().copy_into(&dest.root_bucket())?;
+        dest.inner.borrow_mut().meta.user_version = self.inner.borrow().meta.user_version;
+        dest.set_metadata(&self.metadata())
+    }
+
+    // Returns the blob stored with DB::set_metadata, which is empty if there isn't one.
+    pub(crate) fn metadata(&self) -> Vec<u8> {
+        let tx = self.inner.borrow();
+        match tx.meta.user_meta_page {
+            0 => Vec::new(),
+            page_id => tx.pages.page(page_id).user_meta().to_vec(),
+        }
+    }
+
+    // Replaces the metadata blob with a new page, or removes it if the blob is empty.
+    pub(crate) fn set_metadata(&self, bytes: &[u8]) -> Result<()> {
+        if !self.writable() {
+            return Err(Error::ReadOnlyTx);
+        }
+        let mut tx = self.inner.borrow_mut();
+        let header_size = size_of::<Page>() as u64;
+        if bytes.len() as u64 > tx.meta.pagesize - header_size {
+            return Err(Error::MetadataTooLarge);
+        }
+        let freelist = tx.freelist.clone();
+        let mut freelist = freelist.borrow_mut();
+        if tx.meta.user_meta_page != 0 {
+            freelist.free(tx.meta.user_meta_page, 1);
+            tx.meta.user_meta_page = 0;
+        }
+        if !bytes.is_empty() {
+            let page = freelist.allocate(header_size + bytes.len() as u64)?;
+            page.page_type = Page::TYPE_USER_META;
+            page.count = bytes.len() as u64;
+            page.user_meta_mut().copy_from_slice(bytes);
+            tx.meta.user_meta_page = page.id;
+        }
+        Ok(())
+    }
+
+    // Finds every key / value pair that differs between this transaction and a newer one.
+    pub(crate) fn diff(&self, newer: &Tx) -> Result<Vec<(Vec<Vec<u8>>, ChangeKind)>> {
+        let mut changes = Vec::new();
+        self.root_bucket().diff(
+            &newer.root_bucket(),
+            &mut Vec::new(),
+            &mut |path, difference| {
+                match difference {
+                    Difference::Pair(key, _, kind) => {
+                        let mut key_path = path.to_vec();
+                        key_path.push(key.to_vec());
+                        changes.push((key_path, kind));
+                    }
+                    // Buckets don't have changes of their own, only the pairs in them do
+                    Difference::Bucket(..) => (),
+                }
+                Ok(true)
+            },
+        )?;
+        Ok(changes)
+    }
+
+    /// Returns the number of distinct pages this transaction has read from the file.
+    ///
+    /// Each page is only counted once, however many times it is read, so this is a rough measure
+    /// of how much of the file a transaction needed, and how many page faults it could have caused.
+    /// Pages are only tracked when the database was opened with
+    /// [`OpenOptions::track_page_access`](struct.OpenOptions.html#method.track_page_access), otherwise this is always zero.
+    pub fn pages_accessed(&self) -> usize {
+        let tx = self.inner.borrow();
+        tx.pages
+            .accessed
+            .as_ref()
+            .map_or(0, |accessed| accessed.borrow().len())
+    }
+
+    /// Returns the number of bytes allocated for the dirty pages buffered by this transaction.
+    ///
+    /// Pages are only buffered as nodes are spilled, so this will stay at zero
+    /// for read-only transactions. Use it to keep an eye on how much memory
+    /// a large writable transaction is holding onto before it is committed.
+    /// This is the memory limited by [`OpenOptions::max_tx_memory`](struct.OpenOptions.html#method.max_tx_memory),
+    /// and [`flush_dirty`](#method.flush_dirty) writes the buffered pages out so the space can be reused,
+    /// so it is also how to checkpoint the arena partway through a very large transaction.
+    pub fn arena_bytes(&self) -> usize {
+        let tx = self.inner.borrow();
+        let freelist = tx.freelist.borrow();
+        freelist.arena.allocated_bytes()
+    }
+
+    /// Writes the page layout of every bucket to `w` as a [DOT](https://graphviz.org/doc/info/lang.html) graph.
+    ///
+    /// Each branch and leaf page is a node, labelled with its page ID, the number of elements on it
+    /// and the range of keys it holds. Edges go from branch pages to their children, and from the leaf page
+    /// holding a nested bucket to that bucket's root page, labelled with the bucket's name.
+    /// Only pages that are already in the file are drawn, so changes made in a writable transaction
+    /// don't show up until they are committed or flushed with [`flush_dirty`](#method.flush_dirty).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::File;
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// // render with `dot -Tsvg my.dot -o my.svg`
+    /// tx.write_graph(&mut File::create("my.dot")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_graph<W: Write>(&self, w: &mut W) -> Result<()> {
+        let tx = self.inner.borrow();
+        writeln!(w, "digraph jammdb {{")?;
+        writeln!(w, "  node [shape=box];")?;
+        writeln!(w, "  root [label=\"root bucket\", shape=ellipse];")?;
+        writeln!(w, "  root -> page_{};", tx.meta.root.root_page)?;
+        let mut visited = HashSet::new();
+        let mut page_stack = vec![tx.meta.root.root_page];
+        while let Some(page_id) = page_stack.pop() {
+            // A broken file could point at the same page twice, so only draw each one once
+            if !visited.insert(page_id) {
+                continue;
+            }
+            let page = tx.pages.page(page_id);
+            let (kind, first, last) = match page.page_type {
+                Page::TYPE_BRANCH => {
+                    let elements = page.branch_elements();
+                    for b in elements {
+                        writeln!(w, "  page_{} -> page_{};", page_id, b.page)?;
+                        page_stack.push(b.page);
+                    }
+                    let first = elements.first().map(|b| b.key());
+                    ("branch", first, elements.last().map(|b| b.key()))
+                }
+                Page::TYPE_LEAF => {
+                    let elements = page.leaf_elements();
+                    for leaf in elements {
+                        if leaf.node_type() == Node::TYPE_BUCKET {
+                            let meta: BucketMeta = leaf.value().into();
+                            writeln!(
+                                w,
+                                "  page_{} -> page_{} [label=\"{}\", style=dashed];",
+                                page_id,
+                                meta.root_page,
+                                dot_escape(&String::from_utf8_lossy(leaf.key())),
+                            )?;
+                            page_stack.push(meta.root_page);
+                        }
+                    }
+                    let first = elements.first().map(|l| l.key());
+                    ("leaf", first, elements.last().map(|l| l.key()))
+                }
+                _ => ("unknown", None, None),
+            };
+            let mut label = format!("{} page {}", kind, page_id);
+            if page.overflow > 0 {
+                label.push_str(&format!(" (+{} overflow)", page.overflow));
+            }
+            label = dot_escape(&label);
+            label.push_str(&format!("\\n{} elements", page.count));
+            if let (Some(first), Some(last)) = (first, last) {
+                let range = format!(
+                    "{:?} to {:?}",
+                    String::from_utf8_lossy(first),
+                    String::from_utf8_lossy(last)
+                );
+                label.push_str("\\n");
+                label.push_str(&dot_escape(&range));
+            }
+            writeln!(w, "  page_{} [label=\"{}\"];", page_id, label)?;
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+
+    /// Writes a copy of the whole database, as of when this transaction started, to `w`,
+    /// returning the number of bytes written.
+    ///
+    /// The copy is a complete database file that can be opened with [`DB::open`](struct.DB.html#method.open),
+    /// so this makes a consistent backup of a live database without stopping other transactions.
+    /// Every page is copied from the file as it is, including free ones, so the copy is about as big as the file.
+    /// Writers can keep committing while the copy is written, but the pages this transaction uses
+    /// can't be reused until it is dropped, so the file may grow during a long backup.
+    /// A writable transaction copies the last committed state, without any of its own changes.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error if writing to `w` fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::File;
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let tx = db.tx(false)?;
+    /// tx.write_to(File::create("my.db.backup")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<u64> {
+        let tx = self.inner.borrow();
+        let meta = match tx.lock.writable() {
+            true => tx.db.inner.meta()?,
+            false => tx.meta.clone(),
         };
-        bucket.cursor().to_buckets()
+        let pagesize = meta.pagesize;
+        // Both meta pages hold this transaction's meta, so the copy doesn't have a newer one from the live file
+        for meta_page_id in 0..2 {
+            w.write_all(&meta_page_buf(&meta, meta_page_id))?;
+        }
+        let start = (2 * pagesize) as usize;
+        let end = (meta.num_pages * pagesize) as usize;
+        w.write_all(&tx.pages.data[start..end])?;
+        w.flush()?;
+        Ok(end as u64)
     }
 
     /// Writes the changes made in the writeable transaction to the underlying file.
@@ -256,7 +610,72 @@ impl<'tx> Tx<'tx> {
     ///
     /// Will return an [`IOError`](enum.Error.html#variant.IOError) error if there are any io errors while writing to disk,
     /// or a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction.
+    /// If the database file was written to by something else while this transaction was open,
+    /// a [`Conflict`](enum.Error.html#variant.Conflict) error is returned and nothing is written.
     pub fn commit(self) -> Result<()> {
+        if !self.writable() {
+            return Err(Error::ReadOnlyTx);
+        }
+        let (db, event) = {
+            let mut tx = self.inner.borrow_mut();
+            let freelist = tx.freelist.clone();
+            let mut freelist = freelist.borrow_mut();
+            let meta = {
+                let mut root = tx.root.borrow_mut();
+                root.rebalance(&mut freelist, tx.db.inner.flags.merge_threshold())?;
+                root.spill(&mut freelist)?
+            };
+            tx.meta.root = meta.into();
+            (tx.db, tx.write_data(&mut freelist)?)
+        };
+        // Release the write lock before calling any hooks
+        drop(self);
+        let hooks = db.inner.commit_hooks.lock()?.clone();
+        for hook in hooks.iter() {
+            hook(event);
+        }
+        Ok(())
+    }
+
+    /// Writes all of the transaction's changes so far to the database file, without committing them.
+    ///
+    /// Normally every page modified in a writable transaction is kept in memory until [`commit`](#method.commit) is called.
+    /// For a very large transaction, like a bulk load, you can call this periodically to write out the pages
+    /// built so far and free that memory. The pages are written to space that isn't in use,
+    /// so nothing is visible to other transactions until the transaction is committed,
+    /// and dropping the transaction without committing still discards everything.
+    ///
+    /// Any modified data that is written out is read back from the file if it is used again later in the transaction.
+    /// Cursors and iterators created before calling this must not be used afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if called on a read-only transaction,
+    /// a [`DatabaseFull`](enum.Error.html#variant.DatabaseFull) error if the file would grow past its maximum size,
+    /// or an [`Io`](enum.Error.html#variant.Io) error if the pages could not be written.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB};
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let db = DB::open("my.db")?;
+    /// let mut tx = db.tx(true)?;
+    /// let bucket = tx.get_or_create_bucket("logs")?;
+    ///
+    /// for i in 0..10_000_000_u64 {
+    ///     bucket.put(i.to_be_bytes(), "some data")?;
+    ///     if i % 100_000 == 0 {
+    ///         tx.flush_dirty()?;
+    ///     }
+    /// }
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn flush_dirty(&self) -> Result<()> {
         if !self.writable() {
             return Err(Error::ReadOnlyTx);
         }
@@ -265,21 +684,151 @@ impl<'tx> Tx<'tx> {
         let mut freelist = freelist.borrow_mut();
         let meta = {
             let mut root = tx.root.borrow_mut();
-            root.rebalance(&mut freelist)?;
+            root.rebalance(&mut freelist, tx.db.inner.flags.merge_threshold())?;
             root.spill(&mut freelist)?
         };
-        tx.meta.root = meta;
-        tx.write_data(&mut freelist)
+        tx.meta.root = meta.into();
+        tx.meta.num_pages = freelist.meta.num_pages;
+        let old_pages = tx.pages.clone();
+        tx.write_pages(&freelist)?;
+        if !Arc::ptr_eq(&old_pages.data, &tx.pages.data) {
+            tx.retired_pages.push(old_pages);
+        }
+        // Everything in the arena is on disk now, so we can start over
+        freelist.pages.clear();
+        freelist.arena.reset();
+        let pages = tx.pages.clone();
+        tx.root.borrow_mut().reload(&pages);
+        Ok(())
     }
 
     pub(crate) fn check(&self) -> Result<()> {
         self.inner.borrow().check()
     }
+
+    // Pages that were committed but are neither reachable nor free.
+    pub(crate) fn leaked_pages(&self) -> Result<Vec<PageID>> {
+        let tx = self.inner.borrow();
+        unreachable_pages(&tx.meta, &tx.pages)
+    }
+
+    // Counts the key / value pairs in every committed bucket, straight from the page headers.
+    pub(crate) fn count_all_kv(&self) -> Result<u64> {
+        let mut count = 0;
+        self.for_each_committed_kv(|_| count += 1)?;
+        Ok(count)
+    }
+
+    // Calls f with every key / value pair in every committed bucket, straight from the page headers.
+    pub(crate) fn for_each_committed_kv<F: FnMut(&LeafElement)>(&self, mut f: F) -> Result<()> {
+        let tx = self.inner.borrow();
+        let mut visited = HashSet::new();
+        let mut page_stack = vec![tx.meta.root.root_page];
+        while let Some(page_id) = page_stack.pop() {
+            if !visited.insert(page_id) {
+                return Err(Error::InvalidDB(format!(
+                    "cycle detected at page {}",
+                    page_id
+                )));
+            }
+            // The ids come straight from the file, so don't follow one past the end of it
+            if page_id <= 1 || page_id >= tx.meta.num_pages {
+                return Err(Error::InvalidDB(format!(
+                    "Page {} is out of bounds",
+                    page_id
+                )));
+            }
+            let page = tx.pages.page(page_id);
+            if page_id + page.overflow >= tx.meta.num_pages {
+                return Err(Error::InvalidDB(format!(
+                    "Page {} overflows out of bounds",
+                    page_id
+                )));
+            }
+            match page.page_type {
+                Page::TYPE_BRANCH => page
+                    .branch_elements()
+                    .iter()
+                    .for_each(|b| page_stack.push(b.page)),
+                Page::TYPE_LEAF => {
+                    for leaf in page.leaf_elements() {
+                        if leaf.node_type() == Node::TYPE_BUCKET {
+                            let meta: BucketMeta = leaf.value().into();
+                            page_stack.push(meta.root_page);
+                        } else {
+                            f(leaf);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Escapes text to go inside a quoted DOT string.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
 }
 
 impl<'tx> TxInner<'tx> {
-    fn write_data(&mut self, freelist: &mut TxFreelist) -> Result<()> {
+    // Grows the file if needed, then writes every page allocated so far.
+    // Returns the number of pages and bytes written.
+    fn write_pages(&mut self, freelist: &TxFreelist) -> Result<(u64, u64)> {
+        let pagesize = self.db.inner.pagesize;
+        let (mut pages_written, mut bytes_written) = (0, 0);
         if let TxLock::Rw(file) = &mut self.lock {
+            // Grow the file, if needed
+            let required_size = self.meta.num_pages * pagesize;
+            let current_size = file.metadata()?.len();
+            if current_size < required_size {
+                let size_diff = required_size - current_size;
+                let alloc_size = ((size_diff / MIN_ALLOC_SIZE) + 1) * MIN_ALLOC_SIZE;
+                let mut new_size = current_size + alloc_size;
+                if let Some(max_file_size) = self.db.inner.flags.max_file_size {
+                    if required_size > max_file_size {
+                        return Err(Error::DatabaseFull);
+                    }
+                    new_size = new_size.min(max_file_size);
+                }
+                let mut pages = self.db.inner.resize(file, new_size)?;
+                pages.accessed = self.pages.accessed.clone();
+                pages.value_checksums = self.pages.value_checksums;
+                self.pages = pages;
+                self.resized = true;
+            }
+            self.pages.num_pages = freelist.meta.num_pages;
+
+            // freelist.pages is a BTreeMap so we're writing the pages in order to minmize
+            // the random seeks.
+            for (page_id, (ptr, size)) in freelist.pages.iter() {
+                let buf = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), *size) };
+                file.seek(SeekFrom::Start(pagesize * page_id))?;
+                file.write_all(buf)?;
+                let num_pages = (*size as u64 + pagesize - 1) / pagesize;
+                self.written_pages.extend(*page_id..page_id + num_pages);
+                pages_written += num_pages;
+                bytes_written += *size as u64;
+            }
+        }
+        Ok((pages_written, bytes_written))
+    }
+
+    fn write_data(&mut self, freelist: &mut TxFreelist) -> Result<CommitEvent> {
+        let pagesize = self.db.inner.pagesize;
+        // the meta page is always written
+        let mut event = CommitEvent {
+            tx_id: self.meta.tx_id,
+            pages_written: 1,
+            bytes_written: pagesize,
+        };
+        if self.lock.writable() {
+            // We hold the file lock, so nobody else should have committed since we started.
+            // If they did, writing our pages could clobber theirs.
+            if self.db.inner.meta()?.tx_id + 1 != self.meta.tx_id {
+                return Err(Error::Conflict);
+            }
             // Write the freelist to a new page
             {
                 freelist.free(self.meta.freelist_page, self.num_freelist_pages);
@@ -292,197 +841,362 @@ impl<'tx> TxInner<'tx> {
                 page.freelist_mut()
                     .copy_from_slice(free_page_ids.as_slice());
             }
-
-            // Update our num_pages from the freelist now that we've allocated everything
-            self.meta.num_pages = freelist.meta.num_pages;
-
-            // Grow the file, if needed
-            let required_size = self.meta.num_pages * self.db.inner.pagesize;
-            let current_size = file.metadata()?.len();
-            if current_size < required_size {
-                let size_diff = required_size - current_size;
-                let alloc_size = ((size_diff / MIN_ALLOC_SIZE) + 1) * MIN_ALLOC_SIZE;
-                let data = self.db.inner.resize(file, current_size + alloc_size)?;
-                self.pages = Pages::new(data, self.db.inner.pagesize);
-            }
-
-            // write the data to the file
-            {
-                // freelist.pages is a BTreeMap so we're writing the pages in order to minmize
-                // the random seeks.
-                for (page_id, (ptr, size)) in freelist.pages.iter() {
-                    let buf = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), *size) };
-                    file.seek(SeekFrom::Start(self.db.inner.pagesize * page_id))?;
-                    file.write_all(buf)?;
-                }
-            }
         }
+        // Update our num_pages from the freelist now that we've allocated everything
+        self.meta.num_pages = freelist.meta.num_pages;
+        // Move the file to a newer format if this commit uses anything older releases can't read
+        self.meta.version = self.meta.version.max(freelist.meta.version);
+        // (older releases misread the leaves written with checksums, since the flag shares the node type)
+        if self.meta.user_version != 0
+            || self.meta.user_meta_page != 0
+            || self.db.inner.flags.value_checksums
+        {
+            self.meta.version = self.meta.version.max(VERSION);
+        }
+        let (pages_written, bytes_written) = self.write_pages(freelist)?;
+        event.pages_written += pages_written;
+        event.bytes_written += bytes_written;
         if self.db.inner.flags.strict_mode {
             self.check()?;
         }
+        let unsynced = self.db.inner.unsynced_commits.load(Ordering::Acquire);
+        let sync = match self.db.inner.flags.sync_mode {
+            SyncMode::Always => true,
+            SyncMode::Never => false,
+            SyncMode::EveryNCommits(n) => unsynced + 1 >= u64::from(n),
+        };
+        if let Some(wal) = &self.db.inner.wal {
+            let records = self.changes()?;
+            let mut wal = wal.lock()?;
+            if !records.is_empty() {
+                wal.append(self.meta.tx_id, &records)?;
+            }
+            // The log is synced whenever the database is, before the commit it describes
+            if sync {
+                wal.sync()?;
+            }
+        }
+        // The meta page this commit is based on, if it has to be moved to the new format along with this one
+        let upgrade = Some(self.db.inner.meta()?).filter(|meta| meta.version < self.meta.version);
         if let TxLock::Rw(file) = &mut self.lock {
+            // Hold the history until this commit is in it, so a backup can't see the commit without its pages
+            let mut history = self.db.inner.page_history.lock()?;
             // write meta page to file
-            {
-                let mut buf = vec![0; self.db.inner.pagesize as usize];
-
-                #[allow(clippy::cast_ptr_alignment)]
-                let page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
-                let meta_page_id = u64::from(self.meta.meta_page == 0);
-                page.id = meta_page_id;
-                page.page_type = Page::TYPE_META;
-                let m = page.meta_mut();
-                m.meta_page = meta_page_id as u32;
-                m.magic = self.meta.magic;
-                m.version = self.meta.version;
-                m.pagesize = self.meta.pagesize;
-                m.root = self.meta.root;
-                m.num_pages = self.meta.num_pages;
-                m.freelist_page = self.meta.freelist_page;
-                m.tx_id = self.meta.tx_id;
-                m.hash = m.hash_self();
+            let meta_page_id = u64::from(self.meta.meta_page == 0);
+            write_meta(file, &self.meta, meta_page_id)?;
 
-                file.seek(SeekFrom::Start(self.db.inner.pagesize * meta_page_id))?;
-                file.write_all(buf.as_slice())?;
+            file.flush()?;
+            if !sync {
+                self.db
+                    .inner
+                    .unsynced_commits
+                    .store(unsynced + 1, Ordering::Release);
+            } else if self.db.inner.flags.sync_file_range && !self.resized && unsynced == 0 {
+                // Every page this transaction wrote, including any written early by flush_dirty
+                let mut page_ids = self.written_pages.clone();
+                page_ids.push(meta_page_id);
+                page_ids.sort_unstable();
+                page_ids.dedup();
+                let mut ranges: Vec<(u64, u64)> = Vec::new();
+                for page_id in page_ids {
+                    let (offset, len) = (page_id * pagesize, pagesize);
+                    match ranges.last_mut() {
+                        // merge pages that are next to each other into one range
+                        Some((last_offset, last_len)) if *last_offset + *last_len == offset => {
+                            *last_len += len
+                        }
+                        _ => ranges.push((offset, len)),
+                    }
+                }
+                sync_ranges(file, &ranges)?;
+            } else {
+                // Earlier commits that weren't synced could have written anywhere, so sync everything
+                file.sync_all()?;
+                self.db.inner.unsynced_commits.store(0, Ordering::Release);
             }
 
-            file.flush()?;
-            file.sync_all()?;
+            // Older releases would ignore the meta page we just wrote since its hash covers more,
+            // and silently open the previous commit instead. Upgrade that one too, so they refuse the file.
+            // When this commit is synced, the new meta page is already on disk by now, so one of them is always valid.
+            // Otherwise it's left to the sync mode like everything else this commit wrote.
+            if let Some(mut previous) = upgrade {
+                previous.version = self.meta.version;
+                write_meta(file, &previous, previous.meta_page as u64)?;
+                file.flush()?;
+                if sync {
+                    file.sync_all()?;
+                }
+            }
 
+            if let Some(wal) = &self.db.inner.wal {
+                wal.lock()?.committed()?;
+            }
             let mut lock = self.db.inner.freelist.lock()?;
             *lock = freelist.inner.clone();
-            Ok(())
+            // Keeping any history holds the pages this commit freed until the next one commits,
+            // so they stay intact for the previous meta page
+            self.db
+                .inner
+                .previous_intact
+                .store(self.db.inner.flags.keep_freed_for >= 1, Ordering::Release);
+            history.record(
+                self.meta.tx_id,
+                std::mem::take(&mut self.written_pages),
+                self.db.inner.flags.keep_freed_for,
+            );
+            Ok(event)
         } else {
             unreachable!()
         }
     }
 
     fn check(&self) -> Result<()> {
-        let mut unused_pages: HashSet<PageID> = (2..self.meta.num_pages).collect();
-        let mut page_stack = Vec::new();
-        page_stack.push(self.meta.root.root_page);
-        page_stack.push(self.meta.freelist_page);
-        while let Some(page_id) = page_stack.pop() {
-            // Make sure this page hasn't already been used
-            if !unused_pages.remove(&page_id) {
-                return Err(Error::InvalidDB(format!(
-                    "Page {} missing from unused_pages",
-                    page_id,
-                )));
-            }
-            let page = self.pages.page(page_id);
-            // Make sure none of the overflow pages have been used
-            for i in 0..page.overflow {
-                let page_id = page_id + i + 1;
-                if !unused_pages.remove(&page_id) {
-                    return Err(Error::InvalidDB(format!(
-                        "Overflow Page {} from missing from unused_pages",
-                        page_id,
-                    )));
+        check_meta(&self.meta, &self.pages)
+    }
+
+    // Finds the changes this transaction is committing, once all of its pages have been written to the file.
+    fn changes(&self) -> Result<Vec<u8>> {
+        let bucket = |meta: BucketMeta| Bucket {
+            inner: Rc::new(RefCell::new(InnerBucket::from_meta(
+                meta,
+                self.pages.clone(),
+            ))),
+            freelist: self.freelist.clone(),
+            writable: false,
+            _phantom: PhantomData,
+        };
+        let old = bucket(self.db.inner.meta()?.root.into());
+        let new = bucket(self.meta.root.into());
+        wal::changes(&old, &new)
+    }
+}
+
+// Walks every page reachable from the meta's root bucket and freelist,
+// making sure each page in the database is used exactly once.
+pub(crate) fn check_meta(meta: &Meta, pages: &Pages) -> Result<()> {
+    // Once we've explored all of the pages we can reach from the root bucket and freelist,
+    // If there are any pages left then we have an invalid database.
+    let unused_pages = unreachable_pages(meta, pages)?;
+    if !unused_pages.is_empty() {
+        return Err(Error::InvalidDB(format!(
+            "Unreachable pages {:?}",
+            unused_pages,
+        )));
+    }
+    Ok(())
+}
+
+// Walks every page reachable from the meta's root bucket and freelist,
+// returning the sorted IDs of the pages that couldn't be reached.
+pub(crate) fn unreachable_pages(meta: &Meta, pages: &Pages) -> Result<Vec<PageID>> {
+    // Keep track of who is using each page, so that if two pages overlap
+    // we can report both of them instead of just the page id.
+    let mut owners: HashMap<PageID, String> = HashMap::new();
+    let mut overlaps: Vec<String> = Vec::new();
+    let result = walk_pages(meta, pages, &mut owners, &mut overlaps);
+    // Overlapping pages tend to cause the other errors, so report them first
+    if !overlaps.is_empty() {
+        return Err(Error::InvalidDB(format!(
+            "Overlapping pages: {}",
+            overlaps.join("; ")
+        )));
+    }
+    result?;
+
+    Ok((2..meta.num_pages)
+        .filter(|page_id| !owners.contains_key(page_id))
+        .collect())
+}
+
+// Records that a page is used by `owner`, returning false if it was already in use.
+fn claim_page(
+    meta: &Meta,
+    owners: &mut HashMap<PageID, String>,
+    overlaps: &mut Vec<String>,
+    page_id: PageID,
+    owner: String,
+) -> Result<bool> {
+    if page_id <= 1 || page_id >= meta.num_pages {
+        return Err(Error::InvalidDB(format!(
+            "Page {} used by {} is out of bounds",
+            page_id, owner,
+        )));
+    }
+    match owners.get(&page_id) {
+        Some(existing) => {
+            overlaps.push(format!(
+                "page {} is used by both {} and {}",
+                page_id, existing, owner,
+            ));
+            Ok(false)
+        }
+        None => {
+            owners.insert(page_id, owner);
+            Ok(true)
+        }
+    }
+}
+
+fn walk_pages(
+    meta: &Meta,
+    pages: &Pages,
+    owners: &mut HashMap<PageID, String>,
+    overlaps: &mut Vec<String>,
+) -> Result<()> {
+    // Each page is pushed along with a description of what pointed to it
+    let mut page_stack: Vec<(PageID, String)> = Vec::new();
+    page_stack.push((meta.root.root_page, String::from("the root bucket")));
+    page_stack.push((meta.freelist_page, String::from("the meta page")));
+    if meta.user_meta_page != 0 {
+        page_stack.push((meta.user_meta_page, String::from("the meta page")));
+    }
+    while let Some((page_id, from)) = page_stack.pop() {
+        // Make sure this page hasn't already been used.
+        // If it has, don't explore it again or we could go around in circles.
+        let owner = format!("the page referenced by {}", from);
+        if !claim_page(meta, owners, overlaps, page_id, owner)? {
+            continue;
+        }
+        let page = pages.page(page_id);
+        // Make sure none of the overflow pages have been used
+        for i in 0..page.overflow {
+            let owner = format!("overflow of page {} referenced by {}", page_id, from);
+            claim_page(meta, owners, overlaps, page_id + i + 1, owner)?;
+        }
+        // Check the page type and explore all possible pages
+        match page.page_type {
+            Page::TYPE_BRANCH => {
+                let mut last: Option<&[u8]> = None;
+                for b in page.branch_elements().iter() {
+                    // Catch bad child pointers here, so they're reported as coming from a branch
+                    if b.page <= 1 || b.page >= meta.num_pages {
+                        return Err(Error::InvalidDB(format!(
+                            "Branch page {} points to out of bounds page {}",
+                            page_id, b.page,
+                        )));
+                    }
+                    // Make sure we visit every branch page
+                    page_stack.push((b.page, format!("branch page {}", page_id)));
+                    // and that the keys are in order
+                    if let Some(last) = last {
+                        if last >= b.key() {
+                            return Err(Error::InvalidDB(format!(
+                                "Branch page {} contains unsorted elements",
+                                page_id
+                            )));
+                        }
+                    }
+                    last = Some(b.key());
                 }
             }
-            // Check the page type and explore all possible pages
-            match page.page_type {
-                Page::TYPE_BRANCH => {
-                    let mut last: Option<&[u8]> = None;
-                    for b in page.branch_elements().iter() {
-                        // Make sure we visit every branch page
-                        page_stack.push(b.page);
-                        // and that the keys are in order
-                        if let Some(last) = last {
-                            if last >= b.key() {
+            Page::TYPE_LEAF => {
+                let mut last: Option<&[u8]> = None;
+                for (i, leaf) in page.leaf_elements().iter().enumerate() {
+                    match leaf.node_type() {
+                        Node::TYPE_BUCKET => {
+                            let bucket_meta: BucketMeta = leaf.value().into();
+                            // Catch bad bucket pointers here, where we still know the bucket's name
+                            if bucket_meta.root_page <= 1 || bucket_meta.root_page >= meta.num_pages
+                            {
                                 return Err(Error::InvalidDB(format!(
-                                    "Branch page {} contains unsorted elements",
-                                    page_id
+                                    "Bucket {:?} on page {} has out of bounds root page {}",
+                                    String::from_utf8_lossy(leaf.key()),
+                                    page_id,
+                                    bucket_meta.root_page,
                                 )));
                             }
+                            // Push all nested bucket pages onto the queue for exploration
+                            page_stack.push((
+                                bucket_meta.root_page,
+                                format!(
+                                    "bucket {:?} on page {}",
+                                    String::from_utf8_lossy(leaf.key()),
+                                    page_id
+                                ),
+                            ));
                         }
-                        last = Some(b.key());
-                    }
-                }
-                Page::TYPE_LEAF => {
-                    let mut last: Option<&[u8]> = None;
-                    for (i, leaf) in page.leaf_elements().iter().enumerate() {
-                        match leaf.node_type {
-                            Node::TYPE_BUCKET => {
-                                let meta: BucketMeta = leaf.value().into();
-                                // Push all nested bucket pages onto the queue for exploration
-                                page_stack.push(meta.root_page);
-                            }
-                            // Ignore data nodes since they don't point to more pages
-                            Node::TYPE_DATA => (),
-                            // If somehow it isn't a bucket or data, that's really bad...
-                            _ => {
-                                return Err(Error::InvalidDB(format!(
-                                    "Page {} index {} has an invalid leaf node type {}",
-                                    page_id, i, leaf.node_type,
-                                )))
+                        // Data nodes don't point to more pages, but may have a checksum to check
+                        Node::TYPE_DATA => {
+                            if !leaf.checksum_ok() {
+                                return Err(Error::ValueCorrupt {
+                                    key: leaf.key().to_vec(),
+                                });
                             }
                         }
-                        // Make sure all leaf elements are in order
-                        if let Some(last) = last {
-                            if last >= leaf.key() {
-                                // let keys: Vec<&[u8]> =
-                                //     page.leaf_elements().iter().map(|l| l.key()).collect();
-                                // let key = leaf.key();
-                                return Err(Error::InvalidDB(format!(
-                                    "Leaf page {} contains unsorted elements",
-                                    page_id
-                                )));
-                            }
+                        // If somehow it isn't a bucket or data, that's really bad...
+                        _ => {
+                            return Err(Error::InvalidDB(format!(
+                                "Page {} index {} has an invalid leaf node type {}",
+                                page_id,
+                                i,
+                                leaf.node_type(),
+                            )))
                         }
-                        last = Some(leaf.key());
                     }
-                }
-                Page::TYPE_FREELIST => {
-                    // Make sure our metadata is pointing at the correct freelist page
-                    // and we didn't somehow find our way to another one.
-                    if page_id != self.meta.freelist_page {
-                        return Err(Error::InvalidDB(format!(
-                            "Found Invalid Freelist Page {}",
-                            page_id
-                        )));
-                    }
-                    // "visit" all freelist pages (we don't actually care what data is in these pages)
-                    for page_id in page.freelist() {
-                        if !unused_pages.remove(page_id) {
+                    // Make sure all leaf elements are in order
+                    if let Some(last) = last {
+                        if last >= leaf.key() {
+                            // let keys: Vec<&[u8]> =
+                            //     page.leaf_elements().iter().map(|l| l.key()).collect();
+                            // let key = leaf.key();
                             return Err(Error::InvalidDB(format!(
-                                "Page {} from freelist missing from unused_pages",
-                                page_id,
+                                "Leaf page {} contains unsorted elements",
+                                page_id
                             )));
                         }
                     }
+                    last = Some(leaf.key());
                 }
-                // There are no other valid page types, so getting here is really bad 😅
-                _ => {
+            }
+            Page::TYPE_FREELIST => {
+                // Make sure our metadata is pointing at the correct freelist page
+                // and we didn't somehow find our way to another one.
+                if page_id != meta.freelist_page {
                     return Err(Error::InvalidDB(format!(
-                        "Invalid page type {} for page {}",
-                        page.page_type, page_id,
-                    )))
+                        "Found Invalid Freelist Page {}",
+                        page_id
+                    )));
+                }
+                // "visit" all freelist pages (we don't actually care what data is in these pages)
+                for free_page in page.freelist() {
+                    let owner = format!("the freelist on page {}", page_id);
+                    claim_page(meta, owners, overlaps, *free_page, owner)?;
                 }
             }
+            Page::TYPE_USER_META => {
+                if page_id != meta.user_meta_page {
+                    return Err(Error::InvalidDB(format!(
+                        "Found Invalid User Meta Page {}",
+                        page_id
+                    )));
+                }
+            }
+            // There are no other valid page types, so getting here is really bad 😅
+            _ => {
+                return Err(Error::InvalidDB(format!(
+                    "Invalid page type {} for page {}",
+                    page.page_type, page_id,
+                )))
+            }
         }
-
-        // Once we've explored all of the pages we can reach from the root bucket and freelist,
-        // If there are any pages left then we have an invalid database.
-        if !unused_pages.is_empty() {
-            return Err(Error::InvalidDB(format!(
-                "Unreachable pages {:?}",
-                unused_pages,
-            )));
-        }
-        Ok(())
     }
+
+    Ok(())
 }
 
 impl<'tx> Drop for TxInner<'tx> {
     fn drop(&mut self) {
         if !self.lock.writable() {
             let mut open_txs = self.db.inner.open_ro_txs.lock().unwrap();
-            let index = match open_txs.binary_search(&self.meta.tx_id) {
+            let index = match open_txs.binary_search(&(self.meta.tx_id, self.reader)) {
                 Ok(i) => i,
-                _ => return, // this shouldn't happen, but isn't the end of the world if it does
+                // it was released with DB::force_release_read_tx
+                _ => return,
             };
             open_txs.remove(index);
+            if open_txs.is_empty() {
+                self.db.inner.readers_drained.notify_all();
+            }
         }
     }
 }
@@ -519,6 +1233,645 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_commit_conflict() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        let tx = db.tx(true)?;
+        tx.create_bucket("abc")?;
+        // Pretend another writer committed behind our back
+        {
+            let mut buf = std::fs::read(&random_file)?;
+            for i in 0..2 {
+                #[allow(clippy::cast_ptr_alignment)]
+                let page =
+                    unsafe { &mut *(&mut buf[i * pagesize as usize] as *mut u8 as *mut Page) };
+                let m = page.meta_mut();
+                m.tx_id += 5;
+                m.hash = m.hash_self();
+            }
+            let mut file = std::fs::OpenOptions::new().write(true).open(&random_file)?;
+            file.write_all(&buf[..2 * pagesize as usize])?;
+            file.sync_all()?;
+        }
+        assert_eq!(tx.commit(), Err(Error::Conflict));
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc").err(), Some(Error::BucketMissing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_cycles() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.get_bucket("abc")?.root_page();
+            root_page
+        };
+        // Point one of the branches back at the bucket's own root
+        let mut buf = std::fs::read(&random_file)?;
+        let offset = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            &page.branch_elements()[1].page as *const PageID as usize - buf.as_ptr() as usize
+        };
+        buf[offset..offset + 8].copy_from_slice(&root_page.to_ne_bytes());
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        let cycle = Error::InvalidDB(format!("cycle detected at page {}", root_page));
+        assert_eq!(db.count_all_kv(), Err(cycle.duplicate()));
+        assert!(db.check().is_err());
+        let tx = db.tx(true)?;
+        assert_eq!(tx.get_bucket("abc")?.compact(), Err(cycle.duplicate()));
+        assert_eq!(tx.delete_bucket("abc"), Err(cycle));
+        // nothing was deleted
+        assert!(tx.get_bucket("abc").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_committed_kv_page_bounds() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.get_bucket("abc")?.root_page();
+            root_page
+        };
+        // Point one of the branches far past the end of the file
+        let mut buf = std::fs::read(&random_file)?;
+        let offset = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            &page.branch_elements()[1].page as *const PageID as usize - buf.as_ptr() as usize
+        };
+        let bad_page: PageID = 1 << 40;
+        buf[offset..offset + 8].copy_from_slice(&bad_page.to_ne_bytes());
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        let out_of_bounds = Error::InvalidDB(format!("Page {} is out of bounds", bad_page));
+        assert_eq!(db.count_all_kv(), Err(out_of_bounds.duplicate()));
+        assert_eq!(db.suggest_pagesize(), Err(out_of_bounds));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_bucket_root_bounds() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?;
+            tx.commit()?;
+            db.check()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.inner.borrow().meta.root.root_page;
+            root_page
+        };
+        // Point the "abc" bucket somewhere past the end of the file
+        let mut buf = std::fs::read(&random_file)?;
+        let offset = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            let leaf = &page.leaf_elements()[0];
+            assert_eq!(leaf.key(), b"abc");
+            leaf.value().as_ptr() as usize - buf.as_ptr() as usize
+        };
+        buf[offset..offset + 8].copy_from_slice(&9999_u64.to_ne_bytes());
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        assert_eq!(
+            db.check(),
+            Err(Error::InvalidDB(format!(
+                "Bucket \"abc\" on page {} has out of bounds root page 9999",
+                root_page
+            )))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_branch_bounds() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()?;
+            db.check()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.get_bucket("abc")?.root_page();
+            root_page
+        };
+        let original = std::fs::read(&random_file)?;
+        let (offset, key) = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe {
+                &*(&original[(root_page * pagesize) as usize] as *const u8 as *const Page)
+            };
+            assert_eq!(page.page_type, Page::TYPE_BRANCH);
+            let elem = &page.branch_elements()[1];
+            (
+                &elem.page as *const PageID as usize - original.as_ptr() as usize,
+                elem.key().to_vec(),
+            )
+        };
+        // Point the branch at a meta page, and then past the end of the file
+        for bad_page in [0, 9999_u64] {
+            let mut buf = original.clone();
+            buf[offset..offset + 8].copy_from_slice(&bad_page.to_ne_bytes());
+            std::fs::write(&random_file, buf)?;
+
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            assert_eq!(
+                db.check(),
+                Err(Error::InvalidDB(format!(
+                    "Branch page {} points to out of bounds page {}",
+                    root_page, bad_page
+                )))
+            );
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(
+                b.validate(),
+                Err(Error::InvalidDB(format!(
+                    "Page {} is out of bounds",
+                    bad_page
+                )))
+            );
+            // Reading through the branch fails instead of following it
+            let err = Error::InvalidDB(format!(
+                "Branch page {} points to out of bounds page {}",
+                root_page, bad_page
+            ));
+            assert_eq!(b.try_get(&key).err(), Some(err.duplicate()));
+            // and the methods that can't return an error panic with it
+            let panics = |f: &dyn Fn()| {
+                let payload =
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+                assert_eq!(payload.downcast_ref::<String>(), Some(&err.to_string()));
+            };
+            panics(&|| {
+                b.get(&key);
+            });
+            panics(&|| {
+                b.cursor().seek(&key);
+            });
+            panics(&|| {
+                b.cursor().for_each(drop);
+            });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_file_size() -> Result<()> {
+        let random_file = RandomFile::new();
+        let max_file_size = 1024 * 1024;
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(32)
+            .max_file_size(max_file_size)
+            .open(&random_file)?;
+        let fill = |start: u64, count: u64| -> Result<()> {
+            let tx = db.tx(true)?;
+            let b = tx.get_or_create_bucket("abc")?;
+            for i in start..start + count {
+                b.put(i.to_be_bytes(), vec![0; 100])?;
+            }
+            tx.commit()
+        };
+        fill(0, 1000)?;
+        // the file grows, but not by a whole chunk
+        assert_eq!(random_file.path.metadata()?.len(), max_file_size);
+        assert_eq!(fill(1000, 10_000), Err(Error::DatabaseFull));
+        assert_eq!(random_file.path.metadata()?.len(), max_file_size);
+        db.check()?;
+        {
+            let tx = db.tx(false)?;
+            assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 1000);
+        }
+        // smaller writes still fit
+        fill(1000, 100)?;
+        db.check()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_file_range() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .sync_file_range(true)
+                .open(&random_file)?;
+            // the first commits grow the file, the rest fit in it
+            for i in 0..20_u64 {
+                let tx = db.tx(true)?;
+                let b = tx.get_or_create_bucket("abc")?;
+                for j in 0..100_u64 {
+                    b.put((i * 100 + j).to_be_bytes(), vec![0; 100])?;
+                }
+                tx.commit()?;
+            }
+            // pages written early by flush_dirty are synced along with the rest of the commit
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            for j in 0..100_u64 {
+                b.put(j.to_be_bytes(), vec![1; 100])?;
+            }
+            tx.flush_dirty()?;
+            let flushed = tx.inner.borrow().written_pages.len();
+            assert!(flushed > 0);
+            b.put(0_u64.to_be_bytes(), vec![2; 100])?;
+            assert!(!tx.inner.borrow().resized);
+            tx.commit()?;
+            db.check()?;
+        }
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        db.check()?;
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 2000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pages_accessed() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..2000_u64 {
+                b.put(i.to_be_bytes(), i.to_be_bytes())?;
+            }
+            tx.commit()?;
+            // not tracked by default
+            let tx = db.tx(false)?;
+            tx.get_bucket("abc")?.get(5_u64.to_be_bytes());
+            assert_eq!(tx.pages_accessed(), 0);
+        }
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .track_page_access(true)
+            .open(&random_file)?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        let before = tx.pages_accessed();
+        assert!(before > 0);
+        b.get(5_u64.to_be_bytes());
+        let after = tx.pages_accessed();
+        assert!(after > before);
+        // the same pages again don't count
+        b.get(6_u64.to_be_bytes());
+        assert_eq!(tx.pages_accessed(), after);
+        // a full scan reads every leaf
+        assert_eq!(b.kv_pairs().count(), 2000);
+        assert!(tx.pages_accessed() > after + 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_freed_for() -> Result<()> {
+        // the root page of the "abc" bucket after each of a series of small commits
+        let roots = |keep_freed_for: u64| -> Result<Vec<u64>> {
+            let random_file = RandomFile::new();
+            let db = OpenOptions::new()
+                .keep_freed_for(keep_freed_for)
+                .open(&random_file)?;
+            let mut roots = Vec::new();
+            for i in 0..10_u64 {
+                let tx = db.tx(true)?;
+                tx.get_or_create_bucket("abc")?
+                    .put(i.to_be_bytes(), "value")?;
+                tx.commit()?;
+                roots.push(db.tx(false)?.get_bucket("abc")?.root_page());
+            }
+            db.check()?;
+            Ok(roots)
+        };
+        // by default, a page freed by one commit is reused soon after
+        let default_roots = roots(0)?;
+        assert!(default_roots.windows(5).any(|w| w[1..].contains(&w[0])));
+        // with three transactions of history, a root page is not reused by any of the next four commits
+        let kept_roots = roots(3)?;
+        for w in kept_roots.windows(5) {
+            assert!(!w[1..].contains(&w[0]), "{:?}", kept_roots);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_buckets() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let a = tx.create_bucket("a")?;
+            let b = tx.create_bucket("b")?;
+            let c = tx.create_bucket("c")?;
+            for i in 0..300_u64 {
+                match i % 3 {
+                    0 => a.put(i.to_be_bytes(), "a")?,
+                    1 => b.put(i.to_be_bytes(), "b")?,
+                    _ => c.put(i.to_be_bytes(), "c")?,
+                };
+            }
+            // the same key in two buckets
+            a.put(1_u64.to_be_bytes(), "tie")?;
+            c.create_bucket("nested")?;
+            tx.create_bucket("empty")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let merged: Vec<(usize, Vec<u8>, Vec<u8>)> = tx
+            .merge_buckets(&["c", "b", "empty", "a"])?
+            .map(|(i, kv)| (i, kv.key().to_vec(), kv.value().to_vec()))
+            .collect();
+        assert_eq!(merged.len(), 301);
+        assert_eq!(
+            merged[..4],
+            [
+                (3, 0_u64.to_be_bytes().to_vec(), b"a".to_vec()),
+                (1, 1_u64.to_be_bytes().to_vec(), b"b".to_vec()),
+                (3, 1_u64.to_be_bytes().to_vec(), b"tie".to_vec()),
+                (0, 2_u64.to_be_bytes().to_vec(), b"c".to_vec()),
+            ]
+        );
+        assert!(merged.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        assert_eq!(tx.merge_buckets::<&[u8]>(&[])?.count(), 0);
+        assert!(matches!(
+            tx.merge_buckets(&["a", "missing"]),
+            Err(Error::BucketMissing)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_dirty() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .num_pages(4)
+            .strict_mode(true)
+            // far less than the whole load needs
+            .max_tx_memory(256 * 1024)
+            .open(&random_file)?;
+        {
+            let tx = db.tx(false)?;
+            assert_eq!(tx.flush_dirty(), Err(Error::ReadOnlyTx));
+        }
+        let load = |tx: &Tx| -> Result<()> {
+            let b = tx.get_or_create_bucket("abc")?;
+            let nested = b.get_or_create_bucket("nested")?;
+            for i in 0..20_000_u64 {
+                b.put(i.to_be_bytes(), [1; 16])?;
+                if i % 100 == 0 {
+                    nested.put(i.to_be_bytes(), i.to_le_bytes())?;
+                }
+                if i % 1000 == 999 {
+                    tx.flush_dirty()?;
+                    // flushed data can still be read and changed
+                    assert_eq!(b.get_kv(i.to_be_bytes()).unwrap().value(), [1; 16]);
+                    b.delete((i - 1).to_be_bytes())?;
+                }
+            }
+            Ok(())
+        };
+        {
+            let tx = db.tx(true)?;
+            load(&tx)?;
+            // dropped without committing
+        }
+        db.check()?;
+        assert_eq!(
+            db.tx(false)?.get_bucket("abc").err(),
+            Some(Error::BucketMissing)
+        );
+        {
+            let tx = db.tx(true)?;
+            load(&tx)?;
+            tx.commit()?;
+        }
+        db.check()?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        assert_eq!(b.kv_pairs().count(), 20_000 - 20);
+        assert!(b.get(998_u64.to_be_bytes()).is_none());
+        assert_eq!(b.get_bucket("nested")?.kv_pairs().count(), 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_overlapping_pages() -> Result<()> {
+        let random_file = RandomFile::new();
+        let pagesize = 1024;
+        let root_page = {
+            let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+            let tx = db.tx(true)?;
+            for name in ["abc", "def", "ghi"] {
+                tx.create_bucket(name)?.put("key", "value")?;
+            }
+            tx.commit()?;
+            db.check()?;
+            let tx = db.tx(false)?;
+            let root_page = tx.inner.borrow().meta.root.root_page;
+            root_page
+        };
+        // Point "def" and "ghi" at the same page as "abc"
+        let mut buf = std::fs::read(&random_file)?;
+        let (abc_root, offsets) = {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page =
+                unsafe { &*(&buf[(root_page * pagesize) as usize] as *const u8 as *const Page) };
+            let leaves = page.leaf_elements();
+            let abc: BucketMeta = leaves[0].value().into();
+            let offsets: Vec<usize> = leaves[1..]
+                .iter()
+                .map(|leaf| leaf.value().as_ptr() as usize - buf.as_ptr() as usize)
+                .collect();
+            (abc.root_page, offsets)
+        };
+        for offset in offsets {
+            buf[offset..offset + 8].copy_from_slice(&abc_root.to_ne_bytes());
+        }
+        std::fs::write(&random_file, buf)?;
+
+        let db = OpenOptions::new().pagesize(pagesize).open(&random_file)?;
+        assert_eq!(
+            db.check(),
+            Err(Error::InvalidDB(format!(
+                "Overlapping pages: \
+                page {0} is used by both the page referenced by bucket \"ghi\" on page {1} \
+                and the page referenced by bucket \"def\" on page {1}; \
+                page {0} is used by both the page referenced by bucket \"ghi\" on page {1} \
+                and the page referenced by bucket \"abc\" on page {1}",
+                abc_root, root_page
+            )))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_auto_rebalance() -> Result<()> {
+        // Fill up a bucket then delete most of its data, returning the number of pages used
+        fn run(auto_rebalance: bool) -> Result<u64> {
+            let random_file = RandomFile::new();
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .auto_rebalance(auto_rebalance)
+                .strict_mode(true)
+                .open(&random_file)?;
+            {
+                let tx = db.tx(true)?;
+                let b = tx.create_bucket("abc")?;
+                for i in 0..1000_u64 {
+                    b.put(i.to_be_bytes(), i.to_be_bytes())?;
+                }
+                tx.commit()?;
+            }
+            {
+                let tx = db.tx(true)?;
+                let b = tx.get_bucket("abc")?;
+                for i in (0..1000_u64).filter(|i| i % 4 != 0) {
+                    b.delete(i.to_be_bytes())?;
+                }
+                tx.commit()?;
+            }
+            {
+                // emptying out whole nodes still works
+                let tx = db.tx(true)?;
+                let b = tx.get_bucket("abc")?;
+                for i in (0..500_u64).filter(|i| i % 4 == 0) {
+                    b.delete(i.to_be_bytes())?;
+                }
+                tx.commit()?;
+            }
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let keys: Vec<u64> = b
+                .kv_pairs()
+                .map(|kv| u64::from_be_bytes(kv.key().try_into().unwrap()))
+                .collect();
+            assert_eq!(
+                keys,
+                (500..1000_u64).filter(|i| i % 4 == 0).collect::<Vec<u64>>()
+            );
+            let num_pages = tx.inner.borrow().meta.num_pages;
+            Ok(num_pages)
+        }
+        // underfull nodes are left alone, so new pages were needed to write them
+        assert!(run(false)? > run(true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_threshold() -> Result<()> {
+        // Delete most of a bucket's data, returning the number of leaf pages it has left
+        fn run(merge_threshold: f32) -> Result<usize> {
+            let random_file = RandomFile::new();
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .merge_threshold(merge_threshold)
+                .strict_mode(true)
+                .open(&random_file)?;
+            {
+                let tx = db.tx(true)?;
+                let b = tx.create_bucket("abc")?;
+                for i in 0..1000_u64 {
+                    b.put(i.to_be_bytes(), i.to_be_bytes())?;
+                }
+                tx.commit()?;
+            }
+            {
+                let tx = db.tx(true)?;
+                let b = tx.get_bucket("abc")?;
+                for i in (0..1000_u64).filter(|i| i % 5 != 0) {
+                    b.delete(i.to_be_bytes())?;
+                }
+                tx.commit()?;
+            }
+            let tx = db.tx(false)?;
+            assert_eq!(tx.get_bucket("abc")?.kv_pairs().count(), 200);
+            let mut graph = Vec::new();
+            tx.write_graph(&mut graph)?;
+            Ok(String::from_utf8(graph)
+                .unwrap()
+                .matches("leaf page")
+                .count())
+        }
+        let default = run(0.25)?;
+        // nodes end up about a tenth of a page full, so only the default merges them
+        assert!(run(0.05)? > default);
+        assert!(run(0.0)? > default);
+        assert!(run(0.45)? <= default);
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "Merge threshold must be at least 0.0 and less than 0.5")]
+    fn test_merge_threshold_too_large() {
+        let _ = OpenOptions::new().merge_threshold(0.5);
+    }
+
+    #[test]
+    fn test_arena_bytes() -> Result<()> {
+        let random_file = RandomFile::new();
+        let limit = 64 * 1024;
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .max_tx_memory(limit)
+            .open(&random_file)?;
+        let tx = db.tx(true)?;
+        assert_eq!(tx.arena_bytes(), 0);
+        let b = tx.create_bucket("abc")?;
+        // far more than the limit in total, but the space is reused after every flush
+        for round in 0..100_u64 {
+            for i in 0..10_u64 {
+                b.put((round * 10 + i).to_be_bytes(), vec![0; 100])?;
+            }
+            tx.flush_dirty()?;
+            assert!(tx.arena_bytes() <= limit);
+        }
+        for i in 1000..1200_u64 {
+            b.put(i.to_be_bytes(), vec![0; 1000])?;
+        }
+        // but not if too much is buffered at once
+        assert_eq!(tx.flush_dirty(), Err(Error::TransactionTooLarge));
+        assert!(tx.arena_bytes() > 0);
+        Ok(())
+    }
+
     #[test]
     fn test_concurrent_txs() -> Result<()> {
         let random_file = RandomFile::new();
@@ -537,7 +1890,7 @@ mod tests {
             {
                 let open_ro_txs = tx.db.inner.open_ro_txs.lock().unwrap();
                 assert_eq!(open_ro_txs.len(), 1);
-                assert_eq!(open_ro_txs[0], tx.meta.tx_id);
+                assert_eq!(open_ro_txs[0], (tx.meta.tx_id, tx.reader));
             }
             {
                 // create a writable transaction while the read-only transaction is still open
@@ -612,4 +1965,213 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_readers_dont_wait() -> Result<()> {
+        use std::sync::{mpsc, Barrier};
+        use std::{thread, time::Duration};
+
+        let random_file = RandomFile::new();
+        let db = DB::open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        // hold the writer's lock and the shared freelist while the readers start
+        let write_tx = db.tx(true)?;
+        write_tx.get_bucket("abc")?.put("key", "new value")?;
+        let freelist = db.inner.freelist.lock()?;
+
+        let num_readers = 8;
+        // every reader holds its transaction until all of them have one open
+        let barrier = Arc::new(Barrier::new(num_readers));
+        let (done, wait_for_done) = mpsc::channel();
+        let readers: Vec<_> = (0..num_readers)
+            .map(|_| {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let tx = db.tx(false).unwrap();
+                    let b = tx.get_bucket("abc").unwrap();
+                    barrier.wait();
+                    assert_eq!(b.get_kv("key").unwrap().value(), b"value");
+                    done.send(()).unwrap();
+                })
+            })
+            .collect();
+        for _ in 0..num_readers {
+            wait_for_done
+                .recv_timeout(Duration::from_secs(10))
+                .expect("a reader was blocked");
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        drop(freelist);
+        write_tx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writer() -> Result<()> {
+        use std::thread;
+
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            b.put("a", 0_u64.to_be_bytes())?;
+            b.put("b", 0_u64.to_be_bytes())?;
+            tx.commit()?;
+        }
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 1..=200_u64 {
+                    let tx = db.tx(true)?;
+                    let b = tx.get_bucket("abc")?;
+                    b.put("a", i.to_be_bytes())?;
+                    // grow the file every so often, so readers see remaps too
+                    b.put(i.to_be_bytes(), vec![0; 512])?;
+                    b.put("b", i.to_be_bytes())?;
+                    tx.commit()?;
+                }
+                Ok(())
+            })
+        };
+        let readers: Vec<_> = (0..16)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || -> Result<()> {
+                    let mut last = 0;
+                    for _ in 0..200 {
+                        let tx = db.tx(false)?;
+                        let b = tx.get_bucket("abc")?;
+                        let a = b.get_kv("a").unwrap().as_u64().unwrap();
+                        // every commit changes both, so a transaction must see them match
+                        assert_eq!(Some(a), b.get_kv("b").unwrap().as_u64());
+                        assert!(a >= last);
+                        last = a;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        writer.join().unwrap()?;
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        assert!(db.open_read_tx_ids()?.is_empty());
+        db.check()
+    }
+
+    #[test]
+    fn test_write_graph() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(format!("key-{:03}", i), vec![0; 64])?;
+            }
+            b.create_bucket("say \"hi\"")?.put("a", "b")?;
+            tx.commit()?;
+        }
+        let tx = db.tx(false)?;
+        let mut out = Vec::new();
+        tx.write_graph(&mut out)?;
+        let graph = String::from_utf8(out).unwrap();
+
+        assert!(graph.starts_with("digraph jammdb {\n"));
+        assert!(graph.ends_with("}\n"));
+        let abc = tx.get_bucket("abc")?.root_page();
+        assert!(graph.contains(&format!(
+            "root -> page_{};",
+            tx.inner.borrow().meta.root.root_page
+        )));
+        assert!(graph.contains(&format!("label=\"branch page {}\\n", abc)));
+        assert!(graph.contains("\\\"key-000\\\" to "));
+        let nested = tx.get_bucket_path(&["abc", "say \"hi\""])?.root_page();
+        assert!(graph.contains(&format!(
+            " -> page_{} [label=\"say \\\"hi\\\"\", style=dashed];",
+            nested
+        )));
+        assert!(graph.contains(&format!(
+            "page_{} [label=\"leaf page {}\\n1 elements\\n\\\"a\\\" to \\\"a\\\"\"];",
+            nested, nested
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_to() -> Result<()> {
+        let random_file = RandomFile::new();
+        let copy_file = RandomFile::new();
+        let db = OpenOptions::new().pagesize(1024).open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            b.create_bucket("nested")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        db.set_metadata(b"my-app")?;
+        let expected = db.tx(false)?.get_bucket("abc")?.to_btreemap();
+
+        let tx = db.tx(false)?;
+        // changes committed while the copy is made aren't in it
+        {
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.put("late", "value")?;
+            tx.delete_bucket("abc")?;
+            tx.commit()?;
+        }
+        let mut buf = Vec::new();
+        let written = tx.write_to(&mut buf)?;
+        assert_eq!(written, buf.len() as u64);
+        drop(tx);
+        std::fs::write(&copy_file, &buf)?;
+
+        let copy = OpenOptions::new().pagesize(1024).open(&copy_file)?;
+        copy.check()?;
+        assert_eq!(copy.metadata()?, b"my-app");
+        let tx = copy.tx(false)?;
+        assert_eq!(tx.get_bucket("abc")?.to_btreemap(), expected);
+        assert_eq!(
+            tx.get_bucket_path(&["abc", "nested"])?
+                .get_kv("key")
+                .unwrap()
+                .value(),
+            b"value"
+        );
+        drop(tx);
+        // the copy can be written to
+        let tx = copy.tx(true)?;
+        tx.get_bucket("abc")?.put("new", "value")?;
+        tx.commit()?;
+        copy.check()?;
+
+        // a writable transaction copies what was last committed
+        let tx = db.tx(true)?;
+        tx.create_bucket("uncommitted")?;
+        let mut buf = Vec::new();
+        tx.write_to(&mut buf)?;
+        drop(tx);
+        drop(copy);
+        std::fs::write(&copy_file, &buf)?;
+        let copy = OpenOptions::new().pagesize(1024).open(&copy_file)?;
+        let tx = copy.tx(false)?;
+        assert_eq!(tx.get_bucket("abc").err(), Some(Error::BucketMissing));
+        assert_eq!(
+            tx.get_bucket("uncommitted").err(),
+            Some(Error::BucketMissing)
+        );
+        Ok(())
+    }
 }
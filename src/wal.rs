@@ -0,0 +1,434 @@
+use std::{
+    fs::{File, OpenOptions as FileOpenOptions},
+    hash::Hasher,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use fnv::FnvHasher;
+
+use crate::{
+    bucket::{Bucket, Difference},
+    data::ChangeKind,
+    db::DB,
+    errors::{Error, Result},
+    tx::Tx,
+};
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_CREATE_BUCKET: u8 = 3;
+const OP_DELETE_BUCKET: u8 = 4;
+
+// Each frame is the transaction ID and the length of its records, then the records, then a hash of all of it
+const FRAME_HEADER_SIZE: u64 = 16;
+const FRAME_OVERHEAD: u64 = FRAME_HEADER_SIZE + 8;
+
+// An append-only log of the changes made by every committed transaction.
+pub(crate) struct Wal {
+    file: File,
+    // Length of the log up to the last transaction that was actually committed
+    len: u64,
+    // Whether anything was appended since the log was last synced
+    unsynced: bool,
+}
+
+impl Wal {
+    // Opens the log, throwing away everything from the first frame that was only partly written,
+    // doesn't match its hash, or doesn't belong to a transaction after the one before it
+    // but no newer than the database's last commit.
+    pub(crate) fn open(path: &Path, tx_id: u64) -> Result<Wal> {
+        let mut file = FileOpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut len = 0;
+        let mut last_tx_id = None;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&file);
+        while len + FRAME_OVERHEAD <= file_len {
+            let mut header = [0; FRAME_HEADER_SIZE as usize];
+            reader.read_exact(&mut header)?;
+            let (frame_tx_id, size) = parse_header(&header);
+            let end = match (len + FRAME_OVERHEAD).checked_add(size) {
+                Some(end) if end <= file_len => end,
+                _ => break,
+            };
+            if frame_tx_id > tx_id || last_tx_id.map_or(false, |last| frame_tx_id <= last) {
+                break;
+            }
+            let mut records = Vec::with_capacity(size as usize);
+            reader.by_ref().take(size).read_to_end(&mut records)?;
+            let mut hash = [0; 8];
+            reader.read_exact(&mut hash)?;
+            if u64::from_be_bytes(hash) != frame_hash(frame_tx_id, &records) {
+                break;
+            }
+            last_tx_id = Some(frame_tx_id);
+            len = end;
+        }
+        drop(reader);
+        if len != file_len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        Ok(Wal {
+            file,
+            len,
+            unsynced: false,
+        })
+    }
+
+    // Writes the records for a transaction that is about to be committed.
+    // They aren't synced until sync is called, which follows the database's sync mode.
+    pub(crate) fn append(&mut self, tx_id: u64, records: &[u8]) -> Result<()> {
+        // If the last transaction that was logged failed to commit, replace its frame
+        if self.file.metadata()?.len() != self.len {
+            self.file.set_len(self.len)?;
+        }
+        let mut frame = Vec::with_capacity(records.len() + FRAME_OVERHEAD as usize);
+        frame.extend_from_slice(&tx_id.to_be_bytes());
+        frame.extend_from_slice(&(records.len() as u64).to_be_bytes());
+        frame.extend_from_slice(records);
+        frame.extend_from_slice(&frame_hash(tx_id, records).to_be_bytes());
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&frame)?;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    // Makes sure every frame that was appended is on disk.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Marks the last frame that was appended as committed.
+    pub(crate) fn committed(&mut self) -> Result<()> {
+        self.len = self.file.stream_position()?;
+        Ok(())
+    }
+}
+
+fn parse_header(header: &[u8; FRAME_HEADER_SIZE as usize]) -> (u64, u64) {
+    let (tx_id, size) = header.split_at(8);
+    (
+        u64::from_be_bytes(tx_id.try_into().unwrap()),
+        u64::from_be_bytes(size.try_into().unwrap()),
+    )
+}
+
+fn frame_hash(tx_id: u64, records: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&tx_id.to_be_bytes());
+    hasher.write(records);
+    hasher.finish()
+}
+
+// Finds the changes between two versions of a bucket, encoded as log records.
+// A bucket that was added is logged along with everything in it, while one that was removed
+// is deleted with a single record.
+pub(crate) fn changes(old: &Bucket, new: &Bucket) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    old.diff(new, &mut Vec::new(), &mut |path, difference| {
+        Ok(match difference {
+            Difference::Pair(key, _, ChangeKind::Removed) => {
+                write_record(&mut records, OP_DELETE, path, key, &[]);
+                false
+            }
+            Difference::Pair(key, value, _) => {
+                write_record(&mut records, OP_PUT, path, key, value);
+                false
+            }
+            Difference::Bucket(name, ChangeKind::Removed) => {
+                write_record(&mut records, OP_DELETE_BUCKET, path, name, &[]);
+                false
+            }
+            Difference::Bucket(name, _) => {
+                write_record(&mut records, OP_CREATE_BUCKET, path, name, &[]);
+                true
+            }
+        })
+    })?;
+    Ok(records)
+}
+
+fn write_record(records: &mut Vec<u8>, op: u8, path: &[Vec<u8>], key: &[u8], value: &[u8]) {
+    records.push(op);
+    records.extend_from_slice(&(path.len() as u64).to_be_bytes());
+    for name in path {
+        write_bytes(records, name);
+    }
+    write_bytes(records, key);
+    write_bytes(records, value);
+}
+
+fn write_bytes(records: &mut Vec<u8>, bytes: &[u8]) {
+    records.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    records.extend_from_slice(bytes);
+}
+
+// Applies every complete frame in the log to `dest`, one transaction per frame.
+pub(crate) fn replay(path: &Path, dest: &DB) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    loop {
+        let mut header = [0; FRAME_HEADER_SIZE as usize];
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            // The last frame may have been cut off while it was being written
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let (tx_id, size) = parse_header(&header);
+        let mut records = Vec::new();
+        reader.by_ref().take(size).read_to_end(&mut records)?;
+        let mut hash = [0; 8];
+        if records.len() as u64 != size || reader.read_exact(&mut hash).is_err() {
+            break;
+        }
+        if u64::from_be_bytes(hash) != frame_hash(tx_id, &records) {
+            return Err(Error::InvalidDB(format!(
+                "Log frame for transaction {} is corrupt",
+                tx_id
+            )));
+        }
+        let tx = dest.tx(true)?;
+        apply(&tx, &records)?;
+        tx.commit()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn apply<'tx>(tx: &Tx<'tx>, mut records: &'tx [u8]) -> Result<()> {
+    while !records.is_empty() {
+        let op = take(&mut records, 1)?[0];
+        let mut path = Vec::new();
+        for _ in 0..read_u64(&mut records)? {
+            path.push(read_bytes(&mut records)?.to_vec());
+        }
+        let key = read_bytes(&mut records)?;
+        let value = read_bytes(&mut records)?;
+        let result = match op {
+            OP_PUT => tx
+                .get_or_create_bucket_path(&path)
+                .and_then(|b| b.put(key, value).map(|_| ())),
+            OP_DELETE => tx
+                .get_bucket_path(&path)
+                .and_then(|b| b.delete(key).map(|_| ())),
+            OP_CREATE_BUCKET => {
+                path.push(key.to_vec());
+                tx.get_or_create_bucket_path(&path).map(|_| ())
+            }
+            OP_DELETE_BUCKET if path.is_empty() => tx.delete_bucket(key),
+            OP_DELETE_BUCKET => tx.get_bucket_path(&path).and_then(|b| b.delete_bucket(key)),
+            op => return Err(Error::InvalidDB(format!("Unknown log record type {}", op))),
+        };
+        match result {
+            // Something that is already gone doesn't need to be deleted
+            Ok(()) | Err(Error::BucketMissing) | Err(Error::KeyValueMissing) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn take<'a>(records: &mut &'a [u8], len: u64) -> Result<&'a [u8]> {
+    if (records.len() as u64) < len {
+        return Err(Error::InvalidDB(String::from("Log record is truncated")));
+    }
+    let (head, rest) = records.split_at(len as usize);
+    *records = rest;
+    Ok(head)
+}
+
+fn read_u64(records: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(take(records, 8)?.try_into().unwrap()))
+}
+
+fn read_bytes<'a>(records: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = read_u64(records)?;
+    take(records, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testutil::RandomFile, OpenOptions, SyncMode};
+
+    #[test]
+    fn test_wal() -> Result<()> {
+        let random_file = RandomFile::new();
+        let wal_file = RandomFile::new();
+        let replica_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .wal(Some(wal_file.path.clone()))
+            .open(&random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            b.create_bucket("nested")?.put("a", "b")?;
+            tx.create_bucket("empty")?;
+            tx.create_bucket("doomed")?.put("x", "y")?;
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            let b = tx.get_bucket("abc")?;
+            b.retain(|k, _| k[7] % 2 == 0)?;
+            b.put(2_u64.to_be_bytes(), "changed")?;
+            b.put_force("nested", "now a value")?;
+            tx.delete_bucket("doomed")?;
+            tx.commit()?;
+        }
+        let replica = DB::open(&replica_file)?;
+        assert_eq!(DB::replay_wal_into(&wal_file, &replica)?, 2);
+        let (tx, replica_tx) = (db.tx(false)?, replica.tx(false)?);
+        assert!(tx.logically_eq(&replica_tx)?);
+        assert_eq!(replica_tx.get_bucket("abc")?.kv_pairs().count(), 51);
+        assert!(replica_tx.get_bucket("empty").is_ok());
+        assert!(replica_tx.get_bucket("doomed").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_sync_mode() -> Result<()> {
+        let random_file = RandomFile::new();
+        let wal_file = RandomFile::new();
+        let unsynced = |sync_mode: SyncMode| -> Result<bool> {
+            let db = OpenOptions::new()
+                .sync_mode(sync_mode)
+                .wal(Some(wal_file.path.clone()))
+                .open(&random_file)?;
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("abc")?
+                .put("key", format!("{:?}", sync_mode))?;
+            tx.commit()?;
+            let wal = db.inner.wal.as_ref().unwrap();
+            let unsynced = wal.lock()?.unsynced;
+            // syncing the database syncs the log too
+            db.sync()?;
+            assert!(!wal.lock()?.unsynced);
+            Ok(unsynced)
+        };
+        // the log is only synced when the database is
+        assert!(!unsynced(SyncMode::Always)?);
+        assert!(unsynced(SyncMode::Never)?);
+        assert!(unsynced(SyncMode::EveryNCommits(2))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_trimmed_on_open() -> Result<()> {
+        let random_file = RandomFile::new();
+        let wal_file = RandomFile::new();
+        let replica_file = RandomFile::new();
+        let open = || {
+            OpenOptions::new()
+                .wal(Some(wal_file.path.clone()))
+                .open(&random_file)
+        };
+        {
+            let db = open()?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("a", "b")?;
+            tx.commit()?;
+        }
+        let len = std::fs::metadata(&wal_file)?.len();
+        {
+            // a frame for a transaction that never made it into the database, then half a frame
+            let mut wal = Wal::open(&wal_file.path, u64::MAX)?;
+            let mut records = Vec::new();
+            write_record(&mut records, OP_DELETE_BUCKET, &[], b"abc", &[]);
+            wal.append(100, &records)?;
+            wal.file.write_all(&[1, 2, 3])?;
+        }
+        // replay ignores the partial frame but applies the complete one
+        {
+            let replica = DB::open(&replica_file)?;
+            assert_eq!(DB::replay_wal_into(&wal_file, &replica)?, 2);
+            assert!(replica.tx(false)?.get_bucket("abc").is_err());
+        }
+        let db = open()?;
+        assert_eq!(std::fs::metadata(&wal_file)?.len(), len);
+        {
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.put("c", "d")?;
+            tx.commit()?;
+        }
+        std::fs::remove_file(&replica_file)?;
+        let replica = DB::open(&replica_file)?;
+        assert_eq!(DB::replay_wal_into(&wal_file, &replica)?, 2);
+        let (tx, replica_tx) = (db.tx(false)?, replica.tx(false)?);
+        assert!(tx.logically_eq(&replica_tx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_zero_filled_tail() -> Result<()> {
+        let random_file = RandomFile::new();
+        let wal_file = RandomFile::new();
+        let replica_file = RandomFile::new();
+        let open = || {
+            OpenOptions::new()
+                .wal(Some(wal_file.path.clone()))
+                .open(&random_file)
+        };
+        {
+            let db = open()?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("a", "b")?;
+            tx.commit()?;
+        }
+        let len = std::fs::metadata(&wal_file)?.len();
+        {
+            // a crash can leave the end of the log filled with zeros
+            let mut file = FileOpenOptions::new().append(true).open(&wal_file)?;
+            file.write_all(&[0; 4096])?;
+        }
+        let db = open()?;
+        assert_eq!(std::fs::metadata(&wal_file)?.len(), len);
+        {
+            let tx = db.tx(true)?;
+            tx.get_bucket("abc")?.put("c", "d")?;
+            tx.commit()?;
+        }
+        let replica = DB::open(&replica_file)?;
+        assert_eq!(DB::replay_wal_into(&wal_file, &replica)?, 2);
+        let (tx, replica_tx) = (db.tx(false)?, replica.tx(false)?);
+        assert!(tx.logically_eq(&replica_tx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_unlogged_operations() -> Result<()> {
+        let random_file = RandomFile::new();
+        let wal_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .wal(Some(wal_file.path.clone()))
+            .open(&random_file)?;
+        for i in 0..2_u64 {
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("abc")?
+                .put(i.to_be_bytes(), "value")?;
+            tx.commit()?;
+        }
+        let unsupported = |result: Result<()>| matches!(result, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::Unsupported);
+        assert!(unsupported(db.rollback_to_previous()));
+        assert!(unsupported(db.compact_in_place()));
+        // reclaiming leaked pages is an ordinary commit
+        assert_eq!(db.reclaim_leaked()?, 0);
+        assert_eq!(db.tx(false)?.get_bucket("abc")?.kv_pairs().count(), 2);
+        Ok(())
+    }
+}
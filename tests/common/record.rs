@@ -227,7 +227,7 @@ impl FakeNode {
                 if b.get(&name).is_none() {
                     b.insert(name.clone(), FakeNode::Bucket(BTreeMap::new()));
                 }
-                return b.get_mut(&name).unwrap();
+                b.get_mut(&name).unwrap()
             }
             Self::Value(_) => unreachable!(),
         }
@@ -319,9 +319,11 @@ impl Instructions {
 
 impl Drop for Instructions {
     fn drop(&mut self) {
-        if self.delete && self.path.is_some() {
-            self.f = None;
-            let _ = std::fs::remove_file(self.path.as_ref().unwrap());
+        if self.delete {
+            if let Some(path) = &self.path {
+                self.f = None;
+                let _ = std::fs::remove_file(path);
+            }
         }
     }
 }
@@ -416,7 +418,7 @@ pub fn log_playback(name: &str) -> Result<(), Error> {
     Ok(())
 }
 
-fn mutate_buckets<F>(tx: &Tx, root: &mut FakeNode, path: &Vec<Bytes>, f: F) -> Result<(), Error>
+fn mutate_buckets<F>(tx: &Tx, root: &mut FakeNode, path: &[Bytes], f: F) -> Result<(), Error>
 where
     F: Fn(&Bucket, &mut BTreeMap<Bytes, FakeNode>) -> Result<(), Error>,
 {